@@ -1,7 +1,15 @@
 #![allow(dead_code)]  // the code it warns about is not actually dead, so...
 
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::cmp::{Less, Equal, Greater};
+use std::collections::{HashMap, RingBuf};
+use std::f64;
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use bytecode;
+use hash;
 
 static INDENTATION: uint = 2;
 
@@ -15,12 +23,45 @@ pub enum ExprAst {
    Pointer(PointerAst),
    Ident(IdentAst),
    Symbol(SymbolAst),
+   Keyword(KeywordAst),
    Integer(IntegerAst),
    Float(FloatAst),
    Boolean(BooleanAst),
    Nil(NilAst),
    Comment(CommentAst),
-   Code(CodeAst)
+   Code(CodeAst),
+   Error(ErrorAst),
+   Tuple(TupleAst),
+   Map(MapAst),
+   SortedMap(SortedMapAst),
+   Set(SetAst),
+   Queue(QueueAst),
+   WeakRef(WeakAst),
+   Local(LocalAst),
+   StringBuilder(StringBuilderAst),
+   Matrix(MatrixAst),
+   IntArray(IntArrayAst),
+   FloatArray(FloatArrayAst)
+}
+
+// A pass that looks at every node of a tree (see ExprAst::walk).  The children of a node
+// are the expressions written inside it: the forms of a root, the operands of a call
+// (but not its operator), the items of lists, arrays, tuples and sets, the keys and values
+// of maps, and the data of an error.
+pub trait Visitor {
+   // Called on a node before its children, which are skipped if this returns false.
+   fn visit(&mut self, _: &ExprAst) -> bool { true }
+
+   // Called on a node after its children.
+   fn leave(&mut self, _: &ExprAst) {}
+}
+
+// A pass that rewrites a tree (see ExprAst::fold_children).
+pub trait Folder {
+   // Rewrites a node, by default by rewriting its children.
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      expr.fold_children(self)
+   }
 }
 
 pub trait Ast {
@@ -28,6 +69,9 @@ pub trait Ast {
    //fn eval(&self) -> Option<Box<Any>>;
    fn compile(&self) -> Vec<u8>;
 
+   // Produces source text that reads back as an equivalent AST.
+   fn to_source(&self) -> String;
+
    fn dump(&self) { self.dump_level(0) }
 
    // XXX: this should in actuality be private...
@@ -36,28 +80,90 @@ pub trait Ast {
 
 #[deriving(Clone, PartialEq)]
 pub struct RootAst {
-   pub asts: Vec<ExprAst>
+   pub asts: Vec<ExprAst>,
+   pub trivia: Trivia
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct SexprAst {
    pub op: IdentAst,
-   pub operands: Vec<ExprAst>
+   pub operands: Vec<ExprAst>,
+   pub span: Span,
+   pub trivia: Trivia,
+   // true for the (concat ...) that an interpolated string such as "x = ${x}" is read as,
+   // so that it is written back the same way
+   pub interpolated: bool,
+   pub cache: CallCache
 }
 
-#[deriving(Clone, PartialEq)]
+// The builtin a call site's operator was found to be, or the code a call of a macro
+// expanded to, kept so that later evaluations can skip looking it up or expanding it again
+// (see Interpreter::lookup_operator and Interpreter::expand_macro).  Copies of a node
+// share the cache, and it does not take part in comparisons.
+#[deriving(Clone)]
+pub struct CallCache {
+   // the context the lookup was made in, the number of builtin names that had been
+   // rebound in it at the time and the builtin
+   pub entry: Rc<RefCell<Option<(Weak<RefCell<::interp::Context>>, uint, ::interp::Builtin)>>>,
+   // the context the call was expanded in, the number of macros that had been defined in
+   // it at the time and the expansion
+   pub expansion: Rc<RefCell<Option<(Weak<RefCell<::interp::Context>>, uint, ExprAst)>>>
+}
+
+impl CallCache {
+   pub fn new() -> CallCache {
+      CallCache {
+         entry: Rc::new(RefCell::new(None)),
+         expansion: Rc::new(RefCell::new(None))
+      }
+   }
+}
+
+impl PartialEq for CallCache {
+   fn eq(&self, _: &CallCache) -> bool {
+      true
+   }
+}
+
+// Location of a node in its source file.  Line 0 means the node was not parsed from
+// source (e.g. it was constructed by the interpreter).
+#[deriving(Clone, Show)]
+pub struct Span {
+   pub line: uint,
+   pub column: uint,
+   pub start: uint,
+   pub end: uint
+}
+
+// The comments found between the items of a node, each stored with the index of the
+// item it precedes (or the number of items for comments after the last one).
+#[deriving(Clone, Show)]
+pub struct Trivia {
+   pub comments: Vec<(uint, String)>
+}
+
+// The text of a string is shared by the copies of the value, and by identical literals
+// once the intern-strings pass (see optimize.rs) has pooled them.
+#[deriving(Clone)]
 pub struct StringAst {
-   pub string: String
+   pub string: Rc<String>
+}
+
+// The strings seen so far, so that identical ones can share their text.
+pub struct StringPool {
+   strings: HashMap<String, Rc<String>>
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct ListAst {
-   pub items: Vec<ExprAst>
+   pub items: Vec<ExprAst>,
+   pub trivia: Trivia
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct ArrayAst {
-   pub items: Vec<ExprAst>
+   pub items: Vec<ExprAst>,
+   pub trivia: Trivia
 }
 
 #[deriving(Clone, PartialEq)]
@@ -75,6 +181,12 @@ pub struct SymbolAst {
    pub value: String
 }
 
+// :name, which evaluates to itself.  value does not include the colon.
+#[deriving(Clone, PartialEq)]
+pub struct KeywordAst {
+   pub value: String
+}
+
 #[deriving(Clone, PartialEq)]
 pub struct IntegerAst {
    pub value: i64
@@ -99,10 +211,132 @@ pub struct CommentAst {
 }
 
 #[deriving(Clone, PartialEq)]
+pub struct ErrorAst {
+   pub message: String,
+   pub data: Box<ExprAst>,
+   pub kind: ::interp::ErrorKind,
+   // the error this one was wrapped around by wrap-error
+   pub cause: Option<Box<ErrorAst>>
+}
+
+// Several values returned together by (values a b...).
+#[deriving(Clone, PartialEq)]
+pub struct TupleAst {
+   pub items: Vec<ExprAst>
+}
+
+// An immutable mapping from hashable keys to values, written {key value...}.  Entries
+// keep the order in which they were added.
+#[deriving(Clone)]
+pub struct MapAst {
+   // the hash of each key along with the key and its value
+   pub entries: Vec<(u64, ExprAst, ExprAst)>,
+   pub trivia: Trivia
+}
+
+// An immutable mapping from ordered keys (see interp::compare_values) to values, made by
+// smap, with its entries kept in the order of their keys.  The entries are a sorted vector
+// searched by bisection rather than a tree, as comparing two keys can fail, which the
+// ordered maps of the standard library do not allow for.
+#[deriving(Clone, PartialEq)]
+pub struct SortedMapAst {
+   pub entries: Vec<(ExprAst, ExprAst)>
+}
+
+// An immutable collection of distinct hashable values, in the order they were added.
+#[deriving(Clone)]
+pub struct SetAst {
+   // the hash of each item along with the item
+   pub items: Vec<(u64, ExprAst)>
+}
+
+// A double-ended queue that, unlike other values, is shared rather than copied, so that
+// enqueue, dequeue, push! and pop! change it in place without copying its items.  A
+// frozen queue, made by freeze, shares the items of the queue it was made from but cannot
+// be changed through.
+#[deriving(Clone)]
+pub struct QueueAst {
+   pub items: Rc<RefCell<RingBuf<ExprAst>>>,
+   pub frozen: bool
+}
+
+// A reference to a function parameter resolved before running (see resolve.rs): the
+// parameter in slot index of the environment of the depth-th function call enclosing the
+// reference.
+#[deriving(Clone, PartialEq)]
+pub struct LocalAst {
+   pub name: String,
+   pub depth: uint,
+   pub index: uint
+}
+
+// Text that is appended to in place, so that building a string piece by piece takes time
+// proportional to its length.  Like a queue it is shared rather than copied.  The text is
+// kept with its escape sequences, as strings are.  Like a queue it can be frozen.
+#[deriving(Clone)]
+pub struct StringBuilderAst {
+   pub buf: Rc<RefCell<String>>,
+   pub frozen: bool
+}
+
+// A rows by cols matrix of floats made by make-matrix, kept as one flat buffer in
+// row-major order rather than as a float value per element.  Like a map it is copied
+// rather than changed in place.
+#[deriving(Clone, PartialEq)]
+pub struct MatrixAst {
+   pub rows: uint,
+   pub cols: uint,
+   pub data: Vec<f64>
+}
+
+// Arrays made by int-array and float-array, which hold only integers or only floats
+// unboxed.  Unlike arrays they cannot be changed.
+#[deriving(Clone, PartialEq)]
+pub struct IntArrayAst {
+   pub items: Vec<i64>
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct FloatArrayAst {
+   pub items: Vec<f64>
+}
+
+// A reference to a shared value (currently only a queue) that does not keep it alive.
+#[deriving(Clone)]
+pub struct WeakAst {
+   pub queue: Weak<RefCell<RingBuf<ExprAst>>>,
+   // whether the queue was frozen
+   pub frozen: bool
+}
+
+#[deriving(Clone)]
 pub struct CodeAst {
    pub params: ArrayAst,
    pub code: Vec<ExprAst>,
-   pub env: Rc<RefCell<::interp::Environment>>
+   // the (params, body) pairs of a multiple-arity function; params and code are unused
+   // if there are any
+   pub clauses: Vec<(ArrayAst, Vec<ExprAst>)>,
+   pub doc: Option<String>,
+   // the parameters as they were written, type annotations included (one array for each
+   // clause of a multiple-arity function), and where the function was written, if known
+   pub written_params: Vec<ArrayAst>,
+   pub span: Span,
+   // set for a builtin used as a value, e.g. given to map, which is called instead of the
+   // (empty) body with env as the environment it runs in
+   pub builtin: Option<::interp::Builtin>,
+   pub env: Rc<RefCell<::interp::Environment>>,
+   // the names of the parameters if a call keeps them in slots (see resolve.rs)
+   pub slots: Option<Rc<Vec<String>>>,
+   // the results of earlier calls of a function made by memoize, by the hash of the
+   // arguments and then the arguments
+   pub memo: Option<Rc<RefCell<HashMap<u64, Vec<(Vec<ExprAst>, ExprAst)>>>>>
+}
+
+// Values are shown in their re-readable form, e.g. strings keep their quotes.
+impl fmt::Show for ExprAst {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{}", self.to_source())
+   }
 }
 
 impl Ast for ExprAst {
@@ -116,12 +350,25 @@ impl Ast for ExprAst {
          Pointer(ast) => ast.optimize(),
          Ident(ast) => ast.optimize(),
          Symbol(ast) => ast.optimize(),
+         Keyword(ast) => ast.optimize(),
          Integer(ast) => ast.optimize(),
          Float(ast) => ast.optimize(),
          Boolean(ast) => ast.optimize(),
          Nil(ast) => ast.optimize(),
          Comment(ast) => ast.optimize(),
-         Code(ast) => ast.optimize()
+         Code(ast) => ast.optimize(),
+         Error(ast) => ast.optimize(),
+         Tuple(ast) => ast.optimize(),
+         Map(ast) => ast.optimize(),
+         SortedMap(ast) => ast.optimize(),
+         Set(ast) => ast.optimize(),
+         Queue(ast) => ast.optimize(),
+         WeakRef(ast) => ast.optimize(),
+         Local(ast) => ast.optimize(),
+         StringBuilder(ast) => ast.optimize(),
+         Matrix(ast) => ast.optimize(),
+         IntArray(ast) => ast.optimize(),
+         FloatArray(ast) => ast.optimize()
       }
    }
 
@@ -135,12 +382,57 @@ impl Ast for ExprAst {
          Pointer(ref ast) => ast.compile(),
          Ident(ref ast) => ast.compile(),
          Symbol(ref ast) => ast.compile(),
+         Keyword(ref ast) => ast.compile(),
          Integer(ref ast) => ast.compile(),
          Float(ref ast) => ast.compile(),
          Boolean(ref ast) => ast.compile(),
          Nil(ref ast) => ast.compile(),
          Comment(ref ast) => ast.compile(),
-         Code(ref ast) => ast.compile()
+         Code(ref ast) => ast.compile(),
+         Error(ref ast) => ast.compile(),
+         Tuple(ref ast) => ast.compile(),
+         Map(ref ast) => ast.compile(),
+         SortedMap(ref ast) => ast.compile(),
+         Set(ref ast) => ast.compile(),
+         Queue(ref ast) => ast.compile(),
+         WeakRef(ref ast) => ast.compile(),
+         Local(ref ast) => ast.compile(),
+         StringBuilder(ref ast) => ast.compile(),
+         Matrix(ref ast) => ast.compile(),
+         IntArray(ref ast) => ast.compile(),
+         FloatArray(ref ast) => ast.compile()
+      }
+   }
+
+   fn to_source(&self) -> String {
+      match *self {
+         Root(ref ast) => ast.to_source(),
+         Sexpr(ref ast) => ast.to_source(),
+         String(ref ast) => ast.to_source(),
+         List(ref ast) => ast.to_source(),
+         Array(ref ast) => ast.to_source(),
+         Pointer(ref ast) => ast.to_source(),
+         Ident(ref ast) => ast.to_source(),
+         Symbol(ref ast) => ast.to_source(),
+         Keyword(ref ast) => ast.to_source(),
+         Integer(ref ast) => ast.to_source(),
+         Float(ref ast) => ast.to_source(),
+         Boolean(ref ast) => ast.to_source(),
+         Nil(ref ast) => ast.to_source(),
+         Comment(ref ast) => ast.to_source(),
+         Code(ref ast) => ast.to_source(),
+         Error(ref ast) => ast.to_source(),
+         Tuple(ref ast) => ast.to_source(),
+         Map(ref ast) => ast.to_source(),
+         SortedMap(ref ast) => ast.to_source(),
+         Set(ref ast) => ast.to_source(),
+         Queue(ref ast) => ast.to_source(),
+         WeakRef(ref ast) => ast.to_source(),
+         Local(ref ast) => ast.to_source(),
+         StringBuilder(ref ast) => ast.to_source(),
+         Matrix(ref ast) => ast.to_source(),
+         IntArray(ref ast) => ast.to_source(),
+         FloatArray(ref ast) => ast.to_source()
       }
    }
 
@@ -154,20 +446,111 @@ impl Ast for ExprAst {
          Pointer(ref ast) => ast.dump_level(level),
          Ident(ref ast) => ast.dump_level(level),
          Symbol(ref ast) => ast.dump_level(level),
+         Keyword(ref ast) => ast.dump_level(level),
          Integer(ref ast) => ast.dump_level(level),
          Float(ref ast) => ast.dump_level(level),
          Boolean(ref ast) => ast.dump_level(level),
          Nil(ref ast) => ast.dump_level(level),
          Comment(ref ast) => ast.dump_level(level),
-         Code(ref ast) => ast.dump_level(level)
+         Code(ref ast) => ast.dump_level(level),
+         Error(ref ast) => ast.dump_level(level),
+         Tuple(ref ast) => ast.dump_level(level),
+         Map(ref ast) => ast.dump_level(level),
+         SortedMap(ref ast) => ast.dump_level(level),
+         Set(ref ast) => ast.dump_level(level),
+         Queue(ref ast) => ast.dump_level(level),
+         WeakRef(ref ast) => ast.dump_level(level),
+         Local(ref ast) => ast.dump_level(level),
+         StringBuilder(ref ast) => ast.dump_level(level),
+         Matrix(ref ast) => ast.dump_level(level),
+         IntArray(ref ast) => ast.dump_level(level),
+         FloatArray(ref ast) => ast.dump_level(level)
+      }
+   }
+}
+
+impl ExprAst {
+   // Calls the visitor on this node and, unless it declines, on each of its children
+   // in turn.
+   pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+      if !visitor.visit(self) {
+         return;
+      }
+      match *self {
+         Root(ref ast) => for expr in ast.asts.iter() { expr.walk(visitor) },
+         Sexpr(ref ast) => for expr in ast.operands.iter() { expr.walk(visitor) },
+         List(ref ast) => for expr in ast.items.iter() { expr.walk(visitor) },
+         Array(ref ast) => for expr in ast.items.iter() { expr.walk(visitor) },
+         Tuple(ref ast) => for expr in ast.items.iter() { expr.walk(visitor) },
+         Set(ref ast) => for &(_, ref expr) in ast.items.iter() { expr.walk(visitor) },
+         Map(ref ast) => for &(_, ref key, ref value) in ast.entries.iter() {
+            key.walk(visitor);
+            value.walk(visitor);
+         },
+         Error(ref ast) => ast.data.walk(visitor),
+         _ => {}
+      }
+      visitor.leave(self);
+   }
+
+   // This node with each of its children replaced by what the folder rewrites it to.  The
+   // keys of maps and the items of sets are left alone, as they are stored by their hash.
+   pub fn fold_children<F: Folder>(self, folder: &mut F) -> ExprAst {
+      match self {
+         Root(mut ast) => {
+            ast.asts = fold_all(ast.asts, folder);
+            Root(ast)
+         }
+         Sexpr(mut ast) => {
+            let operands = mem::replace(&mut ast.operands, vec!());
+            ast.operands = fold_all(operands, folder);
+            Sexpr(ast)
+         }
+         List(mut ast) => {
+            ast.items = fold_all(ast.items, folder);
+            List(ast)
+         }
+         Array(mut ast) => {
+            ast.items = fold_all(ast.items, folder);
+            Array(ast)
+         }
+         Tuple(mut ast) => {
+            ast.items = fold_all(ast.items, folder);
+            Tuple(ast)
+         }
+         Map(mut ast) => {
+            ast.entries = ast.entries.move_iter().map(|(hash, key, value)| (hash, key, folder.fold(value))).collect();
+            Map(ast)
+         }
+         Error(mut ast) => {
+            ast.data = box folder.fold(*ast.data);
+            Error(ast)
+         }
+         other => other
+      }
+   }
+}
+
+fn fold_all<F: Folder>(exprs: Vec<ExprAst>, folder: &mut F) -> Vec<ExprAst> {
+   exprs.move_iter().map(|expr| folder.fold(expr)).collect()
+}
+
+fn source_list(items: &Vec<ExprAst>) -> String {
+   let mut result = String::new();
+   for (i, item) in items.iter().enumerate() {
+      if i > 0 {
+         result.push_char(' ');
       }
+      result.push_str(item.to_source().as_slice());
    }
+   result
 }
 
 impl RootAst {
    pub fn new() -> RootAst {
       RootAst {
-         asts: vec!()
+         asts: vec!(),
+         trivia: Trivia::new()
       }
    }
 
@@ -180,13 +563,21 @@ impl Ast for RootAst {
    fn optimize(self) -> Option<ExprAst> {
       let mut result = RootAst::new();
       result.asts = self.asts.move_iter().filter_map(|ast| ast.optimize()).collect();
+      result.trivia = self.trivia;
       Some(Root(result))
    }
 
    fn compile(&self) -> Vec<u8> {
-      let mut result = vec!();
+      let mut result = vec!(bytecode::TAG_ROOT);
+      bytecode::push_exprs(&mut result, &self.asts);
+      result
+   }
+
+   fn to_source(&self) -> String {
+      let mut result = String::new();
       for ast in self.asts.iter() {
-         result.push_all_move(ast.compile());
+         result.push_str(ast.to_source().as_slice());
+         result.push_char('\n');
       }
       result
    }
@@ -204,12 +595,146 @@ impl Ast for RootAst {
    }
 }
 
+impl Span {
+   pub fn new(line: uint, column: uint, start: uint, end: uint) -> Span {
+      Span {
+         line: line,
+         column: column,
+         start: start,
+         end: end
+      }
+   }
+
+   pub fn unknown() -> Span {
+      Span::new(0, 0, 0, 0)
+   }
+
+   pub fn is_known(&self) -> bool {
+      self.line != 0
+   }
+}
+
+// where a node came from does not affect what value it is
+impl PartialEq for Span {
+   fn eq(&self, _: &Span) -> bool {
+      true
+   }
+}
+
+impl Trivia {
+   pub fn new() -> Trivia {
+      Trivia {
+         comments: vec!()
+      }
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.comments.is_empty()
+   }
+
+   // The comments directly before the item at the given index.
+   pub fn before(&self, idx: uint) -> Vec<&String> {
+      self.comments.iter().filter(|&&(i, _)| i == idx).map(|pair| match *pair { (_, ref comment) => comment }).collect()
+   }
+}
+
+// neither do comments
+impl PartialEq for Trivia {
+   fn eq(&self, _: &Trivia) -> bool {
+      true
+   }
+}
+
 impl SexprAst {
    pub fn new(op: IdentAst, operands: Vec<ExprAst>) -> SexprAst {
       SexprAst {
          op: op,
-         operands: operands
+         operands: operands,
+         span: Span::unknown(),
+         trivia: Trivia::new(),
+         interpolated: false,
+         cache: CallCache::new()
+      }
+   }
+
+   // The (define name "doc"? (fn ...)) that (defn name "doc"? [params] body...) stands for,
+   // if this is a defn form.  A multiple-arity function is written with its clauses in
+   // place of [params] body....
+   pub fn expand_defn(&self) -> Option<SexprAst> {
+      if self.op.value.as_slice() != "defn" {
+         return None;
+      }
+      let mut operands = self.operands.clone();
+      let name = match operands.remove(0) {
+         Some(name @ Ident(_)) => name,
+         _ => return None
+      };
+      let mut define = vec!(name);
+      // a string followed by the parameters is the doc string rather than the body
+      let documented = match operands.as_slice() {
+         [String(_), _, ..] => true,
+         _ => false
+      };
+      if documented {
+         define.push(operands.remove(0).unwrap());
+      }
+      if operands.is_empty() {
+         return None;
+      }
+      let mut function = SexprAst::new(IdentAst::new("fn".to_string()), operands);
+      function.span = self.span.clone();
+      define.push(Sexpr(function));
+      let mut result = SexprAst::new(IdentAst::new("define".to_string()), define);
+      result.span = self.span.clone();
+      Some(result)
+   }
+
+   // The index of the name bound by a catch clause, which comes after the kind of error it
+   // catches if it is given one: (catch name ...) or (catch :kind name ...).
+   pub fn catch_name_index(&self) -> uint {
+      match self.operands.as_slice().get(0) {
+         Some(&Keyword(_)) => 1,
+         _ => 0
+      }
+   }
+
+   // The (if cond (do body...)) that (when cond body...) stands for, or the
+   // (if cond nil (do body...)) that (unless cond body...) does.
+   pub fn expand_conditional(&self) -> Option<SexprAst> {
+      let negated = match self.op.value.as_slice() {
+         "when" => false,
+         "unless" => true,
+         _ => return None
+      };
+      let mut operands = self.operands.clone();
+      let cond = match operands.remove(0) {
+         Some(cond) => cond,
+         None => return None
+      };
+      let mut body = SexprAst::new(IdentAst::new("do".to_string()), operands);
+      body.span = self.span.clone();
+      let branches = if negated { vec!(cond, Nil(NilAst::new()), Sexpr(body)) } else { vec!(cond, Sexpr(body)) };
+      let mut result = SexprAst::new(IdentAst::new("if".to_string()), branches);
+      result.span = self.span.clone();
+      Some(result)
+   }
+
+   // The (map-get m :key default?) that (:key m default?) and (.key m default?) stand for,
+   // if this is such a form.
+   pub fn expand_accessor(&self) -> Option<SexprAst> {
+      let op = self.op.value.as_slice();
+      let key = match op.slice_shift_char() {
+         (Some(':'), rest) | (Some('.'), rest) if rest.len() > 0 && !rest.starts_with(".") => rest,
+         _ => return None
+      };
+      if self.operands.len() < 1 || self.operands.len() > 2 {
+         return None;
       }
+      let mut operands = self.operands.clone();
+      operands.insert(1, Keyword(KeywordAst::new(key.to_string())));
+      let mut result = SexprAst::new(IdentAst::new("map-get".to_string()), operands);
+      result.span = self.span.clone();
+      Some(result)
    }
 
    fn is_math_op(&self) -> bool {
@@ -229,7 +754,32 @@ impl Ast for SexprAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_SEXPR);
+      bytecode::push_str(&mut result, self.op.value.as_slice());
+      bytecode::push_exprs(&mut result, &self.operands);
+      bytecode::push_span(&mut result, &self.span);
+      result
+   }
+
+   fn to_source(&self) -> String {
+      if self.interpolated {
+         let mut result = String::from_str("\"");
+         for operand in self.operands.iter() {
+            match *operand {
+               String(ref ast) => result.push_str(ast.string.as_slice()),
+               ref other => result.push_str(format!("${{{}}}", other.to_source()).as_slice())
+            }
+         }
+         result.push_char('"');
+         return result;
+      }
+      if self.operands.len() == 0 {
+         format!("({})", self.op.to_source())
+      } else if self.op.value.is_empty() {
+         format!("({})", source_list(&self.operands))
+      } else {
+         format!("({} {})", self.op.to_source(), source_list(&self.operands))
+      }
    }
 
    fn dump_level(&self, level: uint) {
@@ -249,19 +799,48 @@ impl Ast for SexprAst {
 impl StringAst {
    pub fn new(value: String) -> StringAst {
       StringAst {
-         string: value
+         string: Rc::new(value)
+      }
+   }
+
+   pub fn into_string(self) -> String {
+      (*self.string).clone()
+   }
+}
+
+impl PartialEq for StringAst {
+   fn eq(&self, other: &StringAst) -> bool {
+      // pooled strings are equal without looking at their text
+      (&*self.string as *const String) == (&*other.string as *const String) || self.string == other.string
+   }
+}
+
+impl StringPool {
+   pub fn new() -> StringPool {
+      StringPool {
+         strings: HashMap::new()
       }
    }
+
+   pub fn intern(&mut self, ast: StringAst) -> StringAst {
+      let shared = self.strings.find_or_insert_with((*ast.string).clone(), |_| ast.string.clone()).clone();
+      StringAst { string: shared }
+   }
 }
 
 impl Ast for StringAst {
    fn optimize(self) -> Option<ExprAst> {
-      // TODO: perhaps this should deal with a string table?
       Some(String(self))
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_STRING);
+      bytecode::push_str(&mut result, self.string.as_slice());
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!("\"{}\"", self.string.as_slice())
    }
 
    fn dump_level(&self, level: uint) {
@@ -280,7 +859,7 @@ impl Ast for StringAst {
             buf
          };
       println!("{}StringAst {}", spaces, "{");
-      println!("{}{}\"{}\"", spaces, indent, self.string);
+      println!("{}{}\"{}\"", spaces, indent, self.string.as_slice());
       println!("{}{}", spaces, "}");
    }
 }
@@ -288,7 +867,8 @@ impl Ast for StringAst {
 impl ListAst {
    pub fn new(items: Vec<ExprAst>) -> ListAst {
       ListAst {
-         items: items
+         items: items,
+         trivia: Trivia::new()
       }
    }
 }
@@ -299,7 +879,13 @@ impl Ast for ListAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_LIST);
+      bytecode::push_exprs(&mut result, &self.items);
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!("'({})", source_list(&self.items))
    }
 
    fn dump_level(&self, level: uint) {
@@ -318,7 +904,8 @@ impl Ast for ListAst {
 impl ArrayAst {
    pub fn new(items: Vec<ExprAst>) -> ArrayAst {
       ArrayAst {
-         items: items
+         items: items,
+         trivia: Trivia::new()
       }
    }
 }
@@ -329,7 +916,13 @@ impl Ast for ArrayAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_ARRAY);
+      bytecode::push_exprs(&mut result, &self.items);
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!("[{}]", source_list(&self.items))
    }
 
    fn dump_level(&self, level: uint) {
@@ -351,7 +944,11 @@ impl Ast for PointerAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      self.pointee.compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.pointee.to_source()
    }
 
    fn dump_level(&self, _: uint) { }
@@ -371,7 +968,13 @@ impl Ast for IntegerAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_INTEGER);
+      bytecode::push_u64(&mut result, self.value as u64);
+      result
+   }
+
+   fn to_source(&self) -> String {
+      self.value.to_string()
    }
 
    fn dump_level(&self, level: uint) {
@@ -409,7 +1012,13 @@ impl Ast for IdentAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_IDENT);
+      bytecode::push_str(&mut result, self.value.as_slice());
+      result
+   }
+
+   fn to_source(&self) -> String {
+      self.value.clone()
    }
 
    fn dump_level(&self, level: uint) {
@@ -447,7 +1056,13 @@ impl Ast for SymbolAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_SYMBOL);
+      bytecode::push_str(&mut result, self.value.as_slice());
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!("'{}", self.value)
    }
 
    fn dump_level(&self, level: uint) {
@@ -471,6 +1086,38 @@ impl Ast for SymbolAst {
    }
 }
 
+impl KeywordAst {
+   pub fn new(value: String) -> KeywordAst {
+      KeywordAst {
+         value: value
+      }
+   }
+}
+
+impl Ast for KeywordAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Keyword(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      let mut result = vec!(bytecode::TAG_KEYWORD);
+      bytecode::push_str(&mut result, self.value.as_slice());
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!(":{}", self.value)
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}KeywordAst :{}", spaces, self.value);
+   }
+}
+
 impl FloatAst {
    pub fn new(value: f64) -> FloatAst {
       FloatAst {
@@ -485,7 +1132,17 @@ impl Ast for FloatAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_FLOAT);
+      bytecode::push_u64(&mut result, unsafe { mem::transmute::<f64, u64>(self.value) });
+      result
+   }
+
+   fn to_source(&self) -> String {
+      let mut result = f64::to_str_digits(self.value, 15);
+      if !result.as_slice().contains_char('.') {
+         result.push_str(".0");
+      }
+      result
    }
 
    fn dump_level(&self, level: uint) {
@@ -523,7 +1180,11 @@ impl Ast for BooleanAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      vec!(bytecode::TAG_BOOLEAN, self.value as u8)
+   }
+
+   fn to_source(&self) -> String {
+      self.value.to_string()
    }
 
    fn dump_level(&self, level: uint) {
@@ -559,7 +1220,11 @@ impl Ast for NilAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      vec!(bytecode::TAG_NIL)
+   }
+
+   fn to_source(&self) -> String {
+      "nil".to_string()
    }
 
    fn dump_level(&self, level: uint) {
@@ -585,7 +1250,13 @@ impl Ast for CommentAst {
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_COMMENT);
+      bytecode::push_str(&mut result, self.value.as_slice());
+      result
+   }
+
+   fn to_source(&self) -> String {
+      format!(";{}\n", self.value)
    }
 
    fn dump_level(&self, level: uint) {
@@ -609,24 +1280,844 @@ impl Ast for CommentAst {
    }
 }
 
-impl CodeAst {
-   pub fn new(params: ArrayAst, code: Vec<ExprAst>, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
-      CodeAst {
-         params: params,
-         code: code,
-         env: env
+impl MapAst {
+   pub fn new() -> MapAst {
+      MapAst {
+         entries: vec!(),
+         trivia: Trivia::new()
+      }
+   }
+
+   // Builds a map from alternating keys and values, with later keys replacing earlier
+   // equal ones.
+   pub fn from_items(items: Vec<ExprAst>) -> Result<MapAst, String> {
+      if items.len() % 2 != 0 {
+         return Err("a map needs a value for every key".to_string());
+      }
+      let mut result = MapAst::new();
+      let mut items = items.move_iter();
+      loop {
+         match (items.next(), items.next()) {
+            (Some(key), Some(value)) => try!(result.insert(key, value)),
+            _ => break
+         }
+      }
+      Ok(result)
+   }
+
+   fn position(&self, hash: u64, key: &ExprAst) -> Option<uint> {
+      self.entries.iter().position(|&(khash, ref k, _)| khash == hash && k == key)
+   }
+
+   pub fn find<'a>(&'a self, key: &ExprAst) -> Result<Option<&'a ExprAst>, String> {
+      let hash = try!(hash::hash_value(key));
+      Ok(self.position(hash, key).map(|idx| {
+         let &(_, _, ref value) = &self.entries[idx];
+         value
+      }))
+   }
+
+   pub fn insert(&mut self, key: ExprAst, value: ExprAst) -> Result<(), String> {
+      let hash = try!(hash::hash_value(&key));
+      match self.position(hash, &key) {
+         Some(idx) => *self.entries.get_mut(idx) = (hash, key, value),
+         None => self.entries.push((hash, key, value))
+      }
+      Ok(())
+   }
+
+   pub fn remove(&mut self, key: &ExprAst) -> Result<Option<ExprAst>, String> {
+      let hash = try!(hash::hash_value(key));
+      Ok(match self.position(hash, key) {
+         Some(idx) => self.entries.remove(idx).map(|(_, _, value)| value),
+         None => None
+      })
+   }
+
+   // The keys and values, alternating.
+   pub fn items(&self) -> Vec<ExprAst> {
+      let mut result = vec!();
+      for &(_, ref key, ref value) in self.entries.iter() {
+         result.push(key.clone());
+         result.push(value.clone());
       }
+      result
    }
 }
 
-impl Ast for CodeAst {
+// maps with the same entries are equal whatever order they were added in
+impl PartialEq for MapAst {
+   fn eq(&self, other: &MapAst) -> bool {
+      self.entries.len() == other.entries.len() && self.entries.iter().all(|&(hash, ref key, ref value)| {
+         match other.position(hash, key) {
+            Some(idx) => {
+               let &(_, _, ref other_value) = &other.entries[idx];
+               other_value == value
+            }
+            None => false
+         }
+      })
+   }
+}
+
+impl Ast for MapAst {
    fn optimize(self) -> Option<ExprAst> {
-      Some(Code(self))
+      Some(Map(self))
    }
 
    fn compile(&self) -> Vec<u8> {
-      vec!()
+      let mut result = vec!(bytecode::TAG_MAP);
+      bytecode::push_exprs(&mut result, &self.items());
+      result
    }
 
-   fn dump_level(&self, _: uint) { }
+   fn to_source(&self) -> String {
+      format!("{{{}}}", source_list(&self.items()))
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}MapAst {}", spaces, "{");
+      for &(_, ref key, ref value) in self.entries.iter() {
+         key.dump_level(level + 1);
+         value.dump_level(level + 1);
+      }
+      println!("{}{}", spaces, "}");
+   }
+}
+
+impl SortedMapAst {
+   pub fn new() -> SortedMapAst {
+      SortedMapAst {
+         entries: vec!()
+      }
+   }
+
+   // Builds a map from alternating keys and values, with later keys replacing earlier
+   // equal ones.
+   pub fn from_items(items: Vec<ExprAst>) -> Result<SortedMapAst, String> {
+      if items.len() % 2 != 0 {
+         return Err("a map needs a value for every key".to_string());
+      }
+      let mut result = SortedMapAst::new();
+      let mut items = items.move_iter();
+      loop {
+         match (items.next(), items.next()) {
+            (Some(key), Some(value)) => try!(result.insert(key, value)),
+            _ => break
+         }
+      }
+      Ok(result)
+   }
+
+   // The index of the entry for key if there is one, or else the index it would be
+   // inserted at.
+   fn search(&self, key: &ExprAst) -> Result<Result<uint, uint>, String> {
+      let (mut low, mut high) = (0u, self.entries.len());
+      while low < high {
+         let mid = (low + high) / 2;
+         let &(ref other, _) = &self.entries[mid];
+         match ::interp::compare_values(other, key) {
+            Ok(Less) => low = mid + 1,
+            Ok(Greater) => high = mid,
+            Ok(Equal) => return Ok(Ok(mid)),
+            Err(f) => return Err(f.desc)
+         }
+      }
+      Ok(Err(low))
+   }
+
+   pub fn find<'a>(&'a self, key: &ExprAst) -> Result<Option<&'a ExprAst>, String> {
+      Ok(match try!(self.search(key)) {
+         Ok(idx) => {
+            let &(_, ref value) = &self.entries[idx];
+            Some(value)
+         }
+         Err(_) => None
+      })
+   }
+
+   pub fn insert(&mut self, key: ExprAst, value: ExprAst) -> Result<(), String> {
+      match try!(self.search(&key)) {
+         Ok(idx) => *self.entries.get_mut(idx) = (key, value),
+         Err(idx) => self.entries.insert(idx, (key, value))
+      }
+      Ok(())
+   }
+
+   pub fn remove(&mut self, key: &ExprAst) -> Result<Option<ExprAst>, String> {
+      Ok(match try!(self.search(key)) {
+         Ok(idx) => self.entries.remove(idx).map(|(_, value)| value),
+         Err(_) => None
+      })
+   }
+
+   // The entries with keys from from up to but not including to.
+   pub fn range(&self, from: &ExprAst, to: &ExprAst) -> Result<SortedMapAst, String> {
+      let start = match try!(self.search(from)) { Ok(idx) | Err(idx) => idx };
+      let end = match try!(self.search(to)) { Ok(idx) | Err(idx) => idx };
+      Ok(SortedMapAst {
+         entries: if start < end { self.entries.slice(start, end).to_vec() } else { vec!() }
+      })
+   }
+
+   // The keys and values, alternating.
+   pub fn items(&self) -> Vec<ExprAst> {
+      let mut result = vec!();
+      for &(ref key, ref value) in self.entries.iter() {
+         result.push(key.clone());
+         result.push(value.clone());
+      }
+      result
+   }
+
+   fn as_sexpr(&self) -> SexprAst {
+      SexprAst::new(IdentAst::new("smap".to_string()), self.items())
+   }
+}
+
+impl Ast for SortedMapAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(SortedMap(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}SortedMapAst {}", spaces, "{");
+      for &(ref key, ref value) in self.entries.iter() {
+         key.dump_level(level + 1);
+         value.dump_level(level + 1);
+      }
+      println!("{}{}", spaces, "}");
+   }
+}
+
+impl SetAst {
+   pub fn new() -> SetAst {
+      SetAst {
+         items: vec!()
+      }
+   }
+
+   pub fn from_items(items: Vec<ExprAst>) -> Result<SetAst, String> {
+      let mut result = SetAst::new();
+      for item in items.move_iter() {
+         try!(result.insert(item));
+      }
+      Ok(result)
+   }
+
+   pub fn position(&self, hash: u64, item: &ExprAst) -> Option<uint> {
+      self.items.iter().position(|&(ihash, ref i)| ihash == hash && i == item)
+   }
+
+   pub fn contains(&self, item: &ExprAst) -> Result<bool, String> {
+      let hash = try!(hash::hash_value(item));
+      Ok(self.position(hash, item).is_some())
+   }
+
+   pub fn insert(&mut self, item: ExprAst) -> Result<(), String> {
+      let hash = try!(hash::hash_value(&item));
+      if self.position(hash, &item).is_none() {
+         self.items.push((hash, item));
+      }
+      Ok(())
+   }
+
+   pub fn remove(&mut self, item: &ExprAst) -> Result<(), String> {
+      let hash = try!(hash::hash_value(item));
+      match self.position(hash, item) {
+         Some(idx) => { self.items.remove(idx); }
+         None => {}
+      }
+      Ok(())
+   }
+
+   pub fn values(&self) -> Vec<ExprAst> {
+      self.items.iter().map(|&(_, ref item)| item.clone()).collect()
+   }
+
+   // The (hash-set ...) form that evaluates to this set.
+   pub fn as_sexpr(&self) -> SexprAst {
+      SexprAst::new(IdentAst::new("hash-set".to_string()), self.values())
+   }
+}
+
+// sets with the same items are equal whatever order they were added in
+impl PartialEq for SetAst {
+   fn eq(&self, other: &SetAst) -> bool {
+      self.items.len() == other.items.len() &&
+         self.items.iter().all(|&(hash, ref item)| other.position(hash, item).is_some())
+   }
+}
+
+impl Ast for SetAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Set(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}SetAst {}", spaces, "{");
+      for &(_, ref item) in self.items.iter() {
+         item.dump_level(level + 1);
+      }
+      println!("{}{}", spaces, "}");
+   }
+}
+
+impl QueueAst {
+   pub fn new(items: Vec<ExprAst>) -> QueueAst {
+      QueueAst {
+         items: Rc::new(RefCell::new(items.move_iter().collect())),
+         frozen: false
+      }
+   }
+
+   pub fn is(&self, other: &QueueAst) -> bool {
+      let this: *const RefCell<RingBuf<ExprAst>> = &*self.items;
+      let that: *const RefCell<RingBuf<ExprAst>> = &*other.items;
+      this == that
+   }
+}
+
+// Queues are equal if they are the same queue or have equal items.  A queue that is
+// already being compared (because it contains itself) is only equal to itself.
+impl PartialEq for QueueAst {
+   fn eq(&self, other: &QueueAst) -> bool {
+      if self.is(other) {
+         return true;
+      }
+      match (self.items.try_borrow_mut(), other.items.try_borrow_mut()) {
+         (Some(this), Some(that)) => *this == *that,
+         _ => false
+      }
+   }
+}
+
+impl Ast for QueueAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Queue(self))
+   }
+
+   // Written as the (queue ...) form that makes a queue with the same items, inside
+   // (freeze ...) if it is frozen.  A queue can contain itself, so a queue that is already
+   // being written is written as #cycle (and compiled as nil).
+   fn compile(&self) -> Vec<u8> {
+      if self.frozen {
+         let unfrozen = QueueAst { items: self.items.clone(), frozen: false };
+         return SexprAst::new(IdentAst::new("freeze".to_string()), vec!(Queue(unfrozen))).compile();
+      }
+      match self.items.try_borrow_mut() {
+         Some(items) => {
+            let mut result = vec!(bytecode::TAG_SEXPR);
+            bytecode::push_str(&mut result, "queue");
+            bytecode::push_u64(&mut result, items.len() as u64);
+            for item in items.iter() {
+               result.push_all_move(item.compile());
+            }
+            bytecode::push_span(&mut result, &Span::unknown());
+            result
+         }
+         None => NilAst::new().compile()
+      }
+   }
+
+   fn to_source(&self) -> String {
+      if self.frozen {
+         return format!("(freeze {})", QueueAst { items: self.items.clone(), frozen: false }.to_source());
+      }
+      match self.items.try_borrow_mut() {
+         Some(items) => {
+            let items: Vec<String> = items.iter().map(|item| item.to_source()).collect();
+            if items.is_empty() {
+               "(queue)".to_string()
+            } else {
+               format!("(queue {})", items.connect(" "))
+            }
+         }
+         None => "#cycle".to_string()
+      }
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}QueueAst {}", spaces, "{");
+      match self.items.try_borrow_mut() {
+         Some(items) => for item in items.iter() { item.dump_level(level + 1) },
+         None => println!("{}#cycle", spaces)
+      }
+      println!("{}{}", spaces, "}");
+   }
+}
+
+impl LocalAst {
+   pub fn new(name: String, depth: uint, index: uint) -> LocalAst {
+      LocalAst {
+         name: name,
+         depth: depth,
+         index: index
+      }
+   }
+}
+
+// a resolved reference is written, and compiled, as the name it refers to
+impl Ast for LocalAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Local(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      IdentAst::new(self.name.clone()).compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.name.clone()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}LocalAst {} (depth {}, slot {})", spaces, self.name, self.depth, self.index);
+   }
+}
+
+impl StringBuilderAst {
+   pub fn new(text: String) -> StringBuilderAst {
+      StringBuilderAst {
+         buf: Rc::new(RefCell::new(text)),
+         frozen: false
+      }
+   }
+
+   // The (string-builder "text") form that makes a builder with the same text, inside
+   // (freeze ...) if it is frozen.
+   fn as_sexpr(&self) -> SexprAst {
+      let builder = SexprAst::new(IdentAst::new("string-builder".to_string()), vec!(String(StringAst::new(self.buf.borrow().clone()))));
+      if self.frozen {
+         SexprAst::new(IdentAst::new("freeze".to_string()), vec!(Sexpr(builder)))
+      } else {
+         builder
+      }
+   }
+}
+
+impl MatrixAst {
+   pub fn new(rows: uint, cols: uint, data: Vec<f64>) -> MatrixAst {
+      MatrixAst {
+         rows: rows,
+         cols: cols,
+         data: data
+      }
+   }
+
+   pub fn get(&self, row: uint, col: uint) -> f64 {
+      self.data[row * self.cols + col]
+   }
+
+   pub fn transpose(&self) -> MatrixAst {
+      let mut data = Vec::with_capacity(self.data.len());
+      for col in range(0, self.cols) {
+         for row in range(0, self.rows) {
+            data.push(self.get(row, col));
+         }
+      }
+      MatrixAst::new(self.cols, self.rows, data)
+   }
+
+   // The matrix product, if the number of columns of self is the number of rows of other.
+   pub fn mul(&self, other: &MatrixAst) -> Option<MatrixAst> {
+      if self.cols != other.rows {
+         return None;
+      }
+      let mut data = Vec::from_elem(self.rows * other.cols, 0.0f64);
+      for row in range(0, self.rows) {
+         for k in range(0, self.cols) {
+            let factor = self.get(row, k);
+            for col in range(0, other.cols) {
+               *data.get_mut(row * other.cols + col) += factor * other.get(k, col);
+            }
+         }
+      }
+      Some(MatrixAst::new(self.rows, other.cols, data))
+   }
+
+   fn as_sexpr(&self) -> SexprAst {
+      let data = self.data.iter().map(|&value| Float(FloatAst::new(value))).collect();
+      SexprAst::new(IdentAst::new("make-matrix".to_string()), vec!(
+         Integer(IntegerAst::new(self.rows as i64)),
+         Integer(IntegerAst::new(self.cols as i64)),
+         Array(ArrayAst::new(data))))
+   }
+}
+
+impl Ast for MatrixAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Matrix(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}MatrixAst {}x{}", spaces, self.rows, self.cols);
+   }
+}
+
+impl IntArrayAst {
+   pub fn new(items: Vec<i64>) -> IntArrayAst {
+      IntArrayAst {
+         items: items
+      }
+   }
+
+   pub fn values(&self) -> Vec<ExprAst> {
+      self.items.iter().map(|&item| Integer(IntegerAst::new(item))).collect()
+   }
+}
+
+impl Ast for IntArrayAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(IntArray(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      SexprAst::new(IdentAst::new("int-array".to_string()), self.values()).compile()
+   }
+
+   fn to_source(&self) -> String {
+      SexprAst::new(IdentAst::new("int-array".to_string()), self.values()).to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}IntArrayAst {}", spaces, self.items);
+   }
+}
+
+impl FloatArrayAst {
+   pub fn new(items: Vec<f64>) -> FloatArrayAst {
+      FloatArrayAst {
+         items: items
+      }
+   }
+
+   pub fn values(&self) -> Vec<ExprAst> {
+      self.items.iter().map(|&item| Float(FloatAst::new(item))).collect()
+   }
+}
+
+impl Ast for FloatArrayAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(FloatArray(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      SexprAst::new(IdentAst::new("float-array".to_string()), self.values()).compile()
+   }
+
+   fn to_source(&self) -> String {
+      SexprAst::new(IdentAst::new("float-array".to_string()), self.values()).to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}FloatArrayAst {}", spaces, self.items);
+   }
+}
+
+impl PartialEq for StringBuilderAst {
+   fn eq(&self, other: &StringBuilderAst) -> bool {
+      *self.buf.borrow() == *other.buf.borrow()
+   }
+}
+
+impl Ast for StringBuilderAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(StringBuilder(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}StringBuilderAst \"{}\"", spaces, *self.buf.borrow());
+   }
+}
+
+impl WeakAst {
+   pub fn new(queue: &QueueAst) -> WeakAst {
+      WeakAst {
+         queue: queue.items.downgrade(),
+         frozen: queue.frozen
+      }
+   }
+
+   // The value referred to, unless nothing else refers to it any more.
+   pub fn get(&self) -> Option<ExprAst> {
+      let frozen = self.frozen;
+      self.queue.upgrade().map(|items| Queue(QueueAst { items: items, frozen: frozen }))
+   }
+}
+
+// weak references are equal if they refer to the same live value
+impl PartialEq for WeakAst {
+   fn eq(&self, other: &WeakAst) -> bool {
+      match (self.get(), other.get()) {
+         (Some(Queue(ref this)), Some(Queue(ref that))) => this.is(that),
+         _ => false
+      }
+   }
+}
+
+impl Ast for WeakAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(WeakRef(self))
+   }
+
+   // weak references only make sense while the program runs
+   fn compile(&self) -> Vec<u8> {
+      NilAst::new().compile()
+   }
+
+   fn to_source(&self) -> String {
+      "#weak".to_string()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}WeakAst", spaces);
+   }
+}
+
+impl CodeAst {
+   pub fn new(params: ArrayAst, code: Vec<ExprAst>, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
+      CodeAst {
+         slots: ::resolve::slot_names(&params).map(|names| Rc::new(names)),
+         written_params: vec!(params.clone()),
+         span: Span::unknown(),
+         builtin: None,
+         params: params,
+         code: code,
+         clauses: vec!(),
+         doc: None,
+         env: env,
+         memo: None
+      }
+   }
+
+   pub fn from_builtin(builtin: ::interp::Builtin, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
+      let mut result = CodeAst::new(ArrayAst::new(vec!()), vec!(), env);
+      result.written_params = vec!();
+      result.builtin = Some(builtin);
+      result
+   }
+
+   // The (fn ...) form that evaluates to this code.
+   pub fn as_sexpr(&self) -> SexprAst {
+      let mut operands = vec!();
+      if self.clauses.is_empty() {
+         operands.push(Array(self.params.clone()));
+      }
+      match self.doc {
+         Some(ref doc) => operands.push(String(StringAst::new(doc.clone()))),
+         None => {}
+      }
+      if self.clauses.is_empty() {
+         operands.push_all(self.code.as_slice());
+      } else {
+         for &(ref params, ref body) in self.clauses.iter() {
+            let mut items = vec!(Array(params.clone()));
+            items.push_all(body.as_slice());
+            operands.push(List(ListAst::new(items)));
+         }
+      }
+      SexprAst::new(IdentAst::new("fn".to_string()), operands)
+   }
+}
+
+// A function's environment usually contains the function itself, so comparing the
+// environments structurally would never finish.  Two functions are only equal if they
+// have the same code and captured the very same environment.
+impl PartialEq for CodeAst {
+   fn eq(&self, other: &CodeAst) -> bool {
+      if self.builtin.is_some() || other.builtin.is_some() {
+         return self.builtin == other.builtin;
+      }
+      let this: *const RefCell<::interp::Environment> = &*self.env;
+      let that: *const RefCell<::interp::Environment> = &*other.env;
+      this == that && self.params == other.params && self.code == other.code && self.clauses == other.clauses
+   }
+}
+
+impl Ast for CodeAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Code(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      // the captured environment only exists at runtime, so only the source form is kept,
+      // and a builtin is kept as its name
+      match self.builtin {
+         Some(ref builtin) => Ident(IdentAst::new(builtin.name.to_string())).compile(),
+         None => self.as_sexpr().compile()
+      }
+   }
+
+   fn to_source(&self) -> String {
+      match self.builtin {
+         Some(ref builtin) => format!("#<builtin {}>", builtin.name),
+         None => self.as_sexpr().to_source()
+      }
+   }
+
+   fn dump_level(&self, _: uint) { }
+}
+
+impl ErrorAst {
+   pub fn new(message: String, data: ExprAst) -> ErrorAst {
+      ErrorAst {
+         message: message,
+         data: box data,
+         kind: ::interp::UserError,
+         cause: None
+      }
+   }
+
+   // The (error ...) or (wrap-error ...) form that evaluates to this error.
+   pub fn as_sexpr(&self) -> SexprAst {
+      match self.cause {
+         Some(ref cause) => {
+            let operands = vec!(Sexpr(cause.as_sexpr()), String(StringAst::new(self.message.clone())), (*self.data).clone());
+            return SexprAst::new(IdentAst::new("wrap-error".to_string()), operands);
+         }
+         None => {}
+      }
+      let mut operands = vec!(String(StringAst::new(self.message.clone())), (*self.data).clone());
+      if self.kind != ::interp::UserError {
+         operands.push(Keyword(KeywordAst::new(self.kind.name().to_string())));
+      }
+      SexprAst::new(IdentAst::new("error".to_string()), operands)
+   }
+}
+
+impl Ast for ErrorAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Error(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}ErrorAst {}", spaces, "{");
+      StringAst::new(self.message.clone()).dump_level(level + 1);
+      self.data.dump_level(level + 1);
+      println!("{}{}", spaces, "}");
+   }
+}
+
+impl TupleAst {
+   pub fn new(items: Vec<ExprAst>) -> TupleAst {
+      TupleAst {
+         items: items
+      }
+   }
+
+   // The (values ...) form that evaluates to this tuple.
+   pub fn as_sexpr(&self) -> SexprAst {
+      SexprAst::new(IdentAst::new("values".to_string()), self.items.clone())
+   }
+}
+
+impl Ast for TupleAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Tuple(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      self.as_sexpr().compile()
+   }
+
+   fn to_source(&self) -> String {
+      self.as_sexpr().to_source()
+   }
+
+   fn dump_level(&self, level: uint) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      println!("{}TupleAst {}", spaces, "{");
+      for item in self.items.iter() {
+         item.dump_level(level + 1);
+      }
+      println!("{}{}", spaces, "}");
+   }
 }
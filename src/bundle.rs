@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::io;
+use std::os;
+
+use ast::*;
+use parser::Parser;
+use pkg;
+
+// Combines a script and the modules it imports into a single script, for iron --bundle.
+// Each top-level (import "./module") is replaced by the text of the module, itself
+// bundled, so the result runs without the module files.  A module imported more than once
+// is only included the first time, and an import inside another form is an error because
+// it could not be resolved until the bundle runs.
+
+struct Bundler {
+   included: HashSet<Path>
+}
+
+struct NestedImports {
+   found: Option<Span>
+}
+
+impl Visitor for NestedImports {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      match *expr {
+         Sexpr(ref ast) if ast.op.value.as_slice() == "import" => {
+            if self.found.is_none() {
+               self.found = Some(ast.span.clone());
+            }
+            false
+         }
+         _ => self.found.is_none()
+      }
+   }
+}
+
+// Writes the bundle of the script at entry to the file at output.
+pub fn bundle_file(entry: &Path, output: &Path) -> Result<(), String> {
+   let mut bundler = Bundler { included: HashSet::new() };
+   let code = try!(bundler.bundle(entry));
+   match io::File::create(output).write(code.as_bytes()) {
+      Ok(()) => Ok(()),
+      Err(f) => Err(format!("could not write {}: {}", output.display(), f))
+   }
+}
+
+// The path of the module named by an import in the file at path, found as import does
+// (an installed package is included like any other module).
+pub fn module_path(path: &Path, name: &str) -> Result<Path, String> {
+   if !name.starts_with("./") && !name.starts_with("../") && !Path::new(name).is_absolute() {
+      return pkg::find_module(name);
+   }
+   let mut module = path.dir_path().join(Path::new(name));
+   if !name.ends_with(".irl") {
+      module.set_extension("irl");
+   }
+   Ok(module)
+}
+
+impl Bundler {
+   fn bundle(&mut self, path: &Path) -> Result<String, String> {
+      self.included.insert(os::make_absolute(path));
+      let code = match io::File::open(path).read_to_string() {
+         Ok(code) => code,
+         Err(f) => return Err(format!("could not read {}: {}", path.display(), f))
+      };
+      let root = match Parser::new().try_parse_code(code.clone()) {
+         Ok(Root(root)) => root,
+         Ok(_) => unreachable!(),
+         Err(f) => return Err(format!("{}: {}", path.display(), f))
+      };
+      let mut result = String::new();
+      let mut pos = 0;
+      for expr in root.asts.iter() {
+         let modules = match *expr {
+            Sexpr(ref ast) if ast.op.value.as_slice() == "import" => {
+               let mut modules = vec!();
+               for operand in ast.operands.iter() {
+                  match *operand {
+                     String(ref name) => modules.push(try!(module_path(path, name.string.as_slice()))),
+                     _ => return Err(format!("{}:{}: only imports of strings can be bundled", path.display(), ast.span.line))
+                  }
+               }
+               result.push_str(code.as_slice().slice(pos, ast.span.start));
+               pos = ast.span.end;
+               modules
+            }
+            ref other => {
+               let mut nested = NestedImports { found: None };
+               other.walk(&mut nested);
+               match nested.found {
+                  Some(span) => return Err(format!("{}:{}: only imports at the top level can be bundled", path.display(), span.line)),
+                  None => continue
+               }
+            }
+         };
+         for module in modules.iter() {
+            if self.included.contains(&os::make_absolute(module)) {
+               continue;
+            }
+            result.push_str(format!("; {}\n", module.display()).as_slice());
+            result.push_str(try!(self.bundle(module)).as_slice());
+            result.push_char('\n');
+         }
+      }
+      result.push_str(code.as_slice().slice_from(pos));
+      Ok(result)
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use std::io;
+   use std::io::fs;
+   use std::os;
+
+   use ast::Ast;
+   use interp::Interpreter;
+   use super::bundle_file;
+
+   // A directory holding the files with the given names and code.
+   fn project(name: &str, files: &[(&str, &str)]) -> Path {
+      let dir = os::tmpdir().join(name);
+      fs::mkdir_recursive(&dir, io::UserDir).unwrap();
+      for &(file, code) in files.iter() {
+         io::File::create(&dir.join(file)).write_str(code).unwrap();
+      }
+      dir
+   }
+
+   #[test]
+   fn bundles_run_without_their_modules_and_include_each_once() {
+      let dir = project("iron-bundle", [
+         ("a.irl", "(define a 1)"),
+         ("b.irl", "(import \"./a\")\n(define b (+ a 1))"),
+         ("main.irl", "(import \"./b\" \"./a\")\n(+ a b)")
+      ]);
+      let output = dir.join("bundled.irl");
+      bundle_file(&dir.join("main.irl"), &output).unwrap();
+      let code = io::File::open(&output).read_to_string().unwrap();
+      assert_eq!(code.as_slice().split_str("(define a 1)").count(), 2);
+      assert!(!code.as_slice().contains("import"));
+      let mut interp = Interpreter::new();
+      interp.load_code(code);
+      assert_eq!(interp.run().unwrap().to_source().as_slice(), "3");
+   }
+
+   #[test]
+   fn imports_inside_other_forms_cannot_be_bundled() {
+      let dir = project("iron-bundle-nested", [
+         ("a.irl", "(define a 1)"),
+         ("main.irl", "(define x 1)\n(when true (import \"./a\"))")
+      ]);
+      match bundle_file(&dir.join("main.irl"), &dir.join("bundled.irl")) {
+         Err(f) => assert!(f.as_slice().ends_with("main.irl:2: only imports at the top level can be bundled")),
+         Ok(()) => fail!("a nested import was bundled")
+      }
+   }
+}
@@ -0,0 +1,226 @@
+use std::io;
+use std::io::IoResult;
+use std::mem;
+
+use ast::*;
+use interp::Release;
+use optimize;
+use parser::Parser;
+
+// Layout of a .irlc file: the magic bytes, a format version, the modification time of
+// the source file it was produced from, and then the output of compile() for the
+// optimized RootAst.  The "bytecode" is a serialized tree rather than instructions for a
+// virtual machine, so there is no instruction stream to optimize further: constant
+// arithmetic is folded on the tree by the passes in optimize.rs, and a peephole pass
+// (merging pushes and pops, turning tail calls into jumps) has to wait for a VM.
+static MAGIC: &'static [u8] = b"IRLC";
+static FORMAT_VERSION: u8 = 5;
+
+pub static TAG_ROOT: u8 = 0;
+pub static TAG_SEXPR: u8 = 1;
+pub static TAG_STRING: u8 = 2;
+pub static TAG_LIST: u8 = 3;
+pub static TAG_ARRAY: u8 = 4;
+pub static TAG_IDENT: u8 = 5;
+pub static TAG_SYMBOL: u8 = 6;
+pub static TAG_INTEGER: u8 = 7;
+pub static TAG_FLOAT: u8 = 8;
+pub static TAG_BOOLEAN: u8 = 9;
+pub static TAG_NIL: u8 = 10;
+pub static TAG_COMMENT: u8 = 11;
+pub static TAG_MAP: u8 = 12;
+pub static TAG_KEYWORD: u8 = 13;
+
+pub fn push_u64(buf: &mut Vec<u8>, val: u64) {
+   for i in range(0u, 8).rev() {
+      buf.push((val >> (i * 8)) as u8);
+   }
+}
+
+pub fn push_str(buf: &mut Vec<u8>, val: &str) {
+   push_u64(buf, val.len() as u64);
+   buf.push_all(val.as_bytes());
+}
+
+pub fn push_span(buf: &mut Vec<u8>, span: &Span) {
+   push_u64(buf, span.line as u64);
+   push_u64(buf, span.column as u64);
+   push_u64(buf, span.start as u64);
+   push_u64(buf, span.end as u64);
+}
+
+pub fn push_exprs(buf: &mut Vec<u8>, exprs: &Vec<ExprAst>) {
+   push_u64(buf, exprs.len() as u64);
+   for expr in exprs.iter() {
+      buf.push_all_move(expr.compile());
+   }
+}
+
+pub struct Decoder<'a> {
+   data: &'a [u8],
+   pos: uint,
+   // identical strings in the data share their text, as the intern-strings pass left them
+   strings: StringPool
+}
+
+impl<'a> Decoder<'a> {
+   pub fn new(data: &'a [u8]) -> Decoder<'a> {
+      Decoder {
+         data: data,
+         pos: 0,
+         strings: StringPool::new()
+      }
+   }
+
+   pub fn read_expr(&mut self) -> Result<ExprAst, String> {
+      let tag = try!(self.read_u8());
+      if tag == TAG_ROOT {
+         let mut root = RootAst::new();
+         root.asts = try!(self.read_exprs());
+         Ok(Root(root))
+      } else if tag == TAG_SEXPR {
+         let op = try!(self.read_string());
+         let operands = try!(self.read_exprs());
+         let mut sexpr = SexprAst::new(IdentAst::new(op), operands);
+         sexpr.span = try!(self.read_span());
+         Ok(Sexpr(sexpr))
+      } else if tag == TAG_STRING {
+         let text = try!(self.read_string());
+         Ok(String(self.strings.intern(StringAst::new(text))))
+      } else if tag == TAG_LIST {
+         Ok(List(ListAst::new(try!(self.read_exprs()))))
+      } else if tag == TAG_ARRAY {
+         Ok(Array(ArrayAst::new(try!(self.read_exprs()))))
+      } else if tag == TAG_IDENT {
+         Ok(Ident(IdentAst::new(try!(self.read_string()))))
+      } else if tag == TAG_SYMBOL {
+         Ok(Symbol(SymbolAst::new(try!(self.read_string()))))
+      } else if tag == TAG_KEYWORD {
+         Ok(Keyword(KeywordAst::new(try!(self.read_string()))))
+      } else if tag == TAG_INTEGER {
+         Ok(Integer(IntegerAst::new(try!(self.read_u64()) as i64)))
+      } else if tag == TAG_FLOAT {
+         let bits = try!(self.read_u64());
+         Ok(Float(FloatAst::new(unsafe { mem::transmute::<u64, f64>(bits) })))
+      } else if tag == TAG_BOOLEAN {
+         Ok(Boolean(BooleanAst::new(try!(self.read_u8()) != 0)))
+      } else if tag == TAG_NIL {
+         Ok(Nil(NilAst::new()))
+      } else if tag == TAG_COMMENT {
+         Ok(Comment(CommentAst::new(try!(self.read_string()))))
+      } else if tag == TAG_MAP {
+         Ok(Map(try!(MapAst::from_items(try!(self.read_exprs())))))
+      } else {
+         Err(format!("unknown tag {} at byte {}", tag, self.pos - 1))
+      }
+   }
+
+   fn read_exprs(&mut self) -> Result<Vec<ExprAst>, String> {
+      let count = try!(self.read_u64());
+      let mut exprs = vec!();
+      for _ in range(0, count) {
+         exprs.push(try!(self.read_expr()));
+      }
+      Ok(exprs)
+   }
+
+   fn read_span(&mut self) -> Result<Span, String> {
+      let line = try!(self.read_u64()) as uint;
+      let column = try!(self.read_u64()) as uint;
+      let start = try!(self.read_u64()) as uint;
+      let end = try!(self.read_u64()) as uint;
+      Ok(Span::new(line, column, start, end))
+   }
+
+   fn read_u8(&mut self) -> Result<u8, String> {
+      if self.pos >= self.data.len() {
+         Err("unexpected end of data".to_string())
+      } else {
+         self.pos += 1;
+         Ok(self.data[self.pos - 1])
+      }
+   }
+
+   fn read_u64(&mut self) -> Result<u64, String> {
+      let mut val = 0u64;
+      for _ in range(0u, 8) {
+         val = (val << 8) | try!(self.read_u8()) as u64;
+      }
+      Ok(val)
+   }
+
+   fn read_string(&mut self) -> Result<String, String> {
+      let len = try!(self.read_u64()) as uint;
+      if self.pos + len > self.data.len() {
+         return Err("unexpected end of data".to_string());
+      }
+      let bytes = self.data.slice(self.pos, self.pos + len);
+      self.pos += len;
+      match ::std::str::from_utf8(bytes) {
+         Some(string) => Ok(string.to_string()),
+         None => Err("invalid UTF-8 in string".to_string())
+      }
+   }
+}
+
+pub fn compiled_path(source: &Path) -> Path {
+   source.with_extension("irlc")
+}
+
+// Parses and optimizes the given source file and writes the result next to it.  A file
+// that does not parse fails with an InvalidInput error.
+pub fn compile_file(source: &Path) -> IoResult<Path> {
+   let stat = try!(io::fs::stat(source));
+   let code = try!(try!(io::File::open(source)).read_to_string());
+   let ast = match Parser::new().try_parse_code(code) {
+      Ok(Root(ast)) => Root(optimize::optimize(ast, optimize::default_passes(Release).as_slice())),
+      Ok(_) => unreachable!(),
+      Err(f) => return Err(io::IoError {
+         kind: io::InvalidInput,
+         desc: "could not parse the source file",
+         detail: Some(f.to_string())
+      })
+   };
+   let mut data = MAGIC.to_vec();
+   data.push(FORMAT_VERSION);
+   push_u64(&mut data, stat.modified);
+   data.push_all_move(ast.compile());
+   let path = compiled_path(source);
+   try!(try!(io::File::create(&path)).write(data.as_slice()));
+   Ok(path)
+}
+
+// Returns the AST stored in the .irlc file next to the source file if there is one and
+// it was produced from the current version of the source.
+pub fn load_cached(source: &Path) -> Option<ExprAst> {
+   let path = compiled_path(source);
+   if !path.exists() {
+      return None;
+   }
+   let modified = match io::fs::stat(source) {
+      Ok(stat) => stat.modified,
+      Err(_) => return None
+   };
+   let data = match io::File::open(&path).read_to_end() {
+      Ok(m) => m,
+      Err(_) => return None
+   };
+   let header = MAGIC.len() + 1;
+   if data.len() < header + 8 || data.slice_to(MAGIC.len()) != MAGIC || data[MAGIC.len()] != FORMAT_VERSION {
+      debug!("ignoring malformed {}", path.display());
+      return None;
+   }
+   let mut decoder = Decoder::new(data.slice_from(header));
+   match decoder.read_u64() {
+      Ok(stamp) if stamp == modified => {}
+      _ => return None
+   }
+   match decoder.read_expr() {
+      Ok(ast @ Root(_)) => Some(ast),
+      Ok(_) => None,
+      Err(f) => {
+         debug!("ignoring {}: {}", path.display(), f);
+         None
+      }
+   }
+}
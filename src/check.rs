@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::IoResult;
+use std::os;
+
+use ast::*;
+use bundle;
+use parser::Parser;
+use typecheck;
+
+// Checks a program and every module it imports without running them, for iron check.
+// Starting from the entry file, each import of a string found anywhere in the code is
+// followed to the module it names (found as import finds it), and every file reached is
+// checked by the type checker (see typecheck.rs).  Besides its problems, the report has
+//    unresolved imports   modules that cannot be found, read or parsed, reported where
+//                         they are imported
+//    unused definitions   names defined at the top level of a file of the program by
+//                         define, defglobal, defdynamic, defconst, defn or defmacro
+//                         that no file refers to; installed packages and names
+//                         starting with _ are left out
+// References are found by name, so a name used anywhere (even quoted) counts as used.
+
+pub struct Report {
+   pub file: String,
+   pub span: Span,
+   pub message: String
+}
+
+struct Module {
+   path: Path,
+   root: ExprAst,
+   // whether the module is an installed package rather than part of the program
+   package: bool
+}
+
+fn is_definition(op: &str) -> bool {
+   ["define", "defglobal", "defdynamic", "defconst", "defn", "defmacro"].contains(&op)
+}
+
+// The problems found in the program whose entry point is the file at entry, file by file
+// in the order the files were reached and by position within each file.
+pub fn check_program(entry: &Path) -> Vec<Report> {
+   let mut modules = vec!();
+   let mut seen = HashSet::new();
+   seen.insert(os::make_absolute(entry));
+   match load(entry) {
+      Ok(root) => modules.push(Module { path: entry.clone(), root: root, package: false }),
+      Err(f) => return vec!(Report { file: entry.display().to_string(), span: Span::unknown(), message: f })
+   }
+   let mut reports = vec!();
+   let mut idx = 0;
+   while idx < modules.len() {
+      let file = modules[idx].path.display().to_string();
+      let mut found = vec!();
+      for (name, span) in find_imports(&modules[idx].root).move_iter() {
+         let loaded = bundle::module_path(&modules[idx].path, name.as_slice()).and_then(|path| {
+            if seen.insert(os::make_absolute(&path)) {
+               match load(&path) {
+                  Ok(root) => Ok(Some((path, root))),
+                  Err(f) => Err(f)
+               }
+            } else {
+               Ok(None)
+            }
+         });
+         match loaded {
+            Ok(Some((path, root))) => {
+               let package = !name.as_slice().starts_with("./") && !name.as_slice().starts_with("../") && !Path::new(name.as_slice()).is_absolute();
+               modules.push(Module { path: path, root: root, package: package });
+            }
+            Ok(None) => {}
+            Err(f) => found.push(Report { file: file.clone(), span: span, message: format!("unresolved import \"{}\": {}", name, f) })
+         }
+      }
+      for problem in typecheck::check(&modules[idx].root).move_iter() {
+         found.push(Report { file: file.clone(), span: problem.span, message: problem.message });
+      }
+      reports.push(found);
+      idx += 1;
+   }
+   let mut references = References { names: HashSet::new() };
+   for module in modules.iter() {
+      module.root.walk(&mut references);
+   }
+   let mut result = vec!();
+   for (module, mut found) in modules.iter().zip(reports.move_iter()) {
+      if !module.package {
+         for (name, span) in top_level_definitions(&module.root).move_iter() {
+            if !name.as_slice().starts_with("_") && !references.names.contains(&name) {
+               found.push(Report {
+                  file: module.path.display().to_string(),
+                  span: span,
+                  message: format!("{} is defined but never used", name)
+               });
+            }
+         }
+      }
+      found.sort_by(|a, b| (a.span.line, a.span.column).cmp(&(b.span.line, b.span.column)));
+      result.push_all_move(found);
+   }
+   result
+}
+
+// Writes the problems found in the program whose entry point is the file at entry, one
+// per line, and returns whether there were any (in which case iron check fails).
+pub fn write_report(entry: &Path, out: &mut Writer) -> IoResult<bool> {
+   let reports = check_program(entry);
+   for report in reports.iter() {
+      if report.span.is_known() {
+         try!(out.write_line(format!("{}:{}:{}: {}", report.file, report.span.line, report.span.column, report.message).as_slice()));
+      } else {
+         try!(out.write_line(format!("{}: {}", report.file, report.message).as_slice()));
+      }
+   }
+   Ok(!reports.is_empty())
+}
+
+fn load(path: &Path) -> Result<ExprAst, String> {
+   let code = match io::File::open(path).read_to_string() {
+      Ok(code) => code,
+      Err(f) => return Err(format!("could not read {}: {}", path.display(), f))
+   };
+   match Parser::new().try_parse_code(code) {
+      Ok(root) => Ok(root),
+      Err(f) => Err(format!("{}: {}", path.display(), f))
+   }
+}
+
+struct Imports {
+   found: Vec<(String, Span)>
+}
+
+impl Visitor for Imports {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      match *expr {
+         Sexpr(ref ast) if ast.op.value.as_slice() == "import" => {
+            for operand in ast.operands.iter() {
+               match *operand {
+                  String(ref name) => self.found.push(((*name.string).clone(), ast.span.clone())),
+                  _ => {}
+               }
+            }
+            false
+         }
+         _ => true
+      }
+   }
+}
+
+// The modules imported anywhere in the code, with where.
+fn find_imports(root: &ExprAst) -> Vec<(String, Span)> {
+   let mut imports = Imports { found: vec!() };
+   root.walk(&mut imports);
+   imports.found
+}
+
+fn top_level_definitions(root: &ExprAst) -> Vec<(String, Span)> {
+   let asts = match *root {
+      Root(ref ast) => &ast.asts,
+      _ => return vec!()
+   };
+   asts.iter().filter_map(|expr| match *expr {
+      Sexpr(ref ast) if is_definition(ast.op.value.as_slice()) => match ast.operands.as_slice().get(0) {
+         Some(&Ident(ref name)) => Some((name.value.clone(), ast.span.clone())),
+         _ => None
+      },
+      _ => None
+   }).collect()
+}
+
+// Every name referred to, as an identifier, the operator of a call or a symbol, leaving
+// out the names being defined.
+struct References {
+   names: HashSet<String>
+}
+
+impl Visitor for References {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      match *expr {
+         Sexpr(ref ast) => {
+            if is_definition(ast.op.value.as_slice()) {
+               match ast.operands.as_slice().get(0) {
+                  Some(&Ident(_)) => {
+                     for operand in ast.operands.slice_from(1).iter() {
+                        operand.walk(self);
+                     }
+                     return false;
+                  }
+                  _ => {}
+               }
+            }
+            self.names.insert(ast.op.value.clone());
+         }
+         Ident(ref ast) => { self.names.insert(ast.value.clone()); }
+         Symbol(ref ast) => { self.names.insert(ast.value.clone()); }
+         _ => {}
+      }
+      true
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use std::io::MemWriter;
+   use super::write_report;
+
+   #[test]
+   fn the_example_project_has_an_unresolved_import_and_an_unused_definition() {
+      let mut output = MemWriter::new();
+      assert!(write_report(&Path::new("examples/check/main.irl"), &mut output).unwrap());
+      let output = String::from_utf8(output.unwrap()).unwrap();
+      let lines: Vec<&str> = output.as_slice().lines().collect();
+      assert_eq!(lines.len(), 2);
+      assert!(lines[0].starts_with("examples/check/main.irl:4:1: unresolved import \"./missing\": could not read examples/check/missing.irl"));
+      assert_eq!(lines[1], "examples/check/util.irl:3:1: triple is defined but never used");
+   }
+}
@@ -0,0 +1,64 @@
+use ast::*;
+
+// ANSI colors for REPL output and diagnostics.  Coloring is off unless enabled by
+// --color (or automatically when writing to a terminal).
+
+static mut ENABLED: bool = false;
+
+pub enum Color {
+   Red,
+   Green,
+   Yellow,
+   Blue,
+   Magenta,
+   Cyan,
+   Bold
+}
+
+pub fn set_enabled(enabled: bool) {
+   unsafe { ENABLED = enabled; }
+}
+
+pub fn enabled() -> bool {
+   unsafe { ENABLED }
+}
+
+pub fn paint(text: &str, color: Color) -> String {
+   if !enabled() {
+      return text.to_string();
+   }
+   let code = match color {
+      Red => "31",
+      Green => "32",
+      Yellow => "33",
+      Blue => "34",
+      Magenta => "35",
+      Cyan => "36",
+      Bold => "1"
+   };
+   format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+// The re-readable form of a value with each kind of literal colored differently.
+pub fn highlight(value: &ExprAst) -> String {
+   if !enabled() {
+      return value.to_source();
+   }
+   match *value {
+      String(_) => paint(value.to_source().as_slice(), Green),
+      Integer(_) | Float(_) => paint(value.to_source().as_slice(), Cyan),
+      Symbol(_) | Keyword(_) => paint(value.to_source().as_slice(), Magenta),
+      Boolean(_) | Nil(_) => paint(value.to_source().as_slice(), Yellow),
+      Error(_) => paint(value.to_source().as_slice(), Red),
+      Array(ref ast) => format!("[{}]", highlight_items(&ast.items)),
+      List(ref ast) => format!("'({})", highlight_items(&ast.items)),
+      Map(ref ast) => format!("{{{}}}", highlight_items(&ast.items())),
+      Tuple(ref ast) if !ast.items.is_empty() => format!("(values {})", highlight_items(&ast.items)),
+      _ => value.to_source()
+   }
+}
+
+fn highlight_items(items: &Vec<ExprAst>) -> String {
+   let items: Vec<String> = items.iter().map(|item| highlight(item)).collect();
+   items.connect(" ")
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use ast::*;
+
+// Reports which parts of a program ran, for iron --coverage.  While coverage is enabled
+// the interpreter counts how many times each s-expression of the main file is evaluated,
+// by the byte offset it starts at; a line is counted as often as the s-expression starting
+// on it that ran the most.  The optimizer is turned off for such runs so that the
+// counts are for the code as it is written.  Lines without an s-expression are not
+// counted, and neither are the modules the program imports.
+
+struct Lines {
+   // the offset of each s-expression along with its line
+   sexprs: Vec<(uint, uint)>
+}
+
+impl Visitor for Lines {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      match *expr {
+         Sexpr(ref ast) if ast.span.is_known() => self.sexprs.push((ast.span.start, ast.span.line)),
+         _ => {}
+      }
+      true
+   }
+}
+
+// The number of times each line with code ran, in line order.
+pub fn line_hits(root: &ExprAst, hits: &HashMap<uint, uint>) -> Vec<(uint, uint)> {
+   let mut lines = Lines { sexprs: vec!() };
+   root.walk(&mut lines);
+   let mut counts: HashMap<uint, uint> = HashMap::new();
+   for &(start, line) in lines.sexprs.iter() {
+      let count = hits.find(&start).map(|count| *count).unwrap_or(0);
+      let total = counts.find_or_insert(line, 0);
+      if count > *total {
+         *total = count;
+      }
+   }
+   let mut result: Vec<(uint, uint)> = counts.move_iter().collect();
+   result.sort();
+   result
+}
+
+// A tracefile in the format of lcov (as read by genhtml) for the file with the given path.
+pub fn lcov(file: &str, root: &ExprAst, hits: &HashMap<uint, uint>) -> String {
+   let lines = line_hits(root, hits);
+   let mut result = format!("TN:\nSF:{}\n", file);
+   for &(line, count) in lines.iter() {
+      result.push_str(format!("DA:{},{}\n", line, count).as_slice());
+   }
+   let covered = lines.iter().filter(|&&(_, count)| count > 0).count();
+   result.push_str(format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), covered).as_slice());
+   result
+}
+
+// The source with each line preceded by the number of times it ran, like gcov: "-" for a
+// line without code and "#####" for one that never ran.
+pub fn annotate(code: &str, root: &ExprAst, hits: &HashMap<uint, uint>) -> String {
+   let counts: HashMap<uint, uint> = line_hits(root, hits).move_iter().collect();
+   let mut result = String::new();
+   for (idx, text) in code.lines().enumerate() {
+      let count = match counts.find(&(idx + 1)) {
+         Some(&0) => "#####".to_string(),
+         Some(count) => count.to_string(),
+         None => "-".to_string()
+      };
+      result.push_str(format!("{:>9}:{:>5}: {}\n", count, idx + 1, text).as_slice());
+   }
+   result
+}
+
+#[cfg(test)]
+mod test {
+   use interp::Interpreter;
+   use parser::Parser;
+   use super::{annotate, lcov};
+
+   static CODE: &'static str = "; counts to n\n(define count (fn [n]\n   (if (= n 0)\n      0\n      (count (+ n -1)))))\n(count 2)\n(if false\n   (println \"never\"))\n";
+
+   #[test]
+   fn lines_are_counted_by_the_sexpr_on_them_that_ran_most() {
+      let mut interp = Interpreter::new();
+      interp.enable_coverage();
+      interp.load_code(CODE.to_string());
+      interp.set_file("count.irl".to_string());
+      interp.run().unwrap();
+      let hits = interp.coverage().unwrap();
+      let root = Parser::new().try_parse_code(CODE.to_string()).unwrap();
+      let annotated = annotate(CODE, &root, &hits);
+      assert_eq!(annotated.as_slice().lines().collect::<Vec<&str>>(),
+                 vec!("        -:    1: ; counts to n",
+                      "        1:    2: (define count (fn [n]",
+                      "        3:    3:    (if (= n 0)",
+                      "        -:    4:       0",
+                      "        2:    5:       (count (+ n -1)))))",
+                      "        1:    6: (count 2)",
+                      "        1:    7: (if false",
+                      "    #####:    8:    (println \"never\"))"));
+      assert_eq!(lcov("count.irl", &root, &hits).as_slice(),
+                 "TN:\nSF:count.irl\nDA:2,1\nDA:3,3\nDA:5,2\nDA:6,1\nDA:7,1\nDA:8,0\nLF:6\nLH:5\nend_of_record\n");
+   }
+}
@@ -0,0 +1,88 @@
+// Reading and writing comma-separated values, for csv-parse and csv-write.  Fields may be
+// quoted with double quotes, inside which the delimiter and line breaks are part of the
+// field and a quote is written twice.  Rows end with \n or \r\n, and a line break at the
+// end of the text does not start another row.
+
+pub fn parse(text: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+   let mut rows = vec!();
+   let mut row = vec!();
+   let mut field = String::new();
+   let mut quoted = false;
+   // whether the current field started with a quote, which it may only end with
+   let mut was_quoted = false;
+   let mut line = 1u;
+   let mut chars = text.chars().peekable();
+   loop {
+      let ch = match chars.next() {
+         Some(ch) => ch,
+         None => break
+      };
+      if quoted {
+         if ch == '"' {
+            if chars.peek() == Some(&'"') {
+               chars.next();
+               field.push_char('"');
+            } else {
+               quoted = false;
+            }
+         } else {
+            if ch == '\n' {
+               line += 1;
+            }
+            field.push_char(ch);
+         }
+      } else if ch == '"' && field.is_empty() && !was_quoted {
+         quoted = true;
+         was_quoted = true;
+      } else if ch == delimiter {
+         row.push(field);
+         field = String::new();
+         was_quoted = false;
+      } else if ch == '\n' || (ch == '\r' && chars.peek() == Some(&'\n')) {
+         if ch == '\r' {
+            chars.next();
+         }
+         row.push(field);
+         rows.push(row);
+         row = vec!();
+         field = String::new();
+         was_quoted = false;
+         line += 1;
+      } else if was_quoted {
+         return Err(format!("line {}: unexpected {} after a quoted field", line, ch));
+      } else {
+         field.push_char(ch);
+      }
+   }
+   if quoted {
+      return Err(format!("line {}: unclosed quote", line));
+   }
+   if !row.is_empty() || !field.is_empty() || was_quoted {
+      row.push(field);
+      rows.push(row);
+   }
+   Ok(rows)
+}
+
+// The text of the rows, each ending with \n.  Fields are quoted if they have to be, or
+// all of them if quote_all is set.
+pub fn write(rows: &[Vec<String>], delimiter: char, quote_all: bool) -> String {
+   let mut result = String::new();
+   for row in rows.iter() {
+      for (idx, field) in row.iter().enumerate() {
+         if idx > 0 {
+            result.push_char(delimiter);
+         }
+         let special = field.as_slice().chars().any(|ch| ch == delimiter || ch == '"' || ch == '\n' || ch == '\r');
+         if quote_all || special {
+            result.push_char('"');
+            result.push_str(field.as_slice().replace("\"", "\"\"").as_slice());
+            result.push_char('"');
+         } else {
+            result.push_str(field.as_slice());
+         }
+      }
+      result.push_char('\n');
+   }
+   result
+}
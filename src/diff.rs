@@ -0,0 +1,102 @@
+use ast::*;
+use interp::{escape, unescape};
+
+// The differences between two values, for the diff builtin and failed assert-eq, one line
+// for each.  Arrays, lists and tuples are compared item by item and maps entry by entry,
+// so that only the items that differ are shown, each with its path from the outer value
+// written the way get-in takes it.  Strings are shown from just before the first
+// character that differs.  Equal values have no differences.
+pub fn diff(left: &ExprAst, right: &ExprAst) -> Vec<String> {
+   let mut lines = vec!();
+   diff_at(&mut vec!(), left, right, &mut lines);
+   lines
+}
+
+// Whether diff says more about the value than showing it whole would.
+pub fn is_structured(value: &ExprAst) -> bool {
+   match *value {
+      Array(_) | List(_) | Tuple(_) | Map(_) | SortedMap(_) | String(_) => true,
+      _ => false
+   }
+}
+
+fn diff_at(path: &mut Vec<ExprAst>, left: &ExprAst, right: &ExprAst, lines: &mut Vec<String>) {
+   if left == right {
+      return;
+   }
+   match (left, right) {
+      (&Array(ref left), &Array(ref right)) => diff_items(path, left.items.as_slice(), right.items.as_slice(), lines),
+      (&List(ref left), &List(ref right)) => diff_items(path, left.items.as_slice(), right.items.as_slice(), lines),
+      (&Tuple(ref left), &Tuple(ref right)) => diff_items(path, left.items.as_slice(), right.items.as_slice(), lines),
+      (&Map(ref left), &Map(ref right)) => {
+         let left: Vec<(ExprAst, ExprAst)> = left.entries.iter().map(|&(_, ref key, ref value)| (key.clone(), value.clone())).collect();
+         let right: Vec<(ExprAst, ExprAst)> = right.entries.iter().map(|&(_, ref key, ref value)| (key.clone(), value.clone())).collect();
+         diff_entries(path, left.as_slice(), right.as_slice(), lines)
+      }
+      (&SortedMap(ref left), &SortedMap(ref right)) => diff_entries(path, left.entries.as_slice(), right.entries.as_slice(), lines),
+      (&String(ref left), &String(ref right)) => match (unescape(left.string.as_slice()), unescape(right.string.as_slice())) {
+         (Ok(left), Ok(right)) => lines.push(diff_text(path, left.as_slice(), right.as_slice())),
+         _ => lines.push(format!("{}: {} != {}", show_path(path), left.to_source(), right.to_source()))
+      },
+      _ => lines.push(format!("{}: {} != {}", show_path(path), left.to_source(), right.to_source()))
+   }
+}
+
+fn diff_items(path: &mut Vec<ExprAst>, left: &[ExprAst], right: &[ExprAst], lines: &mut Vec<String>) {
+   for idx in range(0, ::std::cmp::max(left.len(), right.len())) {
+      path.push(Integer(IntegerAst::new(idx as i64)));
+      match (left.get(idx), right.get(idx)) {
+         (Some(left), Some(right)) => diff_at(path, left, right, lines),
+         (Some(left), None) => lines.push(format!("{}: only on the left: {}", show_path(path), left.to_source())),
+         (None, Some(right)) => lines.push(format!("{}: only on the right: {}", show_path(path), right.to_source())),
+         (None, None) => unreachable!()
+      }
+      path.pop();
+   }
+}
+
+fn diff_entries(path: &mut Vec<ExprAst>, left: &[(ExprAst, ExprAst)], right: &[(ExprAst, ExprAst)], lines: &mut Vec<String>) {
+   for &(ref key, ref value) in left.iter() {
+      path.push(key.clone());
+      match right.iter().find(|&&(ref other, _)| other == key) {
+         Some(&(_, ref other)) => diff_at(path, value, other, lines),
+         None => lines.push(format!("{}: only on the left: {}", show_path(path), value.to_source()))
+      }
+      path.pop();
+   }
+   for &(ref key, ref value) in right.iter() {
+      if !left.iter().any(|&(ref other, _)| other == key) {
+         path.push(key.clone());
+         lines.push(format!("{}: only on the right: {}", show_path(path), value.to_source()));
+         path.pop();
+      }
+   }
+}
+
+// The strings from a few characters before the first one that differs, up to a limited
+// number of characters.
+fn diff_text(path: &Vec<ExprAst>, left: &str, right: &str) -> String {
+   let left: Vec<char> = left.chars().collect();
+   let right: Vec<char> = right.chars().collect();
+   let mut first = 0;
+   while first < left.len() && first < right.len() && left[first] == right[first] {
+      first += 1;
+   }
+   let start = if first > 10 { first - 10 } else { 0 };
+   let excerpt = |text: &Vec<char>| {
+      let end = ::std::cmp::min(text.len(), start + 30);
+      let mut result = escape(String::from_chars(text.slice(start, end)).as_slice()).to_source();
+      if start > 0 {
+         result = format!("...{}", result);
+      }
+      if end < text.len() {
+         result.push_str("...");
+      }
+      result
+   };
+   format!("{}: strings differ at character {}: {} != {}", show_path(path), first, excerpt(&left), excerpt(&right))
+}
+
+fn show_path(path: &Vec<ExprAst>) -> String {
+   ArrayAst::new(path.clone()).to_source()
+}
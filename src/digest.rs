@@ -0,0 +1,209 @@
+// Digests of bytes for the md5, sha1, sha256 and crc32 builtins, each written as lowercase
+// hex.  md5 and sha1 are fine for cache keys and checksums but are broken as protection
+// against someone choosing the input.
+
+static MD5_SHIFTS: [uint, ..64] = [
+   7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+   5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+   4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+   6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21
+];
+
+static MD5_CONSTANTS: [u32, ..64] = [
+   0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+   0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+   0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+   0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+   0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+   0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+   0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+   0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+   0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+   0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+   0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+   0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+   0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+   0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+   0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+   0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391
+];
+
+static SHA256_CONSTANTS: [u32, ..64] = [
+   0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+   0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+   0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+   0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+   0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+   0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+   0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+   0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+pub fn md5(data: &[u8]) -> String {
+   let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476];
+   for block in pad(data, false).as_slice().chunks(64) {
+      let mut words = [0u32, ..16];
+      for (idx, bytes) in block.chunks(4).enumerate() {
+         words[idx] = word(bytes, false);
+      }
+      let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+      for idx in range(0u, 64) {
+         let (f, g) = match idx / 16 {
+            0 => ((b & c) | (!b & d), idx),
+            1 => ((d & b) | (!d & c), (5 * idx + 1) % 16),
+            2 => (b ^ c ^ d, (3 * idx + 5) % 16),
+            _ => (c ^ (b | !d), (7 * idx) % 16)
+         };
+         let rotated = rotate_left(a + f + MD5_CONSTANTS[idx] + words[g], MD5_SHIFTS[idx]);
+         a = d;
+         d = c;
+         c = b;
+         b = b + rotated;
+      }
+      state[0] += a;
+      state[1] += b;
+      state[2] += c;
+      state[3] += d;
+   }
+   // unlike the SHA digests, each word is written least significant byte first
+   let mut result = String::new();
+   for value in state.iter() {
+      for shift in range(0u, 4) {
+         result.push_str(format!("{:02x}", (*value >> (shift * 8)) as u8).as_slice());
+      }
+   }
+   result
+}
+
+pub fn sha1(data: &[u8]) -> String {
+   let mut state = [0x67452301u32, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+   for block in pad(data, true).as_slice().chunks(64) {
+      let mut words = [0u32, ..80];
+      for (idx, bytes) in block.chunks(4).enumerate() {
+         words[idx] = word(bytes, true);
+      }
+      for idx in range(16u, 80) {
+         words[idx] = rotate_left(words[idx - 3] ^ words[idx - 8] ^ words[idx - 14] ^ words[idx - 16], 1);
+      }
+      let (mut a, mut b, mut c, mut d, mut e) = (state[0], state[1], state[2], state[3], state[4]);
+      for idx in range(0u, 80) {
+         let (f, k) = match idx / 20 {
+            0 => ((b & c) | (!b & d), 0x5a827999u32),
+            1 => (b ^ c ^ d, 0x6ed9eba1),
+            2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6)
+         };
+         let temp = rotate_left(a, 5) + f + e + k + words[idx];
+         e = d;
+         d = c;
+         c = rotate_left(b, 30);
+         b = a;
+         a = temp;
+      }
+      state[0] += a;
+      state[1] += b;
+      state[2] += c;
+      state[3] += d;
+      state[4] += e;
+   }
+   hex_words(state.as_slice())
+}
+
+pub fn sha256(data: &[u8]) -> String {
+   let mut state = [
+      0x6a09e667u32, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+      0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+   ];
+   for block in pad(data, true).as_slice().chunks(64) {
+      let mut words = [0u32, ..64];
+      for (idx, bytes) in block.chunks(4).enumerate() {
+         words[idx] = word(bytes, true);
+      }
+      for idx in range(16u, 64) {
+         let (early, late) = (words[idx - 15], words[idx - 2]);
+         let s0 = rotate_right(early, 7) ^ rotate_right(early, 18) ^ (early >> 3);
+         let s1 = rotate_right(late, 17) ^ rotate_right(late, 19) ^ (late >> 10);
+         words[idx] = words[idx - 16] + s0 + words[idx - 7] + s1;
+      }
+      let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+      let (mut e, mut f, mut g, mut h) = (state[4], state[5], state[6], state[7]);
+      for idx in range(0u, 64) {
+         let s1 = rotate_right(e, 6) ^ rotate_right(e, 11) ^ rotate_right(e, 25);
+         let choice = (e & f) ^ (!e & g);
+         let temp1 = h + s1 + choice + SHA256_CONSTANTS[idx] + words[idx];
+         let s0 = rotate_right(a, 2) ^ rotate_right(a, 13) ^ rotate_right(a, 22);
+         let majority = (a & b) ^ (a & c) ^ (b & c);
+         let temp2 = s0 + majority;
+         h = g;
+         g = f;
+         f = e;
+         e = d + temp1;
+         d = c;
+         c = b;
+         b = a;
+         a = temp1 + temp2;
+      }
+      state[0] += a;
+      state[1] += b;
+      state[2] += c;
+      state[3] += d;
+      state[4] += e;
+      state[5] += f;
+      state[6] += g;
+      state[7] += h;
+   }
+   hex_words(state.as_slice())
+}
+
+// The CRC-32 used by zip, gzip and PNG (the reflected polynomial 0xedb88320).
+pub fn crc32(data: &[u8]) -> String {
+   let mut crc = 0xffffffffu32;
+   for &byte in data.iter() {
+      crc ^= byte as u32;
+      for _ in range(0u, 8) {
+         crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+      }
+   }
+   format!("{:08x}", !crc)
+}
+
+// The data followed by a 1 bit, enough 0 bits to end 8 bytes short of a multiple of 64
+// bytes, and then its length in bits.
+fn pad(data: &[u8], big_endian: bool) -> Vec<u8> {
+   let mut message = data.to_vec();
+   message.push(0x80);
+   while message.len() % 64 != 56 {
+      message.push(0);
+   }
+   let bits = data.len() as u64 * 8;
+   for idx in range(0u, 8) {
+      let shift = if big_endian { 56 - idx * 8 } else { idx * 8 };
+      message.push((bits >> shift) as u8);
+   }
+   message
+}
+
+fn word(bytes: &[u8], big_endian: bool) -> u32 {
+   let mut result = 0u32;
+   for idx in range(0u, 4) {
+      let byte = bytes[if big_endian { idx } else { 3 - idx }];
+      result = (result << 8) | byte as u32;
+   }
+   result
+}
+
+fn hex_words(words: &[u32]) -> String {
+   let mut result = String::new();
+   for value in words.iter() {
+      result.push_str(format!("{:08x}", *value).as_slice());
+   }
+   result
+}
+
+fn rotate_left(value: u32, count: uint) -> u32 {
+   (value << count) | (value >> (32 - count))
+}
+
+fn rotate_right(value: u32, count: uint) -> u32 {
+   rotate_left(value, 32 - count)
+}
@@ -0,0 +1,100 @@
+use ast::*;
+
+pub struct DocEntry {
+   pub name: String,
+   pub params: Option<ArrayAst>,
+   pub doc: String
+}
+
+// Finds the documented top-level definitions, i.e. (define name "doc" value) and
+// (define name (fn [params] "doc" body...)), or the same written with defn, without
+// evaluating anything.  Definitions
+// without a doc string are documented by the comments directly before them, if any.
+pub fn collect_docs(root: &ExprAst) -> Vec<DocEntry> {
+   let mut result = vec!();
+   let root = match *root {
+      Root(ref ast) => ast,
+      _ => return result
+   };
+   for (idx, ast) in root.asts.iter().enumerate() {
+      match *ast {
+         Sexpr(ref sast) if sast.op.value.as_slice() == "define" => match collect_define(sast, root.trivia.before(idx)) {
+            Some(entry) => result.push(entry),
+            None => {}
+         },
+         Sexpr(ref sast) if sast.op.value.as_slice() == "defn" => match sast.expand_defn() {
+            Some(define) => match collect_define(&define, root.trivia.before(idx)) {
+               Some(entry) => result.push(entry),
+               None => {}
+            },
+            None => {}
+         },
+         _ => {}
+      }
+   }
+   result
+}
+
+fn collect_define(sast: &SexprAst, comments: Vec<&String>) -> Option<DocEntry> {
+   let name = match sast.operands.as_slice().get(0) {
+      Some(&Ident(ref ast)) => ast.value.clone(),
+      _ => return None
+   };
+   let (doc, value) = match sast.operands.len() {
+      2 => (None, &sast.operands[1]),
+      3 => match sast.operands[1] {
+         String(ref ast) => (Some((*ast.string).clone()), &sast.operands[2]),
+         _ => return None
+      },
+      _ => return None
+   };
+   let (params, fndoc) = match *value {
+      Sexpr(ref fnast) if fnast.op.value.as_slice() == "fn" => {
+         let params = match fnast.operands.as_slice().get(0) {
+            Some(&Array(ref ast)) => Some(ast.clone()),
+            _ => None
+         };
+         // multiple-arity functions take the doc string before their clauses
+         let docidx = if params.is_some() { 1 } else { 0 };
+         let fndoc =
+            if fnast.operands.len() > docidx + 1 {
+               match fnast.operands[docidx] {
+                  String(ref ast) => Some((*ast.string).clone()),
+                  _ => None
+               }
+            } else {
+               None
+            };
+         (params, fndoc)
+      }
+      _ => (None, None)
+   };
+   let comments =
+      if comments.is_empty() {
+         None
+      } else {
+         let lines: Vec<&str> = comments.iter().map(|comment| comment.as_slice().trim_left_chars(';').trim()).collect();
+         Some(lines.connect("\n"))
+      };
+   match doc.or(fndoc).or(comments) {
+      Some(doc) => Some(DocEntry {
+         name: name,
+         params: params,
+         doc: doc
+      }),
+      None => None
+   }
+}
+
+pub fn print_docs(root: &ExprAst) {
+   for entry in collect_docs(root).iter() {
+      match entry.params {
+         Some(ref params) => println!("{} {}", entry.name, params.to_source()),
+         None => println!("{}", entry.name)
+      }
+      for line in entry.doc.as_slice().lines() {
+         println!("   {}", line.trim());
+      }
+      println!("");
+   }
+}
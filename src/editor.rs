@@ -0,0 +1,333 @@
+use std::io;
+use std::os;
+use std::str;
+use libc::{c_int, c_uint, c_uchar};
+
+// Minimal line editor for the REPL: cursor movement, history and tab completion on a
+// terminal put into non-canonical mode.  Only the termios layout used by Linux is
+// supported.
+
+#[repr(C)]
+struct Termios {
+   c_iflag: c_uint,
+   c_oflag: c_uint,
+   c_cflag: c_uint,
+   c_lflag: c_uint,
+   c_line: c_uchar,
+   c_cc: [c_uchar, ..32],
+   c_ispeed: c_uint,
+   c_ospeed: c_uint
+}
+
+extern {
+   fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+   fn tcsetattr(fd: c_int, optional_actions: c_int, termios: *const Termios) -> c_int;
+}
+
+static ICANON: c_uint = 0o000002;
+static ECHO: c_uint = 0o000010;
+static TCSAFLUSH: c_int = 2;
+static VTIME: uint = 5;
+static VMIN: uint = 6;
+
+static MAX_HISTORY: uint = 1000;
+
+// Puts the terminal into non-canonical mode without echo until dropped.
+struct RawMode {
+   original: Termios
+}
+
+impl RawMode {
+   fn enable() -> Option<RawMode> {
+      unsafe {
+         let mut original: Termios = ::std::mem::zeroed();
+         if tcgetattr(0, &mut original) != 0 {
+            return None;
+         }
+         let mut raw: Termios = ::std::mem::zeroed();
+         tcgetattr(0, &mut raw);
+         raw.c_lflag &= !(ICANON | ECHO);
+         raw.c_cc[VMIN] = 1;
+         raw.c_cc[VTIME] = 0;
+         if tcsetattr(0, TCSAFLUSH, &raw) != 0 {
+            return None;
+         }
+         Some(RawMode { original: original })
+      }
+   }
+}
+
+impl Drop for RawMode {
+   fn drop(&mut self) {
+      unsafe { tcsetattr(0, TCSAFLUSH, &self.original); }
+   }
+}
+
+pub struct Editor {
+   history: Vec<String>,
+   history_path: Option<Path>,
+   input: io::stdio::StdReader,
+   output: io::LineBufferedWriter<io::stdio::StdWriter>
+}
+
+impl Editor {
+   pub fn new(history_path: Option<Path>) -> Editor {
+      let mut editor = Editor {
+         history: vec!(),
+         history_path: history_path,
+         input: io::stdio::stdin_raw(),
+         output: io::stdout()
+      };
+      editor.load_history();
+      editor
+   }
+
+   // The default location of the history file, ~/.iron_history.
+   pub fn default_history_path() -> Option<Path> {
+      os::homedir().map(|home| home.join(".iron_history"))
+   }
+
+   fn load_history(&mut self) {
+      let path = match self.history_path {
+         Some(ref path) if path.exists() => path.clone(),
+         _ => return
+      };
+      match io::File::open(&path).read_to_string() {
+         Ok(data) => {
+            for line in data.as_slice().lines() {
+               if line.len() > 0 {
+                  self.history.push(line.to_string());
+               }
+            }
+         }
+         Err(f) => debug!("could not read {}: {}", path.display(), f)
+      }
+   }
+
+   pub fn save_history(&self) {
+      let path = match self.history_path {
+         Some(ref path) => path,
+         None => return
+      };
+      let start = if self.history.len() > MAX_HISTORY { self.history.len() - MAX_HISTORY } else { 0 };
+      let result = io::File::create(path).and_then(|mut file| {
+         for entry in self.history.slice_from(start).iter() {
+            try!(file.write_line(entry.as_slice()));
+         }
+         Ok(())
+      });
+      match result {
+         Ok(()) => {}
+         Err(f) => debug!("could not write {}: {}", path.display(), f)
+      }
+   }
+
+   // Remembers an entry, which may span several lines, for the up and down keys.
+   pub fn add_history(&mut self, entry: &str) {
+      let entry = entry.trim();
+      if entry.len() == 0 || self.history.last().map_or(false, |last| last.as_slice() == entry) {
+         return;
+      }
+      // the history file stores one entry per line
+      let entry: Vec<&str> = entry.lines().map(|line| line.trim()).collect();
+      self.history.push(entry.connect(" "));
+   }
+
+   // Reads a line, completing the word before the cursor from names when tab is pressed.
+   // Returns None at the end of input.
+   pub fn read_line(&mut self, prompt: &str, names: &[String]) -> Option<String> {
+      let _raw = match RawMode::enable() {
+         Some(raw) => raw,
+         None => return self.read_plain_line(prompt)
+      };
+      let mut line: Vec<char> = vec!();
+      let mut cursor = 0u;
+      // position in the history, with history.len() meaning the line being edited
+      let mut index = self.history.len();
+      let mut edited: Vec<char> = vec!();
+      self.redraw(prompt, &line, cursor);
+      loop {
+         let byte = match self.input.read_byte() {
+            Ok(byte) => byte,
+            Err(_) => return None
+         };
+         match byte {
+            b'\r' | b'\n' => {
+               self.write("\r\n");
+               return Some(String::from_chars(line.as_slice()));
+            }
+            4 => {
+               // ctrl-d
+               if line.is_empty() {
+                  self.write("\r\n");
+                  return None;
+               } else if cursor < line.len() {
+                  line.remove(cursor);
+               }
+            }
+            3 => {
+               // ctrl-c abandons the line
+               self.write("^C\r\n");
+               return Some(String::new());
+            }
+            127 | 8 => if cursor > 0 {
+               cursor -= 1;
+               line.remove(cursor);
+            },
+            1 => cursor = 0,
+            5 => cursor = line.len(),
+            2 => if cursor > 0 { cursor -= 1 },
+            6 => if cursor < line.len() { cursor += 1 },
+            11 => line.truncate(cursor),
+            21 => {
+               line = line.slice_from(cursor).to_vec();
+               cursor = 0;
+            }
+            16 | 14 => {
+               let up = byte == 16;
+               self.move_history(up, &mut index, &mut edited, &mut line, &mut cursor);
+            }
+            b'\t' => self.complete(prompt, names, &mut line, &mut cursor),
+            27 => match self.read_escape() {
+               Some('A') => self.move_history(true, &mut index, &mut edited, &mut line, &mut cursor),
+               Some('B') => self.move_history(false, &mut index, &mut edited, &mut line, &mut cursor),
+               Some('C') => if cursor < line.len() { cursor += 1 },
+               Some('D') => if cursor > 0 { cursor -= 1 },
+               Some('H') => cursor = 0,
+               Some('F') => cursor = line.len(),
+               Some('~') => if cursor < line.len() { line.remove(cursor); },
+               _ => {}
+            },
+            byte if byte >= 32 => match self.read_char(byte) {
+               Some(ch) => {
+                  line.insert(cursor, ch);
+                  cursor += 1;
+               }
+               None => {}
+            },
+            _ => {}
+         }
+         self.redraw(prompt, &line, cursor);
+      }
+   }
+
+   fn read_plain_line(&mut self, prompt: &str) -> Option<String> {
+      self.write(prompt);
+      let mut line = vec!();
+      loop {
+         match self.input.read_byte() {
+            Ok(b'\n') => break,
+            Ok(byte) => line.push(byte),
+            Err(_) if line.is_empty() => return None,
+            Err(_) => break
+         }
+      }
+      Some(String::from_utf8_lossy(line.as_slice()).into_string())
+   }
+
+   // Reads the rest of an escape sequence, returning the character that identifies it
+   // ('~' for the delete key).
+   fn read_escape(&mut self) -> Option<char> {
+      match self.input.read_byte() {
+         Ok(b'[') | Ok(b'O') => {}
+         _ => return None
+      }
+      match self.input.read_byte() {
+         Ok(byte) if byte >= b'0' && byte <= b'9' => match self.input.read_byte() {
+            Ok(b'~') if byte == b'3' => Some('~'),
+            _ => None
+         },
+         Ok(byte) => Some(byte as char),
+         Err(_) => None
+      }
+   }
+
+   // Decodes the UTF-8 character starting with the given byte.
+   fn read_char(&mut self, first: u8) -> Option<char> {
+      let len =
+         if first < 0x80 {
+            1
+         } else if first >= 0xF0 {
+            4
+         } else if first >= 0xE0 {
+            3
+         } else {
+            2
+         };
+      let mut bytes = vec!(first);
+      for _ in range(1u, len) {
+         match self.input.read_byte() {
+            Ok(byte) => bytes.push(byte),
+            Err(_) => return None
+         }
+      }
+      str::from_utf8(bytes.as_slice()).and_then(|s| s.chars().next())
+   }
+
+   fn move_history(&mut self, up: bool, index: &mut uint, edited: &mut Vec<char>, line: &mut Vec<char>, cursor: &mut uint) {
+      if up && *index > 0 {
+         if *index == self.history.len() {
+            *edited = line.clone();
+         }
+         *index -= 1;
+         *line = self.history[*index].as_slice().chars().collect();
+      } else if !up && *index < self.history.len() {
+         *index += 1;
+         *line =
+            if *index == self.history.len() {
+               edited.clone()
+            } else {
+               self.history[*index].as_slice().chars().collect()
+            };
+      }
+      *cursor = line.len();
+   }
+
+   fn complete(&mut self, prompt: &str, names: &[String], line: &mut Vec<char>, cursor: &mut uint) {
+      let mut start = *cursor;
+      while start > 0 && !is_delimiter(line[start - 1]) {
+         start -= 1;
+      }
+      let prefix = String::from_chars(line.slice(start, *cursor));
+      let candidates: Vec<&String> = names.iter().filter(|name| name.as_slice().starts_with(prefix.as_slice())).collect();
+      if candidates.is_empty() {
+         return;
+      }
+      let mut common: Vec<char> = candidates[0].as_slice().chars().collect();
+      for candidate in candidates.iter().skip(1) {
+         let len = common.iter().zip(candidate.as_slice().chars()).take_while(|&(&a, b)| a == b).count();
+         common.truncate(len);
+      }
+      if common.len() > prefix.as_slice().char_len() {
+         for &ch in common.slice_from(prefix.as_slice().char_len()).iter() {
+            line.insert(*cursor, ch);
+            *cursor += 1;
+         }
+      } else if candidates.len() > 1 {
+         self.write("\r\n");
+         let list: Vec<&str> = candidates.iter().map(|name| name.as_slice()).collect();
+         self.write(list.connect("  ").as_slice());
+         self.write("\r\n");
+         self.redraw(prompt, line, *cursor);
+      }
+   }
+
+   fn redraw(&mut self, prompt: &str, line: &Vec<char>, cursor: uint) {
+      let text = String::from_chars(line.as_slice());
+      let column = prompt.char_len() + cursor;
+      let mut output = format!("\r{}{}\x1b[K\r", prompt, text);
+      if column > 0 {
+         output.push_str(format!("\x1b[{}C", column).as_slice());
+      }
+      self.write(output.as_slice());
+   }
+
+   fn write(&mut self, text: &str) {
+      let _ = self.output.write_str(text);
+      let _ = self.output.flush();
+   }
+}
+
+fn is_delimiter(ch: char) -> bool {
+   ch.is_whitespace() || ch == '(' || ch == ')' || ch == '[' || ch == ']' || ch == '{' || ch == '}' || ch == '\'' || ch == '"'
+}
@@ -0,0 +1,365 @@
+use std::cell::RefCell;
+use std::io;
+use std::os;
+use std::rc::Rc;
+use std::task::TaskBuilder;
+
+use ast;
+use ast::{Ast, ExprAst, Root, Sexpr};
+use bytecode;
+use image;
+use interp;
+use interp::{Capabilities, Continuation, FuelResult, Hooks, InterpResult, Interpreter, Finished, ParseError, Prelude, RuntimeError, Suspended};
+use optimize;
+use parser::Parser;
+
+// Tests of the interface the interpreter offers to programs that embed it.
+
+fn interpreter(code: &str) -> Interpreter {
+   let mut interp = Interpreter::new();
+   interp.load_code(code.to_string());
+   interp
+}
+
+// Writes code to the file at path and sets its modification time, in milliseconds.
+fn write_source(path: &Path, code: &str, modified: u64) {
+   io::File::create(path).write_str(code).unwrap();
+   io::fs::change_file_times(path, modified, modified).unwrap();
+}
+
+// The value of the code in the file at path, run from its .irlc file if that is current.
+fn run_file(path: &Path) -> String {
+   let mut interp = Interpreter::new();
+   interp.load_file(path).unwrap();
+   interp.run().unwrap().to_source()
+}
+
+// Hooks that write down what they are told.
+struct Recorder {
+   events: Rc<RefCell<Vec<String>>>
+}
+
+impl Hooks for Recorder {
+   fn on_call(&mut self, name: &str, args: &[ExprAst]) {
+      let args: Vec<String> = args.iter().map(|arg| arg.to_source()).collect();
+      self.events.borrow_mut().push(format!("call {} {}", name, args.connect(" ")));
+   }
+
+   fn on_return(&mut self, value: &ExprAst) {
+      self.events.borrow_mut().push(format!("return {}", value.to_source()));
+   }
+
+   fn on_error(&mut self, error: &RuntimeError) {
+      self.events.borrow_mut().push(format!("error {}", error.desc));
+   }
+}
+
+fn recorded(interp: &mut Interpreter) -> Rc<RefCell<Vec<String>>> {
+   let events = Rc::new(RefCell::new(vec!()));
+   interp.set_hooks(box Recorder { events: events.clone() });
+   events
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+   items.iter().map(|item| item.to_string()).collect()
+}
+
+fn suspended(result: InterpResult<FuelResult>) -> Continuation {
+   match result {
+      Ok(Suspended(cont)) => cont,
+      Ok(Finished(_)) => fail!("the program was not suspended"),
+      Err(f) => fail!("{}", f)
+   }
+}
+
+#[test]
+fn fuel_stops_an_infinite_loop() {
+   // an iterator that never runs out
+   let mut interp = interpreter("(defn ones [] (values 1 ones)) (for ones (fn [x] x))");
+   match interp.execute_with_fuel(1000) {
+      Err(f) => assert_eq!(f.desc.as_slice(), "out of fuel"),
+      Ok(Finished(_)) => fail!("the loop finished"),
+      Ok(Suspended(_)) => fail!("the loop was suspended")
+   }
+   // code that cannot be suspended may use up to twice the fuel
+   assert!(interp.steps() <= 2001);
+}
+
+#[test]
+fn code_that_does_not_parse_fails_with_a_parse_error() {
+   let code = "(define x 1)\n(+ x";
+   match interpreter(code).run() {
+      Err(f) => {
+         assert_eq!(f.kind, ParseError);
+         assert_eq!(f.span.line, 2);
+      }
+      Ok(_) => fail!("the code ran")
+   }
+   match interpreter(code).execute_with_fuel(1000) {
+      Err(f) => assert_eq!(f.kind, ParseError),
+      Ok(_) => fail!("the code ran")
+   }
+}
+
+#[test]
+fn suspends_and_resumes_inside_a_function() {
+   let mut interp = interpreter("(defn sum [n] (if (= n 0) 0 (+ n (sum (+ n -1))))) (sum 100)");
+   let mut result = interp.execute_with_fuel(50);
+   let mut suspensions = 0u;
+   loop {
+      match result {
+         Ok(Suspended(cont)) => {
+            suspensions += 1;
+            if suspensions == 10 {
+               // deep inside the calls of sum
+               assert!(!cont.between_forms());
+            }
+            result = interp.resume(cont, 50);
+         }
+         Ok(Finished(value)) => {
+            assert_eq!(value.to_source().as_slice(), "5050");
+            break;
+         }
+         Err(f) => fail!("{}", f)
+      }
+   }
+   assert!(suspensions > 10);
+}
+
+#[test]
+fn suspend_hands_back_control() {
+   let mut interp = interpreter("(defn add [a b] (suspend) (+ a b)) (add 1 2)");
+   let cont = suspended(interp.execute_with_fuel(1000));
+   assert!(!cont.between_forms());
+   // the state of the call of add cannot be written out
+   let path = os::tmpdir().join("iron-suspended-inside.irl");
+   match image::save_continuation(&interp, &cont, &path) {
+      Err(f) => assert_eq!(f.kind, io::InvalidInput),
+      Ok(()) => fail!("a program suspended inside a form was saved")
+   }
+   match interp.resume(cont, 1000) {
+      Ok(Finished(value)) => assert_eq!(value.to_source().as_slice(), "3"),
+      _ => fail!("the program did not finish")
+   }
+}
+
+#[test]
+fn saved_continuations_resume_in_another_interpreter() {
+   let mut interp = interpreter("(define x 20) (define y (+ x 1)) (suspend) (+ x y 1)");
+   let cont = suspended(interp.execute_with_fuel(1000));
+   assert!(cont.between_forms());
+   assert_eq!(cont.remaining().len(), 1);
+   let path = os::tmpdir().join("iron-suspended-between.irl");
+   image::save_continuation(&interp, &cont, &path).unwrap();
+   let cont = image::load_continuation(&path).unwrap();
+   let mut other = Interpreter::new();
+   match other.resume(cont, 1000) {
+      Ok(Finished(value)) => assert_eq!(value.to_source().as_slice(), "42"),
+      _ => fail!("the program did not finish")
+   }
+}
+
+#[test]
+fn interpreters_share_a_prelude_but_not_their_globals() {
+   let prelude = Prelude::load("(define greeting \"hello\") (defn greet [name] (concat greeting \", \" name))".to_string()).unwrap();
+   let mut first = Interpreter::with_prelude(&prelude);
+   first.load_code("(define greeting \"hi\") (define mine 1) (concat greeting \" \" (greet \"first\"))".to_string());
+   assert_eq!(first.run().unwrap().to_source().as_slice(), "\"hi hello, first\"");
+   // neither the greeting first bound nor its other globals are seen by second
+   let mut second = Interpreter::with_prelude(&prelude);
+   second.load_code("(greet greeting)".to_string());
+   assert_eq!(second.run().unwrap().to_source().as_slice(), "\"hello, hello\"");
+   second.load_code("mine".to_string());
+   assert!(second.run().is_err());
+}
+
+#[test]
+fn sandboxed_programs_cannot_import_or_save_images() {
+   for &(code, name) in [("(import \"lib/std\")", "import"), ("(save-image \"sandbox.image\")", "save-image")].iter() {
+      let mut interp = interpreter(code);
+      interp.set_capabilities(Capabilities::none());
+      match interp.run() {
+         Err(f) => assert_eq!(f.desc, format!("capability denied: {} is not allowed in this interpreter", name)),
+         Ok(_) => fail!("{} was allowed", name)
+      }
+   }
+}
+
+#[test]
+fn hooks_see_calls_nest() {
+   let mut interp = interpreter("(defn inner [x] (if (= x 0) (throw \"zero\") x))
+                                 (defn outer [x] (+ (inner x) 1))
+                                 (outer 1)
+                                 (try (outer 0) (catch e nil))");
+   let events = recorded(&mut interp);
+   interp.run().unwrap();
+   assert_eq!(*events.borrow(), strings(["call outer 1", "call inner 1", "return 1", "return 2",
+                                         "call outer 0", "call inner 0", "error zero", "error zero"]));
+}
+
+#[test]
+fn hooks_see_calls_in_imported_modules() {
+   let dir = os::tmpdir();
+   io::File::create(&dir.join("iron-hooks-module.irl")).write_str("(defn twice [x] (+ x x)) (define one 1) (twice one)").unwrap();
+   let mut interp = interpreter("(import \"./iron-hooks-module\") (twice 3)");
+   interp.set_file(dir.join("main.irl").as_str().unwrap().to_string());
+   let events = recorded(&mut interp);
+   interp.run().unwrap();
+   assert_eq!(*events.borrow(), strings(["call twice 1", "return 2", "call twice 3", "return 6"]));
+}
+
+#[test]
+fn imported_functions_count_towards_the_depth_limit() {
+   // the test task has too little stack to nest 200 deep
+   let result = TaskBuilder::new().stack_size(interp::stack_size(200)).try(proc() {
+      let dir = os::tmpdir();
+      io::File::create(&dir.join("iron-depth-module.irl")).write_str("(defn apply-to [f n] (f n))").unwrap();
+      let mut interp = interpreter("(import \"./iron-depth-module\")
+                                    (defn down [n] (if (= n 0) 0 (+ 1 (apply-to down (+ n -1)))))
+                                    (down 1000)");
+      interp.set_file(dir.join("main.irl").as_str().unwrap().to_string());
+      interp.set_max_depth(200);
+      match interp.run() {
+         Err(f) => {
+            assert!(f.desc.as_slice().starts_with("maximum recursion depth exceeded (200)"));
+            // the backtrace has the calls of both interpreters (down is called as f)
+            assert!(f.desc.as_slice().contains("in apply-to (line 1)") && f.desc.as_slice().contains("in f (line 2)"));
+         }
+         Ok(_) => fail!("the recursion was not stopped")
+      }
+   });
+   assert!(result.is_ok());
+}
+
+#[test]
+fn execute_gives_the_value_of_the_last_form() {
+   for &(code, value) in [("(define x 1) (+ x 2)", "3"), ("(+ 1 2) (when false 1)", "nil"), ("", "nil"),
+                          ("(str-upcase \"done\")", "\"DONE\"")].iter() {
+      // what iron --print-result prints
+      assert_eq!(format!("{}", interpreter(code).execute().unwrap()).as_slice(), value);
+   }
+   assert!(interpreter("(+ 1 2) (throw \"stop\")").execute().is_none());
+}
+
+#[test]
+fn identical_string_literals_share_their_text() {
+   let root = match Parser::new().parse_code("(println \"abc\" \"abc\" \"abd\")".to_string()) {
+      Root(ast) => optimize::optimize(ast, [optimize::InternStrings]),
+      _ => unreachable!()
+   };
+   let strings: Vec<*const String> = match root.asts.as_slice() {
+      [Sexpr(ref sast)] => sast.operands.iter().filter_map(|operand| match *operand {
+         ast::String(ref ast) => Some(&*ast.string as *const String),
+         _ => None
+      }).collect(),
+      _ => fail!("the program is not one call")
+   };
+   assert_eq!(strings.len(), 3);
+   assert!(strings[0] == strings[1]);
+   assert!(strings[0] != strings[2]);
+}
+
+#[test]
+fn imported_macros_can_be_called() {
+   let dir = os::tmpdir();
+   io::File::create(&dir.join("iron-macro-module.irl")).write_str("(defmacro or-else [x fallback] (if (= x nil) fallback x))").unwrap();
+   let mut interp = interpreter("(import \"./iron-macro-module\") (or-else nil 7)");
+   interp.set_file(dir.join("main.irl").as_str().unwrap().to_string());
+   assert_eq!(interp.run().unwrap().to_source().as_slice(), "7");
+}
+
+#[test]
+fn macro_calls_are_expanded_once() {
+   // each expansion of add-one takes a fresh name for value, and so would each call of
+   // count-down if the expansion were not kept
+   let mut interp = interpreter("(defmacro add-one [x] (let [value x] (+ value 1)))
+                                 (defn count-down [n] (if (= n 0) 0 (count-down (add-one (+ n -2)))))
+                                 (count-down 10)
+                                 (gensym)");
+   assert_eq!(interp.run().unwrap().to_source().as_slice(), "'g__2");
+}
+
+#[test]
+fn builtins_are_equal_to_themselves() {
+   for &(code, value) in [("(define add +) (= add +)", "true"), ("(= + str-len)", "false"),
+                          ("(= [+ str-len] [+ str-len])", "true")].iter() {
+      assert_eq!(interpreter(code).run().unwrap().to_source().as_slice(), value);
+   }
+}
+
+#[test]
+fn fuel_is_used_up_by_imported_functions() {
+   let dir = os::tmpdir();
+   io::File::create(&dir.join("iron-fuel-module.irl")).write_str("(defn spin [n] (if (= n 0) 0 (spin (+ n -1))))").unwrap();
+   let mut interp = interpreter("(import \"./iron-fuel-module\") (spin 200)");
+   interp.set_file(dir.join("main.irl").as_str().unwrap().to_string());
+   let mut cont = suspended(interp.execute_with_fuel(100));
+   loop {
+      match interp.resume(cont, 100) {
+         Ok(Suspended(next)) => cont = next,
+         Ok(Finished(value)) => {
+            assert_eq!(value.to_source().as_slice(), "0");
+            break;
+         }
+         Err(f) => fail!("{}", f)
+      }
+   }
+}
+
+#[test]
+fn map_suspends_between_calls() {
+   let mut interp = interpreter("(define doubled (map (range 0 100) (fn [x] (+ x x)))) (get doubled 99)");
+   let mut cont = suspended(interp.execute_with_fuel(50));
+   assert!(!cont.between_forms());
+   loop {
+      match interp.resume(cont, 50) {
+         Ok(Suspended(next)) => cont = next,
+         Ok(Finished(value)) => {
+            assert_eq!(value.to_source().as_slice(), "198");
+            break;
+         }
+         Err(f) => fail!("{}", f)
+      }
+   }
+}
+
+#[test]
+fn precompiled_code_is_only_used_while_it_is_current() {
+   let source = os::tmpdir().join("iron-precompiled.irl");
+   let compiled = bytecode::compiled_path(&source);
+   write_source(&source, "(+ 40 2)", 1000);
+   bytecode::compile_file(&source).unwrap();
+   // the same modification time, so the (older) precompiled code is run
+   write_source(&source, "(+ 1 1)", 1000);
+   assert!(bytecode::load_cached(&source).is_some());
+   assert_eq!(run_file(&source).as_slice(), "42");
+   // the source is newer than the precompiled code
+   write_source(&source, "(+ 1 1)", 2000);
+   assert!(bytecode::load_cached(&source).is_none());
+   assert_eq!(run_file(&source).as_slice(), "2");
+   // a file written by another version of the format
+   bytecode::compile_file(&source).unwrap();
+   assert!(bytecode::load_cached(&source).is_some());
+   let mut data = io::File::open(&compiled).read_to_end().unwrap();
+   *data.get_mut(4) += 1;
+   io::File::create(&compiled).write(data.as_slice()).unwrap();
+   assert!(bytecode::load_cached(&source).is_none());
+   // a file cut short, and one that is not precompiled code at all
+   bytecode::compile_file(&source).unwrap();
+   let data = io::File::open(&compiled).read_to_end().unwrap();
+   io::File::create(&compiled).write(data.slice_to(data.len() - 3)).unwrap();
+   assert!(bytecode::load_cached(&source).is_none());
+   io::File::create(&compiled).write_str("(+ 1 1)").unwrap();
+   assert!(bytecode::load_cached(&source).is_none());
+   assert_eq!(run_file(&source).as_slice(), "2");
+}
+
+#[test]
+fn code_that_does_not_parse_is_not_compiled() {
+   let source = os::tmpdir().join("iron-unparsable.irl");
+   write_source(&source, "(+ 1", 1000);
+   match bytecode::compile_file(&source) {
+      Err(f) => assert_eq!(f.kind, io::InvalidInput),
+      Ok(_) => fail!("the code was compiled")
+   }
+}
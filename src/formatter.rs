@@ -0,0 +1,157 @@
+use std::cmp;
+
+use ast::*;
+
+// Reprints parsed code with canonical indentation and line breaking.  A form is kept on
+// one line if it fits within WIDTH columns and contains no comments; otherwise its
+// operands go on their own lines, indented by INDENT spaces relative to the form.
+
+static WIDTH: uint = 80;
+static INDENT: uint = 2;
+
+pub fn format_code(root: &ExprAst) -> String {
+   let root = match *root {
+      Root(ref ast) => ast,
+      _ => return format_expr(root, 0)
+   };
+   let mut result = String::new();
+   let mut previous: Option<String> = None;
+   for (idx, ast) in root.asts.iter().enumerate() {
+      let comments = root.trivia.before(idx);
+      let text = format_expr(ast, 0);
+      match previous {
+         // definitions and multi-line forms are separated from their neighbours by a blank
+         // line, and comments stay attached to the form that follows them
+         Some(ref prev) if !comments.is_empty() || is_multiline(prev.as_slice()) || is_multiline(text.as_slice()) || is_definition(ast) =>
+            result.push_char('\n'),
+         _ => {}
+      }
+      for comment in comments.iter() {
+         result.push_str(format!(";{}\n", comment).as_slice());
+      }
+      result.push_str(text.as_slice());
+      result.push_char('\n');
+      previous = Some(text);
+   }
+   let trailing = root.trivia.before(root.asts.len());
+   if !trailing.is_empty() && previous.is_some() {
+      result.push_char('\n');
+   }
+   for comment in trailing.iter() {
+      result.push_str(format!(";{}\n", comment).as_slice());
+   }
+   result
+}
+
+fn format_expr(expr: &ExprAst, indent: uint) -> String {
+   let flat = expr.to_source();
+   if indent + flat.len() <= WIDTH && !has_comment(expr) {
+      return flat;
+   }
+   match *expr {
+      // an interpolated string cannot be broken over several lines without changing it
+      Sexpr(ref ast) if ast.interpolated => flat,
+      Sexpr(ref ast) => {
+         let head = format!("({}", ast.op.value);
+         let mut inline = cmp::min(header_operands(ast.op.value.as_slice()), ast.operands.len());
+         for &(idx, _) in ast.trivia.comments.iter() {
+            inline = cmp::min(inline, idx);
+         }
+         let mut lines = head;
+         for operand in ast.operands.slice_to(inline).iter() {
+            // the function called by ((expr) args...) follows the parenthesis directly
+            if !ast.op.value.is_empty() {
+               lines.push_char(' ');
+            }
+            let text = format_expr(operand, indent + lines.len());
+            lines.push_str(text.as_slice());
+         }
+         format_items(lines, &ast.operands, inline, &ast.trivia, indent + INDENT, indent, ")")
+      }
+      Array(ref ast) => format_block("[", &ast.items, &ast.trivia, indent, "]"),
+      List(ref ast) => format_block("'(", &ast.items, &ast.trivia, indent, ")"),
+      Map(ref ast) => format_block("{", &ast.items(), &ast.trivia, indent, "}"),
+      _ => flat
+   }
+}
+
+// Puts the first item right after the opening delimiter and aligns the others with it.
+fn format_block(open: &str, items: &Vec<ExprAst>, trivia: &Trivia, indent: uint, close: &str) -> String {
+   let inner = indent + open.len();
+   if items.is_empty() || !trivia.before(0).is_empty() {
+      return format_items(open.to_string(), items, 0, trivia, inner, indent, close);
+   }
+   let first = format!("{}{}", open, format_expr(&items[0], inner));
+   format_items(first, items, 1, trivia, inner, indent, close)
+}
+
+// Puts each item from the given index onwards on its own line, preceded by its comments.
+fn format_items(start: String, items: &Vec<ExprAst>, from: uint, trivia: &Trivia, indent: uint, close_indent: uint, close: &str) -> String {
+   let mut result = start;
+   for idx in range(from, items.len()) {
+      for comment in trivia.before(idx).iter() {
+         push_line(&mut result, indent, format!(";{}", comment).as_slice());
+      }
+      let text = format_expr(&items[idx], indent);
+      push_line(&mut result, indent, text.as_slice());
+   }
+   let trailing = trivia.before(items.len());
+   for comment in trailing.iter() {
+      push_line(&mut result, indent, format!(";{}", comment).as_slice());
+   }
+   // a comment runs to the end of the line, so the closing delimiter needs its own
+   if !trailing.is_empty() {
+      result.push_char('\n');
+      push_indent(&mut result, close_indent);
+   }
+   result.push_str(close);
+   result
+}
+
+// The number of operands kept on the same line as the operator of a special form when
+// the form is broken over several lines, e.g. the name in (define name value).
+fn header_operands(op: &str) -> uint {
+   match op {
+      "try" | "finally" | "defer" => 0,
+      _ => 1
+   }
+}
+
+fn push_line(result: &mut String, indent: uint, text: &str) {
+   // the first item of a block follows its opening delimiter directly
+   if !result.as_slice().ends_with("[") && !result.as_slice().ends_with("(") && !result.as_slice().ends_with("{") {
+      result.push_char('\n');
+      push_indent(result, indent);
+   }
+   result.push_str(text);
+}
+
+fn push_indent(result: &mut String, indent: uint) {
+   for _ in range(0, indent) {
+      result.push_char(' ');
+   }
+}
+
+fn has_comment(expr: &ExprAst) -> bool {
+   match *expr {
+      Sexpr(ref ast) => !ast.trivia.is_empty() || ast.operands.iter().any(|operand| has_comment(operand)),
+      Array(ref ast) => !ast.trivia.is_empty() || ast.items.iter().any(|item| has_comment(item)),
+      List(ref ast) => !ast.trivia.is_empty() || ast.items.iter().any(|item| has_comment(item)),
+      Map(ref ast) => !ast.trivia.is_empty() || ast.items().iter().any(|item| has_comment(item)),
+      _ => false
+   }
+}
+
+fn is_multiline(text: &str) -> bool {
+   text.contains_char('\n')
+}
+
+fn is_definition(expr: &ExprAst) -> bool {
+   match *expr {
+      Sexpr(ref ast) => match ast.op.value.as_slice() {
+         "define" | "defn" | "deftest" => true,
+         _ => false
+      },
+      _ => false
+   }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::io::fs;
+use std::os;
+use std::rand::{task_rng, Rng, SeedableRng, StdRng};
+use std::str;
+use std::task;
+
+use parser::Parser;
+
+// Feeds the parser random input, which must only ever produce parse errors, and which
+// Parser::tokenize must split into tokens in order within the input.  The input is
+// either made of the characters that mean something to the parser, arbitrary bytes, or an
+// example program with some of its bytes changed, dropped or repeated.  The input comes
+// from the seed in IRON_FUZZ_SEED, or a new one each run, which is printed if the test
+// fails so that the run can be repeated.
+
+static ROUNDS: uint = 2000;
+static PIECES: &'static [&'static str] = &[
+   "(", ")", "[", "]", "{", "}", "'", "\"", "\"\"\"", "r\"", "${", "$", "\\", ";", "\n", " ",
+   "-", ".", "0", "9", "9223372036854775808", "a", "nil", "true", "é", "漢", "٣"
+];
+
+fn random_pieces<R: Rng>(rng: &mut R) -> Vec<u8> {
+   let mut result = vec!();
+   for _ in range(0, rng.gen_range(0u, 40)) {
+      result.push_all(rng.choose(PIECES).unwrap().as_bytes());
+   }
+   result
+}
+
+fn random_bytes<R: Rng>(rng: &mut R) -> Vec<u8> {
+   Vec::from_fn(rng.gen_range(0u, 40), |_| rng.gen::<u8>())
+}
+
+fn mutate<R: Rng>(rng: &mut R, code: &[u8]) -> Vec<u8> {
+   let mut result = code.to_vec();
+   for _ in range(0, rng.gen_range(1u, 8)) {
+      if result.is_empty() {
+         break;
+      }
+      let idx = rng.gen_range(0, result.len());
+      match rng.gen_range(0u, 3) {
+         0 => *result.get_mut(idx) = rng.gen::<u8>(),
+         1 => { result.remove(idx); }
+         _ => {
+            let byte = result[idx];
+            result.insert(idx, byte);
+         }
+      }
+   }
+   result
+}
+
+fn seed() -> uint {
+   match os::getenv("IRON_FUZZ_SEED") {
+      Some(seed) => match from_str(seed.as_slice()) {
+         Some(seed) => seed,
+         None => fail!("IRON_FUZZ_SEED must be a number, not {}", seed)
+      },
+      None => task_rng().gen()
+   }
+}
+
+#[test]
+fn parser_never_fails() {
+   let seed = seed();
+   let mut rng: StdRng = SeedableRng::from_seed(&[seed]);
+   let examples: Vec<Vec<u8>> = fs::readdir(&Path::new("examples")).unwrap().iter()
+      .filter(|path| path.extension_str() == Some("irl"))
+      .map(|path| io::File::open(path).read_to_end().unwrap())
+      .collect();
+   for _ in range(0, ROUNDS) {
+      let input = match rng.gen_range(0u, 3) {
+         0 => random_pieces(&mut rng),
+         1 => random_bytes(&mut rng),
+         _ => {
+            let example = rng.choose(examples.as_slice()).unwrap().clone();
+            mutate(&mut rng, example.as_slice())
+         }
+      };
+      let code = input.clone();
+      let result = task::try(proc() {
+         let _ = Parser::new().try_parse_bytes(code.as_slice());
+         match str::from_utf8(code.as_slice()) {
+            Some(text) => {
+               let mut end = 0;
+               for token in Parser::tokenize(text).iter() {
+                  assert!(token.span.start >= end && token.span.end > token.span.start && token.span.end <= text.len());
+                  end = token.span.end;
+               }
+            }
+            None => {}
+         }
+      });
+      if result.is_err() {
+         fail!("the parser or tokenizer failed on {} (IRON_FUZZ_SEED={})", input, seed);
+      }
+   }
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::io::fs;
+use std::io::{ChanReader, ChanWriter};
+use std::task::TaskBuilder;
+
+use ast::{Ast, ExprAst};
+use formatter;
+use interp;
+use interp::Interpreter;
+use parser::Parser;
+
+// Runs each program in examples/ and compares what it prints and its exit status with
+// name.expected and name.status (0 if there is no such file), and what it writes to
+// stderr with name.stderr if there is such a file.  Formats each file in examples/fmt/
+// and compares the result with name.formatted, which must format to itself and parse to
+// the same code.  Run with make test, from the top of the repository.
+
+static EXAMPLES: &'static str = "examples";
+static FORMATTED: &'static str = "examples/fmt";
+
+// The output, the text written to stderr and the exit status of the program at path.
+fn run(path: &Path) -> (String, String, int) {
+   let (tx, rx) = channel();
+   let (errtx, errrx) = channel();
+   let path = path.clone();
+   let status = TaskBuilder::new().stack_size(interp::stack_size(interp::DEFAULT_MAX_DEPTH)).try(proc() {
+      io::stdio::set_stdout(box ChanWriter::new(tx));
+      interp::set_stderr(box ChanWriter::new(errtx));
+      let mut interp = Interpreter::new();
+      interp.set_file(path.as_str().unwrap().to_string());
+      match interp.load_file(&path) {
+         Ok(()) => if interp.execute().is_some() { 0 } else { 1 },
+         Err(_) => 1
+      }
+   }).unwrap_or(101);
+   let output = ChanReader::new(rx).read_to_string().unwrap();
+   let errors = ChanReader::new(errrx).read_to_string().unwrap();
+   (output, errors, status)
+}
+
+#[test]
+fn examples() {
+   let mut paths = fs::readdir(&Path::new(EXAMPLES)).unwrap();
+   paths.sort();
+   let mut failures = vec!();
+   for path in paths.iter().filter(|path| path.extension_str() == Some("irl")) {
+      let expected = io::File::open(&path.with_extension("expected")).read_to_string().unwrap();
+      let status_path = path.with_extension("status");
+      let expected_status = if status_path.exists() {
+         from_str(io::File::open(&status_path).read_to_string().unwrap().as_slice().trim()).unwrap()
+      } else {
+         0
+      };
+      let (output, errors, status) = run(path);
+      if output != expected {
+         failures.push(format!("{}: expected output\n{}\nbut got\n{}", path.display(), expected, output));
+      }
+      let stderr_path = path.with_extension("stderr");
+      if stderr_path.exists() {
+         let expected_errors = io::File::open(&stderr_path).read_to_string().unwrap();
+         if errors != expected_errors {
+            failures.push(format!("{}: expected stderr\n{}\nbut got\n{}", path.display(), expected_errors, errors));
+         }
+      }
+      if status != expected_status {
+         failures.push(format!("{}: expected exit status {} but got {}", path.display(), expected_status, status));
+      }
+   }
+   if !failures.is_empty() {
+      fail!("{}", failures.connect("\n"));
+   }
+}
+
+fn parse(code: String) -> ExprAst {
+   Parser::new().try_parse_code(code).unwrap()
+}
+
+#[test]
+fn formatted_examples() {
+   let mut paths = fs::readdir(&Path::new(FORMATTED)).unwrap();
+   paths.sort();
+   let mut failures = vec!();
+   for path in paths.iter().filter(|path| path.extension_str() == Some("irl")) {
+      let code = io::File::open(path).read_to_string().unwrap();
+      let expected = io::File::open(&path.with_extension("formatted")).read_to_string().unwrap();
+      let formatted = formatter::format_code(&parse(code.clone()));
+      if formatted != expected {
+         failures.push(format!("{}: expected formatting\n{}\nbut got\n{}", path.display(), expected, formatted));
+      }
+      let reformatted = formatter::format_code(&parse(formatted.clone()));
+      if reformatted != formatted {
+         failures.push(format!("{}: formatting again gave\n{}", path.display(), reformatted));
+      }
+      if parse(formatted).to_source() != parse(code).to_source() {
+         failures.push(format!("{}: formatting changed the code", path.display()));
+      }
+   }
+   if !failures.is_empty() {
+      fail!("{}", failures.connect("\n"));
+   }
+}
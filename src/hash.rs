@@ -0,0 +1,143 @@
+use ast::*;
+
+// Hashes values for use as map keys and by the hash builtin.  The algorithm is stable
+// across runs and platforms: 64-bit FNV-1a over a byte encoding of the value, in which
+// each value starts with a byte for its kind followed by
+//    integer           the 8 bytes of the integer, least significant first
+//    float             the 8 bytes of its IEEE 754 representation, the same way (with
+//                      -0.0 hashed as 0.0)
+//    string, symbol,   the UTF-8 text followed by a 0xff byte (which never occurs in
+//    keyword and       UTF-8)
+//    identifier
+//    boolean           1 or 0
+//    nil               nothing
+//    list, tuple       the number of items as an integer, then each item
+//    error             the message as a string, then the data
+//    map               the number of entries as an integer, then the sum of the hashes
+//                      of each key and its value, so that the order does not matter
+//    set               the number of items as an integer, then the sum of their hashes
+//    sorted map        its keys and values, alternating, as a list
+// Arrays and queues can be changed in place and code has no meaningful identity, so
+// none of them can be hashed.
+
+static OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+static PRIME: u64 = 0x100000001b3;
+
+struct Hasher {
+   state: u64
+}
+
+impl Hasher {
+   fn new() -> Hasher {
+      Hasher {
+         state: OFFSET_BASIS
+      }
+   }
+
+   fn write_byte(&mut self, byte: u8) {
+      self.state = (self.state ^ byte as u64) * PRIME;
+   }
+
+   fn write_u64(&mut self, val: u64) {
+      for i in range(0u, 8) {
+         self.write_byte((val >> (i * 8)) as u8);
+      }
+   }
+
+   fn write_str(&mut self, val: &str) {
+      for &byte in val.as_bytes().iter() {
+         self.write_byte(byte);
+      }
+      self.write_byte(0xff);
+   }
+
+   fn write_items(&mut self, items: &Vec<ExprAst>) -> Result<(), String> {
+      self.write_u64(items.len() as u64);
+      for item in items.iter() {
+         try!(self.write_value(item));
+      }
+      Ok(())
+   }
+
+   fn write_value(&mut self, value: &ExprAst) -> Result<(), String> {
+      match *value {
+         Integer(ref ast) => {
+            self.write_byte(1);
+            self.write_u64(ast.value as u64);
+         }
+         Float(ref ast) => {
+            self.write_byte(2);
+            // -0.0 = 0.0, so they have to hash the same
+            let val = if ast.value == 0.0 { 0.0 } else { ast.value };
+            self.write_u64(unsafe { ::std::mem::transmute::<f64, u64>(val) });
+         }
+         String(ref ast) => {
+            self.write_byte(3);
+            self.write_str(ast.string.as_slice());
+         }
+         Symbol(ref ast) => {
+            self.write_byte(4);
+            self.write_str(ast.value.as_slice());
+         }
+         Keyword(ref ast) => {
+            self.write_byte(13);
+            self.write_str(ast.value.as_slice());
+         }
+         Ident(ref ast) => {
+            self.write_byte(5);
+            self.write_str(ast.value.as_slice());
+         }
+         Boolean(ref ast) => {
+            self.write_byte(6);
+            self.write_byte(ast.value as u8);
+         }
+         Nil(_) => self.write_byte(7),
+         List(ref ast) => {
+            self.write_byte(8);
+            try!(self.write_items(&ast.items));
+         }
+         Tuple(ref ast) => {
+            self.write_byte(9);
+            try!(self.write_items(&ast.items));
+         }
+         Error(ref ast) => {
+            self.write_byte(10);
+            self.write_str(ast.message.as_slice());
+            try!(self.write_value(&*ast.data));
+         }
+         Map(ref ast) => {
+            self.write_byte(11);
+            self.write_u64(ast.entries.len() as u64);
+            let mut sum = 0u64;
+            for &(hash, _, ref value) in ast.entries.iter() {
+               sum += hash ^ try!(hash_value(value));
+            }
+            self.write_u64(sum);
+         }
+         SortedMap(ref ast) => {
+            self.write_byte(14);
+            try!(self.write_items(&ast.items()));
+         }
+         Set(ref ast) => {
+            self.write_byte(12);
+            self.write_u64(ast.items.len() as u64);
+            let mut sum = 0u64;
+            for &(hash, _) in ast.items.iter() {
+               sum += hash;
+            }
+            self.write_u64(sum);
+         }
+         Array(_) => return Err(format!("{} cannot be hashed because arrays can change", value.to_source())),
+         Queue(_) => return Err(format!("{} cannot be hashed because queues can change", value.to_source())),
+         Code(_) => return Err("functions cannot be hashed".to_string()),
+         ref other => return Err(format!("{} cannot be hashed", other.to_source()))
+      }
+      Ok(())
+   }
+}
+
+pub fn hash_value(value: &ExprAst) -> Result<u64, String> {
+   let mut hasher = Hasher::new();
+   try!(hasher.write_value(value));
+   Ok(hasher.state)
+}
@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::IoResult;
+use std::rc::Rc;
+
+use ast::{Ast, Code, Root};
+use interp::{Continuation, Environment, Interpreter, Value, EnvCode};
+use parser::Parser;
+
+// Images are plain Iron source consisting of one define per global binding.  Native
+// thunks are never written out; they are re-linked by name when the default
+// environment of the loading interpreter is populated.
+pub fn save_image(env: Rc<RefCell<Environment>>, path: &Path) -> IoResult<()> {
+   let mut file = try!(io::File::create(path));
+   write_globals(env, &mut file)
+}
+
+fn write_globals(env: Rc<RefCell<Environment>>, file: &mut Writer) -> IoResult<()> {
+   let global = Environment::global(env);
+   let global = global.borrow();
+   let mut names: Vec<&String> = global.values.keys().collect();
+   names.sort();
+   for name in names.move_iter() {
+      if name.as_slice() == "FILE" {
+         continue;
+      }
+      match *global.values.find(name).unwrap() {
+         // a builtin bound to another name is written as the builtin's own name
+         Value(Code(ref code)) if code.builtin.is_some() =>
+            try!(file.write_line(format!("(define {} {})", name, code.builtin.as_ref().unwrap().name).as_slice())),
+         Value(ref val) => try!(file.write_line(format!("(define {} {})", name, val.to_source()).as_slice())),
+         EnvCode(_) => {}
+      }
+   }
+   Ok(())
+}
+
+pub fn load_image(interp: &mut Interpreter, path: &Path) -> IoResult<()> {
+   let code = try!(try!(io::File::open(path)).read_to_string());
+   interp.load_code(code);
+   interp.execute();
+   Ok(())
+}
+
+// A suspended program is saved as an image followed by the top-level forms that have not
+// run yet, so resuming it redefines the globals and then carries on with those forms.
+// Only a program suspended between top-level forms can be saved, as the state of one
+// suspended inside a form is made of the interpreter's environments.
+pub fn save_continuation(interp: &Interpreter, cont: &Continuation, path: &Path) -> IoResult<()> {
+   if !cont.between_forms() {
+      return Err(io::IoError {
+         kind: io::InvalidInput,
+         desc: "only a program suspended between top-level forms can be saved",
+         detail: Some("it stopped inside a form, whose calls and local environments cannot be written out; suspend it with (suspend) at the top level to save it".to_string())
+      });
+   }
+   let mut file = try!(io::File::create(path));
+   try!(write_globals(interp.env.clone(), &mut file));
+   for form in cont.remaining().iter() {
+      try!(file.write_line(form.to_source().as_slice()));
+   }
+   Ok(())
+}
+
+pub fn load_continuation(path: &Path) -> IoResult<Continuation> {
+   let code = try!(try!(io::File::open(path)).read_to_string());
+   match Parser::new().try_parse_code(code) {
+      Ok(Root(root)) => Ok(Continuation::new(root)),
+      Ok(_) => unreachable!(),
+      Err(f) => Err(io::IoError {
+         kind: io::InvalidInput,
+         desc: "could not parse the saved program",
+         detail: Some(f.to_string())
+      })
+   }
+}
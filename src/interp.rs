@@ -1,13 +1,44 @@
 #![allow(raw_pointer_deriving)]
 
 use std::cell::RefCell;
+use std::cmp::{Ordering, Less, Equal, Greater};
 use std::collections;
 use std::f64;
+use std::fmt;
 use std::io;
+use std::mem;
 use std::rc::Rc;
 
+use serialize::base64;
+use serialize::base64::{FromBase64, ToBase64};
+use serialize::hex::{FromHex, ToHex};
+use serialize::json;
+
 use parser::Parser;
 use ast::*;
+use bytecode;
+use color;
+use csv;
+use diff;
+use digest;
+use hash;
+use image;
+use macros;
+use macros::Macro;
+use pattern;
+use pkg;
+use replay::{Inputs, Live};
+use optimize;
+use optimize::Pass;
+use typecheck;
+
+macro_rules! throw (
+   ($($arg:tt)*) => (return Err(RuntimeError::new(format!($($arg)*))))
+)
+
+macro_rules! throw_kind (
+   ($kind:expr, $($arg:tt)*) => (return Err(RuntimeError::new(format!($($arg)*)).with_kind($kind)))
+)
 
 #[deriving(PartialEq)]
 pub enum InterpMode {
@@ -15,511 +46,5761 @@ pub enum InterpMode {
    Release
 }
 
+// Messages logged by scripts below the interpreter's log level are discarded.
+#[deriving(Clone, PartialEq, PartialOrd)]
+pub enum LogLevel {
+   LogDebug,
+   LogInfo,
+   LogWarn,
+   LogError,
+   LogOff
+}
+
 #[deriving(Clone, PartialEq)]
-enum EnvValue {
-   EnvCode(fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst),
+pub enum EnvValue {
+   EnvCode(Builtin),
    Value(ExprAst)
 }
 
-impl PartialEq for fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-   fn eq(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst) -> bool {
-      let other: *const () = unsafe { ::std::mem::transmute(other) };
-      let this: *const () = unsafe { ::std::mem::transmute(self) };
-      this == other
+// A function implemented by the interpreter along with the arguments it accepts, which
+// are checked before it is called.
+#[deriving(Clone, PartialEq)]
+pub struct Builtin {
+   pub name: &'static str,
+   pub min: uint,
+   pub max: Option<uint>,
+   // the expected type of each argument separated by spaces, with the last one applying
+   // to any remaining arguments.  Empty for builtins that take their arguments
+   // unevaluated.
+   pub types: &'static str,
+   pub func: fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>
+}
+
+impl PartialEq for fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+   fn eq(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>) -> bool {
+      // the functions themselves, not the references to them
+      *self as uint == *other as uint
    }
 
-   fn ne(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst) -> bool {
+   fn ne(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>) -> bool {
       !self.eq(other)
    }
 }
 
+pub struct RuntimeError {
+   pub desc: String,
+   pub span: Span,
+   pub value: Option<ExprAst>,
+   pub kind: ErrorKind,
+   pub cause: Option<Box<ErrorAst>>
+}
+
+// What went wrong, for (error-kind e) and (catch :kind e ...).  Errors raised with throw
+// or made with error are user errors unless they say otherwise.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ErrorKind {
+   // a value of the wrong type was given
+   TypeError,
+   // a function was called with the wrong number of arguments
+   ArityError,
+   // a name was used that is not defined
+   NameError,
+   // reading or writing a file or stream failed
+   IoError,
+   // text given to read is not valid code
+   ParseError,
+   UserError,
+   // anything else, such as an index out of range or an integer overflow
+   OtherError
+}
+
+pub static ERROR_KINDS: &'static [ErrorKind] = &[
+   TypeError, ArityError, NameError, IoError, ParseError, UserError, OtherError
+];
+
+pub type InterpResult<T> = Result<T, RuntimeError>;
+
+// The outcome of running code with a limited amount of fuel.
+pub enum FuelResult {
+   Finished(ExprAst),
+   Suspended(Continuation)
+}
+
+// A program suspended by execute_with_fuel(): the top-level forms that have not run yet
+// and, if it stopped part way through a form, what was left to do in that form.  Passing
+// it to Interpreter::resume() carries on where execution stopped.
+//
+// Calls of functions and the if, do, when, unless and let forms are evaluated with
+// explicit stacks (frames of work to do and the values computed so far) rather than by
+// recursing on the Rust stack, so a program can be suspended anywhere in them, as are the
+// calls for and map make of a function written in Iron for each item of a collection.
+// Anything else, such as the other special forms, macros, for and map over an iterator
+// function and the functions called by other builtins like filter and reduce, runs to
+// completion once started.
+//
+// A continuation that stopped between top-level forms can be persisted with
+// image::save_continuation() along with the global environment.  One that stopped inside
+// a form refers to the interpreter's environments and can only be resumed by that
+// interpreter.
+pub struct Continuation {
+   root: RootAst,
+   next: uint,
+   last: ExprAst,
+   frames: Vec<Frame>,
+   values: Vec<ExprAst>
+}
+
+// The work left to do in the top-level form being run, innermost (next) last.
+enum Frame {
+   // evaluate the expression, leaving its value
+   Eval(Rc<RefCell<Environment>>, ExprAst),
+   // call the operator of the sexpr with the values of its operands
+   Apply(Rc<RefCell<Environment>>, SexprAst),
+   // evaluate the first branch of the if if the condition's value is true, or else the
+   // second
+   Branch(Rc<RefCell<Environment>>, SexprAst),
+   // bind the pattern to the value in the environment of a let
+   Bind(Rc<RefCell<Environment>>, ExprAst),
+   // leave an operand that a special form takes unevaluated as it is
+   Push(ExprAst),
+   // drop the value of an expression of a body that is not the last
+   Discard,
+   // return from a call of the function with the given name, written at the span, whose
+   // environment is given
+   Return(Rc<RefCell<Environment>>, String, Span),
+   // call the function given to for or map (named) for the next of the items, the number
+   // of which have been called for so far, keeping the values of the calls for map
+   Each(String, CodeAst, Vec<ExprAst>, uint, Option<Vec<ExprAst>>, Span)
+}
+
+impl Continuation {
+   pub fn new(root: RootAst) -> Continuation {
+      Continuation {
+         root: root,
+         next: 0,
+         last: Nil(NilAst::new()),
+         frames: vec!(),
+         values: vec!()
+      }
+   }
+
+   // Whether execution stopped between top-level forms (or before the first).
+   pub fn between_forms(&self) -> bool {
+      self.frames.is_empty()
+   }
+
+   pub fn remaining<'a>(&'a self) -> &'a [ExprAst] {
+      self.root.asts.slice_from(self.next)
+   }
+}
+
 pub struct Interpreter {
    mode: InterpMode,
    parser: Parser,
+   root: Option<RootAst>,
    pub env: Rc<RefCell<Environment>>,
-   stack: Vec<ExprAst>
+   stack: Vec<ExprAst>,
+   // the optimizer passes run on the code before it runs
+   passes: Vec<Pass>
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct Environment {
    pub parent: Option<Rc<RefCell<Environment>>>,
-   pub values: collections::HashMap<String, EnvValue>
+   pub values: collections::HashMap<String, EnvValue>,
+   pub context: Rc<RefCell<Context>>,
+   // true for the environments created by function calls (and the global environment)
+   pub frame: bool,
+   pub deferred: Vec<(Rc<RefCell<Environment>>, ExprAst)>,
+   // the parameters of a function call that keeps them in slots (see resolve.rs), in the
+   // order of their names
+   pub slots: Vec<ExprAst>,
+   pub slot_names: Option<Rc<Vec<String>>>,
+   // the names bound by defconst, with where they were defined
+   pub constants: collections::HashMap<String, Span>
 }
 
-impl Interpreter {
-   pub fn new() -> Interpreter {
-      let mut env = Environment::new(None);
-      env.populate_default();
-      Interpreter {
-         parser: Parser::new(),
-         mode: Release,
-         env: Rc::new(RefCell::new(env)),
-         stack: vec!()
-      }
-   }
+// State shared by every environment belonging to the same interpreter.
+#[deriving(Clone, PartialEq)]
+pub struct Context {
+   pub tests: Vec<(String, CodeAst)>,
+   // shared with the interpreters of imported modules, whose code uses up the same fuel
+   pub meter: Rc<RefCell<Meter>>,
+   // the number of environments (function calls and scopes), shared values (functions,
+   // queues and string builders) and checks for finalizers so far, for runtime-stats
+   pub environments: uint,
+   pub allocations: uint,
+   pub collections: uint,
+   // functions registered with on-finalize, to be called once their value is gone
+   pub finalizers: Vec<(WeakAst, CodeAst)>,
+   // the builtins, which are found after the global environment, and the names of those
+   // that have been bound to something else in any environment, which stops calls to them
+   // from being cached
+   pub builtins: Rc<collections::HashMap<String, EnvValue>>,
+   // the globals of the prelude the interpreter was created with, found before the builtins
+   pub prelude: Option<Prelude>,
+   pub capabilities: Capabilities,
+   // where print and write send their text, innermost with-output-to last (stdout if
+   // there is none), shared with the interpreters of imported modules so that their
+   // functions print there too
+   pub output: Rc<RefCell<Vec<Output>>>,
+   // the globals defined with defdynamic, shared with the interpreters of imported modules
+   pub dynamics: Dynamics,
+   pub shadowed: collections::HashSet<String>,
+   // shared with the interpreters of imported modules, so that a program can extend the
+   // protocols of the libraries it imports
+   pub protocols: Rc<RefCell<Protocols>>,
+   pub log_level: LogLevel,
+   // write log messages as one JSON object per line instead of as plain text
+   pub log_json: bool,
+   // where the special form being evaluated is written
+   pub site: Span,
+   // reads files through this so that they can be recorded and replayed (shared with the
+   // interpreters of imported modules)
+   pub inputs: Rc<RefCell<Inputs>>,
+   // with coverage enabled, the number of times each s-expression was evaluated, by the
+   // offset it starts at
+   pub coverage: Option<collections::HashMap<uint, uint>>,
+   pub hooks: Option<SharedHooks>,
+   // shared with the interpreters of imported modules, as calls of their functions run on
+   // the same Rust stack
+   pub call_stack: Rc<RefCell<CallStack>>,
+   // the macros defined with defmacro or imported, by name, and the number of times one
+   // was, which expansions kept at call sites are only good for
+   pub macros: collections::HashMap<String, Macro>,
+   pub macro_definitions: uint,
+   // the number of names made by gensym and macro expansion so far
+   pub gensyms: uint
+}
 
-   pub fn set_mode(&mut self, mode: InterpMode) {
-      self.mode = mode;
-   }
+// The methods of each protocol defined with defprotocol, in order, and the functions given
+// to extend for each protocol and type name, in method order.
+#[deriving(PartialEq)]
+pub struct Protocols {
+   pub methods: collections::HashMap<String, Vec<String>>,
+   pub implementations: collections::HashMap<(String, String), Vec<CodeAst>>
+}
 
-   pub fn set_file(&mut self, file: String) {
-      self.env.clone().borrow_mut().values.insert("FILE".to_string(), Value(String(StringAst::new(file))));
+// The dynamic variables defined with defdynamic, by name, with the environments that hold
+// them: the global environment of the module that defined each one and the environments
+// it was imported into, which parameterize changes together.  Two are only equal if they
+// are the very same registry.
+#[deriving(Clone)]
+pub struct Dynamics {
+   holders: Rc<RefCell<collections::HashMap<String, Vec<Rc<RefCell<Environment>>>>>>
+}
+
+impl Dynamics {
+   pub fn new() -> Dynamics {
+      Dynamics { holders: Rc::new(RefCell::new(collections::HashMap::new())) }
    }
 
-   pub fn load_code(&mut self, code: String) {
-      self.parser.load_code(code);
+   pub fn contains(&self, name: &String) -> bool {
+      self.holders.borrow().contains_key(name)
    }
 
-   pub fn execute(&mut self) -> int {
-      debug!("execute");
-      let mut root: RootAst = match self.parser.parse() { Root(ast) => ast, _ => unreachable!() };
-      if self.mode != Debug {
-         root = match root.optimize().unwrap() { Root(ast) => ast, _ => unreachable!() };
-      }
-      for ast in root.asts.iter() {
-         Interpreter::execute_node(self.env.clone(), &mut self.stack, ast);
-         self.stack.clear();
+   // Records that env holds the dynamic variable name, once.
+   fn add(&self, name: String, env: Rc<RefCell<Environment>>) {
+      let mut holders = self.holders.borrow_mut();
+      let envs = holders.find_or_insert(name, vec!());
+      let new: *const RefCell<Environment> = &*env;
+      if !envs.iter().any(|held| { let held: *const RefCell<Environment> = &**held; held == new }) {
+         envs.push(env);
       }
-      0 // exit status
    }
 
-   pub fn execute_node(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) {
-      debug!("execute_node");
-      let stacklen = stack.len();
-      match *node {
-         Sexpr(ref sast) => {
-            let val: &str = sast.op.value.as_slice();
-            match val {
-               "fn" => {
-                  for subast in sast.operands.iter() {
-                     stack.push(subast.clone());
-                  }
-               }
-               "if" => {
-                  if sast.operands.len() > 0 {
-                     Interpreter::execute_node(env.clone(), stack, &sast.operands[0]);
-                  }
-                  for subast in sast.operands.slice_from(1).iter() {
-                     stack.push(subast.clone());
-                  }
-               }
-               "define" | "set" => {
-                  if sast.operands.len() > 0 {
-                     stack.push(sast.operands[0].clone());
-                     for subast in sast.operands.slice_from(1).iter() {
-                        Interpreter::execute_node(env.clone(), stack, subast);
-                     }
-                  }
-               }
-               _ => {
-                  for subast in sast.operands.iter() {
-                     Interpreter::execute_node(env.clone(), stack, subast);
-                  }
-               }
-            };
-            let thing = match env.borrow().find(&sast.op.value) {
-               Some(thing) => thing,
-               None => fail!("Could not find key")  // XXX: also fix
-            };
-            match thing {
-               EnvCode(thunk) => {
-                  debug!("executing thunk...");
-                  let val = thunk(env, stack as *mut Vec<ExprAst>, sast.operands.len());
-                  stack.push(val);
-               }
-               Value(ast) => match ast {
-                  super::ast::Code(ast) => {
-                     debug!("evaluating code...");
-                     let mut count = 0;
-                     let mut subenv = Environment::new(Some(ast.env.clone()));
-                     let mut len = sast.operands.len();
-                     if len > ast.params.items.len() {
-                        for _ in range(0, len - ast.params.items.len()) {
-                           stack.pop();
-                        }
-                        len = ast.params.items.len();
-                     }
-                     let idx = stack.len() - len;
-                     debug!("begin params");
-                     for param in ast.params.items.iter() {
-                        match *param {
-                           Ident(ref idast) => {
-                              debug!("\t{}", idast.value);
-                              let slice = idast.value.as_slice();
-                              if slice.ends_with("...") {
-                                 let vec = Vec::from_fn(len - count, |_| stack.remove(idx).unwrap());
-                                 subenv.values.insert(slice.slice_to(slice.len() - 3).to_string(),
-                                                      Value(Array(ArrayAst::new(vec))));
-                              } else {
-                                 subenv.values.insert(idast.value.clone(), Value(stack.remove(idx).unwrap()));
-                              }
-                           }
-                           _ => fail!() // XXX: fix
-                        };
-                        count += 1;
-                     }
-                     debug!("end params");
-                     let subenv = Rc::new(RefCell::new(subenv));
-                     for subast in ast.code.iter() {
-                        Interpreter::execute_node(subenv.clone(), stack, subast);
-                     }
-                  }
-                  _ => fail!("Not executable")  // XXX: fix
-               }
-            };
-         }
-         Ident(ref ast) => match env.borrow().find(&ast.value) {
-            Some(val) => match val {
-               Value(ref val) => stack.push(val.clone()),
-               EnvCode(_) => fail!()  // TODO: this should not actually fail
-            },
-            None => fail!("ident {} not declared", ast.value)
-         },
-         ref other => stack.push(other.clone())  // XXX: probably can be fixed
-      }
-      for _ in range(stacklen + 1, stack.len()) {
-         let len = stack.len();
-         stack.remove(len - 1);
+   fn holders(&self, name: &String) -> Vec<Rc<RefCell<Environment>>> {
+      match self.holders.borrow().find(name) {
+         Some(envs) => envs.clone(),
+         None => vec!()
       }
    }
+}
 
-   pub fn dump_ast(&mut self) {
-      self.parser.parse().dump();
+impl PartialEq for Dynamics {
+   fn eq(&self, other: &Dynamics) -> bool {
+      let this: *const RefCell<collections::HashMap<String, Vec<Rc<RefCell<Environment>>>>> = &*self.holders;
+      let that: *const RefCell<collections::HashMap<String, Vec<Rc<RefCell<Environment>>>>> = &*other.holders;
+      this == that
    }
 }
 
-impl Environment {
-   pub fn new(parent: Option<Rc<RefCell<Environment>>>) -> Environment {
-      Environment {
-         parent: parent,
-         values: collections::HashMap::new()
+// The number of evaluation steps (nodes evaluated) taken so far, and while
+// execute_with_fuel is running, the number past which code that cannot be suspended fails.
+// suspending is set by (suspend) to stop execute_with_fuel at the next point it can.
+#[deriving(PartialEq)]
+pub struct Meter {
+   pub steps: uint,
+   pub fuel: Option<uint>,
+   pub suspending: bool
+}
+
+// How deeply execute_node is nested, which fails past max_depth rather than letting the
+// interpreter run out of stack, and the functions being called, innermost last, with where
+// they were written, for the backtrace of that error.
+#[deriving(PartialEq)]
+pub struct CallStack {
+   pub depth: uint,
+   pub max_depth: uint,
+   pub calls: Vec<(String, Span)>
+}
+
+impl CallStack {
+   fn new() -> CallStack {
+      CallStack {
+         depth: 0,
+         max_depth: DEFAULT_MAX_DEPTH,
+         calls: vec!()
       }
    }
 
-   pub fn find(&self, key: &String) -> Option<EnvValue> {
-      match self.values.find(key) {
-         Some(m) => Some(m.clone()),
-         None => match self.parent.clone() {
-            Some(env) => (*env).clone().unwrap().find(key),
-            None => None
-         }
+   // Counts one more level of nesting, or fails if there can be no more, in which case
+   // the error is at span.
+   fn enter(&mut self, span: &Span) -> InterpResult<()> {
+      if self.depth >= self.max_depth {
+         let mut error = RuntimeError::new(format!("maximum recursion depth exceeded ({}){}", self.max_depth,
+                                                   Interpreter::backtrace(self.calls.as_slice())));
+         error.span = span.clone();
+         return Err(error);
       }
+      self.depth += 1;
+      Ok(())
    }
+}
 
-   pub fn replace(&mut self, key: String, value: EnvValue) -> bool {
-      if self.values.contains_key(&key) {
-         self.values.insert(key, value);
-         true
-      } else {
-         match self.parent {
-            Some(ref env) => env.borrow_mut().replace(key, value),
-            None => false
-         }
+impl ErrorKind {
+   pub fn name(&self) -> &'static str {
+      match *self {
+         TypeError => "type-error",
+         ArityError => "arity-error",
+         NameError => "name-error",
+         IoError => "io-error",
+         ParseError => "parse-error",
+         UserError => "user-error",
+         OtherError => "other-error"
       }
    }
 
-   pub fn populate_default(&mut self) {
-      self.values.insert("FILE".to_string(), Value(String(StringAst::new("".to_string()))));
-      self.values.insert("+".to_string(), EnvCode(Environment::add));
-      self.values.insert("=".to_string(), EnvCode(Environment::equal));
-      self.values.insert("print".to_string(), EnvCode(Environment::print));
-      self.values.insert("if".to_string(), EnvCode(Environment::ifexpr));
-      self.values.insert("define".to_string(), EnvCode(Environment::define));
-      self.values.insert("fn".to_string(), EnvCode(Environment::function));
-      self.values.insert("get".to_string(), EnvCode(Environment::get));
-      self.values.insert("set".to_string(), EnvCode(Environment::set));
-      self.values.insert("len".to_string(), EnvCode(Environment::len));
-      self.values.insert("import".to_string(), EnvCode(Environment::importexpr));
-      self.values.insert("type".to_string(), EnvCode(Environment::type_obj));
-   }
-
-   fn add(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("add");
-      let mut ops = ops;
-      let mut val = 0f64;
-      let mut decimal = false;
-      while ops > 0 {
-         match unsafe { (*stack).pop() }.unwrap() {
-            Integer(ref ast) => {
-               val += ast.value as f64;
-            }
-            Float(ref ast) => {
-               decimal = true;
-               val += ast.value;
-            }
-            _ => {
-               fail!("NYI"); // XXX: implement obviously
-            }
-         }
-         ops -= 1;
+   pub fn from_name(name: &str) -> Option<ErrorKind> {
+      ERROR_KINDS.iter().find(|kind| kind.name() == name).map(|kind| kind.clone())
+   }
+}
+
+impl RuntimeError {
+   pub fn new(desc: String) -> RuntimeError {
+      RuntimeError {
+         desc: desc,
+         span: Span::unknown(),
+         value: None,
+         kind: OtherError,
+         cause: None
       }
-      if decimal { Float(FloatAst::new(val)) } else { Integer(IntegerAst::new(val as i64)) }
    }
 
-   fn print(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("print");
-      let mut ops = ops;
-      while ops > 0 {
-         match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-            Integer(ref ast) => print!("{}", ast.value),
-            Float(ref ast) => print!("{}", f64::to_str_digits(ast.value, 15)),
-            String(ref ast) => {
-               let mut output = String::new();
-               let mut escape = false;
-               for ch in ast.string.as_slice().chars() {
-                  if ch == '\\' {
-                     if escape {
-                        escape = false;
-                        output.push_char('\\');
-                     } else {
-                        escape = true;
-                     }
-                  } else if escape {
-                     match ch {
-                        'n' => println!("{}", output),
-                        't' => print!("{}\t", output),
-                        other => fail!("\\\\{} not a valid escape sequence", other)  // XXX: fix
-                     }
-                     escape = false;
-                     output.truncate(0);
-                  } else {
-                     output.push_char(ch);
-                  }
-               }
-               if escape {
-                  fail!("unterminated escape sequence");  // XXX: fix
-               }
-               print!("{}", output);
-            },
-            Symbol(ast) => print!("'{}", ast.value),
-            Boolean(ast) => print!("{}", ast.value),
-            _ => fail!()  // XXX: more of the same
+   pub fn with_kind(mut self, kind: ErrorKind) -> RuntimeError {
+      self.kind = kind;
+      self
+   }
+
+   // An error raised by (throw value).
+   pub fn thrown(value: ExprAst) -> RuntimeError {
+      match value {
+         Error(ast) => {
+            let mut result = RuntimeError::new(ast.message).with_kind(ast.kind);
+            result.value = Some(*ast.data);
+            result.cause = ast.cause;
+            result
+         }
+         other => {
+            let desc = match other {
+               String(ref ast) => (*ast.string).clone(),
+               ref other => other.to_source()
+            };
+            let mut result = RuntimeError::new(desc).with_kind(UserError);
+            result.value = Some(other);
+            result
          }
-         ops -= 1;
       }
-      Integer(IntegerAst::new(0))  // TODO: this should probably be result of output
    }
 
-   // should be able to take stuff like (define var value)
-   fn define(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("define");
-      let ops = ops;
-      if ops != 2 {
-         fail!("define can only take two arguments");  // XXX: fix
-      }
-      let valast = match unsafe { (*stack).pop() }.unwrap() {
-         Sexpr(ast) => {
-            Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &Sexpr(ast));
-            unsafe { (*stack).pop() }.unwrap()
-         }
-         other => other
-      };
-      let name = match unsafe { (*stack).pop() }.unwrap() {
-         Ident(ref ast) => ast.value.clone(),
-         _ => fail!("define must take ident for first argument")  // XXX: fix
+   // The error value bound by a catch clause.
+   pub fn to_value(&self) -> ExprAst {
+      let data = match self.value {
+         Some(ref value) => value.clone(),
+         None => Nil(NilAst::new())
       };
-      // TODO: add checking in env to see if conflicting names
-      env.clone().borrow_mut().values.insert(name.clone(), Value(valast.clone()));
-      valast
+      let mut error = ErrorAst::new(self.desc.clone(), data);
+      error.kind = self.kind.clone();
+      error.cause = self.cause.clone();
+      Error(error)
    }
+}
 
-   fn function(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("function");
-      let mut ops = ops;
-      let mut code = vec!();
-      if ops == 0 {
-         fail!("fn need at least one argument");  // XXX: fix
+impl fmt::Show for RuntimeError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      if self.span.is_known() {
+         let location = format!("error at line {}, column {}:", self.span.line, self.span.column);
+         try!(write!(f, "{} {}", color::paint(location.as_slice(), color::Red), self.desc));
+      } else {
+         try!(write!(f, "{}", self.desc));
       }
-      let params = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!() // XXX: fix
-      };
-      ops -= 1;
-      while ops > 0 {
-         unsafe { code.push((*stack).remove((*stack).len() - ops).unwrap()); }
-         ops -= 1;
+      // each error wrapped by the one before it, down to the original failure
+      let mut cause = &self.cause;
+      let mut depth = 1u;
+      loop {
+         match *cause {
+            Some(ref error) => {
+               try!(write!(f, "\n{}caused by: {}", " ".repeat(depth * 2), error.message));
+               cause = &error.cause;
+               depth += 1;
+            }
+            None => return Ok(())
+         }
       }
-      super::ast::Code(CodeAst::new(params, code, env.clone()))
    }
+}
 
-   fn get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("get");
-      if ops != 2 {
-         fail!("get only takes two values (list/array and index)");  // XXX: fix
+// Callbacks an embedder can set with Interpreter::set_hooks to watch the functions a
+// program calls, e.g. for a profiler, a debugger or an audit log.  Every on_call is
+// followed by on_return or on_error for the same call once it is done, so calls nest.
+// Builtins are not reported, nor are calls answered from the cache of memoize.  The hooks
+// are in use while one of them runs, so any calls it causes, e.g. by running code in an
+// interpreter with the same hooks, are not reported.
+pub trait Hooks {
+   fn on_call(&mut self, _name: &str, _args: &[ExprAst]) { }
+   fn on_return(&mut self, _value: &ExprAst) { }
+   fn on_error(&mut self, _error: &RuntimeError) { }
+}
+
+// The hooks of an interpreter, shared with the interpreters of the modules it imports.
+// Two are only equal if they are the very same hooks.
+#[deriving(Clone)]
+pub struct SharedHooks {
+   hooks: Rc<RefCell<Box<Hooks>>>
+}
+
+impl SharedHooks {
+   fn on_call(&self, name: &str, args: &[ExprAst]) {
+      match self.hooks.try_borrow_mut() {
+         Some(mut hooks) => hooks.on_call(name, args),
+         None => {}
       }
-      let arr = match unsafe { (*stack).remove((*stack).len() - 2) }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let idx = match unsafe { (*stack).pop() }.unwrap() {
-         Integer(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let idx =
-         if idx.value < 0 {
-            let arrlen = arr.items.len();
-            if arrlen < -idx.value as uint {
-               fail!("absolute value of {} is too large for the array/list", idx.value); // XXX: fix
-            } else {
-               arrlen + idx.value as uint
-            }
-         } else {
-            idx.value as uint
-         };
-      // TODO: check bounds
-      arr.items[idx].clone()
    }
 
-   fn set(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("set");
-      if ops != 3 {
-         fail!("set only takes three values (list/array, index, value)");  // XXX: fix
-      }
-      let (idast, mut arrast) = match unsafe { (*stack).remove((*stack).len() - 3) }.unwrap() {
-         Array(_) => return Nil(NilAst::new()),
-         Ident(ast) => match env.clone().borrow().find(&ast.value) {
-            Some(val) => match val {
-               Value(ref val) => match val {
-                  &Array(ref arrast) => (ast, arrast.clone()),
-                  _ => fail!() // XXX: fix
-               },
-               EnvCode(_) => fail!() // XXX: fix
-            },
-            None => fail!() // XXX: fix
+   fn on_result(&self, result: &InterpResult<ExprAst>) {
+      match self.hooks.try_borrow_mut() {
+         Some(mut hooks) => match *result {
+            Ok(ref value) => hooks.on_return(value),
+            Err(ref f) => hooks.on_error(f)
          },
-         _ => fail!()  // XXX: fix
-      };
-      let idx = match unsafe { (*stack).remove((*stack).len() - 2) }.unwrap() {
-         Integer(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let value = unsafe { (*stack).pop() }.unwrap();
-      let idx =
-         if idx.value < 0 {
-            let arrlen = arrast.items.len();
-            if arrlen < -idx.value as uint {
-               fail!("absolute value of {} is too large for the array/list", idx.value); // XXX: fix
-            } else {
-               arrlen + idx.value as uint
-            }
-         } else {
-            idx.value as uint
-         };
-      // TODO: fix this horrifically inefficient mess
-      let mut vec: Vec<ExprAst> = arrast.items.clone().move_iter().collect();
-      vec.grow_set(idx, &Nil(NilAst::new()), value);
-      arrast.items = vec;
-      env.clone().borrow_mut().replace(idast.value, Value(Array(arrast)));
-      Nil(NilAst::new())
+         None => {}
+      }
    }
+}
 
-   fn len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("len");
-      if ops != 1 {
-         fail!("get only takes one value (list/array)");  // XXX: fix
-      }
-      let arr = match unsafe { (*stack).pop() }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      Integer(IntegerAst::new(arr.items.len() as i64))
+impl PartialEq for SharedHooks {
+   fn eq(&self, other: &SharedHooks) -> bool {
+      let this: *const RefCell<Box<Hooks>> = &*self.hooks;
+      let that: *const RefCell<Box<Hooks>> = &*other.hooks;
+      this == that
    }
+}
 
-   fn equal(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("equal");
-      let mut ops = ops;
-      if ops < 2 {
-         fail!("= needs at least two operands"); // XXX: fix
-      }
-      let cmpast = unsafe { (*stack).pop() }.unwrap();
-      ops -= 1;
-      while ops > 0 {
-         if unsafe { (*stack).pop() }.unwrap() != cmpast {
-            return Boolean(BooleanAst::new(false));
-         }
-         ops -= 1;
-      }
-      Boolean(BooleanAst::new(true))
+// A destination for printed text.
+#[deriving(Clone, PartialEq)]
+pub enum Output {
+   ToStdout,
+   ToStderr,
+   ToBuilder(StringBuilderAst)
+}
+
+// What a program is allowed to do outside the interpreter.  A builtin that needs a
+// capability the interpreter lacks fails with a "capability denied" error.  Only
+// filesystem (save-image, read-lines and with-lines) and import (import, including modules
+// imported by modules) are needed by builtins so far; network and processes are there for
+// the builtins that will need them.
+#[deriving(Clone, PartialEq)]
+pub struct Capabilities {
+   pub filesystem: bool,
+   pub network: bool,
+   pub processes: bool,
+   pub import: bool
+}
+
+impl Capabilities {
+   pub fn all() -> Capabilities {
+      Capabilities { filesystem: true, network: true, processes: true, import: true }
    }
 
-   fn ifexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("if");
-      if ops < 2 || ops > 3 {
-         fail!("if needs >= 2 && <= 4 operands");  // XXX: fix
-      }
-      let cond = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-         Boolean(ast) => ast.value,
-         _ => fail!() // XXX: fix
-      };
-      let ontrue = unsafe { (*stack).remove((*stack).len() - ops + 1) }.unwrap();
-      if ops - 2 > 0 {
-         let onfalse = unsafe { (*stack).pop() }.unwrap();
-         if !cond {
-            Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &onfalse);
-         }
-      }
-      if cond {
-         Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &ontrue);
-      }
-      unsafe { (*stack).pop() }.unwrap()
+   pub fn none() -> Capabilities {
+      Capabilities { filesystem: false, network: false, processes: false, import: false }
    }
+}
 
-   fn importexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
+// The default limit on how deeply evaluation can nest, which allows a few hundred nested
+// calls of a simple recursive function.  Each level takes tens of kilobytes of the Rust
+// stack, far more than the main task has, so code should be run in a task with
+// stack_size(depth) of stack, as iron does.
+pub static DEFAULT_MAX_DEPTH: uint = 1000;
+
+// The stack a task needs to evaluate code nested depth deep.
+pub fn stack_size(depth: uint) -> uint {
+   (depth + STACK_LEVELS_SPARE) * STACK_PER_LEVEL
+}
+
+static STACK_PER_LEVEL: uint = 64 * 1024;
+static STACK_LEVELS_SPARE: uint = 32;
+
+// Configures an interpreter before it is created, e.g. to run untrusted code:
+//    InterpreterBuilder::new().capabilities(Capabilities::none()).build()
+pub struct InterpreterBuilder {
+   mode: InterpMode,
+   capabilities: Capabilities,
+   prelude: Option<Prelude>,
+   max_depth: uint
+}
+
+impl InterpreterBuilder {
+   pub fn new() -> InterpreterBuilder {
+      InterpreterBuilder {
+         mode: Release,
+         capabilities: Capabilities::all(),
+         prelude: None,
+         max_depth: DEFAULT_MAX_DEPTH
+      }
+   }
+
+   pub fn mode(mut self, mode: InterpMode) -> InterpreterBuilder {
+      self.mode = mode;
+      self
+   }
+
+   pub fn capabilities(mut self, capabilities: Capabilities) -> InterpreterBuilder {
+      self.capabilities = capabilities;
+      self
+   }
+
+   pub fn filesystem(mut self, allowed: bool) -> InterpreterBuilder {
+      self.capabilities.filesystem = allowed;
+      self
+   }
+
+   pub fn network(mut self, allowed: bool) -> InterpreterBuilder {
+      self.capabilities.network = allowed;
+      self
+   }
+
+   pub fn processes(mut self, allowed: bool) -> InterpreterBuilder {
+      self.capabilities.processes = allowed;
+      self
+   }
+
+   pub fn import(mut self, allowed: bool) -> InterpreterBuilder {
+      self.capabilities.import = allowed;
+      self
+   }
+
+   pub fn prelude(mut self, prelude: Prelude) -> InterpreterBuilder {
+      self.prelude = Some(prelude);
+      self
+   }
+
+   pub fn max_depth(mut self, depth: uint) -> InterpreterBuilder {
+      self.max_depth = depth;
+      self
+   }
+
+   pub fn build(self) -> Interpreter {
+      let mut interp = match self.prelude {
+         Some(ref prelude) => Interpreter::with_prelude(prelude),
+         None => Interpreter::new()
+      };
+      interp.set_mode(self.mode);
+      interp.set_capabilities(self.capabilities);
+      interp.set_max_depth(self.max_depth);
+      interp
+   }
+}
+
+// Globals shared by any number of interpreters, such as a library loaded once by a host
+// running many scripts.  Each interpreter created with Interpreter::with_prelude() finds
+// the prelude's globals after its own, and binding or setting one of their names only
+// changes that interpreter.  The rest is shared, so it can leak between interpreters:
+//  - functions from the prelude run in the prelude's environment, so a defglobal or a
+//    set of a prelude global inside one changes the prelude for everyone
+//  - queues (and weak references to them) are shared rather than copied
+//  - functions from the prelude log, count steps and register finalizers with the
+//    settings and state of the interpreter that loaded the prelude
+#[deriving(Clone, PartialEq)]
+pub struct Prelude {
+   values: Rc<collections::HashMap<String, EnvValue>>
+}
+
+impl Prelude {
+   // Runs the code and keeps the globals it defines.
+   pub fn load(code: String) -> InterpResult<Prelude> {
+      let mut interp = Interpreter::new();
+      interp.load_code(code);
+      Prelude::run(interp)
+   }
+
+   // Like load(), but for the code in a file, which can import other files relative to it.
+   pub fn load_file(path: &Path) -> InterpResult<Prelude> {
+      let mut interp = Interpreter::new();
+      match interp.load_file(path) {
+         Ok(()) => {}
+         Err(f) => throw_kind!(IoError, "could not load {}: {}", path.display(), f)
+      }
+      interp.set_file(path.as_str().unwrap_or("").to_string());
+      Prelude::run(interp)
+   }
+
+   fn run(mut interp: Interpreter) -> InterpResult<Prelude> {
+      try!(interp.run());
+      let mut values = interp.env.borrow().values.clone();
+      values.remove(&"FILE".to_string());
+      Ok(Prelude { values: Rc::new(values) })
+   }
+}
+
+impl Interpreter {
+   pub fn new() -> Interpreter {
+      let mut env = Environment::new(None);
+      env.populate_default();
+      Interpreter {
+         parser: Parser::new(),
+         root: None,
+         mode: Release,
+         env: Rc::new(RefCell::new(env)),
+         stack: vec!(),
+         passes: optimize::default_passes(Release)
+      }
+   }
+
+   // An interpreter that starts with the globals of the prelude (see Prelude).
+   pub fn with_prelude(prelude: &Prelude) -> Interpreter {
+      let interp = Interpreter::new();
+      interp.env.borrow().context.borrow_mut().prelude = Some(prelude.clone());
+      interp
+   }
+
+   // Forgets everything defined so far, keeping the interpreter's mode, log settings, hooks
+   // and depth limit.
+   pub fn reset(&mut self) {
+      let (level, json, inputs, prelude, capabilities, hooks, max_depth) = {
+         let context = self.env.borrow().context.clone();
+         let context = context.borrow();
+         let max_depth = context.call_stack.borrow().max_depth;
+         (context.log_level.clone(), context.log_json, context.inputs.clone(), context.prelude.clone(),
+          context.capabilities.clone(), context.hooks.clone(), max_depth)
+      };
+      let mut env = Environment::new(None);
+      env.populate_default();
+      self.env = Rc::new(RefCell::new(env));
+      self.set_log_level(level);
+      self.set_log_json(json);
+      self.set_inputs(inputs);
+      self.env.borrow().context.borrow_mut().prelude = prelude;
+      self.set_capabilities(capabilities);
+      self.env.borrow().context.borrow_mut().hooks = hooks;
+      self.set_max_depth(max_depth);
+      self.root = None;
+      self.stack.clear();
+   }
+
+   // Sets the mode along with the optimizer passes it enables.
+   pub fn set_mode(&mut self, mode: InterpMode) {
+      self.passes = optimize::default_passes(mode);
+      self.mode = mode;
+   }
+
+   pub fn set_passes(&mut self, passes: Vec<Pass>) {
+      self.passes = passes;
+   }
+
+   pub fn disable_pass(&mut self, pass: Pass) {
+      self.passes.retain(|enabled| *enabled != pass);
+   }
+
+   pub fn set_log_level(&mut self, level: LogLevel) {
+      self.env.borrow().context.borrow_mut().log_level = level;
+   }
+
+   pub fn set_log_json(&mut self, json: bool) {
+      self.env.borrow().context.borrow_mut().log_json = json;
+   }
+
+   // The counts reported by runtime-stats.
+   pub fn runtime_stats(&self) -> ExprAst {
+      Environment::stats(&self.env, self.stack.len())
+   }
+
+   pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+      self.env.borrow().context.borrow_mut().capabilities = capabilities;
+   }
+
+   // Sets how deeply evaluation can nest before failing with "maximum recursion depth
+   // exceeded" (see DEFAULT_MAX_DEPTH).
+   pub fn set_max_depth(&mut self, depth: uint) {
+      self.env.borrow().context.borrow().call_stack.borrow_mut().max_depth = depth;
+   }
+
+   pub fn set_inputs(&mut self, inputs: Rc<RefCell<Inputs>>) {
+      self.env.borrow().context.borrow_mut().inputs = inputs;
+   }
+
+   // Calls the given hooks around every call of a function from now on (see Hooks),
+   // replacing any set before.
+   pub fn set_hooks(&mut self, hooks: Box<Hooks>) {
+      self.env.borrow().context.borrow_mut().hooks = Some(SharedHooks { hooks: Rc::new(RefCell::new(hooks)) });
+   }
+
+   // Starts counting the evaluations of each s-expression (see coverage.rs), which turns
+   // off the optimizer.
+   pub fn enable_coverage(&mut self) {
+      self.passes.clear();
+      self.env.borrow().context.borrow_mut().coverage = Some(collections::HashMap::new());
+   }
+
+   pub fn coverage(&self) -> Option<collections::HashMap<uint, uint>> {
+      self.env.borrow().context.borrow().coverage.clone()
+   }
+
+   pub fn set_file(&mut self, file: String) {
+      self.env.clone().borrow_mut().values.insert("FILE".to_string(), Value(String(StringAst::new(file))));
+   }
+
+   pub fn load_code(&mut self, code: String) {
+      self.root = None;
+      self.parser.load_code(code);
+   }
+
+   // Runs an already parsed (e.g. precompiled) AST on the next call to execute().
+   pub fn load_ast(&mut self, ast: ExprAst) {
+      self.root = match ast {
+         Root(ast) => Some(ast),
+         other => {
+            let mut root = RootAst::new();
+            root.push(other);
+            Some(root)
+         }
+      };
+   }
+
+   // Loads the file at the given path, preferring an up-to-date precompiled version.
+   pub fn load_file(&mut self, path: &Path) -> io::IoResult<()> {
+      if self.mode != Debug {
+         match bytecode::load_cached(path) {
+            Some(ast) => {
+               debug!("using precompiled {}", bytecode::compiled_path(path).display());
+               self.load_ast(ast);
+               return Ok(());
+            }
+            None => {}
+         }
+      }
+      let code = try!(try!(io::File::open(path)).read_to_string());
+      self.load_code(code);
+      Ok(())
+   }
+
+   // Runs the loaded code like run(), logging the error if it fails.
+   pub fn execute(&mut self) -> Option<ExprAst> {
+      match self.run() {
+         Ok(value) => Some(value),
+         Err(f) => {
+            error!("{}", f);
+            None
+         }
+      }
+   }
+
+   // Runs the loaded code, returning the value of the last top-level form (nil if there
+   // are none).
+   pub fn run(&mut self) -> InterpResult<ExprAst> {
+      debug!("execute");
+      let mut root = try!(self.take_root());
+      root = optimize::optimize(root, self.passes.as_slice());
+      let mut result = Ok(());
+      let mut value = Nil(NilAst::new());
+      for ast in root.asts.iter() {
+         result = Interpreter::execute_node(self.env.clone(), &mut self.stack, ast);
+         // a form that leaves no value has the value nil, rather than that of the form
+         // before it
+         value = match (self.stack.pop(), result.is_ok()) {
+            (Some(last), true) => last,
+            _ => Nil(NilAst::new())
+         };
+         self.stack.clear();
+         if result.is_ok() {
+            result = self.run_finalizers();
+         }
+         if result.is_err() {
+            break;
+         }
+      }
+      let cleanup = Interpreter::run_deferred(self.env.clone(), &mut self.stack);
+      self.stack.clear();
+      flush_stdout();
+      try!(result);
+      try!(cleanup);
+      Ok(value)
+   }
+
+   // The AST to run: the one given to load_ast, or else the loaded code parsed, which
+   // fails with a parse error if it does not parse.
+   fn take_root(&mut self) -> InterpResult<RootAst> {
+      match self.root.take() {
+         Some(root) => return Ok(root),
+         None => {}
+      }
+      match self.parser.try_parse() {
+         Ok(Root(ast)) => Ok(ast),
+         Ok(_) => unreachable!(),
+         Err(f) => {
+            let mut error = RuntimeError::new(f.message()).with_kind(ParseError);
+            error.span = Span::new(f.line, f.column, 0, 0);
+            Err(error)
+         }
+      }
+   }
+
+   // The number of evaluation steps taken since the interpreter was created or reset.
+   pub fn steps(&self) -> uint {
+      self.env.borrow().context.borrow().meter.borrow().steps
+   }
+
+   // Runs the loaded code until it finishes, has taken fuel evaluation steps or calls
+   // (suspend).  Execution can be suspended part way through a form (see Continuation).
+   // Code that cannot be suspended is run to completion past the budget, but if it takes
+   // as much fuel again (an infinite loop in a function called by filter, say), it fails
+   // with an "out of fuel" error.  The same code and fuel always stop at the same place.
+   pub fn execute_with_fuel(&mut self, fuel: uint) -> InterpResult<FuelResult> {
+      let mut root = try!(self.take_root());
+      root = optimize::optimize(root, self.passes.as_slice());
+      self.resume(Continuation::new(root), fuel)
+   }
+
+   // Continues suspended code with another fuel evaluation steps.
+   pub fn resume(&mut self, cont: Continuation, fuel: uint) -> InterpResult<FuelResult> {
+      let context = self.env.borrow().context.clone();
+      let start = self.steps();
+      let meter = context.borrow().meter.clone();
+      {
+         let mut meter = meter.borrow_mut();
+         let limit = fuel.checked_mul(&2).and_then(|fuel| start.checked_add(&fuel));
+         meter.fuel = Some(limit.unwrap_or(::std::uint::MAX));
+         meter.suspending = false;
+      }
+      Interpreter::enter_frames(&context, cont.frames.as_slice());
+      let result = self.resume_forms(cont, start, fuel);
+      meter.borrow_mut().fuel = None;
+      result
+   }
+
+   fn resume_forms(&mut self, cont: Continuation, start: uint, fuel: uint) -> InterpResult<FuelResult> {
+      let context = self.env.borrow().context.clone();
+      let root = cont.root;
+      let mut idx = cont.next;
+      let mut result = Ok(cont.last);
+      let mut frames = cont.frames;
+      let mut values = cont.values;
+      loop {
+         if frames.is_empty() {
+            // the value left by a form that has just finished
+            match values.pop() {
+               Some(value) => {
+                  result = match self.run_finalizers() {
+                     Ok(()) => Ok(value),
+                     Err(f) => Err(f)
+                  };
+                  if result.is_err() {
+                     break;
+                  }
+               }
+               None => {}
+            }
+            if idx == root.asts.len() {
+               break;
+            }
+         }
+         let meter = context.borrow().meter.clone();
+         if self.steps() - start >= fuel || meter.borrow().suspending {
+            meter.borrow_mut().suspending = false;
+            Interpreter::leave_frames(&context, frames.as_slice());
+            return Ok(Suspended(Continuation {
+               root: root,
+               next: idx,
+               last: result.unwrap(),
+               frames: frames,
+               values: values
+            }));
+         }
+         if frames.is_empty() {
+            frames.push(Eval(self.env.clone(), root.asts[idx].clone()));
+            idx += 1;
+         }
+         match Interpreter::step(&mut frames, &mut values) {
+            Ok(()) => {}
+            Err(f) => {
+               result = Err(Interpreter::unwind(&mut frames, &mut values, f));
+               break;
+            }
+         }
+      }
+      let cleanup = Interpreter::run_deferred(self.env.clone(), &mut self.stack);
+      self.stack.clear();
+      flush_stdout();
+      let value = try!(result);
+      try!(cleanup);
+      Ok(Finished(value))
+   }
+
+   // Does the innermost piece of work left in a form run with explicit stacks (see
+   // Continuation), leaving the values it computes on values.
+   fn step(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>) -> InterpResult<()> {
+      match frames.pop().unwrap() {
+         Eval(env, expr) => Interpreter::step_eval(frames, values, env, expr),
+         Apply(env, sast) => Interpreter::step_apply(frames, values, env, sast),
+         Branch(env, sast) => {
+            let cond = match values.pop().unwrap() {
+               Boolean(ast) => ast.value,
+               _ => {
+                  let mut error = RuntimeError::new("if only takes a boolean as the condition".to_string()).with_kind(TypeError);
+                  error.span = sast.span.clone();
+                  return Err(error);
+               }
+            };
+            match (cond, sast.operands.as_slice().get(2)) {
+               (true, _) => frames.push(Eval(env, sast.operands[1].clone())),
+               (false, Some(onfalse)) => frames.push(Eval(env, onfalse.clone())),
+               (false, None) => values.push(Nil(NilAst::new()))
+            }
+            Ok(())
+         }
+         Bind(env, pattern) => {
+            let value = values.pop().unwrap();
+            env.borrow_mut().destructure(&pattern, value)
+         }
+         Push(expr) => {
+            values.push(expr);
+            Ok(())
+         }
+         Discard => {
+            values.pop();
+            Ok(())
+         }
+         Return(env, _, _) => {
+            let result = match Interpreter::run_deferred(env.clone(), values) {
+               Ok(()) => Ok(values.last().unwrap().clone()),
+               Err(f) => Err(f)
+            };
+            Interpreter::leave_call(&env, &result);
+            result.map(|_| ())
+         }
+         Each(name, func, items, idx, results, span) => {
+            let mut results = results;
+            if idx > 0 {
+               let value = values.pop().unwrap();
+               match results {
+                  Some(ref mut results) => results.push(value),
+                  None => {}
+               }
+            }
+            if idx == items.len() {
+               values.push(match results {
+                  Some(results) => Array(ArrayAst::new(results)),
+                  None => Nil(NilAst::new())
+               });
+               return Ok(());
+            }
+            let args = Environment::callback_args(&func, items[idx].clone(), idx);
+            frames.push(Each(name.clone(), func.clone(), items, idx + 1, results, span.clone()));
+            Interpreter::enter_call(frames, values, &func, name.as_slice(), args, &span)
+         }
+      }
+   }
+
+   // The frame that makes the calls of (for value f) or (map value f), whose arguments are
+   // on top of values, if they can be made with explicit stacks: f is written in Iron and
+   // value is a collection rather than an iterator function.
+   fn each_frame(values: &mut Vec<ExprAst>, sast: &SexprAst, builtin: &Builtin) -> InterpResult<Option<Frame>> {
+      if sast.operands.len() != 2 || (builtin.name != "for" && builtin.name != "map") {
+         return Ok(None);
+      }
+      let items = match values.slice_from(values.len() - 2) {
+         [ref value, super::ast::Code(ref func)] if func.builtin.is_none() && func.memo.is_none() => match iter_items(value) {
+            Ok(Some(items)) => items,
+            _ => return Ok(None)
+         },
+         _ => return Ok(None)
+      };
+      let func = match values.pop().unwrap() {
+         super::ast::Code(func) => func,
+         _ => unreachable!()
+      };
+      values.pop();
+      let results = if builtin.name == "map" { Some(vec!()) } else { None };
+      Ok(Some(Each(builtin.name.to_string(), func, items, 0, results, sast.span.clone())))
+   }
+
+   fn step_eval(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>, env: Rc<RefCell<Environment>>, expr: ExprAst) -> InterpResult<()> {
+      let sast = match expr {
+         Sexpr(sast) => sast,
+         other => return Interpreter::execute_node(env, values, &other)
+      };
+      {
+         let context = env.borrow().context.clone();
+         let mut context = context.borrow_mut();
+         context.meter.borrow_mut().steps += 1;
+         if sast.span.is_known() {
+            match context.coverage.as_mut() {
+               Some(hits) => *hits.find_or_insert(sast.span.start, 0) += 1,
+               None => {}
+            }
+         }
+      }
+      let operands = sast.operands.as_slice();
+      let special = special_form(sast.op.value.as_slice());
+      if special.is_some() && !Interpreter::explicit_form(&sast) {
+         // the other special forms are called once the operands they take are evaluated, as
+         // execute_nested() does
+         let (evaluated, _) = special.unwrap();
+         frames.push(Apply(env.clone(), sast.clone()));
+         for (idx, operand) in operands.iter().enumerate().rev() {
+            let evaluate = match evaluated {
+               EvaluateNone => false,
+               EvaluateFirst => idx == 0,
+               EvaluateRest => idx > 0
+            };
+            frames.push(if evaluate { Eval(env.clone(), operand.clone()) } else { Push(operand.clone()) });
+         }
+         return Ok(());
+      }
+      match sast.op.value.as_slice() {
+         "if" => {
+            frames.push(Branch(env.clone(), sast.clone()));
+            frames.push(Eval(env, operands[0].clone()));
+         }
+         "do" => Interpreter::push_body(frames, values, env, operands),
+         "when" | "unless" => frames.push(Eval(env, Sexpr(sast.expand_conditional().unwrap()))),
+         "let" => {
+            let bindings = match operands[0] {
+               Array(ref ast) => ast.items.as_slice(),
+               _ => unreachable!()
+            };
+            let subenv = Rc::new(RefCell::new(Environment::new(Some(env))));
+            Interpreter::push_body(frames, values, subenv.clone(), operands.slice_from(1));
+            for binding in bindings.chunks(2).rev() {
+               frames.push(Bind(subenv.clone(), binding[0].clone()));
+               frames.push(Eval(subenv.clone(), binding[1].clone()));
+            }
+         }
+         _ => {
+            match Interpreter::expand_macro(&env, &sast) {
+               Some(expanded) => {
+                  frames.push(Eval(env, try!(expanded)));
+                  return Ok(());
+               }
+               None => {}
+            }
+            match sast.expand_accessor() {
+               Some(expanded) => {
+                  frames.push(Eval(env, Sexpr(expanded)));
+                  return Ok(());
+               }
+               None => {}
+            }
+            frames.push(Apply(env.clone(), sast.clone()));
+            for operand in operands.iter().rev() {
+               frames.push(Eval(env.clone(), operand.clone()));
+            }
+         }
+      }
+      Ok(())
+   }
+
+   // Whether a special form is one of those evaluated with explicit stacks, which it is if
+   // it is well formed; any other use is left to the form itself to report.
+   fn explicit_form(sast: &SexprAst) -> bool {
+      let operands = sast.operands.as_slice();
+      match sast.op.value.as_slice() {
+         "if" => operands.len() == 2 || operands.len() == 3,
+         "do" => true,
+         "when" | "unless" => !operands.is_empty(),
+         "let" => match operands.get(0) {
+            Some(&Array(ref ast)) => ast.items.len() % 2 == 0,
+            _ => false
+         },
+         _ => false
+      }
+   }
+
+   // Pushes the work of evaluating a body, whose value is that of its last expression (nil
+   // if it is empty).
+   fn push_body(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>, env: Rc<RefCell<Environment>>, body: &[ExprAst]) {
+      if body.is_empty() {
+         values.push(Nil(NilAst::new()));
+      }
+      for (idx, expr) in body.iter().enumerate().rev() {
+         if idx + 1 < body.len() {
+            frames.push(Discard);
+         }
+         frames.push(Eval(env.clone(), expr.clone()));
+      }
+   }
+
+   // Calls the operator of a sexpr with the values of its operands, which are on top of
+   // values.  Functions written in Iron are called with explicit stacks, anything else
+   // (special forms, builtins and memoized functions) right away.
+   fn step_apply(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>, env: Rc<RefCell<Environment>>, sast: SexprAst) -> InterpResult<()> {
+      match special_form(sast.op.value.as_slice()) {
+         Some((_, builtin)) => {
+            env.borrow().context.borrow_mut().site = sast.span.clone();
+            let value = try!(Interpreter::call_operator(env, values, &sast, &builtin));
+            values.push(value);
+            return Ok(());
+         }
+         None => {}
+      }
+      let mut count = sast.operands.len();
+      let func = if sast.op.value.is_empty() {
+         count -= 1;
+         let idx = values.len() - sast.operands.len();
+         match values.remove(idx).unwrap() {
+            super::ast::Code(ast) => ast,
+            other => throw_kind!(TypeError, "{} is not executable", other.to_source())
+         }
+      } else {
+         match try!(Interpreter::lookup_operator(&env, &sast)) {
+            EnvCode(builtin) => {
+               match try!(Interpreter::each_frame(values, &sast, &builtin)) {
+                  Some(frame) => {
+                     frames.push(frame);
+                     return Ok(());
+                  }
+                  None => {}
+               }
+               let value = try!(Interpreter::call_operator(env, values, &sast, &builtin));
+               values.push(value);
+               return Ok(());
+            }
+            Value(super::ast::Code(ast)) => ast,
+            Value(_) => throw_kind!(TypeError, "{} is not executable", sast.op.value)
+         }
+      };
+      let fname = if sast.op.value.is_empty() { "fn" } else { sast.op.value.as_slice() };
+      let idx = values.len() - count;
+      let args = Vec::from_fn(count, |_| values.remove(idx).unwrap());
+      Interpreter::enter_call(frames, values, &func, fname, args, &sast.span)
+   }
+
+   // Calls func with explicit stacks, or right away if it is a builtin or memoized, as the
+   // sexpr at span does.
+   fn enter_call(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>, func: &CodeAst, fname: &str, args: Vec<ExprAst>, span: &Span) -> InterpResult<()> {
+      if func.memo.is_some() || func.builtin.is_some() {
+         let value = try!(Interpreter::call(values, func, fname, args));
+         values.push(value);
+         return Ok(());
+      }
+      let context = func.env.borrow().context.clone();
+      {
+         let call_stack = context.borrow().call_stack.clone();
+         let mut call_stack = call_stack.borrow_mut();
+         try!(call_stack.enter(span));
+         call_stack.calls.push((fname.to_string(), func.span.clone()));
+      }
+      let hooks = context.borrow().hooks.clone();
+      match hooks {
+         Some(ref hooks) => hooks.on_call(fname, args.as_slice()),
+         None => {}
+      }
+      // what call_body does, with a Return frame for what it does after the body
+      let mut subenv = Environment::new(Some(func.env.clone()));
+      subenv.frame = true;
+      if func.clauses.is_empty() {
+         subenv.slot_names = func.slots.clone();
+      }
+      let subenv = Rc::new(RefCell::new(subenv));
+      frames.push(Return(subenv.clone(), fname.to_string(), func.span.clone()));
+      let (params, code) = try!(Environment::select_clause(func, fname, &args));
+      try!(Environment::bind_params(subenv.clone(), values as *mut Vec<ExprAst>, fname, params, args));
+      Interpreter::push_body(frames, values, subenv, code.as_slice());
+      Ok(())
+   }
+
+   // What Interpreter::call() does once a function's body has been evaluated.
+   fn leave_call(env: &Rc<RefCell<Environment>>, result: &InterpResult<ExprAst>) {
+      let context = env.borrow().context.clone();
+      let hooks = context.borrow().hooks.clone();
+      match hooks {
+         Some(ref hooks) => hooks.on_result(result),
+         None => {}
+      }
+      let call_stack = context.borrow().call_stack.clone();
+      let mut call_stack = call_stack.borrow_mut();
+      call_stack.calls.pop();
+      call_stack.depth -= 1;
+   }
+
+   // Abandons the work left in a form that failed, returning from the calls it was in.
+   fn unwind(frames: &mut Vec<Frame>, values: &mut Vec<ExprAst>, error: RuntimeError) -> RuntimeError {
+      let mut error = error;
+      loop {
+         match frames.pop() {
+            Some(Return(env, _, _)) => {
+               // the error is reported rather than any from the deferred expressions
+               let _ = Interpreter::run_deferred(env.clone(), values);
+               let result = Err(error);
+               Interpreter::leave_call(&env, &result);
+               error = match result {
+                  Err(f) => f,
+                  Ok(_) => unreachable!()
+               };
+            }
+            Some(_) => {}
+            None => break
+         }
+      }
+      values.clear();
+      error
+   }
+
+   // Takes the calls a suspended form is in off the depth and backtrace of the
+   // interpreter, which only count what is on the Rust stack, and puts them back when it
+   // is resumed.
+   fn leave_frames(context: &Rc<RefCell<Context>>, frames: &[Frame]) {
+      let calls = frames.iter().filter(|frame| match **frame {
+         Return(..) => true,
+         _ => false
+      }).count();
+      let call_stack = context.borrow().call_stack.clone();
+      let mut call_stack = call_stack.borrow_mut();
+      call_stack.depth -= calls;
+      let len = call_stack.calls.len();
+      call_stack.calls.truncate(len - calls);
+   }
+
+   fn enter_frames(context: &Rc<RefCell<Context>>, frames: &[Frame]) {
+      let call_stack = context.borrow().call_stack.clone();
+      let mut call_stack = call_stack.borrow_mut();
+      for frame in frames.iter() {
+         match *frame {
+            Return(_, ref name, ref span) => {
+               call_stack.depth += 1;
+               call_stack.calls.push((name.clone(), span.clone()));
+            }
+            _ => {}
+         }
+      }
+   }
+
+   // Evaluates already parsed code in the global environment, returning the value of
+   // the last expression.  Used by the REPL, which runs deferred expressions after each
+   // entry.
+   pub fn eval_ast(&mut self, ast: &ExprAst) -> InterpResult<ExprAst> {
+      let asts = match *ast {
+         Root(ref root) => root.asts.clone(),
+         ref other => vec!(other.clone())
+      };
+      let mut result = Ok(Nil(NilAst::new()));
+      for ast in asts.iter() {
+         result = Interpreter::execute_node(self.env.clone(), &mut self.stack, ast).map(|()| self.stack.pop().unwrap());
+         self.stack.clear();
+         if result.is_ok() {
+            result = self.run_finalizers().and(result);
+         }
+         if result.is_err() {
+            break;
+         }
+      }
+      let cleanup = Interpreter::run_deferred(self.env.clone(), &mut self.stack);
+      self.stack.clear();
+      flush_stdout();
+      let value = try!(result);
+      try!(cleanup);
+      Ok(value)
+   }
+
+   // Calls the functions registered with on-finalize whose values are gone.
+   fn run_finalizers(&mut self) -> InterpResult<()> {
+      let context = self.env.borrow().context.clone();
+      context.borrow_mut().collections += 1;
+      let (dead, alive) = mem::replace(&mut context.borrow_mut().finalizers, vec!())
+         .partition(|&(ref weak, _)| weak.get().is_none());
+      context.borrow_mut().finalizers = alive;
+      for (_, func) in dead.move_iter() {
+         try!(Interpreter::call(&mut self.stack, &func, "finalizer", vec!()));
+      }
+      Ok(())
+   }
+
+   // Runs the expressions registered with defer in a function's environment, most recent
+   // first.  Every deferred expression runs even if an earlier one fails; the first error
+   // is returned.
+   pub fn run_deferred(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>) -> InterpResult<()> {
+      let deferred = mem::replace(&mut env.borrow_mut().deferred, vec!());
+      let stacklen = stack.len();
+      let mut result = Ok(());
+      for &(ref denv, ref expr) in deferred.iter().rev() {
+         let cleanup = Interpreter::execute_node(denv.clone(), stack, expr);
+         stack.truncate(stacklen);
+         if result.is_ok() {
+            result = cleanup;
+         }
+      }
+      result
+   }
+
+   // Runs every test registered with deftest, returning the number of passed and failed tests.
+   pub fn run_tests(&mut self) -> (uint, uint) {
+      let tests = self.env.borrow().context.borrow().tests.clone();
+      let mut passed = 0;
+      let mut failed = 0;
+      for &(ref name, ref test) in tests.iter() {
+         let mut subenv = Environment::new(Some(test.env.clone()));
+         subenv.frame = true;
+         let subenv = Rc::new(RefCell::new(subenv));
+         let mut result = Ok(());
+         for subast in test.code.iter() {
+            result = Interpreter::execute_node(subenv.clone(), &mut self.stack, subast);
+            if result.is_err() {
+               break;
+            }
+         }
+         let cleanup = Interpreter::run_deferred(subenv, &mut self.stack);
+         if result.is_ok() {
+            result = cleanup;
+         }
+         self.stack.clear();
+         flush_stdout();
+         match result {
+            Ok(()) => {
+               println!("test {} ... ok", name);
+               passed += 1;
+            }
+            Err(f) => {
+               println!("test {} ... FAILED: {}", name, f);
+               failed += 1;
+            }
+         }
+      }
+      println!("");
+      println!("{} passed; {} failed", passed, failed);
+      (passed, failed)
+   }
+
+   pub fn execute_node(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) -> InterpResult<()> {
+      debug!("execute_node");
+      let context = env.borrow().context.clone();
+      {
+         let context = context.borrow();
+         let out_of_fuel = {
+            let mut meter = context.meter.borrow_mut();
+            meter.steps += 1;
+            let steps = meter.steps;
+            meter.fuel.map_or(false, |limit| steps > limit)
+         };
+         if out_of_fuel {
+            let mut error = RuntimeError::new("out of fuel".to_string());
+            match *node {
+               Sexpr(ref ast) => error.span = ast.span.clone(),
+               _ => {}
+            }
+            return Err(error);
+         }
+         let span = match *node {
+            Sexpr(ref ast) => ast.span.clone(),
+            _ => Span::unknown()
+         };
+         try!(context.call_stack.borrow_mut().enter(&span));
+      }
+      let result = Interpreter::execute_nested(env, stack, node);
+      context.borrow().call_stack.borrow_mut().depth -= 1;
+      result
+   }
+
+   // The functions being called, innermost first, as lines to add to an error message.
+   // Only the innermost few are shown, as the calls of a runaway recursion all look alike.
+   fn backtrace(calls: &[(String, Span)]) -> String {
+      let mut result = String::new();
+      for &(ref name, ref span) in calls.iter().rev().take(10) {
+         if span.is_known() {
+            result.push_str(format!("\n   in {} (line {})", name, span.line).as_slice());
+         } else {
+            result.push_str(format!("\n   in {}", name).as_slice());
+         }
+      }
+      if calls.len() > 10 {
+         result.push_str(format!("\n   ... and {} more", calls.len() - 10).as_slice());
+      }
+      result
+   }
+
+   fn execute_nested(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) -> InterpResult<()> {
+      let stacklen = stack.len();
+      match *node {
+         Sexpr(ref sast) => {
+            if sast.span.is_known() {
+               match env.borrow().context.borrow_mut().coverage.as_mut() {
+                  Some(hits) => *hits.find_or_insert(sast.span.start, 0) += 1,
+                  None => {}
+               }
+            }
+            let thing = match special_form(sast.op.value.as_slice()) {
+               Some((evaluated, builtin)) => {
+                  env.borrow().context.borrow_mut().site = sast.span.clone();
+                  for (idx, subast) in sast.operands.iter().enumerate() {
+                     let evaluate = match evaluated {
+                        EvaluateNone => false,
+                        EvaluateFirst => idx == 0,
+                        EvaluateRest => idx > 0
+                     };
+                     if evaluate {
+                        try!(Interpreter::execute_node(env.clone(), stack, subast));
+                     } else {
+                        stack.push(subast.clone());
+                     }
+                  }
+                  EnvCode(builtin)
+               }
+               None => {
+                  match Interpreter::expand_macro(&env, sast) {
+                     Some(expanded) => return Interpreter::execute_node(env.clone(), stack, &try!(expanded)),
+                     None => {}
+                  }
+                  match sast.expand_accessor() {
+                     Some(expanded) => return Interpreter::execute_node(env.clone(), stack, &Sexpr(expanded)),
+                     None => {}
+                  }
+                  for subast in sast.operands.iter() {
+                     try!(Interpreter::execute_node(env.clone(), stack, subast));
+                  }
+                  if sast.op.value.is_empty() {
+                     // ((expr) args...) calls the value of its first operand
+                     let idx = stack.len() - sast.operands.len();
+                     let args = Vec::from_fn(sast.operands.len() - 1, |_| stack.remove(idx + 1).unwrap());
+                     let value = match stack.pop().unwrap() {
+                        super::ast::Code(ast) => try!(Interpreter::call(stack, &ast, "fn", args)),
+                        other => throw_kind!(TypeError, "{} is not executable", other.to_source())
+                     };
+                     stack.push(value);
+                     return Ok(());
+                  }
+                  try!(Interpreter::lookup_operator(&env, sast))
+               }
+            };
+            match thing {
+               EnvCode(builtin) => {
+                  debug!("executing thunk...");
+                  let val = try!(Interpreter::call_operator(env, stack, sast, &builtin));
+                  stack.push(val);
+               }
+               Value(ast) => match ast {
+                  super::ast::Code(ast) => {
+                     let idx = stack.len() - sast.operands.len();
+                     let args = Vec::from_fn(sast.operands.len(), |_| stack.remove(idx).unwrap());
+                     let value = try!(Interpreter::call(stack, &ast, sast.op.value.as_slice(), args));
+                     stack.push(value);
+                  }
+                  _ => throw_kind!(TypeError, "{} is not executable", sast.op.value)
+               }
+            };
+         }
+         Local(ref ast) => match Environment::local(env.clone(), ast.depth, ast.index) {
+            Some(value) => stack.push(value),
+            None => try!(Interpreter::execute_node(env.clone(), stack, &Ident(IdentAst::new(ast.name.clone()))))
+         },
+         Ident(ref ast) => match env.borrow().find(&ast.value) {
+            Some(val) => match val {
+               Value(ref val) => stack.push(val.clone()),
+               EnvCode(builtin) => stack.push(super::ast::Code(CodeAst::from_builtin(builtin, env.clone())))
+            },
+            None => throw_kind!(NameError, "ident {} not declared", ast.value)
+         },
+         ref other => stack.push(other.clone())  // XXX: probably can be fixed
+      }
+      for _ in range(stacklen + 1, stack.len()) {
+         let len = stack.len();
+         stack.remove(len - 1);
+      }
+      Ok(())
+   }
+
+   // Calls the builtin that is the operator of a sexpr with the values of its operands,
+   // which are on top of the stack.
+   #[inline(never)]
+   fn call_operator(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, sast: &SexprAst, builtin: &Builtin) -> InterpResult<ExprAst> {
+      let ops = sast.operands.len();
+      let result = match builtin.check(stack.slice_from(stack.len() - ops)) {
+         Ok(()) => (builtin.func)(env, stack as *mut Vec<ExprAst>, ops),
+         Err(f) => Err(f)
+      };
+      match result {
+         Ok(val) => Ok(val),
+         Err(mut f) => {
+            // report the innermost call that failed
+            if !f.span.is_known() {
+               f.span = sast.span.clone();
+            }
+            Err(f)
+         }
+      }
+   }
+
+   // The code a call of a macro stands for, if the operator names one that is not bound
+   // locally.  The expansion is kept at the call site, like the builtins lookup_operator
+   // finds, for as long as no macro is defined.
+   #[inline(never)]
+   fn expand_macro(env: &Rc<RefCell<Environment>>, sast: &SexprAst) -> Option<InterpResult<ExprAst>> {
+      let context = env.borrow().context.clone();
+      if !context.borrow().macros.contains_key(&sast.op.value) || env.borrow().is_local(&sast.op.value) {
+         return None;
+      }
+      let definitions = context.borrow().macro_definitions;
+      match *sast.cache.expansion.borrow() {
+         Some((ref owner, count, ref expanded)) if count == definitions => match owner.upgrade() {
+            Some(ref owner) if &**owner as *const RefCell<Context> == &*context as *const RefCell<Context> =>
+               return Some(Ok(expanded.clone())),
+            _ => {}
+         },
+         _ => {}
+      }
+      let mac = context.borrow().macros.find(&sast.op.value).unwrap().clone();
+      let expanded = match mac.expand(sast, &mut context.borrow_mut().gensyms) {
+         Ok(expanded) => expanded,
+         Err(f) => {
+            let mut error = RuntimeError::new(f).with_kind(ArityError);
+            error.span = sast.span.clone();
+            return Some(Err(error));
+         }
+      };
+      *sast.cache.expansion.borrow_mut() = Some((context.downgrade(), definitions, expanded.clone()));
+      Some(Ok(expanded))
+   }
+
+   // The value an operator refers to.  A builtin is remembered by the call site and reused
+   // for as long as no builtin has been rebound anywhere, which is the common case, so that
+   // calls in loops need not search every enclosing environment for it.
+   #[inline(never)]
+   fn lookup_operator(env: &Rc<RefCell<Environment>>, sast: &SexprAst) -> InterpResult<EnvValue> {
+      let context = env.borrow().context.clone();
+      let shadowed = context.borrow().shadowed.len();
+      match *sast.cache.entry.borrow() {
+         Some((ref owner, count, ref builtin)) if count == shadowed => match owner.upgrade() {
+            Some(ref owner) if &**owner as *const RefCell<Context> == &*context as *const RefCell<Context> =>
+               return Ok(EnvCode(builtin.clone())),
+            _ => {}
+         },
+         _ => {}
+      }
+      let thing = match env.borrow().find(&sast.op.value) {
+         Some(thing) => thing,
+         None => throw_kind!(NameError, "{} is not defined", sast.op.value)
+      };
+      match thing {
+         EnvCode(ref builtin) if !context.borrow().shadowed.contains(&sast.op.value) =>
+            *sast.cache.entry.borrow_mut() = Some((context.downgrade(), shadowed, builtin.clone())),
+         _ => {}
+      }
+      Ok(thing)
+   }
+
+   // Calls a function with already evaluated arguments, returning the value of the last
+   // expression in its body.
+   #[inline(never)]
+   pub fn call(stack: &mut Vec<ExprAst>, ast: &CodeAst, fname: &str, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      match ast.memo {
+         Some(ref memo) => return Interpreter::call_memoized(stack, ast, memo, fname, args),
+         None => {}
+      }
+      match ast.builtin {
+         Some(ref builtin) => return Interpreter::call_builtin(stack, builtin, ast.env.clone(), args),
+         None => {}
+      }
+      let context = ast.env.borrow().context.clone();
+      let hooks = context.borrow().hooks.clone();
+      context.borrow().call_stack.borrow_mut().calls.push((fname.to_string(), ast.span.clone()));
+      let result = match hooks {
+         Some(ref hooks) => {
+            hooks.on_call(fname, args.as_slice());
+            let result = Interpreter::call_body(stack, ast, fname, args);
+            hooks.on_result(&result);
+            result
+         }
+         None => Interpreter::call_body(stack, ast, fname, args)
+      };
+      context.borrow().call_stack.borrow_mut().calls.pop();
+      result
+   }
+
+   // Calls a builtin used as a value, checking its arguments as a call written out would.
+   fn call_builtin(stack: &mut Vec<ExprAst>, builtin: &Builtin, env: Rc<RefCell<Environment>>, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      try!(builtin.check(args.as_slice()));
+      let stacklen = stack.len();
+      let count = args.len();
+      stack.push_all_move(args);
+      let result = (builtin.func)(env, stack as *mut Vec<ExprAst>, count);
+      stack.truncate(stacklen);
+      result
+   }
+
+   #[inline(never)]
+   fn call_body(stack: &mut Vec<ExprAst>, ast: &CodeAst, fname: &str, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      debug!("evaluating code...");
+      let mut subenv = Environment::new(Some(ast.env.clone()));
+      subenv.frame = true;
+      if ast.clauses.is_empty() {
+         subenv.slot_names = ast.slots.clone();
+      }
+      let subenv = Rc::new(RefCell::new(subenv));
+      let stacklen = stack.len();
+      let (params, code) = try!(Environment::select_clause(ast, fname, &args));
+      try!(Environment::bind_params(subenv.clone(), stack, fname, params, args));
+      let result = Environment::evaluate_body(subenv.clone(), stack as *mut Vec<ExprAst>, code.as_slice());
+      let cleanup = Interpreter::run_deferred(subenv, stack);
+      stack.truncate(stacklen);
+      let value = try!(result);
+      try!(cleanup);
+      Ok(value)
+   }
+
+   // Calls a function made by memoize, reusing the result of an earlier call with equal
+   // arguments.  Calls with arguments that cannot be hashed are not remembered.
+   #[inline(never)]
+   fn call_memoized(stack: &mut Vec<ExprAst>, ast: &CodeAst, memo: &Rc<RefCell<collections::HashMap<u64, Vec<(Vec<ExprAst>, ExprAst)>>>>, fname: &str, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      let key = match hash::hash_value(&List(ListAst::new(args.clone()))) {
+         Ok(key) => key,
+         Err(_) => {
+            let mut plain = ast.clone();
+            plain.memo = None;
+            return Interpreter::call(stack, &plain, fname, args);
+         }
+      };
+      match memo.borrow().find(&key).and_then(|entries| entries.iter().find(|&&(ref seen, _)| seen == &args)) {
+         Some(&(_, ref value)) => return Ok(value.clone()),
+         None => {}
+      }
+      // the memo is not borrowed during the call, which may call the function again
+      let mut plain = ast.clone();
+      plain.memo = None;
+      let value = try!(Interpreter::call(stack, &plain, fname, args.clone()));
+      memo.borrow_mut().find_or_insert_with(key, |_| vec!()).push((args, value.clone()));
+      Ok(value)
+   }
+
+   pub fn dump_ast(&mut self) {
+      match self.root {
+         Some(ref root) => root.dump(),
+         None => self.parser.parse().dump()
+      }
+   }
+}
+
+// The least and (unless there is a rest parameter) the greatest number of arguments
+// accepted by the given parameters.
+pub fn arity(params: &ArrayAst) -> (uint, Option<uint>) {
+   let mut min = 0;
+   let mut max = Some(0);
+   for param in params.items.iter() {
+      match *param {
+         Ident(ref ast) if ast.value.as_slice().ends_with("...") => max = None,
+         Sexpr(ref ast) if !ast.op.value.as_slice().ends_with("?") => max = max.map(|max| max + 1),
+         _ => {
+            min += 1;
+            max = max.map(|max| max + 1);
+         }
+      }
+   }
+   (min, max)
+}
+
+// Which operands of a special form are evaluated before it is called; the others are
+// passed to it as written.
+pub enum Evaluated {
+   EvaluateNone,
+   EvaluateFirst,
+   EvaluateRest
+}
+
+// The names of the special forms below, e.g. for completion.
+pub static SPECIAL_FORMS: &'static [&'static str] = &[
+   "quote", "fn", "let", "if", "match", "define", "set", "try", "defer", "with-open", "deftest",
+   "assert", "assert-eq", "defprotocol", "extend", "defn", "letrec", "defglobal",
+   "defconst", "do",
+   "when", "unless", "with-output-to", "with-output-to-string", "defdynamic", "parameterize",
+   "defmacro"
+];
+
+// The forms that take (some of) their operands unevaluated.  They are recognized before
+// the operator is looked up, so rebinding their names does not change how they behave.
+pub fn special_form(name: &str) -> Option<(Evaluated, Builtin)> {
+   Some(match name {
+      "quote" => (EvaluateNone, Builtin::new("quote", 1, Some(1), "", Environment::quote)),
+      "fn" => (EvaluateNone, Builtin::new("fn", 1, None, "", Environment::function)),
+      "let" => (EvaluateNone, Builtin::new("let", 1, None, "", Environment::let_bind)),
+      "letrec" => (EvaluateNone, Builtin::new("letrec", 1, None, "", Environment::letrec)),
+      "if" => (EvaluateFirst, Builtin::new("if", 2, Some(3), "", Environment::ifexpr)),
+      "do" => (EvaluateNone, Builtin::new("do", 0, None, "", Environment::doexpr)),
+      "when" => (EvaluateNone, Builtin::new("when", 1, None, "", Environment::when)),
+      "unless" => (EvaluateNone, Builtin::new("unless", 1, None, "", Environment::unless)),
+      "match" => (EvaluateFirst, Builtin::new("match", 1, None, "", Environment::matchexpr)),
+      "define" => (EvaluateRest, Builtin::new("define", 2, Some(3), "", Environment::define)),
+      "defglobal" => (EvaluateRest, Builtin::new("defglobal", 2, Some(3), "", Environment::defglobal)),
+      "defconst" => (EvaluateRest, Builtin::new("defconst", 2, Some(2), "", Environment::defconst)),
+      "set" => (EvaluateRest, Builtin::new("set", 3, Some(3), "", Environment::set)),
+      "try" => (EvaluateNone, Builtin::new("try", 0, None, "", Environment::tryexpr)),
+      "defer" => (EvaluateNone, Builtin::new("defer", 1, Some(1), "", Environment::defer)),
+      "with-open" => (EvaluateNone, Builtin::new("with-open", 1, None, "", Environment::with_open)),
+      "deftest" => (EvaluateNone, Builtin::new("deftest", 1, None, "", Environment::deftest)),
+      "assert" => (EvaluateNone, Builtin::new("assert", 1, Some(1), "", Environment::assert)),
+      "assert-eq" => (EvaluateNone, Builtin::new("assert-eq", 2, Some(2), "", Environment::assert_eq)),
+      "defprotocol" => (EvaluateNone, Builtin::new("defprotocol", 2, None, "", Environment::defprotocol)),
+      "extend" => (EvaluateNone, Builtin::new("extend", 3, None, "", Environment::extend)),
+      "defn" => (EvaluateNone, Builtin::new("defn", 2, None, "", Environment::defn)),
+      "defmacro" => (EvaluateNone, Builtin::new("defmacro", 3, None, "", Environment::defmacro)),
+      "with-output-to" => (EvaluateFirst, Builtin::new("with-output-to", 1, None, "", Environment::with_output_to)),
+      "with-output-to-string" => (EvaluateNone, Builtin::new("with-output-to-string", 0, None, "", Environment::with_output_to_string)),
+      "defdynamic" => (EvaluateRest, Builtin::new("defdynamic", 2, Some(3), "", Environment::defdynamic)),
+      "parameterize" => (EvaluateNone, Builtin::new("parameterize", 1, None, "", Environment::parameterize)),
+      _ => return None
+   })
+}
+
+// The text of a string with its escape sequences replaced by the characters they stand
+// for.  Strings keep their escape sequences until they are printed or taken apart.
+pub fn unescape(string: &str) -> InterpResult<String> {
+   let mut result = String::new();
+   let mut escape = false;
+   for ch in string.chars() {
+      if escape {
+         result.push_char(match ch {
+            'n' => '\n',
+            't' => '\t',
+            '"' | '\\' | '$' => ch,
+            other => throw!("\\{} not a valid escape sequence", other)
+         });
+         escape = false;
+      } else if ch == '\\' {
+         escape = true;
+      } else {
+         result.push_char(ch);
+      }
+   }
+   if escape {
+      throw!("unterminated escape sequence");
+   }
+   Ok(result)
+}
+
+// The string holding the given text, i.e. the reverse of unescape().
+pub fn escape(text: &str) -> ExprAst {
+   let mut result = String::new();
+   for ch in text.chars() {
+      match ch {
+         '\n' => result.push_str("\\n"),
+         '\t' => result.push_str("\\t"),
+         '"' | '\\' | '$' => {
+            result.push_char('\\');
+            result.push_char(ch);
+         }
+         ch => result.push_char(ch)
+      }
+   }
+   String(StringAst::new(result))
+}
+
+// The items of a collection, in the order they are iterated over, or None for a function
+// (which is iterated over by calling it, see iter-next).  Strings are iterated over by
+// character and maps by (key value) tuples.  The items are a snapshot taken when the
+// iteration starts, so changing a queue (the one collection that is shared) while for, map,
+// filter or reduce goes over it neither changes nor invalidates the iteration: the items
+// added are not visited and those removed still are.
+fn iter_items(value: &ExprAst) -> InterpResult<Option<Vec<ExprAst>>> {
+   Ok(Some(match *value {
+      Array(ref ast) => ast.items.clone(),
+      List(ref ast) => ast.items.clone(),
+      String(ref ast) => try!(unescape(ast.string.as_slice())).as_slice().chars().map(|ch| escape(String::from_char(1, ch).as_slice())).collect(),
+      Map(ref ast) => ast.entries.iter().map(|&(_, ref key, ref value)| Tuple(TupleAst::new(vec!(key.clone(), value.clone())))).collect(),
+      SortedMap(ref ast) => ast.entries.iter().map(|&(ref key, ref value)| Tuple(TupleAst::new(vec!(key.clone(), value.clone())))).collect(),
+      Set(ref ast) => ast.values(),
+      IntArray(ref ast) => ast.values(),
+      FloatArray(ref ast) => ast.values(),
+      Queue(ref ast) if ast.frozen => ast.items.borrow().iter().map(|item| freeze(item.clone())).collect(),
+      Queue(ref ast) => ast.items.borrow().iter().map(|item| item.clone()).collect(),
+      Nil(_) => vec!(),
+      super::ast::Code(_) => return Ok(None),
+      ref other => throw_kind!(TypeError, "{} is not iterable", other.to_source())
+   }))
+}
+
+// The value with each item of an array, list or tuple, each value of a map and the data
+// of an error replaced by what f makes of it.  Queues are left to the caller, and sets and
+// the keys of maps are left alone as they cannot contain queues or string builders.
+fn map_children(value: ExprAst, f: |ExprAst| -> ExprAst) -> ExprAst {
+   match value {
+      Array(mut ast) => {
+         ast.items = ast.items.move_iter().map(|item| f(item)).collect();
+         Array(ast)
+      }
+      List(mut ast) => {
+         ast.items = ast.items.move_iter().map(|item| f(item)).collect();
+         List(ast)
+      }
+      Tuple(mut ast) => {
+         ast.items = ast.items.move_iter().map(|item| f(item)).collect();
+         Tuple(ast)
+      }
+      Map(mut ast) => {
+         ast.entries = ast.entries.move_iter().map(|(hash, key, value)| (hash, key, f(value))).collect();
+         Map(ast)
+      }
+      SortedMap(mut ast) => {
+         ast.entries = ast.entries.move_iter().map(|(key, value)| (key, f(value))).collect();
+         SortedMap(ast)
+      }
+      Error(mut ast) => {
+         ast.data = box f(*ast.data);
+         Error(ast)
+      }
+      other => other
+   }
+}
+
+// The value with every queue and string builder in it replaced by a frozen view of it.
+// The items of a frozen queue are frozen as they are taken out of it rather than here, so
+// a queue that contains itself can be frozen.
+fn freeze(value: ExprAst) -> ExprAst {
+   match value {
+      Queue(mut ast) => {
+         ast.frozen = true;
+         Queue(ast)
+      }
+      StringBuilder(mut ast) => {
+         ast.frozen = true;
+         StringBuilder(ast)
+      }
+      WeakRef(mut ast) => {
+         ast.frozen = true;
+         WeakRef(ast)
+      }
+      other => map_children(other, |item| freeze(item))
+   }
+}
+
+// A copy of the value that shares nothing with it: every queue and string builder in it is
+// copied (unfrozen).  copies holds the queues copied so far, by the original, so that a
+// queue found twice (or inside itself) is copied once.
+fn deep_copy(value: ExprAst, copies: &mut Vec<(QueueAst, QueueAst)>) -> ExprAst {
+   match value {
+      Queue(ast) => {
+         match copies.iter().find(|&&(ref original, _)| original.is(&ast)) {
+            Some(&(_, ref copy)) => return Queue(copy.clone()),
+            None => {}
+         }
+         let copy = QueueAst::new(vec!());
+         copies.push((ast.clone(), copy.clone()));
+         let items: Vec<ExprAst> = ast.items.borrow().iter().map(|item| item.clone()).collect();
+         for item in items.move_iter() {
+            let item = deep_copy(item, copies);
+            copy.items.borrow_mut().push_back(item);
+         }
+         Queue(copy)
+      }
+      StringBuilder(ast) => StringBuilder(StringBuilderAst::new(ast.buf.borrow().clone())),
+      other => map_children(other, |item| deep_copy(item, copies))
+   }
+}
+
+fn is_comment(expr: &ExprAst) -> bool {
+   match *expr {
+      Comment(_) => true,
+      _ => false
+   }
+}
+
+// Code as the data read describes it: calls as lists starting with a symbol and names as
+// symbols.
+fn as_data(code: ExprAst) -> ExprAst {
+   match code {
+      Sexpr(sast) => {
+         let mut items = vec!(Symbol(SymbolAst::new(sast.op.value)));
+         items.push_all_move(sast.operands.move_iter().filter(|item| !is_comment(item)).map(|item| as_data(item)).collect());
+         List(ListAst::new(items))
+      }
+      Ident(ast) => Symbol(SymbolAst::new(ast.value)),
+      List(mut ast) => {
+         ast.items = ast.items.move_iter().filter(|item| !is_comment(item)).map(|item| as_data(item)).collect();
+         ast.trivia = Trivia::new();
+         List(ast)
+      }
+      Array(mut ast) => {
+         ast.items = ast.items.move_iter().filter(|item| !is_comment(item)).map(|item| as_data(item)).collect();
+         ast.trivia = Trivia::new();
+         Array(ast)
+      }
+      other => map_children(other, |item| as_data(item))
+   }
+}
+
+// The order of two values, for compare and sort.  Numbers are ordered by value (so an
+// integer can be compared with a float), strings, symbols and keywords by their text,
+// false comes before true, and arrays, lists and tuples are compared item by item with a
+// prefix first.  Values of different types, values of the other types and NaN are
+// unordered.
+pub fn compare_values(a: &ExprAst, b: &ExprAst) -> InterpResult<Ordering> {
+   let number = |value: &ExprAst| match *value {
+      Integer(ref ast) => ast.value as f64,
+      Float(ref ast) => ast.value,
+      _ => unreachable!()
+   };
+   match (a, b) {
+      (&Integer(ref x), &Integer(ref y)) => Ok(x.value.cmp(&y.value)),
+      (&Integer(_), &Float(_)) | (&Float(_), &Integer(_)) | (&Float(_), &Float(_)) => match number(a).partial_cmp(&number(b)) {
+         Some(order) => Ok(order),
+         None => throw!("NaN is unordered")
+      },
+      (&String(ref x), &String(ref y)) => Ok(try!(unescape(x.string.as_slice())).cmp(&try!(unescape(y.string.as_slice())))),
+      (&Symbol(ref x), &Symbol(ref y)) => Ok(x.value.cmp(&y.value)),
+      (&Keyword(ref x), &Keyword(ref y)) => Ok(x.value.cmp(&y.value)),
+      (&Boolean(ref x), &Boolean(ref y)) => Ok(x.value.cmp(&y.value)),
+      (&Nil(_), &Nil(_)) => Ok(Equal),
+      (&Array(ref x), &Array(ref y)) => compare_items(&x.items, &y.items),
+      (&List(ref x), &List(ref y)) => compare_items(&x.items, &y.items),
+      (&Tuple(ref x), &Tuple(ref y)) => compare_items(&x.items, &y.items),
+      _ => match (type_name(a), type_name(b)) {
+         (Some(x), Some(y)) if x != y => throw!("cannot compare {} with {}", x, y),
+         (Some(name), _) => throw!("{} values are unordered", name),
+         _ => throw!("{} and {} are unordered", a.to_source(), b.to_source())
+      }
+   }
+}
+
+fn compare_items(a: &Vec<ExprAst>, b: &Vec<ExprAst>) -> InterpResult<Ordering> {
+   for (x, y) in a.iter().zip(b.iter()) {
+      match try!(compare_values(x, y)) {
+         Equal => {}
+         order => return Ok(order)
+      }
+   }
+   Ok(a.len().cmp(&b.len()))
+}
+
+// The text print writes for a value: strings without quotes or escape sequences.
+fn display(value: &ExprAst) -> InterpResult<String> {
+   Ok(match *value {
+      Integer(ref ast) => ast.value.to_string(),
+      Float(ref ast) => f64::to_str_digits(ast.value, 15),
+      String(ref ast) => try!(unescape(ast.string.as_slice())),
+      Symbol(ref ast) => format!("'{}", ast.value),
+      Boolean(ref ast) => ast.value.to_string(),
+      ref other => format!("{}", other)
+   })
+}
+
+// The items of a collection for the builtins that go through them without copying them.
+enum Items<'a> {
+   BoxedItems(Vec<&'a ExprAst>),
+   IntItems(&'a [i64]),
+   FloatItems(&'a [f64])
+}
+
+fn collection_items<'a>(name: &str, coll: &'a ExprAst) -> InterpResult<Items<'a>> {
+   Ok(match *coll {
+      Array(ref ast) => BoxedItems(ast.items.iter().collect()),
+      List(ref ast) => BoxedItems(ast.items.iter().collect()),
+      Tuple(ref ast) => BoxedItems(ast.items.iter().collect()),
+      Set(ref ast) => BoxedItems(ast.items.iter().map(|pair| match *pair { (_, ref item) => item }).collect()),
+      IntArray(ref ast) => IntItems(ast.items.as_slice()),
+      FloatArray(ref ast) => FloatItems(ast.items.as_slice()),
+      ref other => throw_kind!(TypeError, "{} only takes an array, list, tuple, set or typed array, not {}", name, other.to_source())
+   })
+}
+
+// Converts an already rounded float to an integer, failing instead of saturating or
+// wrapping like an `as` cast would.
+fn float_to_int(name: &str, value: f64) -> InterpResult<i64> {
+   if value.is_nan() {
+      throw!("{} cannot convert NaN to an integer", name);
+   }
+   // i64::MIN is exactly representable as a float but i64::MAX is not
+   if value < -9223372036854775808.0 || value >= 9223372036854775808.0 {
+      throw!("{} cannot convert {} to an integer: out of range", name, f64::to_str_digits(value, 15));
+   }
+   Ok(value as i64)
+}
+
+// Every kind of value along with its name as reported by type and the name of its type
+// predicate.  New value types only need to be added here.
+macro_rules! value_types (
+   ($($variant:ident => $name:expr, $pred:expr, $func:ident);*) => (
+      // The name of the type of the given value as reported by type.
+      pub fn type_name(value: &ExprAst) -> Option<&'static str> {
+         match *value {
+            $($variant(_) => Some($name),)*
+            _ => None
+         }
+      }
+
+      // The name of the type predicate that holds for the given value.
+      pub fn predicate_name(value: &ExprAst) -> Option<&'static str> {
+         match *value {
+            $($variant(_) => Some($pred),)*
+            _ => None
+         }
+      }
+
+      impl Environment {
+         $(fn $func(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+            let result = match unsafe { (*stack).pop() }.unwrap() {
+               $variant(_) => true,
+               _ => false
+            };
+            Ok(Boolean(BooleanAst::new(result)))
+         })*
+      }
+
+      // The names of every type, as reported by type.
+      pub fn type_names() -> Vec<&'static str> {
+         vec!($($name),*)
+      }
+
+      fn type_predicates() -> Vec<Builtin> {
+         vec!($(Builtin::new($pred, 1, Some(1), "", Environment::$func)),*)
+      }
+   )
+)
+
+value_types!(
+   Integer => "integer", "integer?", is_integer;
+   Float => "float", "float?", is_float;
+   Array => "array", "array?", is_array;
+   List => "list", "list?", is_list;
+   String => "string", "string?", is_string;
+   Symbol => "symbol", "symbol?", is_symbol;
+   Keyword => "keyword", "keyword?", is_keyword;
+   Code => "code", "fn?", is_code;
+   Boolean => "boolean", "boolean?", is_boolean;
+   Nil => "nil", "nil?", is_nil;
+   Error => "error", "error?", is_error;
+   Tuple => "tuple", "tuple?", is_tuple;
+   Map => "map", "map?", is_map;
+   SortedMap => "sorted-map", "sorted-map?", is_sorted_map;
+   Set => "set", "set?", is_set;
+   Queue => "queue", "queue?", is_queue;
+   WeakRef => "weak", "weak?", is_weak;
+   StringBuilder => "string-builder", "string-builder?", is_string_builder;
+   Matrix => "matrix", "matrix?", is_matrix;
+   IntArray => "int-array", "int-array?", is_int_array;
+   FloatArray => "float-array", "float-array?", is_float_array
+)
+
+impl Builtin {
+   pub fn new(name: &'static str, min: uint, max: Option<uint>, types: &'static str,
+              func: fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>) -> Builtin {
+      Builtin {
+         name: name,
+         min: min,
+         max: max,
+         types: types,
+         func: func
+      }
+   }
+
+   // Checks the number and types of the arguments a builtin is about to be called with.
+   pub fn check(&self, args: &[ExprAst]) -> InterpResult<()> {
+      match arity_error(self.name, self.min, self.max, args.len()) {
+         Some(desc) => throw_kind!(ArityError, "{}", desc),
+         None => {}
+      }
+      for (idx, arg) in args.iter().enumerate() {
+         let expected = match self.expected_type(idx) {
+            Some(expected) => expected,
+            None => return Ok(())
+         };
+         let actual = type_name(arg).unwrap_or("unknown");
+         if !type_matches(expected, actual) {
+            throw_kind!(TypeError, "{} expects {} for argument {} but was given {}", self.name, expected, idx + 1, actual);
+         }
+      }
+      Ok(())
+   }
+
+   // The type expected for the argument at the given index, if the builtin checks it.
+   pub fn expected_type(&self, idx: uint) -> Option<&'static str> {
+      let types: Vec<&'static str> = self.types.words().collect();
+      if types.is_empty() {
+         None
+      } else {
+         Some(types[::std::cmp::min(idx, types.len() - 1)])
+      }
+   }
+}
+
+// The error for calling something taking min to max arguments with count of them, if any.
+pub fn arity_error(name: &str, min: uint, max: Option<uint>, count: uint) -> Option<String> {
+   let plural = |n: uint| if n == 1 { "" } else { "s" };
+   match max {
+      Some(max) if max == min && count != max =>
+         Some(format!("{} takes {} argument{} but was given {}", name, max, plural(max), count)),
+      Some(max) if count < min || count > max =>
+         Some(format!("{} takes {} to {} arguments but was given {}", name, min, max, count)),
+      None if count < min =>
+         Some(format!("{} takes at least {} argument{} but was given {}", name, min, plural(min), count)),
+      _ => None
+   }
+}
+
+// Whether a value of type actual is accepted where expected is, where expected can also
+// be "any" or "number".
+pub fn type_matches(expected: &str, actual: &str) -> bool {
+   match expected {
+      "any" => true,
+      "number" => actual == "integer" || actual == "float",
+      "map" => actual == "map" || actual == "sorted-map",
+      expected => actual == expected
+   }
+}
+
+local_data_key!(DEFAULT_BUILTINS: Rc<collections::HashMap<String, EnvValue>>)
+
+// The builtins, by name.  They are built once per task and shared by every interpreter,
+// including those running imported modules.
+fn default_builtins() -> Rc<collections::HashMap<String, EnvValue>> {
+   match DEFAULT_BUILTINS.get() {
+      Some(builtins) => return builtins.clone(),
+      None => {}
+   }
+   let builtins = Rc::new(make_builtins());
+   DEFAULT_BUILTINS.replace(Some(builtins.clone()));
+   builtins
+}
+
+local_data_key!(STDOUT_BUFFER: RefCell<String>)
+local_data_key!(STDERR: RefCell<Box<Writer + Send>>)
+
+static STDOUT_BUFFER_SIZE: uint = 8192;
+
+// Text printed to stdout is collected here and written out in one go when there is enough
+// of it, on (flush), before anything is written to stderr and when the interpreter
+// finishes running code.  The buffer is shared by every interpreter in the task so that
+// text printed by functions from a prelude or an imported module stays in order.
+fn buffer_stdout(text: &str) {
+   if STDOUT_BUFFER.get().is_none() {
+      STDOUT_BUFFER.replace(Some(RefCell::new(String::new())));
+   }
+   let full = {
+      let buffer = STDOUT_BUFFER.get().unwrap();
+      buffer.borrow_mut().push_str(text);
+      let len = buffer.borrow().len();
+      len >= STDOUT_BUFFER_SIZE
+   };
+   if full {
+      flush_stdout();
+   }
+}
+
+// Writes out the text printed to stdout so far.  print! rather than io::stdout(), which
+// would bypass a task's redirected stdout.
+pub fn flush_stdout() {
+   let text = match STDOUT_BUFFER.get() {
+      Some(buffer) => mem::replace(&mut *buffer.borrow_mut(), String::new()),
+      None => return
+   };
+   if text.len() > 0 {
+      print!("{}", text);
+   }
+   io::stdio::flush();
+}
+
+// Sends what stderr-print, log messages and with-output-to 'stderr write in this task to
+// writer instead of stderr, as io::stdio::set_stdout does for print!.
+pub fn set_stderr(writer: Box<Writer + Send>) {
+   STDERR.replace(Some(RefCell::new(writer)));
+}
+
+// Writes text to stderr (or where set_stderr sent it), after the text printed to stdout
+// before it.
+fn write_stderr(text: &str) -> io::IoResult<()> {
+   flush_stdout();
+   match STDERR.get() {
+      Some(writer) => writer.borrow_mut().write_str(text),
+      None => io::stderr().write_str(text)
+   }
+}
+
+fn make_builtins() -> collections::HashMap<String, EnvValue> {
+   let builtins = [
+      Builtin::new("+", 0, None, "number", Environment::add),
+      Builtin::new("int", 1, Some(1), "number", Environment::int),
+      Builtin::new("truncate", 1, Some(1), "number", Environment::truncate),
+      Builtin::new("float", 1, Some(1), "number", Environment::float),
+      Builtin::new("exact->inexact", 1, Some(1), "number", Environment::float),
+      Builtin::new("=", 2, None, "", Environment::equal),
+      Builtin::new("compare", 2, Some(2), "", Environment::compare),
+      Builtin::new("diff", 2, Some(2), "", Environment::diff),
+      Builtin::new("print", 0, None, "", Environment::print),
+      Builtin::new("stderr-print", 0, None, "", Environment::stderr_print),
+      Builtin::new("current-output", 0, Some(0), "", Environment::current_output),
+      Builtin::new("flush", 0, Some(0), "", Environment::flush),
+      Builtin::new("write", 0, None, "", Environment::write),
+      Builtin::new("show", 1, Some(1), "", Environment::show),
+      Builtin::new("concat", 0, None, "", Environment::concat),
+      Builtin::new("str-len", 1, Some(1), "string", Environment::str_len),
+      Builtin::new("char-at", 2, Some(2), "string integer", Environment::char_at),
+      Builtin::new("str-upcase", 1, Some(1), "string", Environment::str_upcase),
+      Builtin::new("str-downcase", 1, Some(1), "string", Environment::str_downcase),
+      Builtin::new("trim", 1, Some(1), "string", Environment::trim),
+      Builtin::new("trim-left", 1, Some(1), "string", Environment::trim_left),
+      Builtin::new("trim-right", 1, Some(1), "string", Environment::trim_right),
+      Builtin::new("pad-left", 2, Some(3), "string integer string", Environment::pad_left),
+      Builtin::new("pad-right", 2, Some(3), "string integer string", Environment::pad_right),
+      Builtin::new("str-repeat", 2, Some(2), "string integer", Environment::str_repeat),
+      Builtin::new("base64-encode", 1, Some(1), "string", Environment::base64_encode),
+      Builtin::new("base64-decode", 1, Some(1), "string", Environment::base64_decode),
+      Builtin::new("hex-encode", 1, Some(1), "string", Environment::hex_encode),
+      Builtin::new("hex-decode", 1, Some(1), "string", Environment::hex_decode),
+      Builtin::new("md5", 1, Some(1), "string", Environment::md5),
+      Builtin::new("sha1", 1, Some(1), "string", Environment::sha1),
+      Builtin::new("sha256", 1, Some(1), "string", Environment::sha256),
+      Builtin::new("crc32", 1, Some(1), "string", Environment::crc32),
+      Builtin::new("starts-with?", 2, Some(2), "string", Environment::starts_with),
+      Builtin::new("ends-with?", 2, Some(2), "string", Environment::ends_with),
+      Builtin::new("contains?", 2, Some(2), "string", Environment::contains),
+      Builtin::new("index-of", 2, Some(2), "string", Environment::index_of),
+      Builtin::new("replace", 3, Some(4), "string string string symbol", Environment::str_replace),
+      Builtin::new("keyword->string", 1, Some(1), "keyword", Environment::keyword_to_string),
+      Builtin::new("gensym", 0, Some(1), "string", Environment::gensym),
+      Builtin::new("string-builder", 0, None, "string", Environment::string_builder),
+      Builtin::new("sb-push", 1, None, "string-builder string", Environment::sb_push),
+      Builtin::new("sb-push-char", 2, Some(2), "string-builder string", Environment::sb_push_char),
+      Builtin::new("sb-build", 1, Some(1), "string-builder", Environment::sb_build),
+      Builtin::new("int-array", 0, None, "integer", Environment::int_array),
+      Builtin::new("float-array", 0, None, "number", Environment::float_array),
+      Builtin::new("sum", 1, Some(1), "", Environment::sum),
+      Builtin::new("product", 1, Some(1), "", Environment::product),
+      Builtin::new("mean", 1, Some(1), "", Environment::mean),
+      Builtin::new("min", 1, Some(1), "", Environment::min),
+      Builtin::new("max", 1, Some(1), "", Environment::max),
+      Builtin::new("make-matrix", 2, Some(3), "integer integer any", Environment::make_matrix),
+      Builtin::new("m-rows", 1, Some(1), "matrix", Environment::matrix_rows),
+      Builtin::new("m-cols", 1, Some(1), "matrix", Environment::matrix_cols),
+      Builtin::new("m-get", 3, Some(3), "matrix integer", Environment::matrix_get),
+      Builtin::new("m-set", 4, Some(4), "matrix integer integer number", Environment::matrix_set),
+      Builtin::new("m-mul", 2, Some(2), "matrix any", Environment::matrix_mul),
+      Builtin::new("m-transpose", 1, Some(1), "matrix", Environment::matrix_transpose),
+      Builtin::new("values", 0, None, "", Environment::values),
+      Builtin::new("hash", 1, Some(1), "", Environment::hash),
+      Builtin::new("hash-map", 0, None, "", Environment::hash_map),
+      Builtin::new("map-get", 2, Some(3), "map any", Environment::map_get),
+      Builtin::new("map-set", 3, Some(3), "map any", Environment::map_set),
+      Builtin::new("map-remove", 2, Some(2), "map any", Environment::map_remove),
+      Builtin::new("map-contains?", 2, Some(2), "map any", Environment::map_contains),
+      Builtin::new("map-keys", 1, Some(1), "map", Environment::map_keys),
+      Builtin::new("map-values", 1, Some(1), "map", Environment::map_values),
+      Builtin::new("map-len", 1, Some(1), "map", Environment::map_len),
+      Builtin::new("smap", 0, None, "", Environment::smap),
+      Builtin::new("submap", 3, Some(3), "sorted-map any", Environment::submap),
+      Builtin::new("get-in", 2, Some(3), "any array any", Environment::get_in),
+      Builtin::new("assoc-in", 3, Some(3), "any array any", Environment::assoc_in),
+      Builtin::new("hash-set", 0, None, "", Environment::hash_set),
+      Builtin::new("set-add", 2, None, "set any", Environment::set_add),
+      Builtin::new("set-remove", 2, None, "set any", Environment::set_remove),
+      Builtin::new("set-contains?", 2, Some(2), "set any", Environment::set_contains),
+      Builtin::new("set-len", 1, Some(1), "set", Environment::set_len),
+      Builtin::new("union", 0, None, "set", Environment::union),
+      Builtin::new("intersect", 1, None, "set", Environment::intersect),
+      Builtin::new("difference", 1, None, "set", Environment::difference),
+      Builtin::new("set->array", 1, Some(1), "set", Environment::set_to_array),
+      Builtin::new("array->set", 1, Some(1), "array", Environment::array_to_set),
+      Builtin::new("queue", 0, None, "", Environment::queue),
+      Builtin::new("enqueue", 2, None, "queue any", Environment::enqueue),
+      Builtin::new("push!", 2, None, "queue any", Environment::enqueue),
+      Builtin::new("dequeue", 1, Some(1), "queue", Environment::dequeue),
+      Builtin::new("pop!", 1, Some(1), "queue", Environment::pop),
+      Builtin::new("peek", 1, Some(1), "queue", Environment::peek),
+      Builtin::new("peek-back", 1, Some(1), "queue", Environment::peek_back),
+      Builtin::new("freeze", 1, Some(1), "any", Environment::freeze),
+      Builtin::new("copy", 1, Some(1), "any", Environment::copy),
+      Builtin::new("queue-len", 1, Some(1), "queue", Environment::queue_len),
+      Builtin::new("protocol-dispatch", 3, None, "symbol symbol any", Environment::protocol_dispatch),
+      Builtin::new("apply", 2, None, "code any", Environment::apply),
+      Builtin::new("append", 0, None, "array", Environment::append),
+      Builtin::new("identity", 1, Some(1), "", Environment::identity),
+      Builtin::new("constantly", 1, Some(1), "", Environment::constantly),
+      Builtin::new("partial", 1, None, "code any", Environment::partial),
+      Builtin::new("compose", 0, None, "code", Environment::compose),
+      Builtin::new("memoize", 1, Some(1), "code", Environment::memoize),
+      Builtin::new("iter-next", 1, Some(1), "", Environment::iter_next),
+      Builtin::new("for", 2, Some(2), "any code", Environment::for_each),
+      Builtin::new("map", 2, Some(2), "any code", Environment::map),
+      Builtin::new("filter", 2, Some(2), "any code", Environment::filter),
+      Builtin::new("reduce", 3, Some(3), "any any code", Environment::reduce),
+      Builtin::new("sort", 1, Some(1), "", Environment::sort),
+      Builtin::new("range", 1, Some(3), "integer", Environment::range),
+      Builtin::new("weak", 1, Some(1), "queue", Environment::weak),
+      Builtin::new("weak-get", 1, Some(1), "weak", Environment::weak_get),
+      Builtin::new("on-finalize", 2, Some(2), "queue code", Environment::on_finalize),
+      Builtin::new("suspend", 0, Some(0), "", Environment::suspend),
+      Builtin::new("get", 2, Some(2), "any integer", Environment::get),
+      Builtin::new("len", 1, Some(1), "any", Environment::len),
+      Builtin::new("import", 1, None, "string", Environment::importexpr),
+      Builtin::new("type", 1, Some(1), "", Environment::type_obj),
+      Builtin::new("read", 1, Some(1), "string", Environment::read),
+      Builtin::new("runtime-stats", 0, Some(0), "", Environment::runtime_stats),
+      Builtin::new("save-image", 1, Some(1), "string", Environment::save_image),
+      Builtin::new("read-lines", 1, Some(1), "string", Environment::read_lines),
+      Builtin::new("csv-parse", 1, Some(2), "string map", Environment::csv_parse),
+      Builtin::new("csv-write", 1, Some(2), "any map", Environment::csv_write),
+      Builtin::new("with-lines", 2, Some(2), "string code", Environment::with_lines),
+      Builtin::new("doc", 1, Some(1), "code", Environment::doc),
+      Builtin::new("fn-arity", 1, Some(1), "code", Environment::fn_arity),
+      Builtin::new("fn-params", 1, Some(1), "code", Environment::fn_params),
+      Builtin::new("fn-source", 1, Some(1), "code", Environment::fn_source),
+      Builtin::new("fn-location", 1, Some(1), "code", Environment::fn_location),
+      Builtin::new("bound?", 1, Some(1), "symbol", Environment::is_bound),
+      Builtin::new("bindings", 0, Some(0), "", Environment::bindings),
+      Builtin::new("undefine", 1, Some(1), "symbol", Environment::undefine),
+      Builtin::new("log-debug", 1, None, "", Environment::log_debug),
+      Builtin::new("log-info", 1, None, "", Environment::log_info),
+      Builtin::new("log-warn", 1, None, "", Environment::log_warn),
+      Builtin::new("log-error", 1, None, "", Environment::log_error),
+      Builtin::new("throw", 1, Some(1), "", Environment::throw),
+      Builtin::new("error", 1, Some(3), "string any keyword", Environment::error),
+      Builtin::new("error-message", 1, Some(1), "error", Environment::error_message),
+      Builtin::new("error-data", 1, Some(1), "error", Environment::error_data),
+      Builtin::new("error-kind", 1, Some(1), "error", Environment::error_kind),
+      Builtin::new("wrap-error", 2, Some(3), "error string any", Environment::wrap_error),
+      Builtin::new("error-cause", 1, Some(1), "error", Environment::error_cause)
+   ];
+   let predicates = type_predicates();
+   let mut table = collections::HashMap::new();
+   for builtin in builtins.iter().chain(predicates.iter()) {
+      table.insert(builtin.name.to_string(), EnvCode(builtin.clone()));
+   }
+   table
+}
+
+impl Context {
+   pub fn new() -> Context {
+      Context {
+         tests: vec!(),
+         meter: Rc::new(RefCell::new(Meter { steps: 0, fuel: None, suspending: false })),
+         environments: 0,
+         allocations: 0,
+         collections: 0,
+         finalizers: vec!(),
+         builtins: default_builtins(),
+         prelude: None,
+         capabilities: Capabilities::all(),
+         output: Rc::new(RefCell::new(vec!())),
+         dynamics: Dynamics::new(),
+         shadowed: collections::HashSet::new(),
+         protocols: Rc::new(RefCell::new(Protocols {
+            methods: collections::HashMap::new(),
+            implementations: collections::HashMap::new()
+         })),
+         log_level: LogInfo,
+         log_json: false,
+         site: Span::unknown(),
+         inputs: Rc::new(RefCell::new(Live)),
+         coverage: None,
+         hooks: None,
+         call_stack: Rc::new(RefCell::new(CallStack::new())),
+         macros: collections::HashMap::new(),
+         macro_definitions: 0,
+         gensyms: 0
+      }
+   }
+}
+
+impl Environment {
+   pub fn new(parent: Option<Rc<RefCell<Environment>>>) -> Environment {
+      let context = match parent {
+         Some(ref env) => env.borrow().context.clone(),
+         None => Rc::new(RefCell::new(Context::new()))
+      };
+      context.borrow_mut().environments += 1;
+      Environment {
+         frame: parent.is_none(),
+         parent: parent,
+         values: collections::HashMap::new(),
+         context: context,
+         deferred: vec!(),
+         slots: vec!(),
+         slot_names: None,
+         constants: collections::HashMap::new()
+      }
+   }
+
+   // The environment of the innermost function call enclosing the given environment.
+   pub fn frame(env: Rc<RefCell<Environment>>) -> Rc<RefCell<Environment>> {
+      if !env.borrow().frame {
+         match env.borrow().parent {
+            Some(ref parent) => return Environment::frame(parent.clone()),
+            None => {}
+         }
+      }
+      env
+   }
+
+   pub fn find(&self, key: &String) -> Option<EnvValue> {
+      match self.values.find(key) {
+         Some(m) => return Some(m.clone()),
+         None => {}
+      }
+      match self.slot(key) {
+         Some(idx) => return Some(Value(self.slots[idx].clone())),
+         None => {}
+      }
+      match self.parent {
+         Some(ref env) => env.borrow().find(key),
+         None => {
+            let context = self.context.borrow();
+            match context.prelude {
+               Some(ref prelude) => match prelude.values.find(key) {
+                  Some(value) => return Some(value.clone()),
+                  None => {}
+               },
+               None => {}
+            }
+            context.builtins.find(key).map(|value| value.clone())
+         }
+      }
+   }
+
+   // Whether key is bound in a function call or scope around this environment rather than
+   // globally.
+   pub fn is_local(&self, key: &String) -> bool {
+      match self.parent {
+         Some(ref env) => self.values.contains_key(key) || self.slot(key).is_some() || env.borrow().is_local(key),
+         None => false
+      }
+   }
+
+   // The slot the parameter with the given name is kept in, if any.
+   fn slot(&self, key: &String) -> Option<uint> {
+      match self.slot_names {
+         Some(ref names) => match names.iter().position(|name| name == key) {
+            Some(idx) if idx < self.slots.len() => Some(idx),
+            _ => None
+         },
+         None => None
+      }
+   }
+
+   // The value in a slot of the environment of the depth-th function call enclosing the
+   // given environment, if that call keeps its parameters in slots.
+   pub fn local(env: Rc<RefCell<Environment>>, depth: uint, index: uint) -> Option<ExprAst> {
+      let mut frame = Environment::frame(env);
+      for _ in range(0, depth) {
+         let parent = match frame.borrow().parent {
+            Some(ref parent) => parent.clone(),
+            None => return None
+         };
+         frame = Environment::frame(parent);
+      }
+      let frame = frame.borrow();
+      frame.slots.as_slice().get(index).map(|value| value.clone())
+   }
+
+   // The names visible from the given environment, sorted and without duplicates.
+   pub fn names(env: Rc<RefCell<Environment>>) -> Vec<String> {
+      let mut names = vec!();
+      let mut env = Some(env);
+      loop {
+         env = match env {
+            Some(env) => {
+               names.extend(env.borrow().values.keys().map(|name| name.clone()));
+               match env.borrow().slot_names {
+                  Some(ref slots) => names.extend(slots.iter().map(|name| name.clone())),
+                  None => {}
+               }
+               let parent = env.borrow().parent.clone();
+               if parent.is_none() {
+                  let context = env.borrow().context.clone();
+                  let context = context.borrow();
+                  names.extend(context.builtins.keys().map(|name| name.clone()));
+                  match context.prelude {
+                     Some(ref prelude) => names.extend(prelude.values.keys().map(|name| name.clone())),
+                     None => {}
+                  }
+               }
+               parent
+            }
+            None => break
+         };
+      }
+      names.sort();
+      names.dedup();
+      names
+   }
+
+   pub fn global(env: Rc<RefCell<Environment>>) -> Rc<RefCell<Environment>> {
+      match env.borrow().parent {
+         Some(ref parent) => return Environment::global(parent.clone()),
+         None => {}
+      }
+      env
+   }
+
+   pub fn replace(&mut self, key: String, value: EnvValue) -> InterpResult<bool> {
+      try!(self.check_constant(&key));
+      if self.values.contains_key(&key) {
+         self.shadow(&key);
+         self.values.insert(key, value);
+         Ok(true)
+      } else if self.slot(&key).is_some() {
+         let idx = self.slot(&key).unwrap();
+         match value {
+            Value(value) => *self.slots.get_mut(idx) = value,
+            EnvCode(_) => return Ok(false)
+         }
+         Ok(true)
+      } else {
+         match self.parent {
+            Some(ref env) => env.borrow_mut().replace(key, value),
+            None if self.context.borrow().builtins.contains_key(&key) || self.in_prelude(&key) => {
+               self.shadow(&key);
+               self.values.insert(key, value);
+               Ok(true)
+            }
+            None => Ok(false)
+         }
+      }
+   }
+
+   // Binds name to value in this environment.  The names of special forms are reserved,
+   // as are the names bound by defconst in this environment.
+   pub fn bind(&mut self, name: String, value: ExprAst) -> InterpResult<()> {
+      if special_form(name.as_slice()).is_some() {
+         throw!("{} is a special form and cannot be rebound", name);
+      }
+      try!(self.check_constant(&name));
+      self.shadow(&name);
+      self.values.insert(name, Value(value));
+      Ok(())
+   }
+
+   fn check_constant(&self, name: &String) -> InterpResult<()> {
+      match self.constants.find(name) {
+         Some(span) if span.is_known() =>
+            throw!("{} is a constant (defined at line {}, column {}) and cannot be changed", name, span.line, span.column),
+         Some(_) => throw!("{} is a constant and cannot be changed", name),
+         None => Ok(())
+      }
+   }
+
+   // Fails unless the interpreter has the capability the builtin with the given name needs.
+   fn require(env: &Rc<RefCell<Environment>>, name: &str, allowed: |&Capabilities| -> bool) -> InterpResult<()> {
+      let context = env.borrow().context.clone();
+      if allowed(&context.borrow().capabilities) {
+         Ok(())
+      } else {
+         throw!("capability denied: {} is not allowed in this interpreter", name)
+      }
+   }
+
+   fn in_prelude(&self, name: &String) -> bool {
+      match self.context.borrow().prelude {
+         Some(ref prelude) => prelude.values.contains_key(name),
+         None => false
+      }
+   }
+
+   // Notes that a builtin's name is about to be bound to something else.
+   fn shadow(&self, name: &String) {
+      let context = self.context.clone();
+      let builtin = context.borrow().builtins.contains_key(name);
+      if builtin {
+         context.borrow_mut().shadowed.insert(name.clone());
+      }
+   }
+
+   // Binds the parameters of a function to the arguments it was called with.  A
+   // parameter ending in ... collects the remaining positional arguments into an array,
+   // a parameter written as (name default) evaluates default (in the function's
+   // environment, so it can refer to earlier parameters) when no argument is given, and
+   // an argument can be passed by name as :name value.
+   #[inline(never)]
+   pub fn bind_params(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, fname: &str, params: &ArrayAst, args: Vec<ExprAst>) -> InterpResult<()> {
+      let mut positional = vec!();
+      let mut keywords = vec!();
+      // a keyword passes the value after it by name only if it names a parameter, so
+      // keywords passed around as values (say by for or println) stay values
+      let names: Vec<&str> = params.items.iter().filter_map(|param| match *param {
+         Ident(ref ast) => Some(ast.value.as_slice()),
+         Sexpr(ref sast) if !sast.op.value.as_slice().ends_with("?") => Some(sast.op.value.as_slice()),
+         _ => None
+      }).collect();
+      let mut args = args.move_iter().peekable();
+      loop {
+         match args.next() {
+            Some(Keyword(ast)) => {
+               if !names.iter().any(|name| *name == ast.value.as_slice()) || args.peek().is_none() {
+                  positional.push(Keyword(ast));
+                  continue;
+               }
+               let value = args.next().unwrap();
+               keywords.push((ast.value, value));
+            }
+            Some(arg) => positional.push(arg),
+            None => break
+         }
+      }
+      // a keyword argument and its value fill a single parameter, and a rest parameter
+      // (which must come last) can be left empty
+      match params.items.iter().position(|param| match *param {
+         Ident(ref ast) => ast.value.as_slice().ends_with("..."),
+         _ => false
+      }) {
+         Some(idx) if idx + 1 != params.items.len() =>
+            throw!("the rest parameter {} of {} must be its last parameter", params.items[idx].to_source(), fname),
+         _ => {}
+      }
+      let (min, max) = arity(params);
+      match arity_error(fname, min, max, positional.len() + keywords.len()) {
+         Some(desc) => throw_kind!(ArityError, "{}", desc),
+         None => {}
+      }
+      let mut positional = positional.move_iter();
+      // a call keeping its parameters in slots only has plain names as parameters
+      let slotted = env.borrow().slot_names.is_some();
+      for param in params.items.iter() {
+         let (pat, default) = match *param {
+            Ident(ref idast) if idast.value.as_slice().ends_with("...") => {
+               let slice = idast.value.as_slice();
+               let rest = positional.by_ref().collect();
+               if slotted {
+                  env.borrow_mut().slots.push(Array(ArrayAst::new(rest)));
+               } else {
+                  try!(env.borrow_mut().bind(slice.slice_to(slice.len() - 3).to_string(), Array(ArrayAst::new(rest))));
+               }
+               continue;
+            }
+            Sexpr(ref sast) if !sast.op.value.as_slice().ends_with("?") => {
+               if sast.operands.len() != 1 {
+                  throw!("default parameter {} of {} must have exactly one value", sast.op.value, fname);
+               }
+               (Ident(sast.op.clone()), Some(&sast.operands[0]))
+            }
+            ref pat => (pat.clone(), None)
+         };
+         let keyword = match pat {
+            Ident(ref idast) => keywords.iter().position(|&(ref name, _)| name == &idast.value),
+            _ => None
+         };
+         let value = match keyword {
+            Some(pos) => keywords.remove(pos).unwrap().val1(),
+            None => match positional.next() {
+               Some(arg) => arg,
+               None => match default {
+                  Some(expr) => try!(Environment::evaluate(env.clone(), stack, expr)),
+                  None => throw_kind!(ArityError, "missing argument {} in call to {}", pat.to_source(), fname)
+               }
+            }
+         };
+         if slotted {
+            env.borrow_mut().slots.push(value);
+         } else {
+            try!(env.borrow_mut().destructure(&pat, value));
+         }
+      }
+      Ok(())
+   }
+
+   // Binds the identifiers in the pattern to the matching parts of value, failing if
+   // value does not have the shape described by the pattern.
+   pub fn destructure(&mut self, pat: &ExprAst, value: ExprAst) -> InterpResult<()> {
+      let mut bindings = vec!();
+      match pattern::match_pattern(pat, &value, &mut bindings) {
+         Ok(true) => {}
+         Ok(false) => throw!("{} does not match {}", value.to_source(), pat.to_source()),
+         Err(f) => throw!("{}", f)
+      }
+      for (name, val) in bindings.move_iter() {
+         try!(self.bind(name, val));
+      }
+      Ok(())
+   }
+
+   // Binds the globals every program starts with.  The builtins are shared by every
+   // interpreter instead (see default_builtins()).
+   pub fn populate_default(&mut self) {
+      self.values.insert("FILE".to_string(), Value(String(StringAst::new("".to_string()))));
+   }
+
+   fn add(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("add");
+      let mut ops = ops;
+      // integers are summed exactly and only converted if a float is involved
+      let mut intval = 0i64;
+      let mut val = 0f64;
+      let mut decimal = false;
+      while ops > 0 {
+         match unsafe { (*stack).pop() }.unwrap() {
+            Integer(ref ast) => {
+               intval = match intval.checked_add(&ast.value) {
+                  Some(sum) => sum,
+                  None => throw!("integer overflow in +")
+               };
+            }
+            Float(ref ast) => {
+               decimal = true;
+               val += ast.value;
+            }
+            _ => {
+               throw!("+ is not yet implemented for non-numbers"); // XXX: implement obviously
+            }
+         }
+         ops -= 1;
+      }
+      Ok(if decimal { Float(FloatAst::new(val + intval as f64)) } else { Integer(IntegerAst::new(intval)) })
+   }
+
+   // (int x) rounds x to the nearest integer, with halves rounded away from zero
+   fn int(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("int");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Float(ast) => Ok(Integer(IntegerAst::new(try!(float_to_int("int", ast.value.round()))))),
+         other => Ok(other)
+      }
+   }
+
+   // (truncate x) rounds x towards zero
+   fn truncate(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("truncate");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Float(ast) => Ok(Integer(IntegerAst::new(try!(float_to_int("truncate", ast.value.trunc()))))),
+         other => Ok(other)
+      }
+   }
+
+   // (float x) and (exact->inexact x) convert x to a float
+   fn float(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("float");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => Ok(Float(FloatAst::new(ast.value as f64))),
+         other => Ok(other)
+      }
+   }
+
+   fn print(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("print");
+      let text = try!(Environment::print_text(stack, ops));
+      try!(Environment::emit(&env, text.as_slice()));
+      Ok(Integer(IntegerAst::new(0)))  // TODO: this should probably be result of output
+   }
+
+   // (stderr-print value...) prints like print, but always to stderr
+   fn stderr_print(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("stderr-print");
+      let text = try!(Environment::print_text(stack, ops));
+      try!(Environment::emit_to(&ToStderr, text.as_slice()));
+      Ok(Integer(IntegerAst::new(0)))
+   }
+
+   // The text print writes for the values.
+   fn print_text(stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<String> {
+      let mut text = String::new();
+      let mut ops = ops;
+      while ops > 0 {
+         let value = unsafe { (*stack).remove((*stack).len() - ops) }.unwrap();
+         text.push_str(try!(display(&value)).as_slice());
+         ops -= 1;
+      }
+      Ok(text)
+   }
+
+   // Writes text to the current output (see with-output-to).
+   fn emit(env: &Rc<RefCell<Environment>>, text: &str) -> InterpResult<()> {
+      let context = env.borrow().context.clone();
+      let output = context.borrow().output.borrow().last().map(|output| output.clone()).unwrap_or(ToStdout);
+      Environment::emit_to(&output, text)
+   }
+
+   fn emit_to(output: &Output, text: &str) -> InterpResult<()> {
+      let result = match *output {
+         ToStdout => {
+            buffer_stdout(text);
+            Ok(())
+         }
+         ToStderr => write_stderr(text),
+         ToBuilder(ref builder) => {
+            match escape(text) {
+               String(ast) => builder.buf.borrow_mut().push_str(ast.string.as_slice()),
+               _ => unreachable!()
+            }
+            Ok(())
+         }
+      };
+      match result {
+         Ok(()) => Ok(()),
+         Err(f) => throw_kind!(IoError, "could not print: {}", f)
+      }
+   }
+
+   // (flush) writes out the text printed to stdout that is still buffered
+   fn flush(_: Rc<RefCell<Environment>>, _: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("flush");
+      flush_stdout();
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (write value...) prints values in their re-readable form separated by spaces
+   fn write(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("write");
+      let idx = unsafe { (*stack).len() } - ops;
+      let values = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let values: Vec<String> = values.iter().map(|value| value.to_source()).collect();
+      try!(Environment::emit(&env, values.connect(" ").as_slice()));
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (show value) returns the re-readable form of value as a string
+   fn show(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("show");
+      let source = unsafe { (*stack).pop() }.unwrap().to_source();
+      let mut escaped = String::new();
+      for ch in source.as_slice().chars() {
+         if ch == '"' || ch == '\\' {
+            escaped.push_char('\\');
+         }
+         escaped.push_char(ch);
+      }
+      Ok(String(StringAst::new(escaped)))
+   }
+
+   // (concat value...) joins the values into one string, with strings added as they are
+   // and anything else in its re-readable form.  Interpolated strings are read as concat.
+   fn concat(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("concat");
+      let idx = unsafe { (*stack).len() } - ops;
+      let values = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let mut result = String::new();
+      for value in values.iter() {
+         match *value {
+            String(ref ast) => result.push_str(ast.string.as_slice()),
+            ref other => result.push_str(other.to_source().as_slice())
+         }
+      }
+      Ok(String(StringAst::new(result)))
+   }
+
+   // (str-len s) is the number of characters (not bytes) in s
+   fn str_len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("str-len");
+      match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => Ok(Integer(IntegerAst::new(try!(unescape(ast.string.as_slice())).as_slice().char_len() as i64))),
+         _ => throw_kind!(TypeError, "str-len only takes a string")
+      }
+   }
+
+   // (char-at s i) is the character at index i of s as a string, counting from the end
+   // if i is negative
+   fn char_at(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("char-at");
+      let idx = match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => throw_kind!(TypeError, "char-at only takes an integer for second argument")
+      };
+      let chars: Vec<char> = match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => try!(unescape(ast.string.as_slice())).as_slice().chars().collect(),
+         _ => throw_kind!(TypeError, "char-at only takes a string for first argument")
+      };
+      let pos = if idx < 0 { chars.len() as i64 + idx } else { idx };
+      if pos < 0 || pos >= chars.len() as i64 {
+         throw!("index {} is out of range for a string of {} characters", idx, chars.len());
+      }
+      Ok(escape(String::from_char(1, chars[pos as uint]).as_slice()))
+   }
+
+   // (str-upcase s) and (str-downcase s) change the case of every letter in s, including
+   // those outside ASCII
+   fn str_upcase(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("str-upcase");
+      match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => {
+            let text: String = try!(unescape(ast.string.as_slice())).as_slice().chars().map(|ch| ch.to_uppercase()).collect();
+            Ok(escape(text.as_slice()))
+         }
+         _ => throw_kind!(TypeError, "str-upcase only takes a string")
+      }
+   }
+
+   fn str_downcase(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("str-downcase");
+      match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => {
+            let text: String = try!(unescape(ast.string.as_slice())).as_slice().chars().map(|ch| ch.to_lowercase()).collect();
+            Ok(escape(text.as_slice()))
+         }
+         _ => throw_kind!(TypeError, "str-downcase only takes a string")
+      }
+   }
+
+   // (trim s), (trim-left s) and (trim-right s) remove whitespace, including Unicode
+   // spaces, from both ends, the start or the end of s
+   fn trim(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("trim");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_slice().trim()))
+   }
+
+   fn trim_left(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("trim-left");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_slice().trim_left()))
+   }
+
+   fn trim_right(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("trim-right");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_slice().trim_right()))
+   }
+
+   // (pad-left s width fill?) and (pad-right s width fill?) add the character fill (a
+   // space by default) before or after s until it is width characters long
+   fn pad_left(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pad-left");
+      let (text, padding) = try!(Environment::pop_padding(stack, ops, "pad-left"));
+      Ok(escape(format!("{}{}", padding, text).as_slice()))
+   }
+
+   fn pad_right(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pad-right");
+      let (text, padding) = try!(Environment::pop_padding(stack, ops, "pad-right"));
+      Ok(escape(format!("{}{}", text, padding).as_slice()))
+   }
+
+   // Pops the arguments of pad-left or pad-right, returning the text and the padding it
+   // needs.
+   fn pop_padding(stack: *mut Vec<ExprAst>, ops: uint, name: &str) -> InterpResult<(String, String)> {
+      let fill = if ops == 3 { try!(Environment::pop_text(stack)) } else { " ".to_string() };
+      if fill.as_slice().char_len() != 1 {
+         throw_kind!(TypeError, "{} only takes a single character to pad with, not {}", name, escape(fill.as_slice()).to_source());
+      }
+      let width = match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      };
+      let text = try!(Environment::pop_text(stack));
+      let len = text.as_slice().char_len() as i64;
+      let padding = if width > len { String::from_char((width - len) as uint, fill.as_slice().char_at(0)) } else { String::new() };
+      Ok((text, padding))
+   }
+
+   // (str-repeat s n) is s n times over
+   fn str_repeat(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("str-repeat");
+      let count = match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      };
+      if count < 0 {
+         throw!("str-repeat cannot repeat a string {} times", count);
+      }
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_slice().repeat(count as uint).as_slice()))
+   }
+
+   // (base64-encode s) and (hex-encode s) are the UTF-8 bytes of s in base64 (with padding)
+   // or lowercase hex, and base64-decode and hex-decode turn them back into a string.  There
+   // is no type for raw bytes, so decoding fails if the bytes are not UTF-8.
+   fn base64_encode(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("base64-encode");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_bytes().to_base64(base64::STANDARD).as_slice()))
+   }
+
+   fn base64_decode(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("base64-decode");
+      let text = try!(Environment::pop_text(stack));
+      match text.as_slice().from_base64() {
+         Ok(bytes) => Environment::decoded_text("base64-decode", bytes),
+         Err(f) => throw!("base64-decode: {}", f)
+      }
+   }
+
+   fn hex_encode(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("hex-encode");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(text.as_bytes().to_hex().as_slice()))
+   }
+
+   fn hex_decode(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("hex-decode");
+      let text = try!(Environment::pop_text(stack));
+      match text.as_slice().from_hex() {
+         Ok(bytes) => Environment::decoded_text("hex-decode", bytes),
+         Err(f) => throw!("hex-decode: {}", f)
+      }
+   }
+
+   // (md5 s), (sha1 s), (sha256 s) and (crc32 s) are the digests of the UTF-8 bytes of s as
+   // lowercase hex
+   fn md5(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("md5");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(digest::md5(text.as_bytes()).as_slice()))
+   }
+
+   fn sha1(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sha1");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(digest::sha1(text.as_bytes()).as_slice()))
+   }
+
+   fn sha256(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sha256");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(digest::sha256(text.as_bytes()).as_slice()))
+   }
+
+   fn crc32(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("crc32");
+      let text = try!(Environment::pop_text(stack));
+      Ok(escape(digest::crc32(text.as_bytes()).as_slice()))
+   }
+
+   fn decoded_text(name: &str, bytes: Vec<u8>) -> InterpResult<ExprAst> {
+      match String::from_utf8(bytes) {
+         Ok(text) => Ok(escape(text.as_slice())),
+         Err(_) => throw!("{}: the decoded bytes are not UTF-8 text", name)
+      }
+   }
+
+   // Pops a string, returning its text.
+   fn pop_text(stack: *mut Vec<ExprAst>) -> InterpResult<String> {
+      match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => unescape(ast.string.as_slice()),
+         _ => unreachable!()
+      }
+   }
+
+   // (starts-with? s prefix), (ends-with? s suffix) and (contains? s part) check for text
+   // in s
+   fn starts_with(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("starts-with?");
+      let (text, part) = try!(Environment::pop_search(stack));
+      Ok(Boolean(BooleanAst::new(text.as_slice().starts_with(part.as_slice()))))
+   }
+
+   fn ends_with(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("ends-with?");
+      let (text, part) = try!(Environment::pop_search(stack));
+      Ok(Boolean(BooleanAst::new(text.as_slice().ends_with(part.as_slice()))))
+   }
+
+   fn contains(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("contains?");
+      let (text, part) = try!(Environment::pop_search(stack));
+      Ok(Boolean(BooleanAst::new(text.as_slice().contains(part.as_slice()))))
+   }
+
+   // (index-of s part) is the index of the character where part first appears in s, or
+   // nil if it does not
+   fn index_of(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("index-of");
+      let (text, part) = try!(Environment::pop_search(stack));
+      Ok(match text.as_slice().find_str(part.as_slice()) {
+         Some(pos) => Integer(IntegerAst::new(text.as_slice().slice_to(pos).char_len() as i64)),
+         None => Nil(NilAst::new())
+      })
+   }
+
+   // (replace s from to mode?) replaces every appearance of from in s with to.  mode says
+   // how from is matched: 'literal, the default, is the only mode until there are regular
+   // expressions.
+   fn str_replace(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("replace");
+      if ops == 4 {
+         match unsafe { (*stack).pop() }.unwrap() {
+            Symbol(ref ast) if ast.value.as_slice() == "literal" => {}
+            Symbol(ref ast) if ast.value.as_slice() == "regex" => throw!("replace does not support regular expressions yet"),
+            other => throw_kind!(TypeError, "replace only takes 'literal as the mode, not {}", other.to_source())
+         }
+      }
+      let to = try!(Environment::pop_text(stack));
+      let (text, from) = try!(Environment::pop_search(stack));
+      if from.len() == 0 {
+         throw!("replace cannot replace the empty string");
+      }
+      Ok(escape(text.as_slice().replace(from.as_slice(), to.as_slice()).as_slice()))
+   }
+
+   // Pops a string and the text to search it for.
+   fn pop_search(stack: *mut Vec<ExprAst>) -> InterpResult<(String, String)> {
+      let part = try!(Environment::pop_text(stack));
+      let text = try!(Environment::pop_text(stack));
+      Ok((text, part))
+   }
+
+   // (keyword->string :name) is "name"
+   fn keyword_to_string(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("keyword->string");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Keyword(ast) => Ok(escape(ast.value.as_slice())),
+         _ => throw_kind!(TypeError, "keyword->string only takes a keyword")
+      }
+   }
+
+   // (define name "doc"? value) binds name in the current scope: at the top level of a
+   // file, or in the body of the function, let or match it is written in, where it can be
+   // seen for the rest of that body (and by the functions created in it) but not outside.
+   fn define(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("define");
+      Environment::define_in(env.clone(), env, stack, ops, "define")
+   }
+
+   // (defglobal name "doc"? value) binds name at the top level from anywhere, evaluating
+   // value where it is written.
+   fn defglobal(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defglobal");
+      let global = Environment::global(env.clone());
+      Environment::define_in(env, global, stack, ops, "defglobal")
+   }
+
+   // (defdynamic name "doc"? value) defines a dynamic variable: a global whose value can be
+   // changed by parameterize for as long as its body runs, including in the functions the
+   // body calls.
+   fn defdynamic(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defdynamic");
+      let name = match unsafe { &(*stack)[(*stack).len() - ops] } {
+         &Ident(ref ast) => ast.value.clone(),
+         _ => throw_kind!(TypeError, "defdynamic must take an ident for first argument")
+      };
+      let global = Environment::global(env.clone());
+      let value = try!(Environment::define_in(env.clone(), global.clone(), stack, ops, "defdynamic"));
+      env.borrow().context.borrow().dynamics.add(name, global);
+      Ok(value)
+   }
+
+   // (parameterize [name value...] body...) evaluates body with each dynamic variable name
+   // set to value, both in the module that defined it and in those that imported it.  The
+   // values are all evaluated first, and the previous values are put back once body is
+   // done, even if it fails.
+   fn parameterize(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("parameterize");
+      let idx = unsafe { (*stack).len() } - ops;
+      let bindings = match unsafe { (*stack).remove(idx) }.unwrap() {
+         Array(ast) => ast.items,
+         _ => throw_kind!(TypeError, "parameterize must take an array of bindings for first argument")
+      };
+      let body = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      if bindings.len() % 2 != 0 {
+         throw!("parameterize needs a value for each name");
+      }
+      let context = env.borrow().context.clone();
+      let mut values = vec!();
+      for pair in bindings.as_slice().chunks(2) {
+         let name = match pair[0] {
+            Ident(ref ast) => ast.value.clone(),
+            ref other => throw!("parameterize only binds names, not {}", other.to_source())
+         };
+         if !context.borrow().dynamics.contains(&name) {
+            throw!("{} is not a dynamic variable (see defdynamic)", name);
+         }
+         values.push((name, try!(Environment::evaluate(env.clone(), stack, &pair[1]))));
+      }
+      let mut previous = vec!();
+      for (name, value) in values.move_iter() {
+         for holder in context.borrow().dynamics.holders(&name).move_iter() {
+            let old = holder.borrow_mut().values.swap(name.clone(), Value(value.clone()));
+            previous.push((holder, name.clone(), old));
+         }
+      }
+      let result = Environment::evaluate_body(env, stack, body.as_slice());
+      for (holder, name, old) in previous.move_iter().rev() {
+         match old {
+            Some(old) => { holder.borrow_mut().values.insert(name, old); }
+            None => { holder.borrow_mut().values.remove(&name); }
+         }
+      }
+      result
+   }
+
+   // (defconst name value) binds name in the current scope like define, but the binding
+   // cannot be changed afterwards by define, set or anything else.
+   fn defconst(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("defconst");
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let name = match unsafe { (*stack).pop() }.unwrap() {
+         Ident(ast) => ast.value,
+         _ => throw_kind!(TypeError, "defconst must take an ident for first argument")
+      };
+      let site = env.borrow().context.borrow().site.clone();
+      try!(env.borrow_mut().bind(name.clone(), value.clone()));
+      env.borrow_mut().constants.insert(name, site);
+      Ok(value)
+   }
+
+   fn define_in(env: Rc<RefCell<Environment>>, target: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint, form: &str) -> InterpResult<ExprAst> {
+      let mut valast = match unsafe { (*stack).pop() }.unwrap() {
+         Sexpr(ast) => {
+            try!(Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &Sexpr(ast)));
+            unsafe { (*stack).pop() }.unwrap()
+         }
+         other => other
+      };
+      if ops == 3 {
+         let doc = match unsafe { (*stack).pop() }.unwrap() {
+            String(ast) => ast.into_string(),
+            _ => throw_kind!(TypeError, "{} only takes a string as the doc string", form)
+         };
+         match valast {
+            super::ast::Code(ref mut ast) => ast.doc = Some(doc),
+            _ => throw!("doc strings can only be attached to functions")
+         }
+      }
+      match unsafe { (*stack).pop() }.unwrap() {
+         Ident(ast) => try!(target.borrow_mut().bind(ast.value, valast.clone())),
+         pat @ Array(_) => {
+            if ops == 3 {
+               throw!("doc strings cannot be attached to destructuring definitions");
+            }
+            try!(target.borrow_mut().destructure(&pat, valast.clone()));
+         }
+         _ => throw_kind!(TypeError, "{} must take ident or array pattern for first argument", form)
+      }
+      Ok(valast)
+   }
+
+   // (defn name "doc"? [params] body...) defines a function, the same as
+   // (define name "doc"? (fn [params] body...))
+   fn defn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defn");
+      let idx = unsafe { (*stack).len() } - ops;
+      let operands = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let mut sast = SexprAst::new(IdentAst::new("defn".to_string()), operands);
+      sast.span = env.borrow().context.borrow().site.clone();
+      match sast.expand_defn() {
+         Some(define) => Environment::evaluate(env, stack, &Sexpr(define)),
+         None => throw_kind!(TypeError, "defn must take a name followed by the parameters and body of a function")
+      }
+   }
+
+   // (defmacro name [params] template...) defines a macro (see macros.rs)
+   fn defmacro(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defmacro");
+      let idx = unsafe { (*stack).len() } - ops;
+      let operands = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let mac = match Macro::parse(operands.as_slice()) {
+         Ok(mac) => mac,
+         Err(f) => throw_kind!(TypeError, "{}", f)
+      };
+      let name = mac.name().to_string();
+      if special_form(name.as_slice()).is_some() {
+         throw!("{} is a special form and cannot be rebound", name);
+      }
+      let context = env.borrow().context.clone();
+      let mut context = context.borrow_mut();
+      context.macros.insert(name.clone(), mac);
+      context.macro_definitions += 1;
+      Ok(Symbol(SymbolAst::new(name)))
+   }
+
+   // (gensym [prefix]) is a symbol that is not used anywhere else, for naming things in
+   // generated code
+   fn gensym(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("gensym");
+      let prefix = if ops == 1 { try!(Environment::pop_text(stack)) } else { "g".to_string() };
+      let context = env.borrow().context.clone();
+      let name = macros::gensym(prefix.as_slice(), &mut context.borrow_mut().gensyms);
+      Ok(Symbol(SymbolAst::new(name)))
+   }
+
+   // (let [pattern value...] body...) evaluates body with each pattern bound to its value.
+   // Each value can refer to the bindings before it.
+   fn let_bind(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("let");
+      let idx = unsafe { (*stack).len() } - ops;
+      let bindings = match unsafe { (*stack).remove(idx) }.unwrap() {
+         Array(ast) => ast.items,
+         _ => throw_kind!(TypeError, "let must take an array of bindings for first argument")
+      };
+      if bindings.len() % 2 != 0 {
+         throw!("let bindings must be pattern/value pairs");
+      }
+      let body = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let subenv = Rc::new(RefCell::new(Environment::new(Some(env))));
+      for binding in bindings.as_slice().chunks(2) {
+         let value = try!(Environment::evaluate(subenv.clone(), stack, &binding[1]));
+         try!(subenv.borrow_mut().destructure(&binding[0], value));
+      }
+      Environment::evaluate_body(subenv, stack, body.as_slice())
+   }
+
+   // (letrec [name value...] body...) is like let, but every name is bound (to nil) before
+   // any value is evaluated, so the values can be functions that call each other whatever
+   // names outside the letrec they would otherwise refer to.  Functions defined at the top
+   // level need nothing like this, as they look up the names they use when called.
+   fn letrec(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("letrec");
+      let idx = unsafe { (*stack).len() } - ops;
+      let bindings = match unsafe { (*stack).remove(idx) }.unwrap() {
+         Array(ast) => ast.items,
+         _ => throw_kind!(TypeError, "letrec must take an array of bindings for first argument")
+      };
+      if bindings.len() % 2 != 0 {
+         throw!("letrec bindings must be name/value pairs");
+      }
+      let body = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let subenv = Rc::new(RefCell::new(Environment::new(Some(env))));
+      for binding in bindings.as_slice().chunks(2) {
+         match binding[0] {
+            Ident(ref ast) => try!(subenv.borrow_mut().bind(ast.value.clone(), Nil(NilAst::new()))),
+            ref other => throw!("letrec can only bind names, not {}", other.to_source())
+         }
+      }
+      for binding in bindings.as_slice().chunks(2) {
+         let value = try!(Environment::evaluate(subenv.clone(), stack, &binding[1]));
+         try!(subenv.borrow_mut().destructure(&binding[0], value));
+      }
+      Environment::evaluate_body(subenv, stack, body.as_slice())
+   }
+
+   // (quote expr) returns expr without evaluating it
+   fn quote(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("quote");
+      Ok(unsafe { (*stack).pop() }.unwrap())
+   }
+
+   fn function(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("function");
+      env.borrow().context.borrow_mut().allocations += 1;
+      let mut ops = ops;
+      let mut code = vec!();
+      let first = unsafe { (*stack).remove((*stack).len() - ops) }.unwrap();
+      ops -= 1;
+      while ops > 0 {
+         unsafe { code.push((*stack).remove((*stack).len() - ops).unwrap()); }
+         ops -= 1;
+      }
+      let written = match first {
+         Array(ref ast) => ast.clone(),
+         first => return Environment::multiple_arity(env, first, code)
+      };
+      let params = typecheck::strip_annotations(written.clone());
+      typecheck::strip_result_type(&mut code);
+      // a leading string is documentation unless it is the only (and thus returned) value
+      let doc =
+         if code.len() > 1 {
+            match code[0] {
+               String(ref ast) => Some((*ast.string).clone()),
+               _ => None
+            }
+         } else {
+            None
+         };
+      if doc.is_some() {
+         code.remove(0);
+      }
+      let mut result = CodeAst::new(params, code, env.clone());
+      result.doc = doc;
+      result.written_params = vec!(written);
+      result.span = env.borrow().context.borrow().site.clone();
+      Ok(super::ast::Code(result))
+   }
+
+   // (fn "doc"? ([params] body...)...) defines a function with a separate body for each
+   // number of arguments
+   fn multiple_arity(env: Rc<RefCell<Environment>>, first: ExprAst, rest: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      let mut result = CodeAst::new(ArrayAst::new(vec!()), vec!(), env.clone());
+      result.written_params = vec!();
+      result.span = env.borrow().context.borrow().site.clone();
+      let mut operands = vec!(first);
+      operands.push_all_move(rest);
+      for operand in operands.move_iter() {
+         match operand {
+            List(mut ast) => match ast.items.remove(0) {
+               Some(Array(params)) => {
+                  result.written_params.push(params.clone());
+                  typecheck::strip_result_type(&mut ast.items);
+                  result.clauses.push((typecheck::strip_annotations(params), ast.items))
+               }
+               _ => throw_kind!(TypeError, "fn clauses must take an array of parameters for first item")
+            },
+            String(ast) => {
+               if result.doc.is_some() || !result.clauses.is_empty() {
+                  throw_kind!(TypeError, "fn only takes a doc string before its clauses");
+               }
+               result.doc = Some(ast.into_string());
+            }
+            _ => throw_kind!(TypeError, "fn must take an array of parameters or ([params] body...) clauses")
+         }
+      }
+      if result.clauses.is_empty() {
+         throw_kind!(TypeError, "fn must take an array of parameters or ([params] body...) clauses");
+      }
+      Ok(super::ast::Code(result))
+   }
+
+   // Picks the clause of a (possibly multiple-arity) function that accepts the given
+   // arguments.
+   fn select_clause<'a>(code: &'a CodeAst, fname: &str, args: &Vec<ExprAst>) -> InterpResult<(&'a ArrayAst, &'a Vec<ExprAst>)> {
+      if code.clauses.is_empty() {
+         return Ok((&code.params, &code.code));
+      }
+      // a keyword argument and its value fill a single parameter
+      let mut count = 0;
+      let mut iter = args.iter();
+      loop {
+         match iter.next() {
+            Some(&Keyword(_)) => {
+               iter.next();
+               count += 1;
+            }
+            Some(_) => count += 1,
+            None => break
+         }
+      }
+      for clause in code.clauses.iter() {
+         let (ref params, ref body) = *clause;
+         let (min, max) = arity(params);
+         if count >= min && max.map_or(true, |max| count <= max) {
+            return Ok((params, body));
+         }
+      }
+      throw_kind!(ArityError, "{} has no clause taking {} arguments", fname, count)
+   }
+
+   // (string-builder s...) makes a builder holding the given strings joined together, to
+   // which (sb-push sb s...) and (sb-push-char sb ch) append in place and which
+   // (sb-build sb) turns back into a string
+   fn string_builder(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("string-builder");
+      env.borrow().context.borrow_mut().allocations += 1;
+      let builder = StringBuilderAst::new(String::new());
+      try!(Environment::push_strings(&builder, stack, ops));
+      Ok(StringBuilder(builder))
+   }
+
+   fn sb_push(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("sb-push");
+      let builder = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
+         StringBuilder(ast) => ast,
+         _ => throw_kind!(TypeError, "sb-push only takes a string builder for first argument")
+      };
+      if builder.frozen {
+         throw!("sb-push cannot change a frozen string builder");
+      }
+      try!(Environment::push_strings(&builder, stack, ops - 1));
+      Ok(StringBuilder(builder))
+   }
+
+   fn push_strings(builder: &StringBuilderAst, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<()> {
+      let idx = unsafe { (*stack).len() } - ops;
+      let mut buf = builder.buf.borrow_mut();
+      for _ in range(0, ops) {
+         match unsafe { (*stack).remove(idx) }.unwrap() {
+            String(ast) => buf.push_str(ast.string.as_slice()),
+            _ => throw!("string builders can only be given strings")
+         }
+      }
+      Ok(())
+   }
+
+   fn sb_push_char(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sb-push-char");
+      let ch = match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => ast.into_string(),
+         _ => throw_kind!(TypeError, "sb-push-char only takes a string for second argument")
+      };
+      if try!(unescape(ch.as_slice())).as_slice().char_len() != 1 {
+         throw_kind!(TypeError, "sb-push-char only takes a single character, not \"{}\"", ch);
+      }
+      let builder = match unsafe { (*stack).pop() }.unwrap() {
+         StringBuilder(ast) => ast,
+         _ => throw_kind!(TypeError, "sb-push-char only takes a string builder for first argument")
+      };
+      if builder.frozen {
+         throw!("sb-push-char cannot change a frozen string builder");
+      }
+      builder.buf.borrow_mut().push_str(ch.as_slice());
+      Ok(StringBuilder(builder))
+   }
+
+   fn sb_build(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sb-build");
+      match unsafe { (*stack).pop() }.unwrap() {
+         StringBuilder(ast) => Ok(String(StringAst::new(ast.buf.borrow().clone()))),
+         _ => throw_kind!(TypeError, "sb-build only takes a string builder")
+      }
+   }
+
+   // (int-array n...) and (float-array x...) make arrays that keep their integers or
+   // floats unboxed.  len, get and the functions that iterate work on them as on arrays.
+   fn int_array(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("int-array");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| match unsafe { (*stack).remove(idx) }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      });
+      Ok(IntArray(IntArrayAst::new(items)))
+   }
+
+   fn float_array(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("float-array");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| match unsafe { (*stack).remove(idx) }.unwrap() {
+         Integer(ast) => ast.value as f64,
+         Float(ast) => ast.value,
+         _ => unreachable!()
+      });
+      Ok(FloatArray(FloatArrayAst::new(items)))
+   }
+
+   // (sum coll) and (product coll) add up or multiply the numbers in coll like + does,
+   // keeping integers exact until a float turns up.  They are 0 and 1 for an empty
+   // collection.
+   fn sum(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sum");
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      Environment::fold_numbers("sum", &coll, 0, |a, b| a.checked_add(&b), |a, b| a + b).map(|(sum, _)| sum)
+   }
+
+   fn product(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("product");
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      Environment::fold_numbers("product", &coll, 1, |a, b| a.checked_mul(&b), |a, b| a * b).map(|(product, _)| product)
+   }
+
+   // (mean coll) is the average of the numbers in coll as a float, and fails if coll is
+   // empty
+   fn mean(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("mean");
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      let (sum, count) = try!(Environment::fold_numbers("mean", &coll, 0, |a, b| a.checked_add(&b), |a, b| a + b));
+      if count == 0 {
+         throw!("mean of an empty collection");
+      }
+      let total = match sum {
+         Integer(ast) => ast.value as f64,
+         Float(ast) => ast.value,
+         _ => unreachable!()
+      };
+      Ok(Float(FloatAst::new(total / count as f64)))
+   }
+
+   // (min coll) and (max coll) are the smallest and largest items of coll as ordered by
+   // compare, and fail if coll is empty
+   fn min(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("min");
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      Environment::extreme("min", &coll, Less)
+   }
+
+   fn max(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("max");
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      Environment::extreme("max", &coll, Greater)
+   }
+
+   // Combines the numbers in coll starting from identity, returning the result and how
+   // many numbers there were.
+   fn fold_numbers(name: &str, coll: &ExprAst, identity: i64, int_op: |i64, i64| -> Option<i64>, float_op: |f64, f64| -> f64) -> InterpResult<(ExprAst, uint)> {
+      match try!(collection_items(name, coll)) {
+         IntItems(items) => {
+            let mut total = identity;
+            for &item in items.iter() {
+               total = match int_op(total, item) {
+                  Some(total) => total,
+                  None => throw!("integer overflow in {}", name)
+               };
+            }
+            Ok((Integer(IntegerAst::new(total)), items.len()))
+         }
+         FloatItems(items) => {
+            let total = items.iter().fold(identity as f64, |total, &item| float_op(total, item));
+            Ok((Float(FloatAst::new(total)), items.len()))
+         }
+         BoxedItems(items) => {
+            let mut total = Integer(IntegerAst::new(identity));
+            for item in items.iter() {
+               total = match (total, *item) {
+                  (Integer(ref a), &Integer(ref b)) => match int_op(a.value, b.value) {
+                     Some(total) => Integer(IntegerAst::new(total)),
+                     None => throw!("integer overflow in {}", name)
+                  },
+                  (Integer(ref a), &Float(ref b)) => Float(FloatAst::new(float_op(a.value as f64, b.value))),
+                  (Float(ref a), &Integer(ref b)) => Float(FloatAst::new(float_op(a.value, b.value as f64))),
+                  (Float(ref a), &Float(ref b)) => Float(FloatAst::new(float_op(a.value, b.value))),
+                  (_, other) => throw_kind!(TypeError, "{} only takes numbers, not {}", name, other.to_source())
+               };
+            }
+            Ok((total, items.len()))
+         }
+      }
+   }
+
+   // The first item of coll that is wanted (Less for the smallest, Greater for the largest)
+   // compared with every other.
+   fn extreme(name: &str, coll: &ExprAst, wanted: Ordering) -> InterpResult<ExprAst> {
+      let floats: Vec<ExprAst>;
+      let items: Vec<&ExprAst> = match try!(collection_items(name, coll)) {
+         IntItems(items) => {
+            let best = if wanted == Less { items.iter().min() } else { items.iter().max() };
+            return match best {
+               Some(&best) => Ok(Integer(IntegerAst::new(best))),
+               None => throw!("{} of an empty collection", name)
+            };
+         }
+         // compared as values so that NaN is reported
+         FloatItems(items) => {
+            floats = items.iter().map(|&item| Float(FloatAst::new(item))).collect();
+            floats.iter().collect()
+         }
+         BoxedItems(items) => items
+      };
+      let mut best: Option<&ExprAst> = None;
+      for &item in items.iter() {
+         best = match best {
+            Some(current) if try!(compare_values(item, current)) != wanted => Some(current),
+            _ => Some(item)
+         };
+      }
+      match best {
+         Some(best) => Ok(best.clone()),
+         None => throw!("{} of an empty collection", name)
+      }
+   }
+
+   // (make-matrix rows cols init?) is a matrix of floats with every element set to the
+   // number init (0 by default), or taken from the array init in row-major order
+   fn make_matrix(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("make-matrix");
+      let init = if ops == 3 { unsafe { (*stack).pop() }.unwrap() } else { Float(FloatAst::new(0.0)) };
+      let mut dims = Vec::from_fn(2, |_| match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      });
+      let (rows, cols) = (dims.pop().unwrap(), dims.pop().unwrap());
+      if rows < 0 || cols < 0 {
+         throw!("make-matrix cannot make a {}x{} matrix", rows, cols);
+      }
+      let (rows, cols) = (rows as uint, cols as uint);
+      let data = match init {
+         Integer(ast) => Vec::from_elem(rows * cols, ast.value as f64),
+         Float(ast) => Vec::from_elem(rows * cols, ast.value),
+         Array(ast) => {
+            if ast.items.len() != rows * cols {
+               throw!("make-matrix needs {} elements for a {}x{} matrix but was given {}", rows * cols, rows, cols, ast.items.len());
+            }
+            let mut data = Vec::with_capacity(ast.items.len());
+            for item in ast.items.iter() {
+               data.push(try!(Environment::matrix_element("make-matrix", item)));
+            }
+            data
+         }
+         other => throw_kind!(TypeError, "make-matrix only takes a number or an array to fill the matrix with, not {}", other.to_source())
+      };
+      Ok(Matrix(MatrixAst::new(rows, cols, data)))
+   }
+
+   fn matrix_rows(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-rows");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Matrix(ast) => Ok(Integer(IntegerAst::new(ast.rows as i64))),
+         _ => unreachable!()
+      }
+   }
+
+   fn matrix_cols(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-cols");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Matrix(ast) => Ok(Integer(IntegerAst::new(ast.cols as i64))),
+         _ => unreachable!()
+      }
+   }
+
+   // (m-get m row col) is the element of m at row and col, counting from 0
+   fn matrix_get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-get");
+      let (matrix, idx) = try!(Environment::pop_matrix_index(stack, "m-get"));
+      Ok(Float(FloatAst::new(matrix.data[idx])))
+   }
+
+   // (m-set m row col value) is a copy of m with the element at row and col set to value
+   fn matrix_set(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-set");
+      let value = try!(Environment::matrix_element("m-set", &unsafe { (*stack).pop() }.unwrap()));
+      let (mut matrix, idx) = try!(Environment::pop_matrix_index(stack, "m-set"));
+      *matrix.data.get_mut(idx) = value;
+      Ok(Matrix(matrix))
+   }
+
+   // (m-mul a b) is the matrix product of a and b, or a with every element multiplied by b
+   // if b is a number
+   fn matrix_mul(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-mul");
+      let other = unsafe { (*stack).pop() }.unwrap();
+      let matrix = match unsafe { (*stack).pop() }.unwrap() {
+         Matrix(ast) => ast,
+         _ => unreachable!()
+      };
+      match other {
+         Matrix(ref other) => match matrix.mul(other) {
+            Some(product) => Ok(Matrix(product)),
+            None => throw!("m-mul cannot multiply a {}x{} matrix by a {}x{} matrix", matrix.rows, matrix.cols, other.rows, other.cols)
+         },
+         Integer(_) | Float(_) => {
+            let factor = try!(Environment::matrix_element("m-mul", &other));
+            let data = matrix.data.iter().map(|&value| value * factor).collect();
+            Ok(Matrix(MatrixAst::new(matrix.rows, matrix.cols, data)))
+         }
+         other => throw_kind!(TypeError, "m-mul only takes a matrix or a number for second argument, not {}", other.to_source())
+      }
+   }
+
+   fn matrix_transpose(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("m-transpose");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Matrix(ast) => Ok(Matrix(ast.transpose())),
+         _ => unreachable!()
+      }
+   }
+
+   fn matrix_element(name: &str, value: &ExprAst) -> InterpResult<f64> {
+      match *value {
+         Integer(ref ast) => Ok(ast.value as f64),
+         Float(ref ast) => Ok(ast.value),
+         ref other => throw_kind!(TypeError, "{} only takes numbers as matrix elements, not {}", name, other.to_source())
+      }
+   }
+
+   // Pops a matrix followed by a row and column, returning the matrix and the index of the
+   // element in its buffer.
+   fn pop_matrix_index(stack: *mut Vec<ExprAst>, name: &str) -> InterpResult<(MatrixAst, uint)> {
+      let mut pos = Vec::from_fn(2, |_| match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      });
+      let (row, col) = (pos.pop().unwrap(), pos.pop().unwrap());
+      let matrix = match unsafe { (*stack).pop() }.unwrap() {
+         Matrix(ast) => ast,
+         _ => unreachable!()
+      };
+      if row < 0 || col < 0 || row as uint >= matrix.rows || col as uint >= matrix.cols {
+         throw!("{}: ({}, {}) is out of range for a {}x{} matrix", name, row, col, matrix.rows, matrix.cols);
+      }
+      let offset = row as uint * matrix.cols + col as uint;
+      Ok((matrix, offset))
+   }
+
+   // (values a b...) returns several values at once, to be taken apart by a pattern such
+   // as (let [(a b) (values 1 2)] ...)
+   fn values(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("values");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      Ok(Tuple(TupleAst::new(items)))
+   }
+
+   // (hash value) is the stable hash described in hash.rs, as an integer
+   fn hash(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("hash");
+      match hash::hash_value(&unsafe { (*stack).pop() }.unwrap()) {
+         Ok(hash) => Ok(Integer(IntegerAst::new(hash as i64))),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (hash-map key value...) makes a map like the literal {key value...}, but with the
+   // keys and values evaluated
+   fn hash_map(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("hash-map");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      match MapAst::from_items(items) {
+         Ok(map) => Ok(Map(map)),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (map-get m key [default]) is the value of key in m, or default (nil if not given)
+   fn map_get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("map-get");
+      let default = if ops == 3 { unsafe { (*stack).pop() }.unwrap() } else { Nil(NilAst::new()) };
+      let key = unsafe { (*stack).pop() }.unwrap();
+      let found = match unsafe { (*stack).pop() }.unwrap() {
+         Map(ast) => ast.find(&key).map(|value| value.map(|value| value.clone())),
+         SortedMap(ast) => ast.find(&key).map(|value| value.map(|value| value.clone())),
+         _ => throw_kind!(TypeError, "map-get only takes a map for first argument")
+      };
+      match found {
+         Ok(Some(value)) => Ok(value),
+         Ok(None) => Ok(default),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (map-set m key value) is a copy of m with key set to value
+   fn map_set(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-set");
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let key = unsafe { (*stack).pop() }.unwrap();
+      let result = match unsafe { (*stack).pop() }.unwrap() {
+         Map(mut ast) => ast.insert(key, value).and(Ok(Map(ast))),
+         SortedMap(mut ast) => ast.insert(key, value).and(Ok(SortedMap(ast))),
+         _ => throw_kind!(TypeError, "map-set only takes a map for first argument")
+      };
+      match result {
+         Ok(map) => Ok(map),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (map-remove m key) is a copy of m without key
+   fn map_remove(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-remove");
+      let key = unsafe { (*stack).pop() }.unwrap();
+      let result = match unsafe { (*stack).pop() }.unwrap() {
+         Map(mut ast) => ast.remove(&key).and(Ok(Map(ast))),
+         SortedMap(mut ast) => ast.remove(&key).and(Ok(SortedMap(ast))),
+         _ => throw_kind!(TypeError, "map-remove only takes a map for first argument")
+      };
+      match result {
+         Ok(map) => Ok(map),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (get-in coll [key...] default?) follows the keys through nested maps, arrays, lists
+   // and tuples (indexed by integers), returning default (nil if not given) as soon as one
+   // is missing
+   fn get_in(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("get-in");
+      let default = if ops == 3 { unsafe { (*stack).pop() }.unwrap() } else { Nil(NilAst::new()) };
+      let keys = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => ast.items,
+         _ => unreachable!()
+      };
+      let mut value = unsafe { (*stack).pop() }.unwrap();
+      for key in keys.iter() {
+         value = match try!(Environment::lookup_in("get-in", &value, key)) {
+            Some(value) => value,
+            None => return Ok(default)
+         };
+      }
+      Ok(value)
+   }
+
+   // (assoc-in coll [key...] value) is a copy of coll with the value at the end of the
+   // keys replaced by value.  Missing map keys are added, with new maps for the keys in
+   // between.
+   fn assoc_in(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("assoc-in");
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let keys = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => ast.items,
+         _ => unreachable!()
+      };
+      let coll = unsafe { (*stack).pop() }.unwrap();
+      Environment::assoc_keys(coll, keys.as_slice(), value)
+   }
+
+   fn assoc_keys(coll: ExprAst, keys: &[ExprAst], value: ExprAst) -> InterpResult<ExprAst> {
+      if keys.is_empty() {
+         return Ok(value);
+      }
+      let (key, rest) = (&keys[0], keys.slice_from(1));
+      let inner = match try!(Environment::lookup_in("assoc-in", &coll, key)) {
+         Some(inner) => inner,
+         None => Map(MapAst::new())
+      };
+      let inner = try!(Environment::assoc_keys(inner, rest, value));
+      match coll {
+         Map(mut ast) => match ast.insert(key.clone(), inner) {
+            Ok(()) => Ok(Map(ast)),
+            Err(f) => throw!("{}", f)
+         },
+         SortedMap(mut ast) => match ast.insert(key.clone(), inner) {
+            Ok(()) => Ok(SortedMap(ast)),
+            Err(f) => throw!("{}", f)
+         },
+         Nil(_) => match MapAst::from_items(vec!(key.clone(), inner)) {
+            Ok(map) => Ok(Map(map)),
+            Err(f) => throw!("{}", f)
+         },
+         Array(mut ast) => {
+            let idx = try!(Environment::index_in("assoc-in", key, ast.items.len()));
+            *ast.items.get_mut(idx) = inner;
+            Ok(Array(ast))
+         }
+         other => throw!("assoc-in cannot change {}", other.to_source())
+      }
+   }
+
+   // The value at key in coll, if there is one.  nil has nothing in it.
+   fn lookup_in(name: &str, coll: &ExprAst, key: &ExprAst) -> InterpResult<Option<ExprAst>> {
+      let items = match *coll {
+         Map(ref ast) => return match ast.find(key) {
+            Ok(value) => Ok(value.map(|value| value.clone())),
+            Err(f) => throw!("{}", f)
+         },
+         SortedMap(ref ast) => return match ast.find(key) {
+            Ok(value) => Ok(value.map(|value| value.clone())),
+            Err(f) => throw!("{}", f)
+         },
+         Nil(_) => return Ok(None),
+         Array(ref ast) => &ast.items,
+         List(ref ast) => &ast.items,
+         Tuple(ref ast) => &ast.items,
+         ref other => throw!("{} cannot look up {} in {}", name, key.to_source(), other.to_source())
+      };
+      match *key {
+         Integer(ref ast) if ast.value >= 0 && (ast.value as uint) < items.len() => Ok(Some(items[ast.value as uint].clone())),
+         Integer(_) => Ok(None),
+         ref other => throw!("{} can only index {} with an integer, not {}", name, coll.to_source(), other.to_source())
+      }
+   }
+
+   fn index_in(name: &str, key: &ExprAst, len: uint) -> InterpResult<uint> {
+      match *key {
+         Integer(ref ast) if ast.value >= 0 && (ast.value as uint) < len => Ok(ast.value as uint),
+         Integer(ref ast) => throw!("{}: index {} is out of range for an array of {} items", name, ast.value, len),
+         ref other => throw!("{} can only index an array with an integer, not {}", name, other.to_source())
+      }
+   }
+
+   fn map_contains(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-contains?");
+      let key = unsafe { (*stack).pop() }.unwrap();
+      let found = match unsafe { (*stack).pop() }.unwrap() {
+         Map(ast) => ast.find(&key).map(|value| value.is_some()),
+         SortedMap(ast) => ast.find(&key).map(|value| value.is_some()),
+         _ => throw_kind!(TypeError, "map-contains? only takes a map for first argument")
+      };
+      match found {
+         Ok(found) => Ok(Boolean(BooleanAst::new(found))),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (map-keys m) and (map-values m) are arrays in the order the entries were added, or in
+   // key order for a sorted map
+   fn map_keys(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-keys");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Map(ast) => Ok(Array(ArrayAst::new(ast.entries.move_iter().map(|(_, key, _)| key).collect()))),
+         SortedMap(ast) => Ok(Array(ArrayAst::new(ast.entries.move_iter().map(|(key, _)| key).collect()))),
+         _ => throw_kind!(TypeError, "map-keys only takes a map")
+      }
+   }
+
+   fn map_values(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-values");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Map(ast) => Ok(Array(ArrayAst::new(ast.entries.move_iter().map(|(_, _, value)| value).collect()))),
+         SortedMap(ast) => Ok(Array(ArrayAst::new(ast.entries.move_iter().map(|(_, value)| value).collect()))),
+         _ => throw_kind!(TypeError, "map-values only takes a map")
+      }
+   }
+
+   fn map_len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map-len");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Map(ast) => Ok(Integer(IntegerAst::new(ast.entries.len() as i64))),
+         SortedMap(ast) => Ok(Integer(IntegerAst::new(ast.entries.len() as i64))),
+         _ => throw_kind!(TypeError, "map-len only takes a map")
+      }
+   }
+
+   // (smap key value...) makes a sorted map, whose keys are kept in the order given by
+   // compare.  The map builtins work on sorted maps as well.
+   fn smap(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("smap");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      match SortedMapAst::from_items(items) {
+         Ok(map) => Ok(SortedMap(map)),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (submap m from to) is the sorted map of the entries of m with keys from from up to
+   // but not including to
+   fn submap(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("submap");
+      let to = unsafe { (*stack).pop() }.unwrap();
+      let from = unsafe { (*stack).pop() }.unwrap();
+      let map = match unsafe { (*stack).pop() }.unwrap() {
+         SortedMap(ast) => ast,
+         _ => unreachable!()
+      };
+      match map.range(&from, &to) {
+         Ok(map) => Ok(SortedMap(map)),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (hash-set item...) makes a set of the distinct items.  The name set is taken by the
+   // special form that changes an array.
+   fn hash_set(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("hash-set");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      match SetAst::from_items(items) {
+         Ok(set) => Ok(Set(set)),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   // (set-add s item...) and (set-remove s item...) are copies of s with or without the
+   // items
+   fn set_add(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("set-add");
+      let idx = unsafe { (*stack).len() } - (ops - 1);
+      let items = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let mut set = try!(Environment::pop_sets(stack, 1)).pop().unwrap();
+      for item in items.move_iter() {
+         match set.insert(item) {
+            Ok(()) => {}
+            Err(f) => throw!("{}", f)
+         }
+      }
+      Ok(Set(set))
+   }
+
+   fn set_remove(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("set-remove");
+      let idx = unsafe { (*stack).len() } - (ops - 1);
+      let items = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let mut set = try!(Environment::pop_sets(stack, 1)).pop().unwrap();
+      for item in items.iter() {
+         match set.remove(item) {
+            Ok(()) => {}
+            Err(f) => throw!("{}", f)
+         }
+      }
+      Ok(Set(set))
+   }
+
+   fn set_contains(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("set-contains?");
+      let item = unsafe { (*stack).pop() }.unwrap();
+      let set = try!(Environment::pop_sets(stack, 1)).pop().unwrap();
+      match set.contains(&item) {
+         Ok(found) => Ok(Boolean(BooleanAst::new(found))),
+         Err(f) => throw!("{}", f)
+      }
+   }
+
+   fn set_len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("set-len");
+      let set = try!(Environment::pop_sets(stack, 1)).pop().unwrap();
+      Ok(Integer(IntegerAst::new(set.items.len() as i64)))
+   }
+
+   // (union s...) has the items in any of the sets
+   fn union(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("union");
+      let mut result = SetAst::new();
+      for set in try!(Environment::pop_sets(stack, ops)).move_iter() {
+         for (hash, item) in set.items.move_iter() {
+            if result.position(hash, &item).is_none() {
+               result.items.push((hash, item));
+            }
+         }
+      }
+      Ok(Set(result))
+   }
+
+   // (intersect s t...) has the items of s that are in all of the other sets
+   fn intersect(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("intersect");
+      let mut sets = try!(Environment::pop_sets(stack, ops));
+      let mut result = sets.remove(0).unwrap();
+      result.items.retain(|&(hash, ref item)| sets.iter().all(|set| set.position(hash, item).is_some()));
+      Ok(Set(result))
+   }
+
+   // (difference s t...) has the items of s that are in none of the other sets
+   fn difference(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("difference");
+      let mut sets = try!(Environment::pop_sets(stack, ops));
+      let mut result = sets.remove(0).unwrap();
+      result.items.retain(|&(hash, ref item)| sets.iter().all(|set| set.position(hash, item).is_none()));
+      Ok(Set(result))
+   }
+
+   fn set_to_array(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("set->array");
+      let set = try!(Environment::pop_sets(stack, 1)).pop().unwrap();
+      Ok(Array(ArrayAst::new(set.values())))
+   }
+
+   // (array->set arr) has the distinct items of arr
+   fn array_to_set(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("array->set");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => match SetAst::from_items(ast.items) {
+            Ok(set) => Ok(Set(set)),
+            Err(f) => throw!("{}", f)
+         },
+         _ => throw_kind!(TypeError, "array->set only takes an array")
+      }
+   }
+
+   // Removes the given number of sets from the top of the stack, in their original order.
+   fn pop_sets(stack: *mut Vec<ExprAst>, count: uint) -> InterpResult<Vec<SetAst>> {
+      let idx = unsafe { (*stack).len() } - count;
+      let mut result = vec!();
+      for _ in range(0, count) {
+         match unsafe { (*stack).remove(idx) }.unwrap() {
+            Set(ast) => result.push(ast),
+            other => throw_kind!(TypeError, "expected a set but found {}", other.to_source())
+         }
+      }
+      Ok(result)
+   }
+
+   // (queue item...) makes a queue that can be used from the front with enqueue and
+   // dequeue, or from the back as a stack with push! and pop!.  Each takes constant time.
+   fn queue(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("queue");
+      env.borrow().context.borrow_mut().allocations += 1;
+      let idx = unsafe { (*stack).len() } - ops;
+      Ok(Queue(QueueAst::new(Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap()))))
+   }
+
+   // (enqueue q item...) and (push! q item...) add the items to the back of q, returning q
+   fn enqueue(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("enqueue");
+      let idx = unsafe { (*stack).len() } - ops;
+      let items = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx + 1) }.unwrap());
+      let queue = try!(Environment::pop_mutable_queue(stack, "enqueue"));
+      for item in items.move_iter() {
+         queue.items.borrow_mut().push_back(item);
+      }
+      Ok(Queue(queue))
+   }
+
+   // (dequeue q) removes the item at the front of q and returns it
+   fn dequeue(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("dequeue");
+      let queue = try!(Environment::pop_mutable_queue(stack, "dequeue"));
+      let item = queue.items.borrow_mut().pop_front();
+      match item {
+         Some(item) => Ok(item),
+         None => throw!("cannot dequeue from an empty queue")
+      }
+   }
+
+   // (pop! q) removes the item at the back of q and returns it
+   fn pop(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("pop!");
+      let queue = try!(Environment::pop_mutable_queue(stack, "pop!"));
+      let item = queue.items.borrow_mut().pop_back();
+      match item {
+         Some(item) => Ok(item),
+         None => throw!("cannot pop! from an empty queue")
+      }
+   }
+
+   // (peek q) and (peek-back q) return the item at the front or back of q without
+   // removing it
+   fn peek(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("peek");
+      let queue = try!(Environment::pop_queue(stack));
+      let item = queue.items.borrow().front().map(|item| if queue.frozen { freeze(item.clone()) } else { item.clone() });
+      match item {
+         Some(item) => Ok(item),
+         None => throw!("cannot peek into an empty queue")
+      }
+   }
+
+   fn peek_back(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("peek-back");
+      let queue = try!(Environment::pop_queue(stack));
+      let item = queue.items.borrow().back().map(|item| if queue.frozen { freeze(item.clone()) } else { item.clone() });
+      match item {
+         Some(item) => Ok(item),
+         None => throw!("cannot peek into an empty queue")
+      }
+   }
+
+   fn queue_len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("queue-len");
+      let queue = try!(Environment::pop_queue(stack));
+      let len = queue.items.borrow().len();
+      Ok(Integer(IntegerAst::new(len as i64)))
+   }
+
+   fn pop_queue(stack: *mut Vec<ExprAst>) -> InterpResult<QueueAst> {
+      match unsafe { (*stack).pop() }.unwrap() {
+         Queue(ast) => Ok(ast),
+         other => throw_kind!(TypeError, "expected a queue but found {}", other.to_source())
+      }
+   }
+
+   // Pops a queue for a builtin that changes it, which fails if it is frozen.
+   fn pop_mutable_queue(stack: *mut Vec<ExprAst>, name: &str) -> InterpResult<QueueAst> {
+      let queue = try!(Environment::pop_queue(stack));
+      if queue.frozen {
+         throw!("{} cannot change a frozen queue", name);
+      }
+      Ok(queue)
+   }
+
+   // (freeze x) is x with every queue and string builder in it made read-only: they still
+   // share their contents with x, so changes made through x show through, but enqueue,
+   // sb-push and the like fail on them.  Values such as arrays and maps are copied when
+   // changed, so they need no freezing of their own.
+   fn freeze(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("freeze");
+      Ok(freeze(unsafe { (*stack).pop() }.unwrap()))
+   }
+
+   // (copy x) is a deep copy of x, sharing no queue or string builder with it, which can be
+   // changed even if x was frozen
+   fn copy(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("copy");
+      env.borrow().context.borrow_mut().allocations += 1;
+      Ok(deep_copy(unsafe { (*stack).pop() }.unwrap(), &mut vec!()))
+   }
+
+   // (apply f arg... args) calls f with the given arguments followed by the items of the
+   // array args
+   fn apply(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("apply");
+      let mut spread = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => ast.items,
+         _ => throw_kind!(TypeError, "apply only takes an array for last argument")
+      };
+      let idx = unsafe { (*stack).len() } - (ops - 1);
+      let func = match unsafe { (*stack).remove(idx) }.unwrap() {
+         super::ast::Code(ast) => ast,
+         _ => throw_kind!(TypeError, "apply only takes a function for first argument")
+      };
+      let mut args = Vec::from_fn(ops - 2, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      args.push_all_move(mem::replace(&mut spread, vec!()));
+      Interpreter::call(unsafe { &mut *stack }, &func, "apply", args)
+   }
+
+   // (append arr...) is the array of the items of every given array, in order
+   fn append(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("append");
+      let idx = unsafe { (*stack).len() } - ops;
+      let mut items = vec!();
+      for _ in range(0, ops) {
+         match unsafe { (*stack).remove(idx) }.unwrap() {
+            Array(ast) => items.push_all_move(ast.items),
+            _ => throw_kind!(TypeError, "append only takes arrays")
+         }
+      }
+      Ok(Array(ArrayAst::new(items)))
+   }
+
+   // A function with the given parameters and body, in an environment binding each name
+   // to the captured value.  Used by the builtins that make functions.
+   fn closure(env: Rc<RefCell<Environment>>, captured: Vec<(&str, ExprAst)>, params: &[&str], body: ExprAst) -> ExprAst {
+      let mut cenv = Environment::new(Some(Environment::global(env)));
+      for (name, value) in captured.move_iter() {
+         cenv.values.insert(name.to_string(), Value(value));
+      }
+      let params = ArrayAst::new(params.iter().map(|param| Ident(IdentAst::new(param.to_string()))).collect());
+      super::ast::Code(CodeAst::new(params, vec!(body), Rc::new(RefCell::new(cenv))))
+   }
+
+   fn call_form(op: &str, operands: Vec<ExprAst>) -> ExprAst {
+      Sexpr(SexprAst::new(IdentAst::new(op.to_string()), operands))
+   }
+
+   fn ident(name: &str) -> ExprAst {
+      Ident(IdentAst::new(name.to_string()))
+   }
+
+   // (identity x) is x
+   fn identity(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("identity");
+      Ok(unsafe { (*stack).pop() }.unwrap())
+   }
+
+   // (constantly value) is a function that ignores its arguments and returns value
+   fn constantly(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("constantly");
+      let value = unsafe { (*stack).pop() }.unwrap();
+      Ok(Environment::closure(env, vec!(("value", value)), ["args..."], Environment::ident("value")))
+   }
+
+   // (partial f arg...) is a function calling f with the given arguments followed by its own
+   fn partial(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("partial");
+      let idx = unsafe { (*stack).len() } - ops;
+      let func = unsafe { (*stack).remove(idx) }.unwrap();
+      let bound = Array(ArrayAst::new(Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap())));
+      let body = Environment::call_form("apply", vec!(
+         Environment::ident("f"),
+         Environment::call_form("append", vec!(Environment::ident("bound"), Environment::ident("args")))
+      ));
+      Ok(Environment::closure(env, vec!(("f", func), ("bound", bound)), ["args..."], body))
+   }
+
+   // (compose f g...) is a function calling the last function with its arguments and each
+   // function before it with the result of the one after, so ((compose f g) x) is (f (g x))
+   fn compose(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("compose");
+      let idx = unsafe { (*stack).len() } - ops;
+      let funcs = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      if funcs.is_empty() {
+         return Ok(Environment::closure(env, vec!(), ["x"], Environment::ident("x")));
+      }
+      let names: Vec<String> = range(0, funcs.len()).map(|idx| format!("f{}", idx)).collect();
+      let last = names.len() - 1;
+      let mut body = Environment::call_form("apply", vec!(Environment::ident(names[last].as_slice()), Environment::ident("args")));
+      for name in names.slice_to(last).iter().rev() {
+         body = Environment::call_form(name.as_slice(), vec!(body));
+      }
+      let captured = names.iter().map(|name| name.as_slice()).zip(funcs.move_iter()).collect();
+      Ok(Environment::closure(env, captured, ["args..."], body))
+   }
+
+   // (memoize f) is f remembering the result of each call, so that calling it again with
+   // equal arguments returns the same result without calling f.  f should not have side
+   // effects.
+   fn memoize(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("memoize");
+      match unsafe { (*stack).pop() }.unwrap() {
+         super::ast::Code(mut ast) => {
+            ast.memo = Some(Rc::new(RefCell::new(collections::HashMap::new())));
+            Ok(super::ast::Code(ast))
+         }
+         _ => throw_kind!(TypeError, "memoize only takes a function")
+      }
+   }
+
+   // (iter-next value) is nil once value has no more items, or else a tuple of its next
+   // item and the rest of it.  The rest of a string is a string and the rest of an array
+   // or list is an array or list; for any other collection it is a list.  A function is
+   // iterated over by calling it with no arguments, so a function returning what iter-next
+   // would (e.g. a closure over its position) can be used wherever a collection can.
+   fn iter_next(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("iter-next");
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let mut items = match try!(iter_items(&value)) {
+         Some(items) => items,
+         None => return Ok(match try!(Environment::step(stack, &value)) {
+            Some((item, rest)) => Tuple(TupleAst::new(vec!(item, rest))),
+            None => Nil(NilAst::new())
+         })
+      };
+      if items.is_empty() {
+         return Ok(Nil(NilAst::new()));
+      }
+      let item = items.remove(0).unwrap();
+      let rest = match value {
+         Array(_) => Array(ArrayAst::new(items)),
+         String(_) => {
+            let parts: Vec<String> = items.move_iter().map(|item| match item {
+               String(ast) => ast.into_string(),
+               _ => unreachable!()
+            }).collect();
+            String(StringAst::new(parts.concat()))
+         }
+         _ => List(ListAst::new(items))
+      };
+      Ok(Tuple(TupleAst::new(vec!(item, rest))))
+   }
+
+   // Calls a function being iterated over, returning its next item and the rest of it.
+   fn step(stack: *mut Vec<ExprAst>, value: &ExprAst) -> InterpResult<Option<(ExprAst, ExprAst)>> {
+      let ast = match *value {
+         super::ast::Code(ref ast) => ast,
+         _ => unreachable!()
+      };
+      match try!(Interpreter::call(unsafe { &mut *stack }, ast, "iter-next", vec!())) {
+         Nil(_) => Ok(None),
+         Tuple(ref ast) if ast.items.len() == 2 => Ok(Some((ast.items[0].clone(), ast.items[1].clone()))),
+         other => throw_kind!(TypeError, "iterator functions must return nil or a tuple of the next item and the rest, not {}", other.to_source())
+      }
+   }
+
+   // Calls f with each item of value (see iter-next) and its index until f returns false.
+   fn iterate(stack: *mut Vec<ExprAst>, value: ExprAst, f: |ExprAst, uint| -> InterpResult<bool>) -> InterpResult<()> {
+      match try!(iter_items(&value)) {
+         Some(items) => for (idx, item) in items.move_iter().enumerate() {
+            if !try!(f(item, idx)) {
+               break;
+            }
+         },
+         None => {
+            let mut current = value;
+            let mut idx = 0;
+            loop {
+               match try!(Environment::step(stack, &current)) {
+                  Some((item, rest)) => {
+                     if !try!(f(item, idx)) {
+                        break;
+                     }
+                     current = rest;
+                     idx += 1;
+                  }
+                  None => break
+               }
+            }
+         }
+      }
+      Ok(())
+   }
+
+   // Whether the function can be called with the given number of arguments.
+   fn accepts(func: &CodeAst, count: uint) -> bool {
+      match func.builtin {
+         Some(ref builtin) => return count >= builtin.min && builtin.max.map_or(true, |max| count <= max),
+         None => {}
+      }
+      let fits = |params: &ArrayAst| {
+         let (min, max) = arity(params);
+         count >= min && max.map_or(true, |max| count <= max)
+      };
+      if func.clauses.is_empty() {
+         fits(&func.params)
+      } else {
+         func.clauses.iter().any(|&(ref params, _)| fits(params))
+      }
+   }
+
+   // The arguments for the function given to for, map or filter: the item, and its index
+   // if the function takes a second argument.
+   fn callback_args(func: &CodeAst, item: ExprAst, idx: uint) -> Vec<ExprAst> {
+      if Environment::accepts(func, 2) {
+         vec!(item, Integer(IntegerAst::new(idx as i64)))
+      } else {
+         vec!(item)
+      }
+   }
+
+   // Pops the function given as the last argument of for, map, filter and reduce.
+   fn pop_code(stack: *mut Vec<ExprAst>, name: &str) -> InterpResult<CodeAst> {
+      match unsafe { (*stack).pop() }.unwrap() {
+         super::ast::Code(ast) => Ok(ast),
+         _ => throw_kind!(TypeError, "{} only takes a function for last argument", name)
+      }
+   }
+
+   // (for value f) calls (f item index), or (f item), for each item of value
+   fn for_each(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("for");
+      let func = try!(Environment::pop_code(stack, "for"));
+      let value = unsafe { (*stack).pop() }.unwrap();
+      try!(Environment::iterate(stack, value, |item, idx| {
+         try!(Interpreter::call(unsafe { &mut *stack }, &func, "for", Environment::callback_args(&func, item, idx)));
+         Ok(true)
+      }));
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (map value f) is an array of (f item index), or (f item), for each item of value
+   fn map(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("map");
+      let func = try!(Environment::pop_code(stack, "map"));
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let mut result = vec!();
+      try!(Environment::iterate(stack, value, |item, idx| {
+         result.push(try!(Interpreter::call(unsafe { &mut *stack }, &func, "map", Environment::callback_args(&func, item, idx))));
+         Ok(true)
+      }));
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // (filter value f) is an array of the items of value for which (f item index), or
+   // (f item), is true
+   fn filter(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("filter");
+      let func = try!(Environment::pop_code(stack, "filter"));
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let mut result = vec!();
+      try!(Environment::iterate(stack, value, |item, idx| {
+         match try!(Interpreter::call(unsafe { &mut *stack }, &func, "filter", Environment::callback_args(&func, item.clone(), idx))) {
+            Boolean(ast) => if ast.value { result.push(item) },
+            other => throw_kind!(TypeError, "filter functions must return a boolean, not {}", other.to_source())
+         }
+         Ok(true)
+      }));
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // (reduce value init f) is (f (f init first) second)... over the items of value
+   fn reduce(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("reduce");
+      let func = try!(Environment::pop_code(stack, "reduce"));
+      let mut acc = unsafe { (*stack).pop() }.unwrap();
+      let value = unsafe { (*stack).pop() }.unwrap();
+      try!(Environment::iterate(stack, value, |item, _| {
+         acc = try!(Interpreter::call(unsafe { &mut *stack }, &func, "reduce", vec!(acc.clone(), item)));
+         Ok(true)
+      }));
+      Ok(acc)
+   }
+
+   // (sort coll) is a copy of the array or list coll in ascending order as given by
+   // compare.  Equal items keep their order.
+   fn sort(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("sort");
+      let (mut items, list) = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => (ast.items, false),
+         List(ast) => (ast.items, true),
+         other => throw_kind!(TypeError, "sort only takes an array or a list, not {}", other.to_source())
+      };
+      let mut error = None;
+      items.sort_by(|a, b| match compare_values(a, b) {
+         Ok(order) => order,
+         Err(f) => {
+            if error.is_none() {
+               error = Some(f);
+            }
+            Equal
+         }
+      });
+      match error {
+         Some(f) => Err(f),
+         None if list => Ok(List(ListAst::new(items))),
+         None => Ok(Array(ArrayAst::new(items)))
+      }
+   }
+
+   // (range end), (range start end) and (range start end step) are the array of integers
+   // from start (0 by default) up to but not including end, step (1 by default) apart
+   fn range(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("range");
+      let idx = unsafe { (*stack).len() } - ops;
+      let args: Vec<i64> = Vec::from_fn(ops, |_| match unsafe { (*stack).remove(idx) }.unwrap() {
+         Integer(ast) => ast.value,
+         _ => unreachable!()
+      });
+      let (start, end, step) = match args.as_slice() {
+         [end] => (0, end, 1),
+         [start, end] => (start, end, 1),
+         [start, end, step] => (start, end, step),
+         _ => unreachable!()
+      };
+      if step == 0 {
+         throw!("range step cannot be 0");
+      }
+      let mut result = vec!();
+      let mut current = start;
+      while (step > 0 && current < end) || (step < 0 && current > end) {
+         result.push(Integer(IntegerAst::new(current)));
+         // the next integer would be past the largest (or smallest) there is, so past end
+         current = match current.checked_add(&step) {
+            Some(next) => next,
+            None => break
+         };
+      }
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // (weak queue) is a reference to a queue that does not keep it alive, and (weak-get w)
+   // is the queue, or nil once nothing else refers to it.  Queues are the only values
+   // that can be referred to weakly.
+   fn weak(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("weak");
+      let queue = try!(Environment::pop_queue(stack));
+      Ok(WeakRef(WeakAst::new(&queue)))
+   }
+
+   fn weak_get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("weak-get");
+      match unsafe { (*stack).pop() }.unwrap() {
+         WeakRef(ast) => Ok(ast.get().unwrap_or(Nil(NilAst::new()))),
+         _ => throw_kind!(TypeError, "weak-get only takes a weak reference")
+      }
+   }
+
+   // (on-finalize queue f) calls f with no arguments once nothing refers to queue any
+   // more, which is checked after each top-level form.  A queue that refers to itself is
+   // never finalized.  Queues are the only values that can have finalizers, so a
+   // resource has to be represented by one to be cleaned up this way.
+   fn on_finalize(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("on-finalize");
+      let func = match unsafe { (*stack).pop() }.unwrap() {
+         super::ast::Code(ast) => ast,
+         _ => throw_kind!(TypeError, "on-finalize only takes a function for second argument")
+      };
+      let queue = try!(Environment::pop_queue(stack));
+      env.borrow().context.borrow_mut().finalizers.push((WeakAst::new(&queue), func));
+      Ok(Queue(queue))
+   }
+
+   // (suspend) hands control back to the program embedding the interpreter, as if the
+   // fuel given to execute_with_fuel had run out, at the next point the program can be
+   // suspended.  Outside of execute_with_fuel it does nothing.  Only a program suspended
+   // by a (suspend) at the top level can be saved with image::save_continuation.
+   fn suspend(env: Rc<RefCell<Environment>>, _: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("suspend");
+      let meter = env.borrow().context.borrow().meter.clone();
+      let mut meter = meter.borrow_mut();
+      if meter.fuel.is_some() {
+         meter.suspending = true;
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("get");
+      let coll = unsafe { (*stack).remove((*stack).len() - 2) }.unwrap();
+      let arrlen = match coll {
+         Array(ref ast) => ast.items.len(),
+         IntArray(ref ast) => ast.items.len(),
+         FloatArray(ref ast) => ast.items.len(),
+         _ => throw_kind!(TypeError, "get only takes an array for first argument")
+      };
+      let idx = match unsafe { (*stack).pop() }.unwrap() {
+         Integer(ast) => ast,
+         _ => throw_kind!(TypeError, "get only takes an integer for second argument")
+      };
+      let idx =
+         if idx.value < 0 {
+            if arrlen < -idx.value as uint {
+               throw!("absolute value of {} is too large for the array/list", idx.value);
+            } else {
+               arrlen + idx.value as uint
+            }
+         } else {
+            idx.value as uint
+         };
+      if idx >= arrlen {
+         throw!("index {} is out of range for an array of {} items", idx, arrlen);
+      }
+      Ok(match coll {
+         Array(ast) => ast.items[idx].clone(),
+         IntArray(ast) => Integer(IntegerAst::new(ast.items[idx])),
+         FloatArray(ast) => Float(FloatAst::new(ast.items[idx])),
+         _ => unreachable!()
+      })
+   }
+
+   fn set(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("set");
+      let (idast, mut arrast) = match unsafe { (*stack).remove((*stack).len() - 3) }.unwrap() {
+         Array(_) => return Ok(Nil(NilAst::new())),
+         Ident(ast) => match env.clone().borrow().find(&ast.value) {
+            Some(val) => match val {
+               Value(ref val) => match val {
+                  &Array(ref arrast) => (ast, arrast.clone()),
+                  _ => throw_kind!(TypeError, "{} is not an array", ast.value)
+               },
+               EnvCode(_) => throw_kind!(TypeError, "{} is not an array", ast.value)
+            },
+            None => throw_kind!(NameError, "ident {} not declared", ast.value)
+         },
+         _ => throw_kind!(TypeError, "set only takes an array for first argument")
+      };
+      let idx = match unsafe { (*stack).remove((*stack).len() - 2) }.unwrap() {
+         Integer(ast) => ast,
+         _ => throw_kind!(TypeError, "set only takes an integer for second argument")
+      };
+      let value = unsafe { (*stack).pop() }.unwrap();
+      let idx =
+         if idx.value < 0 {
+            let arrlen = arrast.items.len();
+            if arrlen < -idx.value as uint {
+               throw!("absolute value of {} is too large for the array/list", idx.value);
+            } else {
+               arrlen + idx.value as uint
+            }
+         } else {
+            idx.value as uint
+         };
+      // TODO: fix this horrifically inefficient mess
+      let mut vec: Vec<ExprAst> = arrast.items.clone().move_iter().collect();
+      vec.grow_set(idx, &Nil(NilAst::new()), value);
+      arrast.items = vec;
+      try!(env.clone().borrow_mut().replace(idast.value, Value(Array(arrast))));
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("len");
+      let len = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => ast.items.len(),
+         IntArray(ast) => ast.items.len(),
+         FloatArray(ast) => ast.items.len(),
+         _ => throw_kind!(TypeError, "len only takes an array")
+      };
+      Ok(Integer(IntegerAst::new(len as i64)))
+   }
+
+   fn equal(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("equal");
       let mut ops = ops;
-      if ops == 0 {
-         fail!("import requires at least one operand"); // XXX: fix
+      let cmpast = unsafe { (*stack).pop() }.unwrap();
+      ops -= 1;
+      while ops > 0 {
+         if unsafe { (*stack).pop() }.unwrap() != cmpast {
+            return Ok(Boolean(BooleanAst::new(false)));
+         }
+         ops -= 1;
+      }
+      Ok(Boolean(BooleanAst::new(true)))
+   }
+
+   // (compare a b) is -1, 0 or 1 as a comes before, with or after b (see compare_values)
+   fn compare(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("compare");
+      let b = unsafe { (*stack).pop() }.unwrap();
+      let a = unsafe { (*stack).pop() }.unwrap();
+      let result = match try!(compare_values(&a, &b)) {
+         Less => -1,
+         Equal => 0,
+         Greater => 1
+      };
+      Ok(Integer(IntegerAst::new(result)))
+   }
+
+   // (diff a b) is an array of strings describing where a and b differ (see diff.rs), empty
+   // if they are equal
+   fn diff(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("diff");
+      let b = unsafe { (*stack).pop() }.unwrap();
+      let a = unsafe { (*stack).pop() }.unwrap();
+      let lines = diff::diff(&a, &b);
+      Ok(Array(ArrayAst::new(lines.iter().map(|line| escape(line.as_slice())).collect())))
+   }
+
+   fn ifexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("if");
+      let cond = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
+         Boolean(ast) => ast.value,
+         _ => throw_kind!(TypeError, "if only takes a boolean as the condition")
+      };
+      let ontrue = unsafe { (*stack).remove((*stack).len() - ops + 1) }.unwrap();
+      let onfalse = if ops > 2 { unsafe { (*stack).pop() } } else { None };
+      // a branch is a single expression; (do ...) sequences several
+      match (cond, onfalse) {
+         (true, _) => Environment::evaluate_body(env, stack, &[ontrue]),
+         (false, Some(onfalse)) => Environment::evaluate_body(env, stack, &[onfalse]),
+         (false, None) => Ok(Nil(NilAst::new()))
       }
+   }
+
+   // (do expr...) evaluates each expr in order, returning the value of the last
+   fn doexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("do");
+      let idx = unsafe { (*stack).len() } - ops;
+      let body = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      Environment::evaluate_body(env, stack, body.as_slice())
+   }
+
+   // (with-output-to target body...) evaluates body with print and write sending their
+   // text to target, 'stdout, 'stderr or a string builder, instead of the current output.
+   // The output is dynamically bound: it applies to the functions body calls as well.
+   fn with_output_to(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("with-output-to");
+      let idx = unsafe { (*stack).len() } - ops;
+      let output = match unsafe { (*stack).remove(idx) }.unwrap() {
+         Symbol(ref ast) if ast.value.as_slice() == "stdout" => ToStdout,
+         Symbol(ref ast) if ast.value.as_slice() == "stderr" => ToStderr,
+         StringBuilder(ref builder) if builder.frozen => throw!("with-output-to cannot write to a frozen string builder"),
+         StringBuilder(builder) => ToBuilder(builder),
+         other => throw_kind!(TypeError, "with-output-to only takes 'stdout, 'stderr or a string builder, not {}", other.to_source())
+      };
+      let body = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      Environment::with_output(env, stack, output, body.as_slice())
+   }
+
+   // (with-output-to-string body...) is the text printed while evaluating body
+   fn with_output_to_string(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("with-output-to-string");
+      let idx = unsafe { (*stack).len() } - ops;
+      let body = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let builder = StringBuilderAst::new(String::new());
+      try!(Environment::with_output(env, stack, ToBuilder(builder.clone()), body.as_slice()));
+      let text = builder.buf.borrow().clone();
+      Ok(String(StringAst::new(text)))
+   }
+
+   fn with_output(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, output: Output, body: &[ExprAst]) -> InterpResult<ExprAst> {
+      let context = env.borrow().context.clone();
+      context.borrow().output.borrow_mut().push(output);
+      let result = Environment::evaluate_body(env, stack, body);
+      context.borrow().output.borrow_mut().pop();
+      result
+   }
+
+   // (current-output) is where print is writing to: 'stdout, 'stderr or a string builder
+   fn current_output(env: Rc<RefCell<Environment>>, _: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("current-output");
+      let context = env.borrow().context.clone();
+      let output = context.borrow().output.borrow().last().map(|output| output.clone());
+      Ok(match output {
+         None | Some(ToStdout) => Symbol(SymbolAst::new("stdout".to_string())),
+         Some(ToStderr) => Symbol(SymbolAst::new("stderr".to_string())),
+         Some(ToBuilder(builder)) => StringBuilder(builder)
+      })
+   }
+
+   // (when cond body...) evaluates body if cond is true, the same as (if cond (do body...))
+   fn when(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("when");
+      Environment::conditional(env, stack, ops, "when")
+   }
+
+   // (unless cond body...) evaluates body if cond is false
+   fn unless(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("unless");
+      Environment::conditional(env, stack, ops, "unless")
+   }
+
+   fn conditional(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint, form: &str) -> InterpResult<ExprAst> {
+      let idx = unsafe { (*stack).len() } - ops;
+      let operands = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let expanded = SexprAst::new(IdentAst::new(form.to_string()), operands).expand_conditional().unwrap();
+      Environment::evaluate(env, stack, &Sexpr(expanded))
+   }
+
+   // (match value pattern body pattern body...) evaluates the body of the first pattern
+   // that matches value with the identifiers bound by the pattern in scope
+   fn matchexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("match");
+      if ops % 2 == 0 {
+         throw!("match needs a value followed by pattern/body pairs");
+      }
+      let idx = unsafe { (*stack).len() } - ops;
+      let value = unsafe { (*stack).remove(idx) }.unwrap();
+      let clauses = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      for clause in clauses.as_slice().chunks(2) {
+         let mut bindings = vec!();
+         match pattern::match_pattern(&clause[0], &value, &mut bindings) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(f) => throw!("{}", f)
+         }
+         let mut subenv = Environment::new(Some(env.clone()));
+         for (name, val) in bindings.move_iter() {
+            try!(subenv.bind(name, val));
+         }
+         return Environment::evaluate(Rc::new(RefCell::new(subenv)), stack, &clause[1]);
+      }
+      throw!("no pattern matched {}", value.to_source())
+   }
+
+   fn importexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      try!(Environment::require(&env, "import", |capabilities| capabilities.import));
+      let mut ops = ops;
       while ops > 0 {
          match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
             String(ast) => {
                let slice = ast.string.as_slice();
-               let mut path = if slice.starts_with("./") || slice.starts_with("../") {
-                  Path::new(match env.clone().borrow().find(&"FILE".to_string()).unwrap() {
+               let path = if slice.starts_with("./") || slice.starts_with("../") || Path::new(slice).is_absolute() {
+                  let mut path = Path::new(match env.clone().borrow().find(&"FILE".to_string()).unwrap() {
                      Value(val) => match val {
-                        String(ast) => ast.string,
-                        _ => fail!() // XXX: fix
+                        String(ast) => ast.into_string(),
+                        _ => throw_kind!(TypeError, "FILE must be a string")
                      },
-                     EnvCode(_) => fail!() // XXX: fix
-                  }).dir_path()
+                     EnvCode(_) => throw_kind!(TypeError, "FILE must be a string")
+                  }).dir_path().join(Path::new(slice));
+                  if !slice.ends_with(".irl") {
+                     path.set_extension("irl");
+                  }
+                  path
                } else {
-                  fail!();
-                  Path::new("MODULE DIRECTORY GOES HERE") // TODO: ...
-               }.join(Path::new(slice));
-               if !slice.ends_with(".irl") {
-                  path.set_extension("irl");
-               }
-               let code = match io::File::open(&path) {
-                  Ok(m) => m,
-                  Err(_) => fail!() // XXX: fix
-               }.read_to_string().unwrap();
+                  match pkg::find_module(slice) {
+                     Ok(path) => path,
+                     Err(f) => throw!("{}", f)
+                  }
+               };
                let mut interp = Interpreter::new();
-               interp.load_code(code);
+               let inputs = {
+                  let context = env.borrow().context.clone();
+                  let context = context.borrow();
+                  interp.set_log_level(context.log_level.clone());
+                  interp.set_log_json(context.log_json);
+                  interp.set_inputs(context.inputs.clone());
+                  interp.set_capabilities(context.capabilities.clone());
+                  interp.env.borrow().context.borrow_mut().hooks = context.hooks.clone();
+                  interp.env.borrow().context.borrow_mut().call_stack = context.call_stack.clone();
+                  interp.env.borrow().context.borrow_mut().output = context.output.clone();
+                  interp.env.borrow().context.borrow_mut().meter = context.meter.clone();
+                  interp.env.borrow().context.borrow_mut().protocols = context.protocols.clone();
+                  interp.env.borrow().context.borrow_mut().dynamics = context.dynamics.clone();
+                  context.inputs.clone()
+               };
+               // a recorded run reads the source itself, as the precompiled version could
+               // differ between runs
+               let live = inputs.borrow().is_live();
+               let loaded = if live {
+                  interp.load_file(&path).map_err(|f| f.to_string())
+               } else {
+                  inputs.borrow_mut().read_file(&path).map(|code| interp.load_code(code))
+               };
+               match loaded {
+                  Ok(()) => {}
+                  Err(f) => throw_kind!(IoError, "could not import {}: {}", path.display(), f)
+               }
                interp.set_file(path.as_str().unwrap().to_string());
-               interp.execute();
-               env.borrow_mut().values.extend((*interp.env).clone().unwrap().values.move_iter());
+               try!(interp.run());
+               let dynamics = env.borrow().context.borrow().dynamics.clone();
+               for (name, value) in (*interp.env).clone().unwrap().values.move_iter() {
+                  // the importer's own FILE is kept for the relative imports after this one
+                  if name.as_slice() == "FILE" {
+                     continue;
+                  }
+                  match value {
+                     Value(_) => env.borrow().shadow(&name),
+                     EnvCode(_) => {}
+                  }
+                  if dynamics.contains(&name) {
+                     dynamics.add(name.clone(), env.clone());
+                  }
+                  env.borrow_mut().values.insert(name, value);
+               }
+               let macros = interp.env.borrow().context.borrow().macros.clone();
+               if !macros.is_empty() {
+                  let context = env.borrow().context.clone();
+                  let mut context = context.borrow_mut();
+                  for (name, mac) in macros.move_iter() {
+                     context.macros.insert(name, mac);
+                  }
+                  context.macro_definitions += 1;
+               }
+            }
+            _ => throw_kind!(TypeError, "import only takes strings")
+         }
+         ops -= 1;
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn type_obj(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      match type_name(&unsafe { (*stack).pop() }.unwrap()) {
+         Some(name) => Ok(Symbol(SymbolAst::new(name.to_string()))),
+         None => throw!("type does not know this kind of value")
+      }
+   }
+
+   // (runtime-stats) is a map of counts that show where a program spends its time:
+   //    'steps          expressions evaluated
+   //    'environments   environments created, one per function call or scope
+   //    'allocations    shared values created: functions, queues and string builders
+   //    'collections    checks for values with finalizers that are gone (values are
+   //                    reference counted, so there is no garbage collector to count)
+   //    'finalizers     functions registered with on-finalize that have not run yet
+   //    'stack          values on the evaluation stack
+   fn runtime_stats(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("runtime-stats");
+      Ok(Environment::stats(&env, unsafe { (*stack).len() }))
+   }
+
+   pub fn stats(env: &Rc<RefCell<Environment>>, stack: uint) -> ExprAst {
+      let context = env.borrow().context.clone();
+      let context = context.borrow();
+      let counts = [
+         ("steps", context.meter.borrow().steps),
+         ("environments", context.environments),
+         ("allocations", context.allocations),
+         ("collections", context.collections),
+         ("finalizers", context.finalizers.len()),
+         ("stack", stack)
+      ];
+      let mut items = vec!();
+      for &(name, count) in counts.iter() {
+         items.push(Symbol(SymbolAst::new(name.to_string())));
+         items.push(Integer(IntegerAst::new(count as i64)));
+      }
+      Map(MapAst::from_items(items).unwrap())
+   }
+
+   fn save_image(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("save-image");
+      try!(Environment::require(&env, "save-image", |capabilities| capabilities.filesystem));
+      let path = match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => Path::new(ast.into_string()),
+         _ => throw_kind!(TypeError, "save-image only takes a string")
+      };
+      match image::save_image(env, &path) {
+         Ok(()) => Ok(Nil(NilAst::new())),
+         Err(f) => throw_kind!(IoError, "could not save image: {}", f)
+      }
+   }
+
+   // (read-lines path) is an array of the lines of the file at path, without their line
+   // endings
+   fn read_lines(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("read-lines");
+      try!(Environment::require(&env, "read-lines", |capabilities| capabilities.filesystem));
+      let path = Path::new(try!(Environment::pop_text(stack)));
+      let text = try!(Environment::read_text(&env, &path));
+      Ok(Array(ArrayAst::new(text.as_slice().lines_any().map(|line| escape(line)).collect())))
+   }
+
+   // (with-lines path f) calls f with each line of the file at path in turn.  The file is
+   // read a line at a time, so it can be larger than memory, except while inputs are
+   // recorded or replayed, when it is read whole.
+   fn with_lines(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("with-lines");
+      try!(Environment::require(&env, "with-lines", |capabilities| capabilities.filesystem));
+      let func = try!(Environment::pop_code(stack, "with-lines"));
+      let path = Path::new(try!(Environment::pop_text(stack)));
+      let live = env.borrow().context.borrow().inputs.borrow().is_live();
+      if !live {
+         let text = try!(Environment::read_text(&env, &path));
+         for line in text.as_slice().lines_any() {
+            try!(Interpreter::call(unsafe { &mut *stack }, &func, "with-lines", vec!(escape(line))));
+         }
+         return Ok(Nil(NilAst::new()));
+      }
+      let mut reader = match io::File::open(&path) {
+         Ok(file) => io::BufferedReader::new(file),
+         Err(f) => throw_kind!(IoError, "could not read {}: {}", path.display(), f)
+      };
+      loop {
+         let line = match reader.read_line() {
+            Ok(line) => line,
+            Err(ref f) if f.kind == io::EndOfFile => break,
+            Err(f) => throw_kind!(IoError, "could not read {}: {}", path.display(), f)
+         };
+         let line = line.as_slice().trim_right_chars('\n').trim_right_chars('\r');
+         try!(Interpreter::call(unsafe { &mut *stack }, &func, "with-lines", vec!(escape(line))));
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (read text) is the value of the one expression written in text, read as data rather
+   // than run: a call (f x) becomes the list '(f x) and a name becomes a symbol, while
+   // literals, arrays and maps read as themselves.  Text that does not parse fails with a
+   // parse error whose data is [line column].
+   fn read(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("read");
+      let text = try!(Environment::pop_text(stack));
+      let asts = match Parser::for_data().try_parse_code(text) {
+         Ok(Root(ast)) => ast.asts,
+         Ok(_) => unreachable!(),
+         Err(f) => {
+            let mut error = RuntimeError::new(format!("read: line {}, column {}: {}", f.line, f.column, f.message())).with_kind(ParseError);
+            let position = vec!(Integer(IntegerAst::new(f.line as i64)), Integer(IntegerAst::new(f.column as i64)));
+            error.value = Some(Array(ArrayAst::new(position)));
+            return Err(error);
+         }
+      };
+      let mut forms: Vec<ExprAst> = asts.move_iter().filter(|ast| !is_comment(ast)).collect();
+      match forms.len() {
+         1 => Ok(as_data(forms.pop().unwrap())),
+         0 => throw_kind!(ParseError, "read: there is no expression to read"),
+         count => throw_kind!(ParseError, "read: expected one expression but found {}", count)
+      }
+   }
+
+   // (csv-parse text options?) is an array of the rows of the CSV text, each an array of
+   // strings.  The options are a map with the keys
+   //    :delimiter   the string of the character between fields, "," by default
+   //    :header      whether the first row names the fields, in which case the other
+   //                 rows are maps from those names to the fields (false by default)
+   fn csv_parse(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("csv-parse");
+      let options = if ops == 2 { Some(unsafe { (*stack).pop() }.unwrap()) } else { None };
+      let (delimiter, header, _) = try!(Environment::csv_options("csv-parse", options));
+      let text = try!(Environment::pop_text(stack));
+      let mut rows = match csv::parse(text.as_slice(), delimiter) {
+         Ok(rows) => rows.move_iter(),
+         Err(f) => throw!("csv-parse: {}", f)
+      };
+      let names: Vec<ExprAst> = match (rows.next(), header) {
+         (Some(names), true) => names.iter().map(|name| escape(name.as_slice())).collect(),
+         (Some(names), false) => return Ok(Array(ArrayAst::new(
+            Some(names).move_iter().chain(rows).map(|row| Array(ArrayAst::new(row.iter().map(|field| escape(field.as_slice())).collect()))).collect()))),
+         (None, _) => return Ok(Array(ArrayAst::new(vec!())))
+      };
+      let mut result = vec!();
+      for row in rows {
+         let mut map = MapAst::new();
+         for (name, field) in names.iter().zip(row.iter()) {
+            match map.insert(name.clone(), escape(field.as_slice())) {
+               Ok(()) => {}
+               Err(f) => throw!("{}", f)
+            }
+         }
+         result.push(Map(map));
+      }
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // (csv-write rows options?) is the CSV text of rows, an array or list of arrays or
+   // lists of fields, or of maps, whose fields are written in the order of the keys of the
+   // first map.  Fields are written as print would write them.  The options are those of
+   // csv-parse, with :header writing the keys of the first map as the first row, and
+   //    :quote-all   whether to quote every field instead of only those that need it
+   fn csv_write(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("csv-write");
+      let options = if ops == 2 { Some(unsafe { (*stack).pop() }.unwrap()) } else { None };
+      let (delimiter, header, quote_all) = try!(Environment::csv_options("csv-write", options));
+      let rows = match unsafe { (*stack).pop() }.unwrap() {
+         Array(ast) => ast.items,
+         List(ast) => ast.items,
+         other => throw_kind!(TypeError, "csv-write only takes an array or list of rows, not {}", other.to_source())
+      };
+      let keys: Option<Vec<ExprAst>> = match rows.as_slice().get(0) {
+         Some(&Map(ref map)) => Some(map.entries.iter().map(|&(_, ref key, _)| key.clone()).collect()),
+         _ => None
+      };
+      let mut lines = vec!();
+      match keys {
+         Some(ref keys) if header => lines.push(try!(keys.iter().map(|key| display(key)).collect::<InterpResult<Vec<String>>>())),
+         _ => {}
+      }
+      for row in rows.iter() {
+         let fields: Vec<ExprAst> = match (row, &keys) {
+            (&Array(ref ast), &None) => ast.items.clone(),
+            (&List(ref ast), &None) => ast.items.clone(),
+            (&Map(ref ast), &Some(ref keys)) => {
+               let mut fields = vec!();
+               for key in keys.iter() {
+                  fields.push(match ast.find(key) {
+                     Ok(Some(value)) => value.clone(),
+                     Ok(None) => String(StringAst::new(String::new())),
+                     Err(f) => throw!("{}", f)
+                  });
+               }
+               fields
             }
-            _ => fail!() // XXX: fix
+            (other, _) => throw!("csv-write cannot write the row {}", other.to_source())
+         };
+         lines.push(try!(fields.iter().map(|field| display(field)).collect::<InterpResult<Vec<String>>>()));
+      }
+      Ok(escape(csv::write(lines.as_slice(), delimiter, quote_all).as_slice()))
+   }
+
+   // The delimiter, header and quote-all options of csv-parse and csv-write.
+   fn csv_options(name: &str, options: Option<ExprAst>) -> InterpResult<(char, bool, bool)> {
+      let (mut delimiter, mut header, mut quote_all) = (',', false, false);
+      let options: Vec<(ExprAst, ExprAst)> = match options {
+         Some(Map(ast)) => ast.entries.move_iter().map(|(_, key, value)| (key, value)).collect(),
+         Some(SortedMap(ast)) => ast.entries,
+         Some(_) => unreachable!(),
+         None => vec!()
+      };
+      for &(ref key, ref value) in options.iter() {
+         match (key, value) {
+            (&Keyword(ref key), &String(ref ast)) if key.value.as_slice() == "delimiter" => {
+               let text = try!(unescape(ast.string.as_slice()));
+               if text.as_slice().char_len() != 1 {
+                  throw!("{}: the delimiter must be a single character, not {}", name, value.to_source());
+               }
+               delimiter = text.as_slice().char_at(0);
+            }
+            (&Keyword(ref key), &Boolean(ref ast)) if key.value.as_slice() == "header" => header = ast.value,
+            (&Keyword(ref key), &Boolean(ref ast)) if key.value.as_slice() == "quote-all" => quote_all = ast.value,
+            _ => throw!("{}: unknown option {} {}", name, key.to_source(), value.to_source())
          }
+      }
+      Ok((delimiter, header, quote_all))
+   }
+
+   // Reads the whole file at path through the interpreter's inputs, so that it can be
+   // recorded and replayed.
+   fn read_text(env: &Rc<RefCell<Environment>>, path: &Path) -> InterpResult<String> {
+      let inputs = env.borrow().context.borrow().inputs.clone();
+      let result = inputs.borrow_mut().read_file(path);
+      match result {
+         Ok(text) => Ok(text),
+         Err(f) => throw_kind!(IoError, "could not read {}: {}", path.display(), f)
+      }
+   }
+
+   fn doc(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("doc");
+      match unsafe { (*stack).pop() }.unwrap() {
+         super::ast::Code(ast) => Ok(match ast.doc {
+            Some(doc) => String(StringAst::new(doc)),
+            None => Nil(NilAst::new())
+         }),
+         _ => throw_kind!(TypeError, "doc only takes a function")
+      }
+   }
+
+   // (fn-arity f) is [min max], the least and greatest number of arguments f takes, with
+   // max nil if it takes any number
+   fn fn_arity(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("fn-arity");
+      let code = Environment::pop_function(stack);
+      let (min, max) =
+         if code.builtin.is_some() {
+            let builtin = code.builtin.unwrap();
+            (builtin.min, builtin.max)
+         } else if code.clauses.is_empty() {
+            arity(&code.params)
+         } else {
+            let arities: Vec<(uint, Option<uint>)> = code.clauses.iter().map(|&(ref params, _)| arity(params)).collect();
+            let min = arities.iter().map(|&(min, _)| min).min().unwrap();
+            let max = arities.iter().fold(Some(0), |result, &(_, max)| match (result, max) {
+               (Some(result), Some(max)) => Some(::std::cmp::max(result, max)),
+               _ => None
+            });
+            (min, max)
+         };
+      let max = match max {
+         Some(max) => Integer(IntegerAst::new(max as i64)),
+         None => Nil(NilAst::new())
+      };
+      Ok(Array(ArrayAst::new(vec!(Integer(IntegerAst::new(min as i64)), max))))
+   }
+
+   // (fn-params f) is the parameters of f as they were written, type annotations and
+   // defaults included, or an array of them for each clause of a multiple-arity function
+   fn fn_params(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("fn-params");
+      let code = Environment::pop_function(stack);
+      let mut written = code.written_params.move_iter().map(|params| Array(params));
+      Ok(if code.clauses.is_empty() {
+         written.next().unwrap()
+      } else {
+         Array(ArrayAst::new(written.collect()))
+      })
+   }
+
+   // (fn-source f) is the body of f as a list of unevaluated expressions, or for a
+   // multiple-arity function a list of its ([params] body...) clauses
+   fn fn_source(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("fn-source");
+      let code = Environment::pop_function(stack);
+      if code.clauses.is_empty() {
+         return Ok(List(ListAst::new(code.code)));
+      }
+      let clauses = code.clauses.move_iter().zip(code.written_params.move_iter()).map(|((_, body), params)| {
+         let mut items = vec!(Array(params));
+         items.push_all_move(body);
+         List(ListAst::new(items))
+      }).collect();
+      Ok(List(ListAst::new(clauses)))
+   }
+
+   // (fn-location f) is [line column] of where f was written, or nil if that is not known
+   // (e.g. for a function loaded from bytecode)
+   fn fn_location(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("fn-location");
+      let code = Environment::pop_function(stack);
+      Ok(if code.span.is_known() {
+         Array(ArrayAst::new(vec!(Integer(IntegerAst::new(code.span.line as i64)),
+                                  Integer(IntegerAst::new(code.span.column as i64)))))
+      } else {
+         Nil(NilAst::new())
+      })
+   }
+
+   fn pop_function(stack: *mut Vec<ExprAst>) -> CodeAst {
+      match unsafe { (*stack).pop() }.unwrap() {
+         super::ast::Code(ast) => ast,
+         _ => unreachable!()
+      }
+   }
+
+   // (bound? 'name) is whether name can be seen from where it is called
+   fn is_bound(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("bound?");
+      let name = Environment::pop_symbol(stack);
+      Ok(Boolean(BooleanAst::new(env.borrow().find(&name).is_some())))
+   }
+
+   // (bindings) is an array of the names that can be seen from where it is called as
+   // symbols, sorted, including the builtins
+   fn bindings(env: Rc<RefCell<Environment>>, _: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("bindings");
+      let names = Environment::names(env);
+      Ok(Array(ArrayAst::new(names.move_iter().map(|name| Symbol(SymbolAst::new(name))).collect())))
+   }
+
+   // (undefine 'name) removes the binding of name from the current scope, leaving those of
+   // enclosing scopes alone, and is whether there was one to remove.  Constants cannot be
+   // undefined.
+   fn undefine(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("undefine");
+      let name = Environment::pop_symbol(stack);
+      try!(env.borrow().check_constant(&name));
+      Ok(Boolean(BooleanAst::new(env.borrow_mut().values.remove(&name))))
+   }
+
+   fn pop_symbol(stack: *mut Vec<ExprAst>) -> String {
+      match unsafe { (*stack).pop() }.unwrap() {
+         Symbol(ast) => ast.value,
+         _ => unreachable!()
+      }
+   }
+
+   // (deftest name body...) registers body to be run by the test runner
+   fn deftest(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("deftest");
+      let mut ops = ops;
+      let name = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
+         Ident(ast) => ast.value,
+         String(ast) => ast.into_string(),
+         _ => throw_kind!(TypeError, "deftest must take ident for first argument")
+      };
+      ops -= 1;
+      let mut code = vec!();
+      while ops > 0 {
+         unsafe { code.push((*stack).remove((*stack).len() - ops).unwrap()); }
          ops -= 1;
       }
-      Nil(NilAst::new())
-   }
-
-   fn type_obj(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      if ops != 1 {
-         fail!("type only takes one object"); // XXX: fix
-      }
-      Symbol(SymbolAst::new(match unsafe { (*stack).pop() }.unwrap() {
-         Integer(_) => "integer",
-         Float(_) => "float",
-         Array(_) => "array",
-         List(_) => "list",
-         String(_) => "string",
-         Symbol(_) => "symbol",
-         super::ast::Code(_) => "code",
-         Boolean(_) => "boolean",
-         Nil(_) => "nil",
-         _ => fail!() // XXX: fix
-      }.to_string()))
+      let test = CodeAst::new(ArrayAst::new(vec!()), code, env.clone());
+      env.borrow().context.borrow_mut().tests.push((name, test));
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (assert expr) fails with the text of expr unless it evaluates to something truthy
+   fn assert(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("assert");
+      let expr = unsafe { (*stack).pop() }.unwrap();
+      match try!(Environment::evaluate(env, stack, &expr)) {
+         Boolean(BooleanAst { value: false }) | Nil(_) => throw!("assertion failed: {}", expr.to_source()),
+         _ => Ok(Nil(NilAst::new()))
+      }
+   }
+
+   // (assert-eq actual expected) fails with the text of both expressions and their values
+   fn assert_eq(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("assert-eq");
+      let rightexpr = unsafe { (*stack).pop() }.unwrap();
+      let leftexpr = unsafe { (*stack).pop() }.unwrap();
+      let left = try!(Environment::evaluate(env.clone(), stack, &leftexpr));
+      let right = try!(Environment::evaluate(env, stack, &rightexpr));
+      if left != right {
+         if diff::is_structured(&left) && diff::is_structured(&right) {
+            throw!("assertion failed: {} = {}\n   {}", leftexpr.to_source(), rightexpr.to_source(),
+                   diff::diff(&left, &right).connect("\n   "));
+         }
+         throw!("assertion failed: {} = {}\n   left: {}\n  right: {}",
+                leftexpr.to_source(), rightexpr.to_source(), left.to_source(), right.to_source());
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (try body... (catch name handler...) (finally cleanup...)) evaluates body, running
+   // handler with the error bound to name if anything in body fails.  (catch :kind name
+   // handler...) only handles errors of that kind (see ErrorKind), letting others through.
+   // cleanup always runs afterwards.
+   fn tryexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("try");
+      let idx = unsafe { (*stack).len() } - ops;
+      let mut body = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let cleanup = match body.last() {
+         Some(&Sexpr(ref sast)) if sast.op.value.as_slice() == "finally" => Some(sast.clone()),
+         _ => None
+      };
+      if cleanup.is_some() {
+         body.pop();
+      }
+      let handler = match body.last() {
+         Some(&Sexpr(ref sast)) if sast.op.value.as_slice() == "catch" => Some(sast.clone()),
+         _ => None
+      };
+      if handler.is_some() {
+         body.pop();
+      }
+      let stacklen = unsafe { (*stack).len() };
+      let result = Environment::evaluate_body(env.clone(), stack, body.as_slice());
+      let result = match (result, handler) {
+         (Err(f), Some(handler)) => {
+            unsafe { (*stack).truncate(stacklen) };
+            Environment::catch(env.clone(), stack, f, &handler)
+         }
+         (result, _) => result
+      };
+      unsafe { (*stack).truncate(stacklen) };
+      match cleanup {
+         Some(cleanup) => {
+            for expr in cleanup.operands.iter() {
+               try!(Environment::evaluate(env.clone(), stack, expr));
+            }
+         }
+         None => {}
+      }
+      result
+   }
+
+   fn catch(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, err: RuntimeError, handler: &SexprAst) -> InterpResult<ExprAst> {
+      let start = handler.catch_name_index();
+      if start == 1 {
+         let kind = match handler.operands[0] {
+            Keyword(ref ast) => match ErrorKind::from_name(ast.value.as_slice()) {
+               Some(kind) => kind,
+               None => throw!("catch: {} is not a kind of error", ast.to_source())
+            },
+            _ => unreachable!()
+         };
+         if err.kind != kind {
+            return Err(err);
+         }
+      }
+      let name = match handler.operands.as_slice().get(start) {
+         Some(&Ident(ref ast)) => ast.value.clone(),
+         _ => throw_kind!(TypeError, "catch must take ident for first argument")
+      };
+      let mut subenv = Environment::new(Some(env));
+      try!(subenv.bind(name, err.to_value()));
+      let subenv = Rc::new(RefCell::new(subenv));
+      let mut value = Nil(NilAst::new());
+      for expr in handler.operands.slice_from(start + 1).iter() {
+         value = try!(Environment::evaluate(subenv.clone(), stack, expr));
+      }
+      Ok(value)
+   }
+
+   // (defer expr) evaluates expr when the enclosing function returns or fails
+   fn defer(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("defer");
+      let expr = unsafe { (*stack).pop() }.unwrap();
+      Environment::frame(env.clone()).borrow_mut().deferred.push((env, expr));
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (with-open [name value...] body...) is like let, but when the body is left, even by an
+   // error, the function registered with on-finalize for each value that is a queue is
+   // called and dropped, last value first, instead of waiting for the queue to become
+   // unreachable.  Other values are bound as let would, with nothing to close.  The first
+   // error is returned.
+   fn with_open(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("with-open");
+      let idx = unsafe { (*stack).len() } - ops;
+      let bindings = match unsafe { (*stack).remove(idx) }.unwrap() {
+         Array(ast) => ast.items,
+         _ => throw_kind!(TypeError, "with-open must take an array of bindings for first argument")
+      };
+      if bindings.len() % 2 != 0 {
+         throw!("with-open bindings must be name/value pairs");
+      }
+      let body = Vec::from_fn(ops - 1, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let subenv = Rc::new(RefCell::new(Environment::new(Some(env.clone()))));
+      let mut opened = vec!();
+      let mut result = Ok(Nil(NilAst::new()));
+      for binding in bindings.as_slice().chunks(2) {
+         let value = match Environment::evaluate(subenv.clone(), stack, &binding[1]) {
+            Ok(value) => value,
+            Err(f) => {
+               result = Err(f);
+               break;
+            }
+         };
+         match value {
+            Queue(ref ast) => opened.push(ast.clone()),
+            _ => {}
+         }
+         match subenv.borrow_mut().destructure(&binding[0], value) {
+            Ok(()) => {}
+            Err(f) => {
+               result = Err(f);
+               break;
+            }
+         }
+      }
+      if result.is_ok() {
+         result = Environment::evaluate_body(subenv.clone(), stack, body.as_slice());
+      }
+      let context = env.borrow().context.clone();
+      for queue in opened.iter().rev() {
+         let pos = context.borrow().finalizers.iter().position(|&(ref weak, _)| match weak.get() {
+            Some(Queue(ref ast)) => ast.is(queue),
+            _ => false
+         });
+         let func = match pos {
+            Some(pos) => context.borrow_mut().finalizers.remove(pos).unwrap().val1(),
+            None => continue
+         };
+         let closed = Interpreter::call(unsafe { &mut *stack }, &func, "with-open", vec!());
+         if result.is_ok() {
+            result = closed.and(result);
+         }
+      }
+      result
+   }
+
+   // (defprotocol Name (method [params])...) defines each method as a function that calls
+   // the implementation given to extend for the type of its first argument
+   fn defprotocol(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defprotocol");
+      let idx = unsafe { (*stack).len() } - ops;
+      let mut operands = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap()).move_iter();
+      let name = match operands.next().unwrap() {
+         Ident(ast) => ast.value,
+         _ => throw_kind!(TypeError, "defprotocol only takes an identifier as the protocol name")
+      };
+      let mut methods = vec!();
+      for operand in operands {
+         let (method, params) = match operand {
+            Sexpr(ast) => match ast.operands.as_slice() {
+               [Array(ref params)] => (ast.op.value.clone(), params.clone()),
+               _ => throw!("protocol method {} must have exactly one parameter list", ast.op.value)
+            },
+            other => throw!("{} is not a protocol method like (name [params])", other.to_source())
+         };
+         if params.items.is_empty() {
+            throw!("protocol method {} needs a parameter to dispatch on", method);
+         }
+         // the method takes the parameters it is declared with, so that map and the like
+         // pass it as many arguments as the implementations expect
+         let mut args = vec!(Symbol(SymbolAst::new(name.clone())), Symbol(SymbolAst::new(method.clone())));
+         let mut spread = false;
+         for param in params.items.iter() {
+            match *param {
+               Ident(ref ast) if ast.value.as_slice().ends_with("...") => {
+                  let value = ast.value.as_slice();
+                  args.push(Ident(IdentAst::new(value.slice_to(value.len() - 3).to_string())));
+                  spread = true;
+               }
+               Ident(ref ast) => args.push(Ident(ast.clone())),
+               ref other => throw!("the parameters of protocol method {} must be names, not {}", method, other.to_source())
+            }
+         }
+         let dispatch = if spread {
+            args.insert(0, Ident(IdentAst::new("protocol-dispatch".to_string())));
+            SexprAst::new(IdentAst::new("apply".to_string()), args)
+         } else {
+            SexprAst::new(IdentAst::new("protocol-dispatch".to_string()), args)
+         };
+         let mut code = CodeAst::new(params.clone(), vec!(Sexpr(dispatch)), env.clone());
+         code.doc = Some(format!("{} from protocol {}", SexprAst::new(IdentAst::new(method.clone()), params.items).to_source(), name));
+         try!(env.borrow_mut().bind(method.clone(), super::ast::Code(code)));
+         methods.push(method);
+      }
+      let context = env.borrow().context.clone();
+      context.borrow().protocols.borrow_mut().methods.insert(name.clone(), methods);
+      Ok(Symbol(SymbolAst::new(name)))
+   }
+
+   // (extend type Protocol f...) gives the functions implementing the protocol's methods,
+   // in the order they were defined, for values of the given type
+   fn extend(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("extend");
+      let idx = unsafe { (*stack).len() } - ops;
+      let mut operands = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap()).move_iter();
+      let typename = match operands.next().unwrap() {
+         Ident(ast) => ast.value,
+         _ => throw_kind!(TypeError, "extend only takes a type name for first argument")
+      };
+      if !type_names().iter().any(|name| name == &typename.as_slice()) {
+         throw!("{} is not a type", typename);
+      }
+      let protocol = match operands.next().unwrap() {
+         Ident(ast) => ast.value,
+         _ => throw_kind!(TypeError, "extend only takes a protocol name for second argument")
+      };
+      let context = env.borrow().context.clone();
+      let count = match context.borrow().protocols.borrow().methods.find(&protocol) {
+         Some(methods) => methods.len(),
+         None => throw!("{} is not a protocol", protocol)
+      };
+      let mut funcs = vec!();
+      for operand in operands {
+         match try!(Environment::evaluate(env.clone(), stack, &operand)) {
+            super::ast::Code(ast) => funcs.push(ast),
+            other => throw_kind!(TypeError, "extend only takes functions as implementations, not {}", other.to_source())
+         }
+      }
+      if funcs.len() != count {
+         throw!("{} has {} methods but {} implementations were given for {}", protocol, count, funcs.len(), typename);
+      }
+      context.borrow().protocols.borrow_mut().implementations.insert((protocol, typename), funcs);
+      Ok(Nil(NilAst::new()))
+   }
+
+   // Calls the implementation of a protocol method for the type of the first argument.
+   fn protocol_dispatch(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("protocol-dispatch");
+      let idx = unsafe { (*stack).len() } - ops + 2;
+      let args = Vec::from_fn(ops - 2, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let method = match unsafe { (*stack).pop() }.unwrap() {
+         Symbol(ast) => ast.value,
+         _ => unreachable!()
+      };
+      let protocol = match unsafe { (*stack).pop() }.unwrap() {
+         Symbol(ast) => ast.value,
+         _ => unreachable!()
+      };
+      let typename = match args.as_slice().get(0).and_then(|arg| type_name(arg)) {
+         Some(name) => name.to_string(),
+         None => throw!("{} needs an argument to dispatch on", method)
+      };
+      let context = env.borrow().context.clone();
+      let func = {
+         let protocols = context.borrow().protocols.clone();
+         let protocols = protocols.borrow();
+         let pos = match protocols.methods.find(&protocol).and_then(|methods| methods.iter().position(|name| name == &method)) {
+            Some(pos) => pos,
+            None => throw!("{} is not a method of {}", method, protocol)
+         };
+         match protocols.implementations.find(&(protocol.clone(), typename.clone())) {
+            Some(funcs) => funcs[pos].clone(),
+            None => throw!("{} does not implement {} (needed by {})", typename, protocol, method)
+         }
+      };
+      Interpreter::call(unsafe { &mut *stack }, &func, method.as_slice(), args)
+   }
+
+   fn log_debug(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      Environment::log(env, stack, ops, LogDebug)
+   }
+
+   fn log_info(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      Environment::log(env, stack, ops, LogInfo)
+   }
+
+   fn log_warn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      Environment::log(env, stack, ops, LogWarn)
+   }
+
+   fn log_error(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      Environment::log(env, stack, ops, LogError)
+   }
+
+   // (log-info value...) writes the values to stderr separated by spaces, with strings
+   // written as they are, if the interpreter's log level lets messages of that level through
+   fn log(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint, level: LogLevel) -> InterpResult<ExprAst> {
+      debug!("log");
+      let idx = unsafe { (*stack).len() } - ops;
+      let values = Vec::from_fn(ops, |_| unsafe { (*stack).remove(idx) }.unwrap());
+      let context = env.borrow().context.clone();
+      let context = context.borrow();
+      if level < context.log_level {
+         return Ok(Nil(NilAst::new()));
+      }
+      let parts: Vec<String> = values.iter().map(|value| match *value {
+         String(ref ast) => (*ast.string).clone(),
+         ref other => other.to_source()
+      }).collect();
+      let message = parts.connect(" ");
+      let name = match level {
+         LogDebug => "debug",
+         LogInfo => "info",
+         LogWarn => "warn",
+         LogError | LogOff => "error"
+      };
+      let file = match env.borrow().find(&"FILE".to_string()) {
+         Some(Value(String(ast))) => ast.into_string(),
+         _ => String::new()
+      };
+      let line =
+         if context.log_json {
+            format!("{{\"level\":{},\"file\":{},\"message\":{}}}",
+                    json::String(name.to_string()), json::String(file), json::String(message))
+         } else if file.len() > 0 {
+            format!("{} {}: {}", name, file, message)
+         } else {
+            format!("{}: {}", name, message)
+         };
+      match write_stderr(format!("{}\n", line).as_slice()) {
+         Ok(()) => Ok(Nil(NilAst::new())),
+         Err(f) => throw_kind!(IoError, "could not write log message: {}", f)
+      }
+   }
+
+   fn throw(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("throw");
+      Err(RuntimeError::thrown(unsafe { (*stack).pop() }.unwrap()))
+   }
+
+   // (error message [data [kind]]) creates an error value without raising it, of the
+   // given kind (such as :io-error) or else a user error
+   fn error(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("error");
+      let kind =
+         if ops == 3 {
+            try!(Environment::pop_error_kind(stack, "error"))
+         } else {
+            UserError
+         };
+      let data =
+         if ops >= 2 {
+            unsafe { (*stack).pop() }.unwrap()
+         } else {
+            Nil(NilAst::new())
+         };
+      match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => {
+            let mut error = ErrorAst::new(ast.into_string(), data);
+            error.kind = kind;
+            Ok(Error(error))
+         }
+         _ => throw_kind!(TypeError, "error only takes a string for first argument")
+      }
+   }
+
+   fn pop_error_kind(stack: *mut Vec<ExprAst>, name: &str) -> InterpResult<ErrorKind> {
+      match unsafe { (*stack).pop() }.unwrap() {
+         Keyword(ast) => match ErrorKind::from_name(ast.value.as_slice()) {
+            Some(kind) => Ok(kind),
+            None => {
+               let names: Vec<&str> = ERROR_KINDS.iter().map(|kind| kind.name()).collect();
+               throw!("{}: {} is not a kind of error (the kinds are {})", name, ast.to_source(), names.connect(", "))
+            }
+         },
+         other => throw_kind!(TypeError, "{} only takes a keyword for the kind of error, not {}", name, other.to_source())
+      }
+   }
+
+   // (wrap-error e message [data]) is an error with the message that has e as its cause,
+   // adding context to it without losing it.  It keeps the kind of e.
+   fn wrap_error(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("wrap-error");
+      let data =
+         if ops == 3 {
+            unsafe { (*stack).pop() }.unwrap()
+         } else {
+            Nil(NilAst::new())
+         };
+      let message = match unsafe { (*stack).pop() }.unwrap() {
+         String(ast) => ast.into_string(),
+         _ => unreachable!()
+      };
+      match unsafe { (*stack).pop() }.unwrap() {
+         Error(cause) => {
+            let mut error = ErrorAst::new(message, data);
+            error.kind = cause.kind.clone();
+            error.cause = Some(box cause);
+            Ok(Error(error))
+         }
+         _ => throw_kind!(TypeError, "wrap-error only takes an error for first argument")
+      }
+   }
+
+   // (error-cause e) is the error that e was wrapped around, or nil
+   fn error_cause(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("error-cause");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Error(ast) => Ok(ast.cause.map_or(Nil(NilAst::new()), |cause| Error(*cause))),
+         _ => throw_kind!(TypeError, "error-cause only takes an error")
+      }
+   }
+
+   // (error-kind e) is the kind of the error as a keyword, such as :type-error
+   fn error_kind(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("error-kind");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Error(ast) => Ok(Keyword(KeywordAst::new(ast.kind.name().to_string()))),
+         _ => throw_kind!(TypeError, "error-kind only takes an error")
+      }
+   }
+
+   fn error_message(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("error-message");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Error(ast) => Ok(String(StringAst::new(ast.message))),
+         _ => throw_kind!(TypeError, "error-message only takes an error")
+      }
+   }
+
+   fn error_data(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, _: uint) -> InterpResult<ExprAst> {
+      debug!("error-data");
+      match unsafe { (*stack).pop() }.unwrap() {
+         Error(ast) => Ok(*ast.data),
+         _ => throw_kind!(TypeError, "error-data only takes an error")
+      }
+   }
+
+   // Evaluates an operand that was passed to a builtin unevaluated.
+   fn evaluate(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, expr: &ExprAst) -> InterpResult<ExprAst> {
+      try!(Interpreter::execute_node(env, unsafe { ::std::mem::transmute(stack) }, expr));
+      Ok(unsafe { (*stack).pop() }.unwrap())
+   }
+
+   // Evaluates the expressions of a body (of a function, let, do and so on) in order,
+   // returning the value of the last one, or nil if there are none.
+   fn evaluate_body(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, body: &[ExprAst]) -> InterpResult<ExprAst> {
+      let mut value = Nil(NilAst::new());
+      for expr in body.iter() {
+         value = try!(Environment::evaluate(env.clone(), stack, expr));
+      }
+      Ok(value)
    }
 }
@@ -9,25 +9,80 @@
 extern crate collections;
 extern crate getopts;
 extern crate libc;
+extern crate serialize;
 
+use std::cell::RefCell;
 use std::io;
 use std::os;
+use std::rc::Rc;
+use std::task::TaskBuilder;
 
 mod interp;
 mod ast;
 mod parser;
+mod image;
+mod bytecode;
+mod doc;
+mod pattern;
+mod resolve;
+mod editor;
+mod repl;
+mod color;
+mod formatter;
+mod hash;
+mod lsp;
+mod tags;
+mod typecheck;
+mod optimize;
+mod native;
+mod bundle;
+mod check;
+mod pkg;
+mod replay;
+mod coverage;
+mod csv;
+mod diff;
+mod digest;
+mod macros;
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+mod fuzz;
+#[cfg(test)]
+mod embedding;
 
 static NAME: &'static str = "iron";
 static VERSION: &'static str = "0.1";
 
 fn main() {
    let args = os::args();
-   let program = args[0].as_slice();
 
    let opts = [
       getopts::optflag("d", "debug", "debug mode"),
       getopts::optflag("", "ast", "print out the AST instead of interpreting the code"),
       getopts::optflag("", "status", "print out the exit status of the program"),
+      getopts::optflag("", "print-result", "print the value of the last top-level form of the program"),
+      getopts::optflag("", "test", "run the tests defined with deftest after running the program"),
+      getopts::optflag("", "doc", "print the documented definitions in each input file"),
+      getopts::optflag("", "fmt", "print each input file with canonical formatting"),
+      getopts::optflag("", "tags", "print a ctags index of the definitions in the input files"),
+      getopts::optflag("", "etags", "print an etags (Emacs) index of the definitions in the input files"),
+      getopts::optflag("", "check-types", "check the type annotations and calls in the input files without running them"),
+      getopts::optflag("", "lsp", "run a language server over stdin and stdout"),
+      getopts::optflag("", "compile", "write an optimized .irlc file next to each input file instead of running it"),
+      getopts::optflag("", "compile-native", "translate each input file to C and build it into an executable with cc"),
+      getopts::optopt("", "bundle", "write the input file and the modules it imports to FILE as a single script", "FILE"),
+      getopts::optflag("", "no-inline", "do not inline calls of small functions"),
+      getopts::optopt("", "opt-passes", "the optimizer passes to run, separated by commas, or all or none (by default all, or none with --debug)", "PASSES"),
+      getopts::optopt("", "color", "color the output and diagnostics: auto (the default), always or never", "WHEN"),
+      getopts::optopt("", "log-level", "only show log messages of this level or above: debug, info (the default), warn, error or off", "LEVEL"),
+      getopts::optflag("", "log-json", "write log messages as JSON objects, one per line"),
+      getopts::optflag("", "sandbox", "run untrusted code: deny access to files, the network, other processes and import"),
+      getopts::optopt("", "record", "write the files and other outside inputs the program reads to LOG", "LOG"),
+      getopts::optopt("", "replay", "feed the program the inputs recorded in LOG instead of reading them", "LOG"),
+      getopts::optopt("", "coverage", "write how many times each line ran to FILE, as an lcov tracefile if it ends in .info and as an annotated listing otherwise", "FILE"),
+      getopts::optopt("", "max-depth", "fail with \"maximum recursion depth exceeded\" once evaluation nests DEPTH deep (1000 by default)", "DEPTH"),
+      getopts::optopt("", "load-image", "restore the environment saved by save-image before running", "FILE"),
       getopts::optflag("V", "version", "print the version number"),
       getopts::optflag("h", "help", "print this help menu"),
    ];
@@ -41,39 +96,269 @@ fn main() {
       }
    };
 
+   // evaluation recurses on the Rust stack, so the rest runs in a task with room for it to
+   // nest as deeply as the depth limit allows (--max-depth is checked in new_interpreter)
+   let depth = matches.opt_str("max-depth").and_then(|depth| from_str(depth.as_slice())).unwrap_or(interp::DEFAULT_MAX_DEPTH);
+   let result = TaskBuilder::new().stack_size(interp::stack_size(depth)).try(proc() {
+      run(args[0].as_slice(), opts.as_slice(), matches)
+   });
+   if result.is_err() {
+      os::set_exit_status(101);
+   }
+}
+
+fn run(program: &str, opts: &[getopts::OptGroup], matches: getopts::Matches) {
+   let color = match matches.opt_str("color") {
+      None => stdout_is_tty(),
+      Some(ref when) if when.as_slice() == "auto" => stdout_is_tty(),
+      Some(ref when) if when.as_slice() == "always" => true,
+      Some(ref when) if when.as_slice() == "never" => false,
+      Some(when) => {
+         error!("--color takes auto, always or never, not {}", when);
+         os::set_exit_status(1);
+         return
+      }
+   };
+   color::set_enabled(color);
+
    if matches.opt_present("h") {
       help_menu(program, opts);
    } else if matches.opt_present("V") {
       version();
-   } else if matches.free.len() == 0 {
-      error!("REPL NYI");
-      os::set_exit_status(1);
-   } else {
-      let mode =
-         if matches.opt_present("d") {
-            interp::Debug
-         } else {
-            interp::Release
-         };
-      let code = match io::File::open(&Path::new(matches.free[0].as_slice())) {
-         Ok(mut file) => file.read_to_string().unwrap(),
+   } else if matches.opt_present("lsp") {
+      match lsp::run() {
+         Ok(_) => {}
          Err(f) => {
             error!("{}", f);
             os::set_exit_status(1);
-            return
          }
+      }
+   } else if matches.free.len() > 0 && matches.free[0].as_slice() == "pkg" {
+      match matches.free.slice_from(1) {
+         [ref command, ..sources] if command.as_slice() == "install" && sources.len() > 0 => {
+            for source in sources.iter() {
+               match pkg::install(source.as_slice()) {
+                  Ok(name) => println!("installed {} in {}", name, pkg::package_dir().join(name.as_slice()).display()),
+                  Err(f) => {
+                     error!("{}", f);
+                     os::set_exit_status(1);
+                  }
+               }
+            }
+         }
+         _ => {
+            error!("usage: {} pkg install <git-url|path>...", program);
+            os::set_exit_status(1);
+         }
+      }
+   } else if matches.free.len() > 0 && matches.free[0].as_slice() == "check" {
+      match matches.free.slice_from(1) {
+         [ref entry] => match check::write_report(&Path::new(entry.as_slice()), &mut io::stdout()) {
+            Ok(true) => os::set_exit_status(1),
+            Ok(false) => {}
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+            }
+         },
+         _ => {
+            error!("usage: {} check <entry-file>", program);
+            os::set_exit_status(1);
+         }
+      }
+   } else if matches.free.len() == 0 && stdin_is_tty() {
+      match new_interpreter(&matches) {
+         Some(mut interp) => {
+            interp.set_file("<repl>".to_string());
+            repl::run(&mut interp);
+         }
+         None => {}
+      }
+   } else if matches.opt_present("doc") {
+      for file in matches.free.iter() {
+         let code = match io::File::open(&Path::new(file.as_slice())).read_to_string() {
+            Ok(code) => code,
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+               continue;
+            }
+         };
+         match parser::Parser::new().try_parse_code(code) {
+            Ok(root) => doc::print_docs(&root),
+            Err(f) => {
+               error!("{}: {}", file, f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+   } else if matches.opt_present("fmt") {
+      for file in matches.free.iter() {
+         let code = match io::File::open(&Path::new(file.as_slice())).read_to_string() {
+            Ok(code) => code,
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+               continue;
+            }
+         };
+         match parser::Parser::new().try_parse_code(code) {
+            Ok(root) => print!("{}", formatter::format_code(&root)),
+            Err(f) => {
+               error!("{}: {}", file, f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+   } else if matches.opt_present("check-types") {
+      for file in matches.free.iter() {
+         let code = match io::File::open(&Path::new(file.as_slice())).read_to_string() {
+            Ok(code) => code,
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+               continue;
+            }
+         };
+         match parser::Parser::new().try_parse_code(code) {
+            Ok(root) => for problem in typecheck::check(&root).iter() {
+               println!("{}:{}:{}: {}", file, problem.span.line, problem.span.column, problem.message);
+               os::set_exit_status(1);
+            },
+            Err(f) => {
+               error!("{}: {}", file, f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+   } else if matches.opt_present("tags") || matches.opt_present("etags") {
+      let mut files = vec!();
+      for file in matches.free.iter() {
+         let code = match io::File::open(&Path::new(file.as_slice())).read_to_string() {
+            Ok(code) => code,
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+               continue;
+            }
+         };
+         match parser::Parser::new().try_parse_code(code.clone()) {
+            Ok(root) => files.push((file.clone(), code, tags::collect_definitions(&root))),
+            Err(f) => {
+               error!("{}: {}", file, f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+      if matches.opt_present("etags") {
+         print!("{}", tags::etags(files.as_slice()));
+      } else {
+         print!("{}", tags::ctags(files.as_slice()));
+      }
+   } else if matches.opt_present("compile") {
+      for file in matches.free.iter() {
+         match bytecode::compile_file(&Path::new(file.as_slice())) {
+            Ok(_) => {}
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+   } else if matches.opt_present("bundle") {
+      let output = Path::new(matches.opt_str("bundle").unwrap());
+      match matches.free.as_slice() {
+         [ref file] => match bundle::bundle_file(&Path::new(file.as_slice()), &output) {
+            Ok(()) => {}
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+            }
+         },
+         _ => {
+            error!("--bundle takes exactly one input file");
+            os::set_exit_status(1);
+         }
+      }
+   } else if matches.opt_present("compile-native") {
+      for file in matches.free.iter() {
+         match native::compile_file(&Path::new(file.as_slice())) {
+            Ok(_) => {}
+            Err(f) => {
+               error!("{}", f);
+               os::set_exit_status(1);
+            }
+         }
+      }
+   } else {
+      let mut interp = match new_interpreter(&matches) {
+         Some(interp) => interp,
+         None => return
       };
-      let mut interp = interp::Interpreter::new();
-      interp.set_mode(mode);
-      interp.set_file(matches.free[0].to_string());
+      let file =
+         if matches.free.len() == 0 {
+            "-"
+         } else {
+            matches.free[0].as_slice()
+         };
       //interp.load_code("(fn hi [param] (+ 1 param))".to_string());
       //interp.load_code("(fn hi 1 \"hello world\" 1.05 '(1 2 3.0 4 3.4) [hi 2.354 0.1 \"hi\" (hi)])".to_string());
       //interp.load_code("(println (add 2 3.4))".to_string());
-      interp.load_code(code);
+      let coverage = matches.opt_str("coverage").map(|report| Path::new(report));
+      if coverage.is_some() {
+         if file == "-" {
+            error!("--coverage needs the program to be in a file");
+            os::set_exit_status(1);
+            return
+         }
+         interp.enable_coverage();
+      }
+      let loaded =
+         if file == "-" {
+            interp.set_file("<stdin>".to_string());
+            io::stdin().read_to_string().map(|code| interp.load_code(code))
+         } else if coverage.is_some() {
+            // the counts are for the source, not a precompiled version of it
+            interp.set_file(file.to_string());
+            io::File::open(&Path::new(file)).read_to_string().map(|code| interp.load_code(code))
+         } else {
+            interp.set_file(file.to_string());
+            interp.load_file(&Path::new(file))
+         };
+      match loaded {
+         Ok(()) => {}
+         Err(f) => {
+            error!("{}", f);
+            os::set_exit_status(1);
+            return
+         }
+      }
       if matches.opt_present("ast") {
          interp.dump_ast();
       } else {
-         let status = interp.execute();
+         let result = interp.execute();
+         match result {
+            Some(ref value) if matches.opt_present("print-result") => println!("{}", value),
+            _ => {}
+         }
+         let mut status = if result.is_some() { 0i } else { 1 };
+         if status == 0 && matches.opt_present("test") {
+            let (_, failed) = interp.run_tests();
+            if failed > 0 {
+               status = 1;
+            }
+         }
+         match coverage {
+            Some(report) => match write_coverage(&interp, file, &report) {
+               Ok(()) => {}
+               Err(f) => {
+                  error!("{}", f);
+                  status = 1;
+               }
+            },
+            None => {}
+         }
+         os::set_exit_status(status as int);
          if matches.opt_present("status") {
             println!("exit status: {}", status);
          }
@@ -81,6 +366,123 @@ fn main() {
    }
 }
 
+// Writes the coverage of the file after it has run to the report file.
+fn write_coverage(interp: &interp::Interpreter, file: &str, report: &Path) -> Result<(), String> {
+   let code = match io::File::open(&Path::new(file)).read_to_string() {
+      Ok(code) => code,
+      Err(f) => return Err(format!("could not read {}: {}", file, f))
+   };
+   let root = match parser::Parser::new().try_parse_code(code.clone()) {
+      Ok(root) => root,
+      Err(f) => return Err(format!("{}: {}", file, f))
+   };
+   let hits = interp.coverage().unwrap();
+   let text = if report.extension_str() == Some("info") {
+      coverage::lcov(file, &root, &hits)
+   } else {
+      coverage::annotate(code.as_slice(), &root, &hits)
+   };
+   match io::File::create(report).write(text.as_bytes()) {
+      Ok(()) => Ok(()),
+      Err(f) => Err(format!("could not write {}: {}", report.display(), f))
+   }
+}
+
+// Creates an interpreter in the mode given on the command line, restoring the image given
+// with --load-image if there is one.
+fn new_interpreter(matches: &getopts::Matches) -> Option<interp::Interpreter> {
+   let mode =
+      if matches.opt_present("d") {
+         interp::Debug
+      } else {
+         interp::Release
+      };
+   let level = match matches.opt_str("log-level") {
+      None => interp::LogInfo,
+      Some(level) => match level.as_slice() {
+         "debug" => interp::LogDebug,
+         "info" => interp::LogInfo,
+         "warn" => interp::LogWarn,
+         "error" => interp::LogError,
+         "off" => interp::LogOff,
+         _ => {
+            error!("--log-level takes debug, info, warn, error or off, not {}", level);
+            os::set_exit_status(1);
+            return None
+         }
+      }
+   };
+   let mut interp = interp::Interpreter::new();
+   interp.set_mode(mode);
+   interp.set_log_level(level);
+   interp.set_log_json(matches.opt_present("log-json"));
+   match matches.opt_str("opt-passes") {
+      Some(spec) => match optimize::parse_passes(spec.as_slice()) {
+         Ok(passes) => interp.set_passes(passes),
+         Err(f) => {
+            error!("--opt-passes: {}", f);
+            os::set_exit_status(1);
+            return None
+         }
+      },
+      None => {}
+   }
+   if matches.opt_present("no-inline") {
+      interp.disable_pass(optimize::InlineFunctions);
+   }
+   if matches.opt_present("sandbox") {
+      interp.set_capabilities(interp::Capabilities::none());
+   }
+   match matches.opt_str("max-depth") {
+      Some(depth) => match from_str::<uint>(depth.as_slice()) {
+         Some(depth) if depth > 0 => interp.set_max_depth(depth),
+         _ => {
+            error!("--max-depth takes a positive number, not {}", depth);
+            os::set_exit_status(1);
+            return None
+         }
+      },
+      None => {}
+   }
+   let inputs = match (matches.opt_str("record"), matches.opt_str("replay")) {
+      (Some(_), Some(_)) => Err("--record and --replay cannot be used together".to_string()),
+      (Some(log), None) => replay::Inputs::record(&Path::new(log)).map(Some),
+      (None, Some(log)) => replay::Inputs::replay(&Path::new(log)).map(Some),
+      (None, None) => Ok(None)
+   };
+   match inputs {
+      Ok(Some(inputs)) => interp.set_inputs(Rc::new(RefCell::new(inputs))),
+      Ok(None) => {}
+      Err(f) => {
+         error!("{}", f);
+         os::set_exit_status(1);
+         return None
+      }
+   }
+   match matches.opt_str("load-image") {
+      Some(file) => match image::load_image(&mut interp, &Path::new(file)) {
+         Ok(()) => {}
+         Err(f) => {
+            error!("{}", f);
+            os::set_exit_status(1);
+            return None
+         }
+      },
+      None => {}
+   }
+   Some(interp)
+}
+
+#[inline(always)]
+fn stdin_is_tty() -> bool {
+   unsafe { libc::isatty(libc::STDIN_FILENO) != 0 }
+}
+
+#[inline(always)]
+fn stdout_is_tty() -> bool {
+   unsafe { libc::isatty(libc::STDOUT_FILENO) != 0 }
+}
+
 #[inline(always)]
 fn version() {
    println!("{} v{}", NAME, VERSION);
@@ -92,6 +494,10 @@ fn help_menu(program: &str, opts: &[getopts::OptGroup]) {
    println!("");
    println!("Usage:");
    println!("    {} [OPTIONS...] FILES...", program);
+   println!("    {} [OPTIONS...] -   (read the program from stdin)", program);
+   println!("    {} [OPTIONS...]     (start the REPL)", program);
+   println!("    {} pkg install <git-url|path>...   (install packages for import)", program);
+   println!("    {} check FILE   (check FILE and the modules it imports without running them)", program);
    println!("");
    print!("{}", getopts::usage("A simple, Lisp-based programming language written in Rust.", opts));
 }
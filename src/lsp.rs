@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::IoResult;
+use serialize::json;
+use serialize::json::Json;
+
+use ast::*;
+use interp::{Environment, Interpreter, SPECIAL_FORMS};
+use parser::Parser;
+use tags;
+
+// A language server speaking the Language Server Protocol over stdin and stdout.  It
+// publishes parse errors as diagnostics, finds the define for a name and completes the
+// names of builtins and of the definitions in the document.
+
+static CAPABILITIES: &'static str =
+   r#"{"capabilities":{"textDocumentSync":1,"definitionProvider":true,"completionProvider":{}}}"#;
+
+struct Server<R, W> {
+   documents: HashMap<String, String>,
+   builtins: Vec<String>,
+   input: R,
+   output: W
+}
+
+pub fn run() -> IoResult<()> {
+   Server::new(io::stdin(), io::stdout()).serve()
+}
+
+impl<R: Buffer, W: Writer> Server<R, W> {
+   // A server reading messages from input and writing its replies to output.
+   fn new(input: R, output: W) -> Server<R, W> {
+      let mut builtins = Environment::names(Interpreter::new().env);
+      builtins.extend(SPECIAL_FORMS.iter().map(|name| name.to_string()));
+      Server {
+         documents: HashMap::new(),
+         builtins: builtins,
+         input: input,
+         output: output
+      }
+   }
+
+   fn serve(&mut self) -> IoResult<()> {
+      loop {
+         let message = match try!(self.read_message()) {
+            Some(message) => message,
+            None => return Ok(())
+         };
+         let request = match json::from_str(message.as_slice()) {
+            Ok(request) => request,
+            Err(f) => {
+               debug!("ignoring malformed message: {}", f);
+               continue;
+            }
+         };
+         let method = field(&request, ["method"]).and_then(|method| method.as_string()).unwrap_or("").to_string();
+         let id = field(&request, ["id"]).map(|id| id.to_string());
+         match method.as_slice() {
+            "initialize" => try!(self.respond(id, CAPABILITIES.to_string())),
+            "shutdown" => try!(self.respond(id, "null".to_string())),
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+               let uri = string_field(&request, ["params", "textDocument", "uri"]);
+               let text = string_field(&request, ["params", "textDocument", "text"]);
+               try!(self.update(uri, text));
+            }
+            "textDocument/didChange" => {
+               let uri = string_field(&request, ["params", "textDocument", "uri"]);
+               // the whole document is sent on each change (textDocumentSync 1)
+               let text = match field(&request, ["params", "contentChanges"]).and_then(|changes| changes.as_list()) {
+                  Some(changes) => changes.last().and_then(|change| field(change, ["text"])).and_then(|text| text.as_string()).unwrap_or("").to_string(),
+                  None => String::new()
+               };
+               try!(self.update(uri, text));
+            }
+            "textDocument/didClose" => {
+               let uri = string_field(&request, ["params", "textDocument", "uri"]);
+               self.documents.remove(&uri);
+               try!(self.notify("textDocument/publishDiagnostics", format!(r#"{{"uri":{},"diagnostics":[]}}"#, quote(uri.as_slice()))));
+            }
+            "textDocument/definition" => {
+               let result = self.definition(&request);
+               try!(self.respond(id, result));
+            }
+            "textDocument/completion" => {
+               let result = self.completion(&request);
+               try!(self.respond(id, result));
+            }
+            _ => if id.is_some() {
+               let error = format!(r#"{{"code":-32601,"message":{}}}"#, quote(format!("unsupported method {}", method).as_slice()));
+               try!(self.write_message(format!(r#"{{"jsonrpc":"2.0","id":{},"error":{}}}"#, id.unwrap(), error)));
+            }
+         }
+      }
+   }
+
+   fn update(&mut self, uri: String, text: String) -> IoResult<()> {
+      let diagnostics = match Parser::new().try_parse_code(text.clone()) {
+         Ok(_) => "[]".to_string(),
+         Err(f) => {
+            let (line, column) = start(&Span::new(f.line, f.column, 0, 0));
+            format!(r#"[{{"range":{},"severity":1,"source":"iron","message":{}}}]"#,
+                    range(line, column, line, column + 1), quote(f.desc.as_slice()))
+         }
+      };
+      self.documents.insert(uri.clone(), text);
+      self.notify("textDocument/publishDiagnostics", format!(r#"{{"uri":{},"diagnostics":{}}}"#, quote(uri.as_slice()), diagnostics))
+   }
+
+   fn definition(&self, request: &Json) -> String {
+      let (uri, root, name) = match self.lookup(request) {
+         Some(found) => found,
+         None => return "null".to_string()
+      };
+      // definitions made by code that was not read from a file have no place to go to
+      match tags::collect_definitions(&root).iter().find(|definition| definition.name == name && definition.span.is_known()) {
+         Some(definition) => {
+            let (line, column) = start(&definition.span);
+            format!(r#"{{"uri":{},"range":{}}}"#, quote(uri.as_slice()), range(line, column, line, column + 1))
+         }
+         None => "null".to_string()
+      }
+   }
+
+   fn completion(&self, request: &Json) -> String {
+      let mut names = self.builtins.clone();
+      match self.lookup(request) {
+         Some((_, root, _)) => {
+            names.extend(tags::collect_definitions(&root).move_iter().map(|definition| definition.name));
+         }
+         None => {}
+      }
+      names.sort();
+      names.dedup();
+      let items: Vec<String> = names.iter().map(|name| format!(r#"{{"label":{}}}"#, quote(name.as_slice()))).collect();
+      format!("[{}]", items.connect(","))
+   }
+
+   // The document a request refers to, its AST and the word at the requested position.
+   fn lookup(&self, request: &Json) -> Option<(String, ExprAst, String)> {
+      let uri = string_field(request, ["params", "textDocument", "uri"]);
+      let text = match self.documents.find(&uri) {
+         Some(text) => text,
+         None => return None
+      };
+      let line = field(request, ["params", "position", "line"]).and_then(|line| line.as_number());
+      let column = field(request, ["params", "position", "character"]).and_then(|column| column.as_number());
+      let word = match (line, column) {
+         (Some(line), Some(column)) => word_at(text.as_slice(), line as uint, column as uint),
+         _ => String::new()
+      };
+      match Parser::new().try_parse_code(text.clone()) {
+         Ok(root) => Some((uri, root, word)),
+         Err(_) => None
+      }
+   }
+
+   fn respond(&mut self, id: Option<String>, result: String) -> IoResult<()> {
+      match id {
+         Some(id) => self.write_message(format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, id, result)),
+         None => Ok(())
+      }
+   }
+
+   fn notify(&mut self, method: &str, params: String) -> IoResult<()> {
+      self.write_message(format!(r#"{{"jsonrpc":"2.0","method":{},"params":{}}}"#, quote(method), params))
+   }
+
+   fn read_message(&mut self) -> IoResult<Option<String>> {
+      let mut length = None;
+      loop {
+         let line = match self.input.read_line() {
+            Ok(line) => line,
+            Err(ref f) if f.kind == io::EndOfFile => return Ok(None),
+            Err(f) => return Err(f)
+         };
+         let line = line.as_slice().trim();
+         if line.len() == 0 {
+            break;
+         }
+         if line.starts_with("Content-Length:") {
+            length = from_str::<uint>(line.slice_from("Content-Length:".len()).trim());
+         }
+      }
+      let length = match length {
+         Some(length) => length,
+         None => return Ok(Some(String::new()))
+      };
+      let body = try!(self.input.read_exact(length));
+      Ok(Some(String::from_utf8_lossy(body.as_slice()).into_string()))
+   }
+
+   fn write_message(&mut self, body: String) -> IoResult<()> {
+      try!(self.output.write_str(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_slice()));
+      self.output.flush()
+   }
+}
+
+fn field<'a>(json: &'a Json, path: &[&str]) -> Option<&'a Json> {
+   let mut current = json;
+   for key in path.iter() {
+      current = match current.find(&key.to_string()) {
+         Some(value) => value,
+         None => return None
+      };
+   }
+   Some(current)
+}
+
+fn string_field(json: &Json, path: &[&str]) -> String {
+   field(json, path).and_then(|value| value.as_string()).unwrap_or("").to_string()
+}
+
+fn word_at(text: &str, line: uint, column: uint) -> String {
+   let line: Vec<char> = match text.lines().nth(line) {
+      Some(line) => line.chars().collect(),
+      None => return String::new()
+   };
+   let column = if column > line.len() { line.len() } else { column };
+   let mut start = column;
+   while start > 0 && !is_delimiter(line[start - 1]) {
+      start -= 1;
+   }
+   let mut end = column;
+   while end < line.len() && !is_delimiter(line[end]) {
+      end += 1;
+   }
+   String::from_chars(line.slice(start, end))
+}
+
+fn is_delimiter(ch: char) -> bool {
+   ch.is_whitespace() || ch == '(' || ch == ')' || ch == '[' || ch == ']' || ch == '{' || ch == '}' || ch == '\'' || ch == '"' || ch == ';'
+}
+
+// The zero-based line and column where span starts, or the start of the document if
+// where it is is not known.
+fn start(span: &Span) -> (uint, uint) {
+   if span.is_known() {
+      (span.line - 1, if span.column > 0 { span.column - 1 } else { 0 })
+   } else {
+      (0, 0)
+   }
+}
+
+fn range(line: uint, column: uint, end_line: uint, end_column: uint) -> String {
+   format!(r#"{{"start":{{"line":{},"character":{}}},"end":{{"line":{},"character":{}}}}}"#,
+           line, column, end_line, end_column)
+}
+
+// Encodes a string as a JSON string literal.
+fn quote(text: &str) -> String {
+   let mut result = String::from_str("\"");
+   for ch in text.chars() {
+      match ch {
+         '"' => result.push_str("\\\""),
+         '\\' => result.push_str("\\\\"),
+         '\n' => result.push_str("\\n"),
+         '\r' => result.push_str("\\r"),
+         '\t' => result.push_str("\\t"),
+         ch if (ch as u32) < 0x20 => result.push_str(format!("\\u{:04x}", ch as u32).as_slice()),
+         ch => result.push_char(ch)
+      }
+   }
+   result.push_char('"');
+   result
+}
+
+#[cfg(test)]
+mod test {
+   use std::io::{MemReader, MemWriter};
+   use super::{CAPABILITIES, Server};
+
+   // The message with the header the protocol puts before it.
+   fn framed(body: &str) -> String {
+      format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+   }
+
+   #[test]
+   fn definition_finds_the_define_of_a_name() {
+      let input = [
+         r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+         r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.irl","text":"(define x 1)\n(println x)"}}}"#,
+         r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///a.irl"},"position":{"line":1,"character":9}}}"#
+      ].iter().map(|body| framed(*body)).collect::<Vec<String>>().concat();
+      let mut server = Server::new(MemReader::new(input.into_bytes()), MemWriter::new());
+      server.serve().unwrap();
+      let output = String::from_utf8(server.output.unwrap()).unwrap();
+      let expected = [
+         format!(r#"{{"jsonrpc":"2.0","id":1,"result":{}}}"#, CAPABILITIES),
+         r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{"uri":"file:///a.irl","diagnostics":[]}}"#.to_string(),
+         r#"{"jsonrpc":"2.0","id":2,"result":{"uri":"file:///a.irl","range":{"start":{"line":0,"character":0},"end":{"line":0,"character":1}}}}"#.to_string()
+      ].iter().map(|body| framed(body.as_slice())).collect::<Vec<String>>().concat();
+      assert_eq!(output, expected);
+   }
+}
@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::*;
+use interp::arity_error;
+
+// Macros defined with (defmacro name [params] template...).  A call of a macro is replaced
+// by its template, with each parameter replaced by the code given for it (not its value)
+// and a rest parameter (name...) spliced in as the rest of the code given, and the result
+// runs in place of the call.  Macros are recognized by name, like the special forms, except
+// where a parameter, let or the like binds the name locally.
+//
+// Expansion is hygienic for the names the template binds: each name bound by a let,
+// letrec, with-open, fn or catch in the template (other than a parameter) is renamed to a
+// fresh one, as gensym makes them, so that it cannot capture a name used in the code given
+// to the macro.  Names the template refers to without binding them, and names bound by
+// define and the like, are left as they are.
+//
+// A macro defined by an imported module can be used by the code that imports it.  Each call
+// is expanded once, and the expansion kept with the call until a macro is next defined.
+
+#[deriving(Clone, PartialEq)]
+pub struct Macro {
+   name: String,
+   params: Vec<String>,
+   rest: Option<String>,
+   template: Vec<ExprAst>
+}
+
+// A fresh name that no program would choose, made from count (which is advanced) and
+// prefix.
+pub fn gensym(prefix: &str, count: &mut uint) -> String {
+   *count += 1;
+   format!("{}__{}", prefix, *count)
+}
+
+impl Macro {
+   // The macro defined by the operands of a defmacro.
+   pub fn parse(operands: &[ExprAst]) -> Result<Macro, String> {
+      let (name, params, template) = match operands {
+         [Ident(ref name), Array(ref params), ..template] if !template.is_empty() => (name, params, template),
+         _ => return Err("defmacro must take a name, an array of parameters and a template".to_string())
+      };
+      let mut result = Macro {
+         name: name.value.clone(),
+         params: vec!(),
+         rest: None,
+         template: template.to_vec()
+      };
+      for (idx, param) in params.items.iter().enumerate() {
+         let param = match *param {
+            Ident(ref ast) => ast.value.as_slice(),
+            ref other => return Err(format!("the parameters of macro {} must be names, not {}", result.name, other.to_source()))
+         };
+         if param.ends_with("...") {
+            if idx + 1 != params.items.len() {
+               return Err(format!("the rest parameter {} of macro {} must be its last parameter", param, result.name));
+            }
+            result.rest = Some(param.slice_to(param.len() - 3).to_string());
+         } else {
+            result.params.push(param.to_string());
+         }
+      }
+      Ok(result)
+   }
+
+   pub fn name(&self) -> &str {
+      self.name.as_slice()
+   }
+
+   // The code a call of the macro stands for.
+   pub fn expand(&self, call: &SexprAst, gensyms: &mut uint) -> Result<ExprAst, String> {
+      let args = call.operands.as_slice();
+      let max = if self.rest.is_some() { None } else { Some(self.params.len()) };
+      match arity_error(self.name.as_slice(), self.params.len(), max, args.len()) {
+         Some(desc) => return Err(desc),
+         None => {}
+      }
+      let mut expansion = Expansion {
+         args: HashMap::new(),
+         rest: None,
+         renamed: HashMap::new()
+      };
+      for (param, arg) in self.params.iter().zip(args.iter()) {
+         expansion.args.insert(param.clone(), unresolve(arg.clone()));
+      }
+      match self.rest {
+         Some(ref rest) => {
+            let items = args.slice_from(self.params.len()).iter().map(|arg| unresolve(arg.clone())).collect();
+            expansion.rest = Some((rest.clone(), items));
+         }
+         None => {}
+      }
+      let mut bound = HashSet::new();
+      for expr in self.template.iter() {
+         collect_bound(expr, &mut bound);
+      }
+      for name in bound.move_iter() {
+         if !self.params.contains(&name) && self.rest.as_ref() != Some(&name) {
+            let fresh = gensym(name.as_slice(), gensyms);
+            expansion.renamed.insert(name, fresh);
+         }
+      }
+      let mut body: Vec<ExprAst> = expansion.substitute_all(&self.template);
+      Ok(if body.len() == 1 {
+         body.pop().unwrap()
+      } else {
+         let mut sast = SexprAst::new(IdentAst::new("do".to_string()), body);
+         sast.span = call.span.clone();
+         Sexpr(sast)
+      })
+   }
+}
+
+// The code given to a macro as it was written: references to parameters that the
+// optimizer resolved to slots are turned back into names, as the template can put them
+// inside functions of its own.
+fn unresolve(code: ExprAst) -> ExprAst {
+   match code {
+      Local(ast) => Ident(IdentAst::new(ast.name)),
+      other => other.fold_children(&mut Unresolver)
+   }
+}
+
+struct Unresolver;
+
+impl Folder for Unresolver {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      unresolve(expr)
+   }
+}
+
+// The names bound in the template by the forms that introduce local names.
+fn collect_bound(expr: &ExprAst, bound: &mut HashSet<String>) {
+   match *expr {
+      Sexpr(ref sast) => {
+         let operands = sast.operands.as_slice();
+         match sast.op.value.as_slice() {
+            "quote" => return,
+            "let" | "letrec" | "with-open" => match operands.get(0) {
+               Some(&Array(ref bindings)) => for (idx, item) in bindings.items.iter().enumerate() {
+                  if idx % 2 == 0 {
+                     collect_pattern(item, bound);
+                  }
+               },
+               _ => {}
+            },
+            "fn" => for operand in operands.iter() {
+               match *operand {
+                  Array(_) => collect_pattern(operand, bound),
+                  List(ref clause) => match clause.items.as_slice().get(0) {
+                     Some(params @ &Array(_)) => collect_pattern(params, bound),
+                     _ => {}
+                  },
+                  _ => {}
+               }
+            },
+            "catch" => match operands.get(sast.catch_name_index()) {
+               Some(name) => collect_pattern(name, bound),
+               None => {}
+            },
+            _ => {}
+         }
+         for operand in operands.iter() {
+            collect_bound(operand, bound);
+         }
+      }
+      Array(ref ast) => for item in ast.items.iter() { collect_bound(item, bound) },
+      List(ref ast) => for item in ast.items.iter() { collect_bound(item, bound) },
+      _ => {}
+   }
+}
+
+fn collect_pattern(pat: &ExprAst, bound: &mut HashSet<String>) {
+   match *pat {
+      Ident(ref ast) => {
+         let name = base_name(ast.value.as_slice());
+         if name != "_" && !name.starts_with(":") {
+            bound.insert(name.to_string());
+         }
+      }
+      Array(ref ast) => for item in ast.items.iter() { collect_pattern(item, bound) },
+      _ => {}
+   }
+}
+
+// The name without the ... that marks a rest parameter.
+fn base_name(name: &str) -> &str {
+   if name.ends_with("...") { name.slice_to(name.len() - 3) } else { name }
+}
+
+struct Expansion {
+   args: HashMap<String, ExprAst>,
+   rest: Option<(String, Vec<ExprAst>)>,
+   renamed: HashMap<String, String>
+}
+
+impl Expansion {
+   // The items with the rest parameter spliced in where it appears on its own, written
+   // with or without its ...
+   fn substitute_all(&self, items: &Vec<ExprAst>) -> Vec<ExprAst> {
+      let mut result = vec!();
+      for item in items.iter() {
+         match (item, &self.rest) {
+            (&Ident(ref ast), &Some((ref rest, ref code))) if base_name(ast.value.as_slice()) == rest.as_slice() =>
+               result.push_all(code.as_slice()),
+            _ => result.push(self.substitute(item))
+         }
+      }
+      result
+   }
+
+   fn substitute(&self, expr: &ExprAst) -> ExprAst {
+      match *expr {
+         Ident(ref ast) => match self.args.find(&ast.value) {
+            Some(code) => code.clone(),
+            None => Ident(self.rename(ast))
+         },
+         Sexpr(ref sast) if sast.op.value.as_slice() == "quote" => expr.clone(),
+         Sexpr(ref sast) => {
+            let mut result = sast.clone();
+            // the node is a different call in each expansion, so it does not share the
+            // cache of the template's
+            result.cache = CallCache::new();
+            // a parameter given a name can be used as the operator
+            result.op = match self.args.find(&sast.op.value) {
+               Some(&Ident(ref name)) => name.clone(),
+               _ => self.rename(&sast.op)
+            };
+            result.operands = self.substitute_all(&sast.operands);
+            Sexpr(result)
+         }
+         Array(ref ast) => {
+            let mut result = ast.clone();
+            result.items = self.substitute_all(&ast.items);
+            Array(result)
+         }
+         // the clauses of a fn
+         List(ref ast) => {
+            let mut result = ast.clone();
+            result.items = self.substitute_all(&ast.items);
+            List(result)
+         }
+         ref other => other.clone()
+      }
+   }
+
+   fn rename(&self, ident: &IdentAst) -> IdentAst {
+      let name = ident.value.as_slice();
+      let base = base_name(name);
+      match self.renamed.find_equiv(&base) {
+         Some(fresh) => {
+            let mut result = ident.clone();
+            result.value = format!("{}{}", fresh, name.slice_from(base.len()));
+            result
+         }
+         None => ident.clone()
+      }
+   }
+}
@@ -0,0 +1,464 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use ast::*;
+use interp;
+use interp::Release;
+use optimize;
+use parser::Parser;
+use typecheck;
+
+// Compiles a program to C and builds it into an executable with the system's C compiler
+// (cc), for iron --compile-native.  Only a subset of the language is supported, enough for
+// numeric and string code built from top-level functions:
+//
+//  - integer, float, string, boolean and nil literals
+//  - define, defconst and defn at the top level, of values and of functions with plain
+//    parameters (which can only be called, not used as values)
+//  - if, when, unless, do and let
+//  - calls of +, =, print, concat and the functions defined at the top level
+//
+// Anything else is reported as unsupported rather than translated into something that
+// behaves differently.  The generated C uses statement expressions, a GNU extension that
+// both gcc and clang support.
+
+static RUNTIME: &'static str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef enum { T_UNSET, T_NIL, T_INT, T_FLOAT, T_BOOL, T_STR } tag;
+
+typedef struct {
+   tag t;
+   union { int64_t i; double f; int b; const char *s; } u;
+} value;
+
+static void irl_fail(const char *msg) {
+   fprintf(stderr, "%s\n", msg);
+   exit(1);
+}
+
+static value mk_nil(void) { value v; v.t = T_NIL; return v; }
+static value mk_int(int64_t i) { value v; v.t = T_INT; v.u.i = i; return v; }
+static value mk_float(double f) { value v; v.t = T_FLOAT; v.u.f = f; return v; }
+static value mk_bool(int b) { value v; v.t = T_BOOL; v.u.b = b; return v; }
+static value mk_str(const char *s) { value v; v.t = T_STR; v.u.s = s; return v; }
+
+static value irl_global(value v, const char *name) {
+   if (v.t == T_UNSET) {
+      fprintf(stderr, "ident %s not declared\n", name);
+      exit(1);
+   }
+   return v;
+}
+
+static int irl_test(value v) {
+   if (v.t != T_BOOL) irl_fail("if only takes a boolean as the condition");
+   return v.u.b;
+}
+
+/* a float printed with at most 15 decimals, and with a decimal point if source is set */
+static void irl_float(double f, char *buf, size_t len, int source) {
+   char *end;
+   snprintf(buf, len, "%.15f", f);
+   if (strchr(buf, '.') != NULL) {
+      end = buf + strlen(buf) - 1;
+      while (*end == '0') *end-- = '\0';
+      if (*end == '.') *end = '\0';
+   }
+   if (source && strchr(buf, '.') == NULL) strncat(buf, ".0", len - strlen(buf) - 1);
+}
+
+static char *irl_format(value v, int source) {
+   char buf[512];
+   switch (v.t) {
+   case T_INT: snprintf(buf, sizeof buf, "%lld", (long long) v.u.i); break;
+   case T_FLOAT: irl_float(v.u.f, buf, sizeof buf, source); break;
+   case T_BOOL: strcpy(buf, v.u.b ? "true" : "false"); break;
+   case T_STR: return strdup(v.u.s);
+   default: strcpy(buf, "nil");
+   }
+   return strdup(buf);
+}
+
+static int irl_same(value a, value b) {
+   if (a.t != b.t) return 0;
+   switch (a.t) {
+   case T_INT: return a.u.i == b.u.i;
+   case T_FLOAT: return a.u.f == b.u.f;
+   case T_BOOL: return a.u.b == b.u.b;
+   case T_STR: return strcmp(a.u.s, b.u.s) == 0;
+   default: return 1;
+   }
+}
+
+/* the builtins take their arguments in an array, like the interpreter's stack */
+static value irl_add(int n, value *args) {
+   int64_t i = 0;
+   double f = 0;
+   int decimal = 0, k;
+   for (k = n - 1; k >= 0; k--) {
+      if (args[k].t == T_INT) {
+         if (__builtin_add_overflow(i, args[k].u.i, &i)) irl_fail("integer overflow in +");
+      } else if (args[k].t == T_FLOAT) {
+         decimal = 1;
+         f += args[k].u.f;
+      } else {
+         irl_fail("+ only takes numbers");
+      }
+   }
+   return decimal ? mk_float(f + (double) i) : mk_int(i);
+}
+
+static value irl_equal(int n, value *args) {
+   int k;
+   for (k = 0; k < n - 1; k++) {
+      if (!irl_same(args[k], args[n - 1])) return mk_bool(0);
+   }
+   return mk_bool(1);
+}
+
+static value irl_print(int n, value *args) {
+   int k;
+   for (k = 0; k < n; k++) {
+      char *text = irl_format(args[k], 0);
+      fputs(text, stdout);
+      free(text);
+   }
+   return mk_int(0);
+}
+
+static value irl_concat(int n, value *args) {
+   char *result = strdup("");
+   int k;
+   for (k = 0; k < n; k++) {
+      char *text = irl_format(args[k], 1);
+      result = realloc(result, strlen(result) + strlen(text) + 1);
+      strcat(result, text);
+      free(text);
+   }
+   return mk_str(result);
+}
+"#;
+
+// The C functions implementing the supported builtins, with the least number of
+// arguments each takes.
+fn builtin(name: &str) -> Option<(&'static str, uint)> {
+   match name {
+      "+" => Some(("irl_add", 0)),
+      "=" => Some(("irl_equal", 2)),
+      "print" => Some(("irl_print", 0)),
+      "concat" => Some(("irl_concat", 0)),
+      _ => None
+   }
+}
+
+struct Translator {
+   // the number of parameters of each function defined at the top level
+   functions: HashMap<String, uint>,
+   globals: HashSet<String>,
+   // the parameters and let bindings in scope, innermost last
+   locals: Vec<String>,
+   temps: uint
+}
+
+// Builds the program in the file at source into an executable next to it (with the
+// extension removed), leaving the C it was translated to in a .c file.
+pub fn compile_file(source: &Path) -> Result<Path, String> {
+   let code = match io::File::open(source).read_to_string() {
+      Ok(code) => code,
+      Err(f) => return Err(format!("{}", f))
+   };
+   let root = match Parser::new().try_parse_code(code) {
+      Ok(Root(root)) => optimize::optimize(root, optimize::default_passes(Release).as_slice()),
+      Ok(_) => unreachable!(),
+      Err(f) => return Err(format!("{}: {}", source.display(), f))
+   };
+   let c = match translate(&root) {
+      Ok(c) => c,
+      Err(f) => return Err(format!("{}: {}", source.display(), f))
+   };
+   let cpath = source.with_extension("c");
+   let exe = source.with_extension("");
+   match io::File::create(&cpath).write(c.as_bytes()) {
+      Ok(()) => {}
+      Err(f) => return Err(format!("{}", f))
+   }
+   match io::Command::new("cc").arg("-O2").arg("-o").arg(&exe).arg(&cpath).output() {
+      Ok(ref output) if output.status.success() => Ok(exe),
+      Ok(output) => Err(format!("cc failed to build {}:\n{}", cpath.display(), String::from_utf8_lossy(output.error.as_slice()))),
+      Err(f) => Err(format!("could not run cc: {}", f))
+   }
+}
+
+// The C source of a program.
+pub fn translate(root: &RootAst) -> Result<String, String> {
+   let mut translator = Translator {
+      functions: HashMap::new(),
+      globals: HashSet::new(),
+      locals: vec!(),
+      temps: 0
+   };
+   // every definition is known before any code is translated, so that functions can
+   // call the ones defined after them
+   let mut forms = vec!();
+   for ast in root.asts.iter() {
+      let form = try!(translator.declare(ast));
+      forms.push(form);
+   }
+   let mut result = String::from_str(RUNTIME);
+   result.push_char('\n');
+   for name in translator.globals.iter() {
+      result.push_str(format!("static value {};\n", global(name.as_slice())).as_slice());
+   }
+   for (name, arity) in translator.functions.iter() {
+      result.push_str(format!("static value {}({});\n", function(name.as_slice()), params(*arity)).as_slice());
+   }
+   let mut main = vec!();
+   for form in forms.move_iter() {
+      match form {
+         Function(name, params, body) => {
+            translator.locals = params.clone();
+            let body = try!(translator.sequence(body.as_slice()));
+            translator.locals.clear();
+            let params: Vec<String> = params.iter().map(|param| format!("value {}", local(param.as_slice()))).collect();
+            let params = if params.is_empty() { "void".to_string() } else { params.connect(", ") };
+            result.push_str(format!("\nstatic value {}({}) {{\n   return {};\n}}\n", function(name.as_slice()), params, body).as_slice());
+         }
+         Global(name, value) => {
+            let value = try!(translator.expr(&value));
+            main.push(format!("   {} = {};\n", global(name.as_slice()), value));
+         }
+         Statement(expr) => {
+            let expr = try!(translator.expr(&expr));
+            main.push(format!("   (void) {};\n", expr));
+         }
+      }
+   }
+   result.push_str("\nint main(void) {\n");
+   for line in main.iter() {
+      result.push_str(line.as_slice());
+   }
+   result.push_str("   return 0;\n}\n");
+   Ok(result)
+}
+
+enum TopLevel {
+   Function(String, Vec<String>, Vec<ExprAst>),
+   Global(String, ExprAst),
+   Statement(ExprAst)
+}
+
+// The name of a binding in C, with every character other than a letter or digit escaped.
+fn mangle(prefix: &str, name: &str) -> String {
+   let mut result = String::from_str(prefix);
+   for ch in name.chars() {
+      if ch.is_alphanumeric() && ch.is_ascii() {
+         result.push_char(ch);
+      } else {
+         result.push_str(format!("_{:x}_", ch as u32).as_slice());
+      }
+   }
+   result
+}
+
+fn local(name: &str) -> String { mangle("v_", name) }
+fn global(name: &str) -> String { mangle("g_", name) }
+fn function(name: &str) -> String { mangle("f_", name) }
+
+fn params(count: uint) -> String {
+   if count == 0 {
+      "void".to_string()
+   } else {
+      Vec::from_elem(count, "value").connect(", ")
+   }
+}
+
+fn unsupported(expr: &ExprAst) -> String {
+   format!("{} is not supported by --compile-native", expr.to_source())
+}
+
+// A string literal in C with the same characters as the string.
+fn c_string(text: &str) -> String {
+   let mut result = String::from_str("\"");
+   for byte in text.bytes() {
+      match byte {
+         b'"' => result.push_str("\\\""),
+         b'\\' => result.push_str("\\\\"),
+         b'\n' => result.push_str("\\n"),
+         b'\t' => result.push_str("\\t"),
+         0x20 .. 0x7e => result.push_char(byte as char),
+         _ => result.push_str(format!("\\{:03o}", byte).as_slice())
+      }
+   }
+   result.push_char('"');
+   result
+}
+
+impl Translator {
+   fn declare(&mut self, ast: &ExprAst) -> Result<TopLevel, String> {
+      let sast = match *ast {
+         Sexpr(ref sast) => match sast.expand_defn() {
+            Some(define) => define,
+            None => sast.clone()
+         },
+         ref other => return Ok(Statement(other.clone()))
+      };
+      if sast.op.value.as_slice() != "define" && sast.op.value.as_slice() != "defconst" {
+         return Ok(Statement(Sexpr(sast)));
+      }
+      let (name, value) = match sast.operands.as_slice() {
+         [Ident(ref name), ref value] | [Ident(ref name), String(_), ref value] => (name.value.clone(), value.clone()),
+         _ => return Err(unsupported(ast))
+      };
+      if self.functions.contains_key(&name) || self.globals.contains(&name) {
+         return Err(format!("{} is defined more than once, which is not supported by --compile-native", name));
+      }
+      match value {
+         Sexpr(ref value) if value.op.value.as_slice() == "fn" => {
+            let (params, mut body) = match value.operands.as_slice() {
+               [Array(ref params), ..body] => (typecheck::strip_annotations(params.clone()), body.to_vec()),
+               _ => return Err(unsupported(ast))
+            };
+            typecheck::strip_result_type(&mut body);
+            // a leading string is documentation unless it is the only value
+            match body.as_slice() {
+               [String(_), _, ..] => { body.remove(0); }
+               _ => {}
+            }
+            let mut names = vec!();
+            for param in params.items.iter() {
+               match *param {
+                  Ident(ref ast) if !ast.value.as_slice().ends_with("...") => names.push(ast.value.clone()),
+                  ref other => return Err(unsupported(other))
+               }
+            }
+            self.functions.insert(name.clone(), names.len());
+            Ok(Function(name, names, body))
+         }
+         value => {
+            self.globals.insert(name.clone());
+            Ok(Global(name, value))
+         }
+      }
+   }
+
+   fn temp(&mut self) -> String {
+      self.temps += 1;
+      format!("t{}", self.temps)
+   }
+
+   // Expressions evaluated in order, as a single C expression with the value of the last.
+   fn sequence(&mut self, exprs: &[ExprAst]) -> Result<String, String> {
+      if exprs.is_empty() {
+         return Ok("mk_nil()".to_string());
+      }
+      let mut parts = vec!();
+      for expr in exprs.iter() {
+         parts.push(try!(self.expr(expr)));
+      }
+      Ok(format!("({{ {}; }})", parts.connect("; ")))
+   }
+
+   fn expr(&mut self, expr: &ExprAst) -> Result<String, String> {
+      match *expr {
+         Integer(ref ast) => Ok(format!("mk_int(INT64_C({}))", ast.value)),
+         Float(ref ast) => Ok(format!("mk_float({:e})", ast.value)),
+         Boolean(ref ast) => Ok(format!("mk_bool({})", if ast.value { 1u } else { 0 })),
+         Nil(_) => Ok("mk_nil()".to_string()),
+         String(ref ast) => match interp::unescape(ast.string.as_slice()) {
+            Ok(text) => Ok(format!("mk_str({})", c_string(text.as_slice()))),
+            Err(f) => Err(f.desc)
+         },
+         Ident(ref ast) => {
+            let name = ast.value.as_slice();
+            if self.locals.iter().any(|local| local.as_slice() == name) {
+               Ok(local(name))
+            } else if self.globals.contains_equiv(&name) {
+               Ok(format!("irl_global({}, {})", global(name), c_string(name)))
+            } else {
+               Err(unsupported(expr))
+            }
+         }
+         Sexpr(ref sast) => self.call(sast),
+         ref other => Err(unsupported(other))
+      }
+   }
+
+   fn call(&mut self, sast: &SexprAst) -> Result<String, String> {
+      let operands = sast.operands.as_slice();
+      match sast.op.value.as_slice() {
+         "if" => {
+            let (cond, ontrue, onfalse) = match operands {
+               [ref cond, ref ontrue] => (cond, ontrue, "mk_nil()".to_string()),
+               [ref cond, ref ontrue, ref onfalse] => (cond, ontrue, try!(self.expr(onfalse))),
+               _ => return Err(unsupported(&Sexpr(sast.clone())))
+            };
+            let cond = try!(self.expr(cond));
+            let ontrue = try!(self.expr(ontrue));
+            return Ok(format!("(irl_test({}) ? {} : {})", cond, ontrue, onfalse));
+         }
+         "when" | "unless" => match sast.expand_conditional() {
+            Some(expanded) => return self.call(&expanded),
+            None => return Err(unsupported(&Sexpr(sast.clone())))
+         },
+         "do" => return self.sequence(operands),
+         "let" => {
+            let bindings = match operands.get(0) {
+               Some(&Array(ref bindings)) if bindings.items.len() % 2 == 0 => bindings.items.clone(),
+               _ => return Err(unsupported(&Sexpr(sast.clone())))
+            };
+            let depth = self.locals.len();
+            let mut parts = vec!();
+            for binding in bindings.as_slice().chunks(2) {
+               let name = match binding[0] {
+                  Ident(ref ast) => ast.value.clone(),
+                  ref other => return Err(unsupported(other))
+               };
+               // the value is evaluated before the name is in scope
+               let temp = self.temp();
+               parts.push(format!("value {} = {}", temp, try!(self.expr(&binding[1]))));
+               parts.push(format!("value {} = {}", local(name.as_slice()), temp));
+               self.locals.push(name);
+            }
+            parts.push(try!(self.sequence(operands.slice_from(1))));
+            self.locals.truncate(depth);
+            return Ok(format!("({{ {}; }})", parts.connect("; ")));
+         }
+         _ => {}
+      }
+      let op = sast.op.value.as_slice();
+      let shadowed = self.locals.iter().any(|local| local.as_slice() == op);
+      let target = match (self.functions.find_equiv(&op), builtin(op)) {
+         (Some(&arity), _) if !shadowed => {
+            if arity != operands.len() {
+               return Err(interp::arity_error(op, arity, Some(arity), operands.len()).unwrap());
+            }
+            None
+         }
+         (None, Some((_, min))) if !shadowed && !self.globals.contains_equiv(&op) => {
+            if operands.len() < min {
+               return Err(interp::arity_error(op, min, None, operands.len()).unwrap());
+            }
+            builtin(op)
+         }
+         _ => return Err(unsupported(&Sexpr(sast.clone())))
+      };
+      // the arguments are evaluated in order before the call, as the interpreter does
+      let mut parts = vec!();
+      let mut args = vec!();
+      for operand in operands.iter() {
+         let temp = self.temp();
+         parts.push(format!("value {} = {}", temp, try!(self.expr(operand))));
+         args.push(temp);
+      }
+      let call = match target {
+         Some((cfunc, _)) if args.is_empty() => format!("{}(0, NULL)", cfunc),
+         Some((cfunc, _)) => format!("{}({}, (value[]) {{ {} }})", cfunc, args.len(), args.connect(", ")),
+         None => format!("{}({})", function(op), args.connect(", "))
+      };
+      parts.push(call);
+      Ok(format!("({{ {}; }})", parts.connect("; ")))
+   }
+}
@@ -0,0 +1,609 @@
+use std::collections::HashMap;
+use std::mem;
+
+use ast::*;
+use interp::{InterpMode, Debug};
+use resolve;
+
+// The optimizer: a series of passes that each rewrite the code of a file before it runs
+// without changing what it does.  The enabled passes run in the order they are listed in
+// below.  Release mode enables all of them and Debug mode none, so that code runs as it
+// was written; --opt-passes picks them explicitly.
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Pass {
+   // removes comments
+   StripComments,
+   // replaces the references to constants defined with defconst by their values
+   InlineConstants,
+   // replaces calls of small functions without effects by their bodies
+   InlineFunctions,
+   // evaluates the calls of + and = whose arguments are all literals
+   ConstantFold,
+   // keeps only the branch taken by an if (when or unless) with a literal condition, and
+   // removes the literals in a do that are not its value
+   DeadCode,
+   // makes identical string literals share one copy of their text, which equality checks
+   // then recognize without comparing it
+   InternStrings,
+   // rewrites references to function parameters into slots (see resolve.rs)
+   ResolveLocals
+}
+
+pub static PASSES: &'static [Pass] = &[
+   StripComments, InlineConstants, InlineFunctions, ConstantFold, DeadCode, InternStrings, ResolveLocals
+];
+
+// The largest function body (in nodes) that InlineFunctions copies into its callers.
+static INLINE_LIMIT: uint = 12;
+
+impl Pass {
+   pub fn name(&self) -> &'static str {
+      match *self {
+         StripComments => "strip-comments",
+         InlineConstants => "inline-constants",
+         InlineFunctions => "inline-functions",
+         ConstantFold => "constant-fold",
+         DeadCode => "dead-code",
+         InternStrings => "intern-strings",
+         ResolveLocals => "resolve-locals"
+      }
+   }
+}
+
+pub fn default_passes(mode: InterpMode) -> Vec<Pass> {
+   if mode == Debug {
+      vec!()
+   } else {
+      PASSES.to_vec()
+   }
+}
+
+// Reads a comma-separated list of pass names, or all or none.
+pub fn parse_passes(spec: &str) -> Result<Vec<Pass>, String> {
+   match spec {
+      "all" => return Ok(PASSES.to_vec()),
+      "none" | "" => return Ok(vec!()),
+      _ => {}
+   }
+   let mut result = vec!();
+   for name in spec.split(',').map(|name| name.trim()) {
+      match PASSES.iter().find(|pass| pass.name() == name) {
+         Some(pass) => result.push(pass.clone()),
+         None => {
+            let names: Vec<&str> = PASSES.iter().map(|pass| pass.name()).collect();
+            return Err(format!("{} is not an optimizer pass (the passes are {})", name, names.connect(", ")));
+         }
+      }
+   }
+   Ok(result)
+}
+
+pub fn optimize(root: RootAst, passes: &[Pass]) -> RootAst {
+   let mut root = match root.optimize() {
+      Some(Root(ast)) => ast,
+      _ => unreachable!()
+   };
+   for pass in PASSES.iter().filter(|pass| passes.contains(*pass)) {
+      debug!("optimizer pass {}", pass.name());
+      root = match *pass {
+         StripComments => fold_root(root, &mut CommentStripper),
+         InlineConstants => inline_constants(root),
+         InlineFunctions => inline_functions(root),
+         ConstantFold => {
+            let bindings = binding_counts(root.asts.as_slice());
+            let mut folder = ConstantFolder { bindings: bindings };
+            fold_root(root, &mut folder)
+         }
+         DeadCode => fold_root(root, &mut DeadCodeRemover),
+         InternStrings => fold_root(root, &mut StringInterner { pool: StringPool::new() }),
+         ResolveLocals => resolve::resolve(root)
+      };
+   }
+   root
+}
+
+fn fold_root<F: Folder>(root: RootAst, folder: &mut F) -> RootAst {
+   match folder.fold(Root(root)) {
+      Root(ast) => ast,
+      _ => unreachable!()
+   }
+}
+
+fn is_literal(expr: &ExprAst) -> bool {
+   match *expr {
+      Integer(_) | Float(_) | String(_) | Boolean(_) | Nil(_) => true,
+      _ => false
+   }
+}
+
+// The number of times each name could be bound in the code, or None if it imports a module
+// (which could bind any name).
+fn binding_counts(asts: &[ExprAst]) -> Option<HashMap<String, uint>> {
+   let mut counts = HashMap::new();
+   for ast in asts.iter() {
+      if !count_bindings(ast, &mut counts) {
+         return None;
+      }
+   }
+   Some(counts)
+}
+
+// Whether nothing in the code can bind name, other than (if defined) its single top-level
+// definition.
+fn unbound(counts: &Option<HashMap<String, uint>>, name: &str, defined: bool) -> bool {
+   match *counts {
+      Some(ref counts) => counts.find_equiv(&name).map_or(0, |count| *count) == if defined { 1 } else { 0 },
+      None => false
+   }
+}
+
+fn count_bindings(expr: &ExprAst, counts: &mut HashMap<String, uint>) -> bool {
+   match *expr {
+      Sexpr(ref sast) => {
+         let operands = sast.operands.as_slice();
+         match sast.op.value.as_slice() {
+            "import" => return false,
+            "define" | "defglobal" | "defconst" | "defdynamic" | "defn" => match operands.get(0) {
+               Some(pat) => count_pattern(pat, counts),
+               None => {}
+            },
+            "catch" => match operands.get(sast.catch_name_index()) {
+               Some(pat) => count_pattern(pat, counts),
+               None => {}
+            },
+            "let" | "letrec" | "with-open" => match operands.get(0) {
+               Some(&Array(ref bindings)) => for (idx, item) in bindings.items.iter().enumerate() {
+                  if idx % 2 == 0 {
+                     count_pattern(item, counts);
+                  }
+               },
+               _ => {}
+            },
+            "match" => for (idx, operand) in operands.iter().enumerate() {
+               if idx % 2 == 1 {
+                  count_pattern(operand, counts);
+               }
+            },
+            "defprotocol" => for operand in operands.iter() {
+               count_pattern(operand, counts);
+            },
+            _ => {}
+         }
+         // function parameters, including those of defn and of each clause
+         for operand in operands.iter() {
+            match *operand {
+               Array(_) if sast.op.value.as_slice() == "fn" || sast.op.value.as_slice() == "defn" => count_pattern(operand, counts),
+               List(ref clause) => match clause.items.as_slice().get(0) {
+                  Some(params @ &Array(_)) => count_pattern(params, counts),
+                  _ => {}
+               },
+               _ => {}
+            }
+            if !count_bindings(operand, counts) {
+               return false;
+            }
+         }
+         true
+      }
+      Array(ref ast) => ast.items.iter().all(|item| count_bindings(item, counts)),
+      List(ref ast) => ast.items.iter().all(|item| count_bindings(item, counts)),
+      _ => true
+   }
+}
+
+fn count_pattern(pat: &ExprAst, counts: &mut HashMap<String, uint>) {
+   match *pat {
+      Ident(ref ast) => {
+         let name = ast.value.as_slice();
+         let name = if name.ends_with("...") { name.slice_to(name.len() - 3) } else { name };
+         *counts.find_or_insert(name.to_string(), 0) += 1;
+      }
+      Array(ref ast) => for item in ast.items.iter() { count_pattern(item, counts) },
+      List(ref ast) => for item in ast.items.iter() { count_pattern(item, counts) },
+      Sexpr(ref ast) => {
+         count_pattern(&Ident(ast.op.clone()), counts);
+         for operand in ast.operands.iter() {
+            count_pattern(operand, counts);
+         }
+      }
+      _ => {}
+   }
+}
+
+struct CommentStripper;
+
+fn is_comment(expr: &ExprAst) -> bool {
+   match *expr {
+      Comment(_) => true,
+      _ => false
+   }
+}
+
+impl Folder for CommentStripper {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      let expr = match expr {
+         Root(mut ast) => {
+            ast.asts.retain(|expr| !is_comment(expr));
+            ast.trivia = Trivia::new();
+            Root(ast)
+         }
+         Sexpr(mut ast) => {
+            ast.operands.retain(|expr| !is_comment(expr));
+            ast.trivia = Trivia::new();
+            Sexpr(ast)
+         }
+         Array(mut ast) => {
+            ast.items.retain(|expr| !is_comment(expr));
+            ast.trivia = Trivia::new();
+            Array(ast)
+         }
+         List(mut ast) => {
+            ast.items.retain(|expr| !is_comment(expr));
+            ast.trivia = Trivia::new();
+            List(ast)
+         }
+         Map(mut ast) => {
+            ast.trivia = Trivia::new();
+            Map(ast)
+         }
+         other => other
+      };
+      expr.fold_children(self)
+   }
+}
+
+// Substitutes the constants defined at the top level with (defconst name literal) whose
+// name is bound nowhere else.
+fn inline_constants(root: RootAst) -> RootAst {
+   let counts = binding_counts(root.asts.as_slice());
+   let mut constants = HashMap::new();
+   for ast in root.asts.iter() {
+      match *ast {
+         Sexpr(ref sast) if sast.op.value.as_slice() == "defconst" => match sast.operands.as_slice() {
+            [Ident(ref name), ref value] if is_literal(value) && unbound(&counts, name.value.as_slice(), true) => {
+               constants.insert(name.value.clone(), value.clone());
+            }
+            _ => {}
+         },
+         _ => {}
+      }
+   }
+   if constants.is_empty() {
+      return root;
+   }
+   fold_root(root, &mut ConstantInliner { constants: constants })
+}
+
+struct ConstantInliner {
+   constants: HashMap<String, ExprAst>
+}
+
+impl Folder for ConstantInliner {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      match expr {
+         Ident(ast) => match self.constants.find(&ast.value) {
+            Some(value) => value.clone(),
+            None => Ident(ast)
+         },
+         Sexpr(mut sast) => {
+            // the names being defined or changed are not references
+            let skip = match sast.op.value.as_slice() {
+               "quote" | "deftest" | "defprotocol" | "defmacro" => return Sexpr(sast),
+               "define" | "defglobal" | "defconst" | "defdynamic" | "set" | "defn" => 1,
+               "catch" => sast.catch_name_index() + 1,
+               "extend" => 2,
+               _ => 0
+            };
+            let operands = mem::replace(&mut sast.operands, vec!());
+            sast.operands = operands.move_iter().enumerate().map(|(idx, operand)| {
+               if idx < skip { operand } else { self.fold(operand) }
+            }).collect();
+            Sexpr(sast)
+         }
+         // lists are quoted
+         List(ast) => List(ast),
+         other => other.fold_children(self)
+      }
+   }
+}
+
+// The builtins that do nothing but compute a value from their arguments.
+static PURE_BUILTINS: &'static [&'static str] = &[
+   "+", "=", "int", "truncate", "float", "exact->inexact", "concat", "str-len", "str-upcase",
+   "str-downcase"
+];
+
+// A function that can be inlined: one defined at the top level whose name is bound
+// nowhere else, taking plain parameters, and whose body is a single small expression
+// that only refers to its parameters and only calls pure builtins and other such
+// functions, so that evaluating it has no effects.
+struct Inlinable {
+   params: Vec<String>,
+   body: ExprAst,
+   // true if the body uses each parameter exactly once and in order, so that arguments
+   // are still evaluated once each and in order when a call is replaced by the body
+   linear: bool
+}
+
+struct BodyChecker<'a> {
+   params: &'a [String],
+   counts: &'a Option<HashMap<String, uint>>,
+   inlinable: &'a HashMap<String, Inlinable>,
+   nodes: uint,
+   uses: Vec<String>,
+   ok: bool
+}
+
+impl<'a> Visitor for BodyChecker<'a> {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      self.nodes += 1;
+      self.ok = self.ok && match *expr {
+         Ident(ref ast) => {
+            self.uses.push(ast.value.clone());
+            self.params.iter().any(|param| param == &ast.value)
+         }
+         Sexpr(ref ast) => {
+            let op = ast.op.value.as_slice();
+            (PURE_BUILTINS.iter().any(|name| *name == op) && unbound(self.counts, op, false)) || self.inlinable.contains_key_equiv(&op)
+         }
+         // quoted, so any names in it are not references
+         List(_) => return false,
+         Array(_) => true,
+         ref other => is_literal(other)
+      };
+      self.ok
+   }
+}
+
+fn inline_functions(root: RootAst) -> RootAst {
+   let counts = binding_counts(root.asts.as_slice());
+   if counts.is_none() {
+      return root;
+   }
+   let mut candidates = vec!();
+   for ast in root.asts.iter() {
+      let define = match *ast {
+         Sexpr(ref sast) if sast.op.value.as_slice() == "define" => sast.clone(),
+         Sexpr(ref sast) => match sast.expand_defn() {
+            Some(define) => define,
+            None => continue
+         },
+         _ => continue
+      };
+      let (name, params, body) = match define.operands.as_slice() {
+         [Ident(ref name), Sexpr(ref value)] if value.op.value.as_slice() == "fn" => match value.operands.as_slice() {
+            [Array(ref params), ref body] => (name.value.clone(), params.clone(), body.clone()),
+            _ => continue
+         },
+         _ => continue
+      };
+      let plain = params.items.iter().all(|param| match *param {
+         Ident(ref ast) => !ast.value.as_slice().ends_with("...") && !ast.value.as_slice().contains_char(':'),
+         _ => false
+      });
+      if plain && unbound(&counts, name.as_slice(), true) {
+         let params: Vec<String> = params.items.iter().map(|param| param.to_source()).collect();
+         candidates.push((name, params, body));
+      }
+   }
+   // a function can be inlined once the functions it calls can be, so functions that
+   // call themselves (or each other) never are
+   let mut functions = HashMap::new();
+   loop {
+      let mut added = vec!();
+      for &(ref name, ref params, ref body) in candidates.iter() {
+         if functions.contains_key(name) {
+            continue;
+         }
+         let mut checker = BodyChecker {
+            params: params.as_slice(),
+            counts: &counts,
+            inlinable: &functions,
+            nodes: 0,
+            uses: vec!(),
+            ok: true
+         };
+         body.walk(&mut checker);
+         if checker.ok && checker.nodes <= INLINE_LIMIT {
+            added.push((name.clone(), Inlinable {
+               params: params.clone(),
+               body: body.clone(),
+               linear: checker.uses == *params
+            }));
+         }
+      }
+      if added.is_empty() {
+         break;
+      }
+      functions.extend(added.move_iter());
+   }
+   if functions.is_empty() {
+      return root;
+   }
+   fold_root(root, &mut FunctionInliner { functions: functions })
+}
+
+struct FunctionInliner {
+   functions: HashMap<String, Inlinable>
+}
+
+impl Folder for FunctionInliner {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      let sast = match expr {
+         Sexpr(sast) => sast,
+         // lists are quoted
+         List(ast) => return List(ast),
+         other => return other.fold_children(self)
+      };
+      if sast.op.value.as_slice() == "quote" {
+         return Sexpr(sast);
+      }
+      let sast = match Sexpr(sast).fold_children(self) {
+         Sexpr(sast) => sast,
+         _ => unreachable!()
+      };
+      let literal_args = sast.operands.iter().all(|operand| is_literal(operand));
+      let inlined = match self.functions.find(&sast.op.value) {
+         // unless the body uses the arguments once each and in order, they have to be
+         // literals, for which it makes no difference how often they are evaluated
+         Some(function) if function.params.len() == sast.operands.len() && (function.linear || literal_args) => {
+            let args: HashMap<&str, &ExprAst> = function.params.iter().map(|param| param.as_slice()).zip(sast.operands.iter()).collect();
+            Some(substitute(&function.body, &args))
+         }
+         _ => None
+      };
+      match inlined {
+         // the body may call other functions that can be inlined
+         Some(body) => self.fold(body),
+         None => Sexpr(sast)
+      }
+   }
+}
+
+fn substitute(expr: &ExprAst, args: &HashMap<&str, &ExprAst>) -> ExprAst {
+   match *expr {
+      Ident(ref ast) => match args.find(&ast.value.as_slice()) {
+         Some(arg) => (*arg).clone(),
+         None => expr.clone()
+      },
+      Sexpr(ref ast) => {
+         let mut result = ast.clone();
+         result.operands = ast.operands.iter().map(|operand| substitute(operand, args)).collect();
+         Sexpr(result)
+      }
+      Array(ref ast) => {
+         let mut result = ast.clone();
+         result.items = ast.items.iter().map(|item| substitute(item, args)).collect();
+         Array(result)
+      }
+      ref other => other.clone()
+   }
+}
+
+struct ConstantFolder {
+   bindings: Option<HashMap<String, uint>>
+}
+
+impl Folder for ConstantFolder {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      let sast = match expr {
+         Sexpr(sast) => sast,
+         List(ast) => return List(ast),
+         other => return other.fold_children(self)
+      };
+      if sast.op.value.as_slice() == "quote" {
+         return Sexpr(sast);
+      }
+      let sast = match Sexpr(sast).fold_children(self) {
+         Sexpr(sast) => sast,
+         _ => unreachable!()
+      };
+      if !unbound(&self.bindings, sast.op.value.as_slice(), false) {
+         return Sexpr(sast);
+      }
+      match fold_call(&sast) {
+         Some(value) => value,
+         None => Sexpr(sast)
+      }
+   }
+}
+
+// The value of a call of + or = with literal arguments, computed the same way as the
+// builtins do.  Calls that would fail are left to fail when run.
+fn fold_call(sast: &SexprAst) -> Option<ExprAst> {
+   let operands = sast.operands.as_slice();
+   if !operands.iter().all(|operand| is_literal(operand)) {
+      return None;
+   }
+   match sast.op.value.as_slice() {
+      "+" => {
+         let mut intval = 0i64;
+         let mut val = 0f64;
+         let mut decimal = false;
+         // the builtin takes its arguments from the last
+         for operand in operands.iter().rev() {
+            match *operand {
+               Integer(ref ast) => intval = match intval.checked_add(&ast.value) {
+                  Some(sum) => sum,
+                  None => return None
+               },
+               Float(ref ast) => {
+                  decimal = true;
+                  val += ast.value;
+               }
+               _ => return None
+            }
+         }
+         Some(if decimal { Float(FloatAst::new(val + intval as f64)) } else { Integer(IntegerAst::new(intval)) })
+      }
+      "=" if operands.len() >= 2 => {
+         let last = operands.last().unwrap();
+         Some(Boolean(BooleanAst::new(operands.iter().all(|operand| operand == last))))
+      }
+      _ => None
+   }
+}
+
+struct DeadCodeRemover;
+
+impl Folder for DeadCodeRemover {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      let sast = match expr {
+         Sexpr(sast) => sast,
+         List(ast) => return List(ast),
+         other => return other.fold_children(self)
+      };
+      match sast.op.value.as_slice() {
+         "quote" => return Sexpr(sast),
+         "when" | "unless" => match sast.operands.as_slice().get(0) {
+            Some(&Boolean(_)) => return self.fold(Sexpr(sast.expand_conditional().unwrap())),
+            _ => {}
+         },
+         _ => {}
+      }
+      let mut sast = match Sexpr(sast).fold_children(self) {
+         Sexpr(sast) => sast,
+         _ => unreachable!()
+      };
+      match sast.op.value.as_slice() {
+         "if" => {
+            let taken = match sast.operands.as_slice() {
+               [Boolean(ref cond), ref ontrue] => Some(if cond.value { ontrue.clone() } else { Nil(NilAst::new()) }),
+               [Boolean(ref cond), ref ontrue, ref onfalse] => Some(if cond.value { ontrue.clone() } else { onfalse.clone() }),
+               _ => None
+            };
+            match taken {
+               Some(taken) => taken,
+               None => Sexpr(sast)
+            }
+         }
+         "do" => {
+            let count = sast.operands.len();
+            let operands = mem::replace(&mut sast.operands, vec!());
+            sast.operands = operands.move_iter().enumerate().filter(|&(idx, ref operand)| idx + 1 == count || !is_literal(operand)).map(|(_, operand)| operand).collect();
+            match sast.operands.len() {
+               0 => Nil(NilAst::new()),
+               1 => sast.operands.pop().unwrap(),
+               _ => Sexpr(sast)
+            }
+         }
+         _ => Sexpr(sast)
+      }
+   }
+}
+
+// Numbers, booleans and nil are kept inline in the tree, so strings are the only literals
+// worth pooling.
+struct StringInterner {
+   pool: StringPool
+}
+
+impl Folder for StringInterner {
+   fn fold(&mut self, expr: ExprAst) -> ExprAst {
+      match expr {
+         String(ast) => String(self.pool.intern(ast)),
+         other => other.fold_children(self)
+      }
+   }
+}
@@ -1,5 +1,10 @@
-use std::num;
+use std::fmt;
+use std::str;
 use ast::*;
+use color;
+
+// Besides letters and digits (from any script), identifiers can contain these.
+static IDENT_SYMBOLS: &'static str = "+-*/<>=!?.:_&%$^~|@#";
 
 macro_rules! parse_subexprs (
    ($this:ident, $expfn:ident, $($others:ident),+) => ({
@@ -48,23 +53,78 @@ pub struct Parser {
    code: String,
    pos: uint,
    line: uint,
-   column: uint
+   column: uint,
+   // whether sexprs are read as data, for read, so that a list need not start with a name
+   data: bool
 }
 
 pub struct ParseError {
-   line: uint,
-   column: uint,
-   desc: String
+   pub line: uint,
+   pub column: uint,
+   pub desc: String,
+   // what the parser was looking for and what it found instead, if that is what went wrong
+   unexpected: Option<(String, String)>
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
+// The kinds of token produced by Parser::tokenize().
+#[deriving(Clone, PartialEq, Show)]
+pub enum TokenKind {
+   // ( ) [ ] { } and the ' before a quoted list
+   TokenDelimiter,
+   TokenComment,
+   TokenString,
+   TokenNumber,
+   // true, false and nil
+   TokenKeyword,
+   // 'name and :name
+   TokenSymbol,
+   TokenIdent,
+   // the identifier right after an opening parenthesis
+   TokenOperator,
+   // a character that cannot appear in code, or a string that is not closed
+   TokenInvalid
+}
+
+#[deriving(Clone, Show)]
+pub struct Token {
+   pub kind: TokenKind,
+   pub span: Span
+}
+
 impl ParseError {
    pub fn new(line: uint, col: uint, desc: String) -> ParseError {
       ParseError {
          line: line,
          column: col,
-         desc: desc
+         desc: desc,
+         unexpected: None
+      }
+   }
+
+   // What went wrong, without where.
+   pub fn message(&self) -> String {
+      match self.unexpected {
+         Some((ref expect, ref found)) => format!("expected {} but found {}", expect, found),
+         None => self.desc.clone()
+      }
+   }
+
+   // Whether the code ended in the middle of an expression, i.e. more input could make
+   // it parse.
+   pub fn is_eof(&self) -> bool {
+      self.desc.as_slice() == "end of file"
+   }
+}
+
+impl fmt::Show for ParseError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let location = color::paint(format!("error at line {}, column {}:", self.line, self.column).as_slice(), color::Red);
+      match self.unexpected {
+         Some((ref expect, ref found)) =>
+            write!(f, "{} expected {} but found {}", location, color::paint(expect.as_slice(), color::Yellow), found),
+         None => write!(f, "{} {}", location, self.desc)
       }
    }
 }
@@ -75,10 +135,19 @@ impl Parser {
          code: "".to_string(),
          pos: 0,
          line: 1,
-         column: 1
+         column: 1,
+         data: false
       }
    }
 
+   // A parser that reads (1 2 3) as a list rather than failing because a call must start
+   // with the name of what it calls.
+   pub fn for_data() -> Parser {
+      let mut parser = Parser::new();
+      parser.data = true;
+      parser
+   }
+
    pub fn load_code(&mut self, code: String) {
       self.code = code;
       self.pos = 0;
@@ -91,315 +160,655 @@ impl Parser {
       self.parse()
    }
 
+   pub fn try_parse_code(&mut self, code: String) -> ParseResult<ExprAst> {
+      self.load_code(code);
+      self.try_parse()
+   }
+
+   // Splits code into tokens without building an AST, for syntax highlighting.  This never
+   // fails: whatever cannot be read becomes a TokenInvalid, and the code between tokens is
+   // whitespace.  The tokens do not say whether the code parses, e.g. if its parentheses
+   // are balanced.
+   pub fn tokenize(code: &str) -> Vec<Token> {
+      let mut parser = Parser::new();
+      parser.load_code(code.to_string());
+      let mut tokens = vec!();
+      loop {
+         parser.skip_whitespace();
+         let (start, line, column) = (parser.pos, parser.line, parser.column);
+         let ch = match parser.current() {
+            Some(ch) => ch,
+            None => return tokens
+         };
+         let after_paren = match tokens.last() {
+            Some(&Token { kind: TokenDelimiter, ref span }) => parser.code.as_slice().char_at(span.start) == '(',
+            _ => false
+         };
+         let kind = match ch {
+            '(' | ')' | '[' | ']' | '{' | '}' => {
+               parser.inc_pos_col();
+               TokenDelimiter
+            }
+            ';' => {
+               let _ = parser.parse_comment();
+               TokenComment
+            }
+            ch if ch == '"' || parser.rest().starts_with("r\"") => parser.string_token(),
+            '\'' => match parser.next_char() {
+               Some(next) if parser.is_ident_char(next) => {
+                  parser.inc_pos_col();
+                  parser.skip_word();
+                  TokenSymbol
+               }
+               _ => {
+                  parser.inc_pos_col();
+                  TokenDelimiter
+               }
+            },
+            ch if ch.is_digit() || (ch == '-' && parser.next_char().map_or(false, |next| next.is_digit())) => {
+               parser.inc_char(ch);
+               parser.skip_word();
+               TokenNumber
+            }
+            ch if ch.is_alphanumeric() || IDENT_SYMBOLS.contains_char(ch) => {
+               parser.skip_word();
+               match parser.code.as_slice().slice(start, parser.pos) {
+                  "true" | "false" | "nil" => TokenKeyword,
+                  word if word.len() > 1 && word.starts_with(":") => TokenSymbol,
+                  _ if after_paren => TokenOperator,
+                  _ => TokenIdent
+               }
+            }
+            ch => {
+               parser.inc_char(ch);
+               TokenInvalid
+            }
+         };
+         tokens.push(Token {
+            kind: kind,
+            span: Span::new(line, column, start, parser.pos)
+         });
+      }
+   }
+
+   // Like try_parse_code(), but for code that might not be valid UTF-8.
+   pub fn try_parse_bytes(&mut self, bytes: &[u8]) -> ParseResult<ExprAst> {
+      match str::from_utf8(bytes) {
+         Some(code) => self.try_parse_code(code.to_string()),
+         None => Err(invalid_utf8_error(bytes))
+      }
+   }
+
    pub fn parse(&mut self) -> ExprAst {
+      match self.try_parse() {
+         Ok(root) => root,
+         Err(f) => {
+            error!("{}", f);
+            fail!(); // fix fail! later
+         }
+      }
+   }
+
+   // Like parse(), but returns parse errors instead of failing.
+   pub fn try_parse(&mut self) -> ParseResult<ExprAst> {
       let mut root = RootAst::new();
-      self.skip_whitespace();
+      try!(self.skip_trivia(&mut root.trivia, 0));
       while self.pos < self.code.len() {
-         let expr = match self.parse_expr() {
-            Ok(m) => m,
-            Err(f) => {
-               error!("error at line {}, column {}: {}", f.line, f.column, f.desc);
-               fail!(); // fix fail! later
-            }
-         };
-         root.push(expr);
-         self.skip_whitespace();
+         root.push(try!(self.parse_expr()));
+         let idx = root.asts.len();
+         try!(self.skip_trivia(&mut root.trivia, idx));
       }
-      Root(root)
+      Ok(Root(root))
    }
 
    fn parse_expr(&mut self) -> ParseResult<ExprAst> {
-      let expr = parse_subexprs!(self, parse_sexpr, parse_float, parse_integer, parse_boolean, parse_nil, parse_ident, parse_string, parse_symbol, parse_list, parse_array, parse_comment);
+      let expr = parse_subexprs!(self, parse_sexpr, parse_float, parse_integer, parse_boolean, parse_nil, parse_string, parse_ident, parse_symbol, parse_list, parse_array, parse_map);
       Ok(expr)
    }
-
    fn parse_sexpr(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos == code.len() {
-         Err(self.eof_error())
-      } else if code.char_at(self.pos) == '(' {
-         self.inc_pos_col();
-         let op = try!(self.parse_ident_stack());
-         let mut operands = vec!();
-         loop {
+      match self.current() {
+         None => Err(self.eof_error()),
+         Some('(') => {
+            let (start, line, column) = (self.pos, self.line, self.column);
+            self.inc_pos_col();
             self.skip_whitespace();
-            if self.pos == code.len() {
-               return Err(self.eof_error());
+            // the clauses of multiple-arity functions, e.g. ([x] body...), are read as lists
+            // and a call of the value of a sexpr, e.g. ((fn [x] x) 1), as a sexpr whose
+            // operator has no name and whose first operand is the function.  When reading
+            // data, anything that does not start with a name is a list
+            let (op, callee) = match self.current() {
+               Some('[') => (None, None),
+               Some(ch) if self.data && !self.starts_name(ch) => (None, None),
+               Some('(') => (Some(IdentAst::new(String::new())), Some(try!(self.parse_sexpr()))),
+               _ => (Some(try!(self.parse_ident_stack())), None)
+            };
+            let mut trivia = Trivia::new();
+            let mut operands = try!(self.parse_items(')', &mut trivia));
+            match callee {
+               Some(callee) => {
+                  operands.insert(0, callee);
+                  for comment in trivia.comments.mut_iter() {
+                     match *comment {
+                        (ref mut idx, _) => *idx += 1
+                     }
+                  }
+               }
+               None => {}
+            }
+            match op {
+               Some(op) => {
+                  let mut sexpr = SexprAst::new(op, operands);
+                  sexpr.span = Span::new(line, column, start, self.pos);
+                  sexpr.trivia = trivia;
+                  Ok(Sexpr(sexpr))
+               }
+               None => {
+                  let mut list = ListAst::new(operands);
+                  list.trivia = trivia;
+                  Ok(List(list))
+               }
             }
-            if code.char_at(self.pos) == ')' {
+         }
+         Some(ch) => Err(self.unexpected_error("'('", format!("'{}'", ch)))
+      }
+   }
+
+   // Parses the items of a collection up to and including the character that closes it.
+   fn parse_items(&mut self, close: char, trivia: &mut Trivia) -> ParseResult<Vec<ExprAst>> {
+      let mut items = vec!();
+      loop {
+         try!(self.skip_trivia(trivia, items.len()));
+         match self.current() {
+            None => return Err(self.eof_error()),
+            Some(ch) if ch == close => {
                self.inc_pos_col();
-               break;
+               return Ok(items);
             }
-            operands.push(try!(self.parse_expr()));
+            Some(_) => items.push(try!(self.parse_expr()))
          }
-         Ok(Sexpr(SexprAst::new(op, operands)))
-      } else {
-         Err(self.unexpected_error("'('", format!("'{}'", code.char_at(self.pos))))
       }
    }
 
    fn parse_integer(&mut self) -> ParseResult<ExprAst> {
-      Ok(Integer(IntegerAst::new(try!(self.parse_integer_val()).val0())))
+      Ok(Integer(IntegerAst::new(try!(self.parse_integer_val()))))
    }
 
-   fn parse_integer_val(&mut self) -> ParseResult<(i64, uint)> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
+   fn parse_integer_val(&mut self) -> ParseResult<i64> {
       self.skip_whitespace();
-      if self.pos == code.len() {
+      if self.current().is_none() {
          return Err(self.eof_error());
       }
+      let (line, column) = (self.line, self.column);
       let neg =
-         if code.char_at(self.pos) == '-' {
+         if self.current() == Some('-') {
             self.inc_pos_col();
             true
          } else {
             false
          };
-      let mut number = 0;
-      let mut digits = 0;
-      while self.pos < code.len() && code.char_at(self.pos).is_digit() {
+      let mut number = 0i64;
+      let mut digits = 0u;
+      loop {
+         // only ASCII digits, as other digits have no value to_digit() knows
+         let digit = match self.current() {
+            Some(ch @ '0' .. '9') => ch.to_digit(10).unwrap() as i64,
+            _ => break
+         };
+         // the digits are subtracted for a negative number so that i64::MIN fits
+         let next = number.checked_mul(&10).and_then(|number| if neg { number.checked_sub(&digit) } else { number.checked_add(&digit) });
+         number = match next {
+            Some(number) => number,
+            None => return Err(ParseError::new(line, column, "integer literal is too large".to_string()))
+         };
          digits += 1;
-         number = number * 10 + code.char_at(self.pos).to_digit(10).unwrap() as i64;
          self.inc_pos_col();
       }
       if digits == 0 {
-         Err(self.unexpected_error("integer", format!("'{}'", code.char_at(self.pos))))
+         Err(self.unexpected_error("integer", self.found()))
       } else {
-         Ok((if neg { -number } else { number }, digits))
+         Ok(number)
       }
    }
 
    fn parse_float(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      let front = try!(self.parse_integer_val()).val0();
-      if self.pos + 1 >= code.len() {
-         Err(self.eof_error())
-      } else if code.char_at(self.pos) != '.' {
-         Err(self.unexpected_error("'.'", format!("'{}'", code.char_at(self.pos))))
-      } else {
-         self.inc_pos_col();
-         if !code.char_at(self.pos).is_digit() {
-            Err(self.unexpected_error("float", format!("'{}'", code.char_at(self.pos))))
-         } else {
-            let back = try!(self.parse_integer_val());
-            Ok(Float(FloatAst::new(front as f64 + back.val0() as f64 / num::pow(10u, back.val1()) as f64)))
+      self.skip_whitespace();
+      let start = self.pos;
+      try!(self.parse_integer_val());
+      if self.pos + 1 >= self.code.len() {
+         return Err(self.eof_error());
+      }
+      if self.current() != Some('.') {
+         return Err(self.unexpected_error("'.'", self.found()));
+      }
+      self.inc_pos_col();
+      match self.current() {
+         Some('0' .. '9') => {}
+         _ => return Err(self.unexpected_error("float", self.found()))
+      }
+      loop {
+         match self.current() {
+            Some('0' .. '9') => self.inc_pos_col(),
+            _ => break
          }
       }
+      let value: f64 = from_str(self.code.as_slice().slice(start, self.pos)).unwrap();
+      Ok(Float(FloatAst::new(value)))
    }
 
    fn parse_array(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos + 1 >= code.len() {
+      if self.pos + 1 >= self.code.len() {
          Err(self.eof_error())
-      } else if code.char_at(self.pos) == '[' {
+      } else if self.current() == Some('[') {
          self.inc_pos_col();
-         let mut items = vec!();
-         loop {
-            self.skip_whitespace();
-            if self.pos == code.len() {
-               return Err(self.eof_error());
-            }
-            if code.char_at(self.pos) == ']' {
-               self.inc_pos_col();
-               break;
-            }
-            items.push(try!(self.parse_expr()));
-         }
-         Ok(Array(ArrayAst::new(items)))
+         let mut trivia = Trivia::new();
+         let mut array = ArrayAst::new(try!(self.parse_items(']', &mut trivia)));
+         array.trivia = trivia;
+         Ok(Array(array))
       } else {
-         Err(self.unexpected_error("'['", format!("'{}'", code.char_at(self.pos))))
+         Err(self.unexpected_error("'['", self.found()))
+      }
+   }
+
+   // {key value...} is a map literal, whose keys must be hashable
+   fn parse_map(&mut self) -> ParseResult<ExprAst> {
+      self.skip_whitespace();
+      if self.pos + 1 >= self.code.len() {
+         Err(self.eof_error())
+      } else if self.current() == Some('{') {
+         let (line, column) = (self.line, self.column);
+         self.inc_pos_col();
+         let mut trivia = Trivia::new();
+         let items = try!(self.parse_items('}', &mut trivia));
+         let mut map = match MapAst::from_items(items) {
+            Ok(map) => map,
+            Err(f) => return Err(ParseError::new(line, column, f))
+         };
+         map.trivia = trivia;
+         Ok(Map(map))
+      } else {
+         Err(self.unexpected_error("'{'", self.found()))
       }
    }
 
    fn parse_list(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos + 2 >= code.len() {
+      if self.pos + 2 >= self.code.len() {
          Err(self.eof_error())
-      } else if code.char_at(self.pos) == '\'' {
+      } else if self.current() == Some('\'') {
          self.inc_pos_col();
-         if code.char_at(self.pos) == '(' {
+         if self.current() == Some('(') {
             self.inc_pos_col();
-            let mut items = vec!();
-            loop {
-               self.skip_whitespace();
-               if self.pos == code.len() {
-                  return Err(self.eof_error());
-               }
-               if code.char_at(self.pos) == ')' {
-                  self.inc_pos_col();
-                  break;
-               }
-               items.push(try!(self.parse_expr()));
-            }
-            Ok(List(ListAst::new(items)))
+            let mut trivia = Trivia::new();
+            let mut list = ListAst::new(try!(self.parse_items(')', &mut trivia)));
+            list.trivia = trivia;
+            Ok(List(list))
          } else {
-            Err(self.unexpected_error("'('", format!("'{}'", code.char_at(self.pos))))
+            Err(self.unexpected_error("'('", self.found()))
          }
       } else {
-         Err(self.unexpected_error("'''", format!("'{}'", code.char_at(self.pos))))
+         Err(self.unexpected_error("'''", self.found()))
       }
    }
 
+   // Parses an identifier, or a keyword if it starts with a colon.  A lone colon (as in
+   // type annotations) is an identifier.
    fn parse_ident(&mut self) -> ParseResult<ExprAst> {
       let val = try!(self.parse_ident_stack());
-      Ok(Ident(val))
+      if val.value.len() > 1 && val.value.as_slice().starts_with(":") {
+         Ok(Keyword(KeywordAst::new(val.value.as_slice().slice_from(1).to_string())))
+      } else {
+         Ok(Ident(val))
+      }
    }
 
    fn parse_ident_stack(&mut self) -> ParseResult<IdentAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos == code.len() {
-         Err(self.eof_error())
-      } else {
-         let mut ident = String::new();
-         loop {
-            let ch = code.char_at(self.pos);
-            if !self.is_ident_char(ch) {
-               break;
-            }
-            ident.push_char(ch);
-            self.inc_pos_col();
-            if self.pos == code.len() {
-               break;
-            }
+      let mut ident = String::new();
+      loop {
+         let ch = match self.current() {
+            Some(ch) => ch,
+            None => break
+         };
+         // digits can only start numbers
+         if !self.is_ident_char(ch) || (ident.len() == 0 && ch.is_digit()) {
+            break;
          }
-         if ident.len() == 0 {
-            if self.pos == code.len() {
-               Err(self.eof_error())
-            } else {
-               Err(self.unexpected_error("ident", format!("'{}'", code.char_at(self.pos))))
-            }
-         } else {
-            Ok(IdentAst::new(ident))
+         if !ch.is_alphanumeric() && !IDENT_SYMBOLS.contains_char(ch) {
+            return Err(ParseError::new(self.line, self.column, format!("{} cannot be used in an identifier", ch.escape_unicode())));
          }
+         ident.push_char(ch);
+         self.inc_char(ch);
+      }
+      if ident.len() == 0 {
+         match self.current() {
+            None => Err(self.eof_error()),
+            Some(ch) => Err(self.unexpected_error("ident", format!("'{}'", ch)))
+         }
+      } else {
+         Ok(IdentAst::new(ident))
       }
    }
 
+   // Strings can contain ${expr}, which is read as (concat "text" expr "text"...).
    fn parse_string(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos == code.len() {
+      if self.current().is_none() {
          Err(self.eof_error())
-      } else if code.char_at(self.pos) == '"' {
+      } else if self.rest().starts_with("r\"") || self.rest().starts_with("\"\"\"") {
+         self.parse_raw_string()
+      } else if self.current() == Some('"') {
+         let (start, line, column) = (self.pos, self.line, self.column);
          self.inc_pos_col();
          let mut buf = String::new();
-         while self.pos < code.len() && (code.char_at(self.pos) != '"' || code.char_at(self.pos - 1) == '\\') {
-            buf.push_char(code.char_at(self.pos));
-            if code.char_at(self.pos) == '\n' {
+         let mut parts = vec!();
+         let mut inner = String::new();
+         let mut inner_start = (0, 0);
+         let mut escape = false;
+         let mut dollar = false;
+         // how many braces of an interpolated expression are open and whether the
+         // expression is in the middle of a string of its own
+         let mut depth = 0u;
+         let mut quoted = false;
+         loop {
+            let ch = match self.current() {
+               Some(ch) => ch,
+               None => break
+            };
+            if depth == 0 {
+               if escape {
+                  escape = false;
+                  dollar = false;
+                  buf.push_char(ch);
+               } else if ch == '"' {
+                  break;
+               } else if ch == '{' && dollar {
+                  buf.pop_char();
+                  if buf.len() > 0 {
+                     parts.push(String(StringAst::new(buf.clone())));
+                     buf.truncate(0);
+                  }
+                  depth = 1;
+                  inner.truncate(0);
+                  inner_start = (self.line, self.column + 1);
+               } else {
+                  escape = ch == '\\';
+                  dollar = ch == '$';
+                  buf.push_char(ch);
+               }
+            } else {
+               if quoted {
+                  if escape {
+                     escape = false;
+                  } else if ch == '\\' {
+                     escape = true;
+                  } else if ch == '"' {
+                     quoted = false;
+                  }
+               } else if ch == '"' {
+                  quoted = true;
+               } else if ch == '{' {
+                  depth += 1;
+               } else if ch == '}' {
+                  depth -= 1;
+               }
+               if depth == 0 {
+                  parts.push(try!(self.parse_interpolated(inner.clone(), inner_start)));
+                  dollar = false;
+               } else {
+                  inner.push_char(ch);
+               }
+            }
+            if ch == '\n' {
                self.add_line();
             } else {
                self.column += 1;
             }
-            self.pos += 1;
+            self.pos += ch.len_utf8_bytes();
          }
-         if self.pos == code.len() {
+         if self.current().is_none() {
             Err(self.eof_error())
          } else {
             self.inc_pos_col();
-            Ok(String(StringAst::new(buf)))
+            if parts.is_empty() {
+               return Ok(String(StringAst::new(buf)));
+            }
+            if buf.len() > 0 {
+               parts.push(String(StringAst::new(buf)));
+            }
+            let mut sexpr = SexprAst::new(IdentAst::new("concat".to_string()), parts);
+            sexpr.span = Span::new(line, column, start, self.pos);
+            sexpr.interpolated = true;
+            Ok(Sexpr(sexpr))
          }
       } else {
-         Err(self.unexpected_error("\"", format!("'{}'", code.char_at(self.pos))))
+         Err(self.unexpected_error("\"", self.found()))
       }
    }
 
-   fn parse_boolean(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
-      if self.pos == code.len() {
-         Err(self.eof_error())
-      } else {
-         let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos).is_alphabetic() {
-            buf.push_char(code.char_at(self.pos));
-            self.inc_pos_col();
+   // Raw strings, written r"..." or """...""", contain exactly the text between their
+   // quotes: backslashes and ${ are not special, and a """ string can contain single
+   // quotes.  A newline right after the opening """ is not part of the string.  The text
+   // is stored escaped, like that of ordinary strings.
+   fn parse_raw_string(&mut self) -> ParseResult<ExprAst> {
+      let delim = if self.current() == Some('r') { "\"" } else { "\"\"\"" };
+      if delim == "\"" {
+         self.inc_pos_col();
+      }
+      for _ in range(0, delim.len()) {
+         self.inc_pos_col();
+      }
+      if delim != "\"" && self.rest().starts_with("\n") {
+         self.pos += 1;
+         self.add_line();
+      }
+      let mut buf = String::new();
+      while !self.rest().starts_with(delim) {
+         let ch = match self.current() {
+            Some(ch) => ch,
+            None => return Err(self.eof_error())
+         };
+         match ch {
+            '\\' => buf.push_str("\\\\"),
+            '"' => buf.push_str("\\\""),
+            '$' if self.rest().slice_from(1).starts_with("{") => buf.push_str("\\$"),
+            ch => buf.push_char(ch)
          }
-         let string: &str = buf.as_slice();
-         match string {
-            "true" => Ok(Boolean(BooleanAst::new(true))),
-            "false" => Ok(Boolean(BooleanAst::new(false))),
-            other => Err(self.unexpected_error("\"true\" or \"false\"", format!("\"{}\"", other)))
+         if ch == '\n' {
+            self.add_line();
+         } else {
+            self.column += 1;
          }
+         self.pos += ch.len_utf8_bytes();
+      }
+      for _ in range(0, delim.len()) {
+         self.inc_pos_col();
+      }
+      Ok(String(StringAst::new(buf)))
+   }
+
+   // Parses the expression inside ${...}, which starts at the given line and column.
+   fn parse_interpolated(&self, code: String, (line, column): (uint, uint)) -> ParseResult<ExprAst> {
+      let mut parser = Parser::new();
+      parser.load_code(code);
+      parser.line = line;
+      parser.column = column;
+      match try!(parser.try_parse()) {
+         Root(mut root) => {
+            if root.asts.len() != 1 {
+               return Err(ParseError::new(line, column, "string interpolation must contain exactly one expression".to_string()));
+            }
+            Ok(root.asts.pop().unwrap())
+         }
+         _ => unreachable!()
+      }
+   }
+
+   // The letters at the current position, for keywords such as true and nil.
+   fn parse_word(&mut self) -> String {
+      let mut buf = String::new();
+      loop {
+         match self.current() {
+            Some(ch) if ch.is_alphabetic() => {
+               buf.push_char(ch);
+               self.inc_char(ch);
+            }
+            _ => return buf
+         }
+      }
+   }
+
+   fn parse_boolean(&mut self) -> ParseResult<ExprAst> {
+      self.skip_whitespace();
+      if self.current().is_none() {
+         return Err(self.eof_error());
+      }
+      match self.parse_word().as_slice() {
+         "true" => Ok(Boolean(BooleanAst::new(true))),
+         "false" => Ok(Boolean(BooleanAst::new(false))),
+         other => Err(self.unexpected_error("\"true\" or \"false\"", format!("\"{}\"", other)))
       }
    }
 
    fn parse_nil(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
       self.skip_whitespace();
-      if self.pos == code.len() {
-         Err(self.eof_error())
-      } else {
-         let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos).is_alphabetic() {
-            buf.push_char(code.char_at(self.pos));
+      if self.current().is_none() {
+         return Err(self.eof_error());
+      }
+      match self.parse_word().as_slice() {
+         "nil" => Ok(Nil(NilAst::new())),
+         other => Err(self.unexpected_error("\"nil\"", format!("\"{}\"", other)))
+      }
+   }
+
+   fn parse_symbol(&mut self) -> ParseResult<ExprAst> {
+      self.skip_whitespace();
+      match (self.current(), self.next_char()) {
+         (None, _) | (_, None) => Err(self.eof_error()),
+         (_, Some(next)) if !self.is_ident_char(next) => {
+            self.column += 1;
+            Err(self.unexpected_error("alphabetic character", format!("'{}'", next)))
+         }
+         (Some('\''), _) => {
             self.inc_pos_col();
+            let ident = try!(self.parse_ident_stack());
+            Ok(Symbol(SymbolAst::new(ident.value)))
          }
-         let string: &str = buf.as_slice();
-         if string == "nil" {
-            Ok(Nil(NilAst::new()))
+         (Some(ch), _) => Err(self.unexpected_error("\"'\"", format!("'{}'", ch)))
+      }
+   }
+
+   // Skips whitespace and comments, recording the comments as coming before the item at
+   // the given index.
+   fn skip_trivia(&mut self, trivia: &mut Trivia, idx: uint) -> ParseResult<()> {
+      loop {
+         self.skip_whitespace();
+         if self.current() == Some(';') {
+            let comment = try!(self.parse_comment());
+            trivia.comments.push((idx, comment));
          } else {
-            Err(self.unexpected_error("\"nil\"", format!("\"{}\"", string)))
+            return Ok(());
          }
       }
    }
 
-   fn parse_symbol(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
+   fn parse_comment(&mut self) -> ParseResult<String> {
       self.skip_whitespace();
-      if self.pos + 1 >= code.len() {
-         Err(self.eof_error())
-      } else if !self.is_ident_char(code.char_at(self.pos + 1)) {
-         self.column += 1;
-         Err(self.unexpected_error("alphabetic character", format!("'{}'", code.char_at(self.pos + 1))))
-      } else if code.char_at(self.pos) == '\'' {
-         self.inc_pos_col();
-         let ident = try!(self.parse_ident_stack());
-         Ok(Symbol(SymbolAst::new(ident.value)))
-      } else {
-         Err(self.unexpected_error("\"'\"", format!("'{}'", code.char_at(self.pos))))
+      match self.current() {
+         None => Err(self.eof_error()),
+         Some(';') => {
+            self.inc_pos_col();
+            let mut buf = String::new();
+            loop {
+               match self.current() {
+                  Some(ch) if ch != '\n' => {
+                     buf.push_char(ch);
+                     self.inc_char(ch);
+                  }
+                  _ => return Ok(buf)
+               }
+            }
+         }
+         Some(ch) => Err(self.unexpected_error("';'", format!("'{}'", ch)))
       }
    }
 
-   fn parse_comment(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
-      if self.pos == code.len() {
-         Err(self.eof_error())
-      } else if code.char_at(self.pos) == ';' {
-         self.inc_pos_col();
-         let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos) != '\n' {
-            buf.push_char(code.char_at(self.pos));
-            self.inc_pos_col();
+   // Reads a string for tokenize().  A string that is not closed, or whose interpolated
+   // code is invalid, runs to the end of the code.
+   fn string_token(&mut self) -> TokenKind {
+      match self.parse_string() {
+         Ok(_) => TokenString,
+         Err(_) => {
+            self.pos = self.code.len();
+            TokenInvalid
+         }
+      }
+   }
+
+   // Skips the characters that can be part of an identifier or number.
+   fn skip_word(&mut self) {
+      loop {
+         match self.current() {
+            Some(ch) if self.is_ident_char(ch) => self.inc_char(ch),
+            _ => return
          }
-         Ok(Comment(CommentAst::new(buf)))
-      } else {
-         Err(self.unexpected_error("';'", format!("'{}'", code.char_at(self.pos))))
       }
    }
 
    #[inline(always)]
+   // Whether ch starts a name rather than a number or some other kind of expression.
+   fn starts_name(&self, ch: char) -> bool {
+      self.is_ident_char(ch) && !ch.is_digit() && !(ch == '-' && self.next_char().map_or(false, |next| next.is_digit()))
+   }
+
    fn is_ident_char(&self, ch: char) -> bool {
-      if ch.is_digit() || ch.is_whitespace() || ch == '(' || ch == ')' || ch == '[' || ch == ']' || ch == '\'' || ch == '"' || ch == ';' {
+      if ch.is_whitespace() || ch == '(' || ch == ')' || ch == '[' || ch == ']' || ch == '{' || ch == '}' || ch == '\'' || ch == '"' || ch == ';' {
          false
       } else {
          true
       }
    }
 
+   // The character at the current position, or None at the end of the code.  The
+   // position is always at the start of a character, as it only moves by whole characters.
+   #[inline(always)]
+   fn current(&self) -> Option<char> {
+      self.rest().chars().next()
+   }
+
+   // The character after the current one.
+   #[inline(always)]
+   fn next_char(&self) -> Option<char> {
+      let mut chars = self.rest().chars();
+      chars.next();
+      chars.next()
+   }
+
+   #[inline(always)]
+   fn rest<'a>(&'a self) -> &'a str {
+      self.code.as_slice().slice_from(self.pos)
+   }
+
+   // What is at the current position, for error messages.
+   fn found(&self) -> String {
+      match self.current() {
+         Some(ch) => format!("'{}'", ch),
+         None => "end of file".to_string()
+      }
+   }
+
    #[inline(always)]
    fn skip_whitespace(&mut self) {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      while self.pos < code.len() && code.char_at(self.pos).is_whitespace() {
-         if code.char_at(self.pos) == '\n' {
-            self.add_line();
-         } else {
-            self.column += 1;
+      loop {
+         match self.current() {
+            Some('\n') => {
+               self.add_line();
+               self.pos += 1;
+            }
+            Some(ch) if ch.is_whitespace() => self.inc_char(ch),
+            _ => return
          }
-         self.pos += 1;
       }
    }
 
@@ -415,6 +824,13 @@ impl Parser {
       self.pos += 1;
    }
 
+   // Like inc_pos_col(), but for characters that might take up more than one byte.
+   #[inline(always)]
+   fn inc_char(&mut self, ch: char) {
+      self.column += 1;
+      self.pos += ch.len_utf8_bytes();
+   }
+
    #[inline(always)]
    fn eof_error(&self) -> ParseError {
       ParseError::new(self.line, self.column, "end of file".to_string())
@@ -427,6 +843,55 @@ impl Parser {
 
    #[inline(always)]
    fn unexpected_error<T: Str, U: Str>(&self, expect: T, found: U) -> ParseError {
-      ParseError::new(self.line, self.column, format!("expected {} but found {}", expect.as_slice(), found.as_slice()))
+      let mut error = ParseError::new(self.line, self.column, format!("expected {} but found {}", expect.as_slice(), found.as_slice()));
+      error.unexpected = Some((expect.as_slice().to_string(), found.as_slice().to_string()));
+      error
+   }
+}
+
+// The error for code that is not valid UTF-8, at the first byte that is not part of a
+// valid character.
+fn invalid_utf8_error(bytes: &[u8]) -> ParseError {
+   let mut pos = 0;
+   while pos < bytes.len() {
+      let width = str::utf8_char_width(bytes[pos]);
+      if width == 0 || pos + width > bytes.len() || str::from_utf8(bytes.slice(pos, pos + width)).is_none() {
+         break;
+      }
+      pos += width;
+   }
+   let valid = str::from_utf8(bytes.slice_to(pos)).unwrap();
+   let line_start = valid.rfind('\n').map_or(0, |idx| idx + 1);
+   let line = valid.chars().filter(|&ch| ch == '\n').count() + 1;
+   let column = valid.slice_from(line_start).char_len() + 1;
+   let desc = match bytes.get(pos) {
+      Some(byte) => format!("invalid UTF-8 (byte 0x{:02x})", *byte),
+      None => "invalid UTF-8".to_string()
+   };
+   ParseError::new(line, column, desc)
+}
+
+#[cfg(test)]
+mod test {
+   use super::{Parser, TokenKind, TokenComment, TokenDelimiter, TokenInvalid, TokenOperator, TokenString, TokenSymbol};
+
+   // The kind, line, column, start and end of each token of the code.
+   fn tokens(code: &str) -> Vec<(TokenKind, uint, uint, uint, uint)> {
+      Parser::tokenize(code).iter().map(|token| (token.kind, token.span.line, token.span.column, token.span.start, token.span.end)).collect()
+   }
+
+   #[test]
+   fn tokenize_gives_the_kind_and_span_of_each_token() {
+      assert_eq!(tokens("(f r\"C:\\dir\" :key 'sym)\n; note\n, \"open"), vec!(
+         (TokenDelimiter, 1, 1, 0, 1),
+         (TokenOperator, 1, 2, 1, 2),
+         (TokenString, 1, 4, 3, 12),
+         (TokenSymbol, 1, 14, 13, 17),
+         (TokenSymbol, 1, 19, 18, 22),
+         (TokenDelimiter, 1, 23, 22, 23),
+         (TokenComment, 2, 1, 24, 30),
+         (TokenInvalid, 3, 1, 31, 32),
+         (TokenInvalid, 3, 3, 33, 38)
+      ));
    }
 }
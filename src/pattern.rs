@@ -0,0 +1,78 @@
+use ast::*;
+use interp::predicate_name;
+
+// Checks whether value has the shape described by pat, pushing the identifiers bound
+// by pat onto bindings.  Supported patterns are:
+//    _                 matches anything
+//    name              matches anything and binds it to name
+//    literal           matches values equal to the literal
+//    [a b rest...]     matches arrays, binding the remaining items to rest
+//    (a b)             matches tuples made by values, binding each of their items
+//    (integer? pat)    matches values of the given type that also match pat
+pub fn match_pattern(pat: &ExprAst, value: &ExprAst, bindings: &mut Vec<(String, ExprAst)>) -> Result<bool, String> {
+   match *pat {
+      Ident(ref ast) => {
+         if ast.value.as_slice() != "_" {
+            bindings.push((ast.value.clone(), value.clone()));
+         }
+         Ok(true)
+      }
+      Array(ref ast) => match *value {
+         Array(ref arr) => match_items(&ast.items, &arr.items, bindings),
+         _ => Ok(false)
+      },
+      Sexpr(ref ast) => {
+         let op = ast.op.value.as_slice();
+         if !op.ends_with("?") {
+            let mut pats = vec!(Ident(ast.op.clone()));
+            pats.push_all(ast.operands.as_slice());
+            return match *value {
+               Tuple(ref tuple) => match_items(&pats, &tuple.items, bindings),
+               _ => Ok(false)
+            };
+         }
+         if ast.operands.len() > 1 {
+            return Err(format!("{} is not a valid pattern", pat.to_source()));
+         }
+         if predicate_name(value) != Some(op) {
+            return Ok(false);
+         }
+         match ast.operands.as_slice().get(0) {
+            Some(inner) => match_pattern(inner, value, bindings),
+            None => Ok(true)
+         }
+      }
+      Comment(_) | Code(_) | Root(_) | Pointer(_) | Error(_) =>
+         Err(format!("{} is not a valid pattern", pat.to_source())),
+      ref literal => Ok(literal == value)
+   }
+}
+
+fn match_items(pats: &Vec<ExprAst>, items: &Vec<ExprAst>, bindings: &mut Vec<(String, ExprAst)>) -> Result<bool, String> {
+   for (idx, pat) in pats.iter().enumerate() {
+      match *pat {
+         Ident(ref ast) if ast.value.as_slice().ends_with("...") => {
+            if idx + 1 != pats.len() {
+               return Err(format!("{} must be the last item in an array pattern", ast.value));
+            }
+            if items.len() < idx {
+               return Ok(false);
+            }
+            let name = ast.value.as_slice().slice_to(ast.value.len() - 3);
+            if name != "_" {
+               let rest = items.slice_from(idx).to_vec();
+               bindings.push((name.to_string(), Array(ArrayAst::new(rest))));
+            }
+            return Ok(true);
+         }
+         _ => {}
+      }
+      match items.as_slice().get(idx) {
+         Some(item) => if !try!(match_pattern(pat, item, bindings)) {
+            return Ok(false);
+         },
+         None => return Ok(false)
+      }
+   }
+   Ok(pats.len() == items.len())
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::fs;
+use std::os;
+
+// Libraries installed with iron pkg install, which import finds by name: (import "foo")
+// loads the main file of the package foo and (import "foo/bar") loads bar.irl inside it.
+// Packages are kept in $IRON_PACKAGES, or ~/.iron/packages if that is not set, each in a
+// directory named after the package.
+//
+// A package describes itself in an iron.toml file, which uses a small part of TOML:
+//
+//    name = "foo"
+//    version = "0.1.0"
+//    main = "src/foo.irl"    # optional, foo.irl by default
+//
+//    [dependencies]
+//    bar = "https://example.com/bar.git"
+//    baz = "../baz"
+//
+// Dependencies are installed along with the package unless a package of that name is
+// already installed.  A path is relative to the directory of the manifest.
+
+pub static MANIFEST: &'static str = "iron.toml";
+
+pub struct Manifest {
+   pub name: Option<String>,
+   pub version: Option<String>,
+   pub main: Option<String>,
+   pub dependencies: HashMap<String, String>
+}
+
+pub fn package_dir() -> Path {
+   match os::getenv("IRON_PACKAGES") {
+      Some(dir) => Path::new(dir),
+      None => os::homedir().unwrap_or(Path::new(".")).join_many([".iron", "packages"])
+   }
+}
+
+// The file loaded by importing a package name such as "foo" or "foo/bar".
+pub fn find_module(name: &str) -> Result<Path, String> {
+   let (package, rest) = match name.find('/') {
+      Some(idx) => (name.slice_to(idx), name.slice_from(idx + 1)),
+      None => (name, "")
+   };
+   let dir = package_dir().join(package);
+   if !dir.is_dir() {
+      return Err(format!("no package named {} is installed (see iron pkg install)", package));
+   }
+   let mut path = if rest.is_empty() {
+      let manifest = try!(read_manifest(&dir.join(MANIFEST)));
+      match manifest.main {
+         Some(main) => dir.join(main),
+         None => dir.join(package)
+      }
+   } else {
+      dir.join(rest)
+   };
+   if path.extension().is_none() {
+      path.set_extension("irl");
+   }
+   Ok(path)
+}
+
+// The line without the comment at its end, if any: a # that is not inside a string starts
+// a comment.
+fn strip_comment(line: &str) -> &str {
+   let mut quoted = false;
+   for (idx, ch) in line.char_indices() {
+      match ch {
+         '"' => quoted = !quoted,
+         '#' if !quoted => return line.slice_to(idx),
+         _ => {}
+      }
+   }
+   line
+}
+
+pub fn parse_manifest(text: &str) -> Result<Manifest, String> {
+   let mut manifest = Manifest {
+      name: None,
+      version: None,
+      main: None,
+      dependencies: HashMap::new()
+   };
+   let mut section = String::new();
+   for (num, line) in text.lines().enumerate() {
+      let line = strip_comment(line).trim();
+      if line.is_empty() {
+         continue;
+      }
+      if line.starts_with("[") && line.ends_with("]") {
+         section = line.slice(1, line.len() - 1).trim().to_string();
+         continue;
+      }
+      let (key, value) = match line.find('=') {
+         Some(idx) => (line.slice_to(idx).trim(), line.slice_from(idx + 1).trim()),
+         None => return Err(format!("line {}: expected key = \"value\"", num + 1))
+      };
+      if value.len() < 2 || !value.starts_with("\"") || !value.ends_with("\"") {
+         return Err(format!("line {}: the value of {} must be a string", num + 1, key));
+      }
+      let value = value.slice(1, value.len() - 1).to_string();
+      match (section.as_slice(), key) {
+         ("", "name") | ("package", "name") => manifest.name = Some(value),
+         ("", "version") | ("package", "version") => manifest.version = Some(value),
+         ("", "main") | ("package", "main") => manifest.main = Some(value),
+         ("dependencies", _) => { manifest.dependencies.insert(key.to_string(), value); }
+         _ => return Err(format!("line {}: unknown key {}", num + 1, key))
+      }
+   }
+   Ok(manifest)
+}
+
+// Reads the manifest at path, which is treated as empty if it does not exist.
+pub fn read_manifest(path: &Path) -> Result<Manifest, String> {
+   if !path.exists() {
+      return parse_manifest("");
+   }
+   match io::File::open(path).read_to_string() {
+      Ok(text) => parse_manifest(text.as_slice()).map_err(|f| format!("{}: {}", path.display(), f)),
+      Err(f) => Err(format!("could not read {}: {}", path.display(), f))
+   }
+}
+
+fn is_git_url(source: &str) -> bool {
+   source.contains("://") || source.starts_with("git@") || source.ends_with(".git")
+}
+
+// Installs the package at source, a git URL or a directory, and its dependencies, and
+// returns its name.
+pub fn install(source: &str) -> Result<String, String> {
+   let packages = package_dir();
+   match fs::mkdir_recursive(&packages, io::UserDir) {
+      Ok(()) => {}
+      Err(f) => return Err(format!("could not create {}: {}", packages.display(), f))
+   }
+   let dir = if is_git_url(source) {
+      let dir = packages.join(".download");
+      if dir.exists() {
+         let _ = fs::rmdir_recursive(&dir);
+      }
+      match io::Command::new("git").arg("clone").arg("--depth").arg("1").arg(source).arg(&dir).output() {
+         Ok(ref output) if output.status.success() => {}
+         Ok(output) => return Err(format!("git clone {} failed:\n{}", source, String::from_utf8_lossy(output.error.as_slice()))),
+         Err(f) => return Err(format!("could not run git: {}", f))
+      }
+      let _ = fs::rmdir_recursive(&dir.join(".git"));
+      dir
+   } else {
+      let dir = os::make_absolute(&Path::new(source));
+      if !dir.is_dir() {
+         return Err(format!("{} is not a directory or a git URL", source));
+      }
+      dir
+   };
+   let manifest = try!(read_manifest(&dir.join(MANIFEST)));
+   let name = match manifest.name {
+      Some(name) => name,
+      None if !is_git_url(source) => dir.filename_str().unwrap_or("").to_string(),
+      None => {
+         let base = source.trim_right_chars('/').rsplitn('/', 1).next().unwrap_or("");
+         if base.ends_with(".git") { base.slice_to(base.len() - 4) } else { base }.to_string()
+      }
+   };
+   if name.is_empty() || name.as_slice().contains_char('/') || name.as_slice().starts_with(".") {
+      return Err(format!("{} is not a valid package name", name));
+   }
+   // relative dependencies are found next to the directory the package came from
+   let base = if is_git_url(source) { packages.join(name.as_slice()) } else { dir.clone() };
+   let target = packages.join(name.as_slice());
+   if target.exists() {
+      match fs::rmdir_recursive(&target) {
+         Ok(()) => {}
+         Err(f) => return Err(format!("could not remove the installed {}: {}", name, f))
+      }
+   }
+   let result = if is_git_url(source) {
+      fs::rename(&dir, &target)
+   } else {
+      copy_dir(&dir, &target)
+   };
+   match result {
+      Ok(()) => {}
+      Err(f) => return Err(format!("could not install {} to {}: {}", name, target.display(), f))
+   }
+   for (dep, dep_source) in manifest.dependencies.iter() {
+      if packages.join(dep.as_slice()).is_dir() {
+         continue;
+      }
+      let dep_source = if is_git_url(dep_source.as_slice()) {
+         dep_source.clone()
+      } else {
+         base.join(dep_source.as_slice()).as_str().unwrap().to_string()
+      };
+      try!(install(dep_source.as_slice()));
+   }
+   Ok(name)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::IoResult<()> {
+   try!(fs::mkdir_recursive(to, io::UserDir));
+   for path in try!(fs::readdir(from)).iter() {
+      let target = to.join(path.filename().unwrap());
+      if path.is_dir() {
+         if path.filename_str() != Some(".git") {
+            try!(copy_dir(path, &target));
+         }
+      } else {
+         try!(fs::copy(path, &target));
+      }
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod test {
+   use std::io;
+   use std::io::fs;
+   use std::os;
+
+   use super::{find_module, install, parse_manifest};
+
+   #[test]
+   fn manifests_have_a_package_and_a_dependencies_section() {
+      let manifest = parse_manifest("# a comment\nname = \"foo\"\n\n[package]\nversion = \"0.1.0\" # another\nmain = \"src/foo.irl\"\n\n[dependencies]\nbar = \"../bar\"\n").unwrap();
+      assert_eq!(manifest.name, Some("foo".to_string()));
+      assert_eq!(manifest.version, Some("0.1.0".to_string()));
+      assert_eq!(manifest.main, Some("src/foo.irl".to_string()));
+      assert_eq!(manifest.dependencies.find_equiv(&"bar"), Some(&"../bar".to_string()));
+      assert_eq!(parse_manifest("name = foo").err(), Some("line 1: the value of name must be a string".to_string()));
+      assert_eq!(parse_manifest("owner = \"me\"").err(), Some("line 1: unknown key owner".to_string()));
+   }
+
+   #[test]
+   fn installed_packages_and_their_dependencies_are_found_by_name() {
+      let root = os::tmpdir().join("iron-pkg");
+      let _ = fs::rmdir_recursive(&root);
+      let packages = root.join("packages");
+      os::setenv("IRON_PACKAGES", packages.as_str().unwrap());
+      for &(file, text) in [("greet/iron.toml", "name = \"greet\"\nmain = \"lib.irl\"\n\n[dependencies]\nhelper = \"../helper\"\n"),
+                            ("greet/lib.irl", "(define greeting \"hello\")"),
+                            ("greet/other.irl", "(define other 1)"),
+                            ("helper/helper.irl", "(define helped true)")].iter() {
+         let path = root.join("sources").join(file);
+         fs::mkdir_recursive(&path.dir_path(), io::UserDir).unwrap();
+         io::File::create(&path).write_str(text).unwrap();
+      }
+      assert_eq!(install(root.join_many(["sources", "greet"]).as_str().unwrap()), Ok("greet".to_string()));
+      // the main file comes from the manifest, or is named after the package without one
+      assert!(find_module("greet") == Ok(packages.join_many(["greet", "lib.irl"])));
+      assert!(find_module("greet/other") == Ok(packages.join_many(["greet", "other.irl"])));
+      assert!(find_module("helper") == Ok(packages.join_many(["helper", "helper.irl"])));
+      assert!(fs::stat(&packages.join_many(["helper", "helper.irl"])).is_ok());
+      assert_eq!(find_module("missing").err(), Some("no package named missing is installed (see iron pkg install)".to_string()));
+   }
+}
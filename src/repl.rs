@@ -0,0 +1,125 @@
+use ast::*;
+use color;
+use editor::Editor;
+use interp::{Environment, Interpreter, Value, SPECIAL_FORMS, flush_stdout, type_name};
+use parser::Parser;
+
+static PROMPT: &'static str = "iron> ";
+static CONTINUE_PROMPT: &'static str = "  ... ";
+
+// Reads expressions from the terminal and prints their values until the end of input.
+// An entry that is not a complete expression yet is continued on the next line.
+pub fn run(interp: &mut Interpreter) {
+   let mut editor = Editor::new(Editor::default_history_path());
+   let mut entry = String::new();
+   loop {
+      flush_stdout();
+      let prompt = if entry.len() == 0 { PROMPT } else { CONTINUE_PROMPT };
+      let names = completions(interp);
+      let line = match editor.read_line(prompt, names.as_slice()) {
+         Some(line) => line,
+         None => break
+      };
+      if entry.len() == 0 && line.as_slice().trim_left().starts_with(":") {
+         editor.add_history(line.as_slice());
+         match command(interp, line.as_slice().trim()) {
+            Quit => break,
+            Continue => continue
+         }
+      }
+      entry.push_str(line.as_slice());
+      entry.push_char('\n');
+      let ast = match Parser::new().try_parse_code(entry.clone()) {
+         Ok(ast) => ast,
+         Err(ref f) if f.is_eof() => continue,
+         Err(f) => {
+            println!("{}", f);
+            editor.add_history(entry.as_slice());
+            entry.truncate(0);
+            continue;
+         }
+      };
+      editor.add_history(entry.as_slice());
+      entry.truncate(0);
+      match interp.eval_ast(&ast) {
+         Ok(Nil(_)) => {}
+         Ok(value) => println!("{}", color::highlight(&value)),
+         Err(f) => println!("{}", f)
+      }
+   }
+   editor.save_history();
+}
+
+enum CommandResult {
+   Continue,
+   Quit
+}
+
+// Runs a REPL command such as :env.  These are handled here rather than being part of
+// the language.
+fn command(interp: &mut Interpreter, line: &str) -> CommandResult {
+   let (name, arg) = match line.find(|ch: char| ch.is_whitespace()) {
+      Some(idx) => (line.slice_to(idx), line.slice_from(idx).trim()),
+      None => (line, "")
+   };
+   match name {
+      ":quit" | ":q" => return Quit,
+      ":help" => {
+         println!(":env           list the bindings defined in this session");
+         println!(":type EXPR     show the type of EXPR without printing its value");
+         println!(":load FILE     run FILE in this session");
+         println!(":reset         forget everything defined in this session");
+         println!(":quit          leave the REPL");
+      }
+      ":env" => {
+         let env = interp.env.clone();
+         let global = env.borrow();
+         for name in Environment::names(interp.env.clone()).iter() {
+            match global.values.find(name) {
+               Some(&Value(ref value)) if name.as_slice() != "FILE" =>
+                  println!("{} = {}", name, value.to_source()),
+               _ => {}
+            }
+         }
+      }
+      ":type" => match Parser::new().try_parse_code(arg.to_string()) {
+         Ok(ast) => match interp.eval_ast(&ast) {
+            Ok(value) => println!("{}", type_name(&value).unwrap_or("unknown")),
+            Err(f) => println!("{}", f)
+         },
+         Err(f) => println!("{}", f)
+      },
+      ":load" => {
+         if arg.len() == 0 {
+            println!(":load needs a file name");
+         } else {
+            let path = Path::new(arg);
+            match interp.load_file(&path) {
+               Ok(()) => {
+                  interp.set_file(arg.to_string());
+                  match interp.run() {
+                     Ok(_) => {}
+                     Err(f) => println!("{}", f)
+                  }
+                  interp.set_file("<repl>".to_string());
+               }
+               Err(f) => println!("could not load {}: {}", arg, f)
+            }
+         }
+      }
+      ":reset" => {
+         interp.reset();
+         interp.set_file("<repl>".to_string());
+      }
+      other => println!("unknown command {} (try :help)", other)
+   }
+   Continue
+}
+
+fn completions(interp: &Interpreter) -> Vec<String> {
+   let mut names = Environment::names(interp.env.clone());
+   names.extend(SPECIAL_FORMS.iter().map(|name| name.to_string()));
+   names.sort();
+   names.dedup();
+   names
+}
@@ -0,0 +1,156 @@
+use std::io;
+use serialize::json;
+
+// Recording and replaying the inputs a program reads from outside the interpreter, for
+// iron --record LOG and iron --replay LOG, so that a run can be reproduced exactly from a
+// bug report.  Every such read goes through Inputs::input, which in a live run just does
+// the read, while recording also appends it to the log and while replaying returns the
+// next value from the log instead.
+//
+// The log holds one JSON object per line: {"kind":"file","key":"lib/std.irl","value":...}.
+// For now the only inputs are the files read by import, read-lines and with-lines;
+// builtins that read the clock, random numbers or stdin must go through Inputs::input as
+// well when they are added.
+
+#[deriving(Clone, PartialEq)]
+pub struct Input {
+   pub kind: String,
+   pub key: String,
+   pub value: String
+}
+
+#[deriving(Clone, PartialEq)]
+pub enum Inputs {
+   Live,
+   Recording(Path),
+   Replaying(Vec<Input>, uint)
+}
+
+impl Inputs {
+   // Starts recording to the file at path, emptying it.
+   pub fn record(path: &Path) -> Result<Inputs, String> {
+      match io::File::create(path) {
+         Ok(_) => Ok(Recording(path.clone())),
+         Err(f) => Err(format!("could not create {}: {}", path.display(), f))
+      }
+   }
+
+   pub fn replay(path: &Path) -> Result<Inputs, String> {
+      let text = match io::File::open(path).read_to_string() {
+         Ok(text) => text,
+         Err(f) => return Err(format!("could not read {}: {}", path.display(), f))
+      };
+      let mut inputs = vec!();
+      for (num, line) in text.as_slice().lines().enumerate() {
+         if line.trim().is_empty() {
+            continue;
+         }
+         let object = match json::from_str(line) {
+            Ok(json::Object(object)) => object,
+            _ => return Err(format!("{}:{}: expected a JSON object", path.display(), num + 1))
+         };
+         let field = |name: &str| match object.find(&name.to_string()) {
+            Some(&json::String(ref value)) => Ok(value.clone()),
+            _ => Err(format!("{}:{}: missing the string {}", path.display(), num + 1, name))
+         };
+         inputs.push(Input {
+            kind: try!(field("kind")),
+            key: try!(field("key")),
+            value: try!(field("value"))
+         });
+      }
+      Ok(Replaying(inputs, 0))
+   }
+
+   pub fn is_live(&self) -> bool {
+      match *self {
+         Live => true,
+         _ => false
+      }
+   }
+
+   // Reads an input of the given kind, named by key, with read unless it is being
+   // replayed.
+   pub fn input(&mut self, kind: &str, key: &str, read: || -> Result<String, String>) -> Result<String, String> {
+      match *self {
+         Live => read(),
+         Recording(ref path) => {
+            let value = try!(read());
+            let line = format!("{{\"kind\":{},\"key\":{},\"value\":{}}}",
+                               json::String(kind.to_string()), json::String(key.to_string()), json::String(value.clone()));
+            match io::File::open_mode(path, io::Append, io::Write).and_then(|mut file| file.write_line(line.as_slice())) {
+               Ok(()) => Ok(value),
+               Err(f) => Err(format!("could not record to {}: {}", path.display(), f))
+            }
+         }
+         Replaying(ref inputs, ref mut pos) => {
+            if *pos >= inputs.len() {
+               return Err(format!("replay diverged: the log has no more inputs, but the program read {} {}", kind, key));
+            }
+            let input = &inputs[*pos];
+            if input.kind.as_slice() != kind || input.key.as_slice() != key {
+               return Err(format!("replay diverged: the log has {} {} next, but the program read {} {}", input.kind, input.key, kind, key));
+            }
+            *pos += 1;
+            Ok(input.value.clone())
+         }
+      }
+   }
+
+   pub fn read_file(&mut self, path: &Path) -> Result<String, String> {
+      self.input("file", path.display().to_string().as_slice(), || match io::File::open(path).read_to_string() {
+         Ok(text) => Ok(text),
+         Err(f) => Err(f.to_string())
+      })
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use std::cell::RefCell;
+   use std::io;
+   use std::io::fs;
+   use std::os;
+   use std::rc::Rc;
+
+   use ast::Ast;
+   use interp::Interpreter;
+   use super::{Inputs, Live};
+
+   // The value of (import "./data") data run from main.irl in dir with the given inputs.
+   fn run(dir: &Path, inputs: Inputs) -> String {
+      let mut interp = Interpreter::new();
+      interp.set_inputs(Rc::new(RefCell::new(inputs)));
+      interp.load_code("(import \"./data\") data".to_string());
+      interp.set_file(dir.join("main.irl").as_str().unwrap().to_string());
+      interp.run().unwrap().to_source()
+   }
+
+   #[test]
+   fn replays_read_the_files_recorded_rather_than_the_files_now() {
+      let dir = os::tmpdir().join("iron-replay");
+      fs::mkdir_recursive(&dir, io::UserDir).unwrap();
+      let log = dir.join("inputs.log");
+      io::File::create(&dir.join("data.irl")).write_str("(define data 1)").unwrap();
+      assert_eq!(run(&dir, Inputs::record(&log).unwrap()).as_slice(), "1");
+      io::File::create(&dir.join("data.irl")).write_str("(define data 2)").unwrap();
+      assert_eq!(run(&dir, Inputs::replay(&log).unwrap()).as_slice(), "1");
+      assert_eq!(run(&dir, Live).as_slice(), "2");
+   }
+
+   #[test]
+   fn replays_fail_once_the_program_reads_something_else() {
+      let dir = os::tmpdir().join("iron-replay-diverged");
+      fs::mkdir_recursive(&dir, io::UserDir).unwrap();
+      let log = dir.join("inputs.log");
+      let mut recording = Inputs::record(&log).unwrap();
+      assert_eq!(recording.input("clock", "now", || Ok("12".to_string())), Ok("12".to_string()));
+      let mut replaying = Inputs::replay(&log).unwrap();
+      assert_eq!(replaying.input("clock", "today", || Ok("13".to_string())).err(),
+                 Some("replay diverged: the log has clock now next, but the program read clock today".to_string()));
+      let mut replaying = Inputs::replay(&log).unwrap();
+      assert_eq!(replaying.input("clock", "now", || Ok("13".to_string())), Ok("12".to_string()));
+      assert_eq!(replaying.input("clock", "now", || Ok("13".to_string())).err(),
+                 Some("replay diverged: the log has no more inputs, but the program read clock now".to_string()));
+   }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::mem;
+
+use ast::*;
+use typecheck;
+
+// Rewrites references to function parameters into their position in the environment of
+// the call that binds them (see LocalAst), so that they are read without searching for
+// them by name.  A function keeps its parameters in slots when they are all plain names,
+// optionally ending with a rest parameter.  A reference is only rewritten when nothing can
+// bind the same name between it and the parameter: names bound in a function's body (by
+// define, defconst, defn, let, letrec, match, with-open, catch, defprotocol or import)
+// are always looked up by name within that function, and so is everything under quote or
+// deftest.
+
+// The names a function's parameters are bound to, if they can be kept in slots.
+pub fn slot_names(params: &ArrayAst) -> Option<Vec<String>> {
+   let mut names = vec!();
+   for (idx, param) in params.items.iter().enumerate() {
+      match *param {
+         Ident(ref ast) => {
+            let name = ast.value.as_slice();
+            if name.ends_with("...") {
+               if idx + 1 != params.items.len() {
+                  return None;
+               }
+               names.push(name.slice_to(name.len() - 3).to_string());
+            } else {
+               names.push(name.to_string());
+            }
+         }
+         _ => return None
+      }
+   }
+   Some(names)
+}
+
+// A function whose body is being resolved.
+struct Scope {
+   // the parameters if they are kept in slots
+   slots: Option<Vec<String>>,
+   // every name bound by the parameters or in the body
+   bound: HashSet<String>,
+   // true if the body imports a module, which could bind any name
+   opaque: bool
+}
+
+pub fn resolve(root: RootAst) -> RootAst {
+   let mut root = root;
+   let mut scopes = vec!();
+   root.asts = resolve_all(root.asts, &mut scopes);
+   root
+}
+
+fn resolve_all(exprs: Vec<ExprAst>, scopes: &mut Vec<Scope>) -> Vec<ExprAst> {
+   exprs.move_iter().map(|expr| resolve_expr(expr, scopes)).collect()
+}
+
+fn resolve_expr(expr: ExprAst, scopes: &mut Vec<Scope>) -> ExprAst {
+   match expr {
+      Ident(ast) => lookup(ast, scopes.as_slice()),
+      Array(mut ast) => {
+         ast.items = resolve_all(ast.items, scopes);
+         Array(ast)
+      }
+      Sexpr(ast) => Sexpr(resolve_sexpr(ast, scopes)),
+      other => other
+   }
+}
+
+fn lookup(ast: IdentAst, scopes: &[Scope]) -> ExprAst {
+   for (depth, scope) in scopes.iter().rev().enumerate() {
+      if scope.opaque {
+         break;
+      }
+      match scope.slots {
+         Some(ref slots) => match slots.iter().position(|name| name == &ast.value) {
+            Some(_) if scope.bound.contains(&ast.value) => break,
+            Some(index) => return Local(LocalAst::new(ast.value, depth, index)),
+            None => {}
+         },
+         None => {}
+      }
+      if scope.bound.contains(&ast.value) {
+         break;
+      }
+   }
+   Ident(ast)
+}
+
+fn resolve_sexpr(sast: SexprAst, scopes: &mut Vec<Scope>) -> SexprAst {
+   let mut sast = sast;
+   let catch_name = sast.catch_name_index();
+   let operands = mem::replace(&mut sast.operands, vec!());
+   sast.operands = match sast.op.value.as_slice() {
+      "quote" | "deftest" | "defprotocol" | "defmacro" => operands,
+      "fn" => match operands.as_slice().get(0) {
+         Some(&Array(_)) => {
+            let mut operands = operands.move_iter();
+            let params = match operands.next() {
+               Some(Array(params)) => params,
+               _ => unreachable!()
+            };
+            let mut result = vec!(Array(params.clone()));
+            result.push_all_move(resolve_function(&params, operands.collect(), scopes));
+            result
+         }
+         _ => operands.move_iter().map(|operand| match operand {
+            List(mut clause) => {
+               let params = match clause.items.as_slice().get(0) {
+                  Some(&Array(ref params)) => Some(params.clone()),
+                  _ => None
+               };
+               match params {
+                  Some(params) => {
+                     let body = clause.items.slice_from(1).to_vec();
+                     clause.items.truncate(1);
+                     clause.items.push_all_move(resolve_function(&params, body, scopes));
+                  }
+                  None => {}
+               }
+               List(clause)
+            }
+            other => other
+         }).collect()
+      },
+      "let" | "letrec" | "with-open" | "parameterize" => {
+         let mut operands = operands.move_iter();
+         let mut result = vec!();
+         match operands.next() {
+            Some(Array(mut bindings)) => {
+               bindings.items = bindings.items.move_iter().enumerate().map(|(idx, item)| {
+                  if idx % 2 == 1 { resolve_expr(item, scopes) } else { item }
+               }).collect();
+               result.push(Array(bindings));
+            }
+            Some(other) => result.push(other),
+            None => {}
+         }
+         result.push_all_move(resolve_all(operands.collect(), scopes));
+         result
+      }
+      // the patterns are not evaluated
+      "match" => operands.move_iter().enumerate().map(|(idx, operand)| {
+         if idx == 0 || idx % 2 == 0 { resolve_expr(operand, scopes) } else { operand }
+      }).collect(),
+      // the name being defined or set, or the variable caught, is not evaluated
+      "define" | "defglobal" | "defconst" | "defdynamic" | "set" => resolve_rest(operands, 1, scopes),
+      // (catch :kind name ...) also skips the kind
+      "catch" => resolve_rest(operands, catch_name + 1, scopes),
+      // resolved as the fn it stands for, keeping the form as written
+      "defn" => match SexprAst::new(sast.op.clone(), operands.clone()).expand_defn() {
+         Some(mut define) => {
+            let function = match define.operands.pop() {
+               Some(Sexpr(function)) => resolve_sexpr(function, scopes),
+               _ => unreachable!()
+            };
+            let mut result = define.operands;
+            result.push_all_move(function.operands);
+            result
+         }
+         None => operands
+      },
+      "extend" => resolve_rest(operands, 2, scopes),
+      _ => resolve_all(operands, scopes)
+   };
+   sast
+}
+
+fn resolve_rest(operands: Vec<ExprAst>, skip: uint, scopes: &mut Vec<Scope>) -> Vec<ExprAst> {
+   operands.move_iter().enumerate().map(|(idx, operand)| {
+      if idx < skip { operand } else { resolve_expr(operand, scopes) }
+   }).collect()
+}
+
+// Resolves the body of a function with the given parameters.
+fn resolve_function(params: &ArrayAst, body: Vec<ExprAst>, scopes: &mut Vec<Scope>) -> Vec<ExprAst> {
+   let params = typecheck::strip_annotations(params.clone());
+   let mut scope = Scope {
+      slots: slot_names(&params),
+      bound: HashSet::new(),
+      opaque: false
+   };
+   for param in params.items.iter() {
+      collect_pattern(param, &mut scope.bound);
+   }
+   // the bound names are those of the parameters that are not kept in slots
+   match scope.slots {
+      Some(ref slots) => for name in slots.iter() {
+         scope.bound.remove(name);
+      },
+      None => {}
+   }
+   for expr in body.iter() {
+      collect_bound(expr, &mut scope);
+   }
+   // the annotation of the result is not a reference
+   let annotated = match body.as_slice() {
+      [Ident(ref colon), Ident(_), _, ..] => colon.value.as_slice() == ":",
+      _ => false
+   };
+   scopes.push(scope);
+   let result = body.move_iter().enumerate().map(|(idx, expr)| {
+      if annotated && idx < 2 { expr } else { resolve_expr(expr, scopes) }
+   }).collect();
+   scopes.pop();
+   result
+}
+
+// Adds the names bound by the forms in expr, outside of any function inside it, to the
+// scope of the function whose body it is in.
+fn collect_bound(expr: &ExprAst, scope: &mut Scope) {
+   let sast = match *expr {
+      Sexpr(ref sast) => sast,
+      Array(ref ast) => {
+         for item in ast.items.iter() {
+            collect_bound(item, scope);
+         }
+         return;
+      }
+      _ => return
+   };
+   match sast.op.value.as_slice() {
+      "quote" | "deftest" | "fn" => return,
+      "import" => scope.opaque = true,
+      "define" | "defconst" => match sast.operands.as_slice().get(0) {
+         Some(pat) => collect_pattern(pat, &mut scope.bound),
+         None => {}
+      },
+      "catch" => match sast.operands.as_slice().get(sast.catch_name_index()) {
+         Some(pat) => collect_pattern(pat, &mut scope.bound),
+         None => {}
+      },
+      "defn" => {
+         match sast.operands.as_slice().get(0) {
+            Some(name) => collect_pattern(name, &mut scope.bound),
+            None => {}
+         }
+         return;
+      }
+      "let" | "letrec" | "with-open" => match sast.operands.as_slice().get(0) {
+         Some(&Array(ref bindings)) => for (idx, item) in bindings.items.iter().enumerate() {
+            if idx % 2 == 0 {
+               collect_pattern(item, &mut scope.bound);
+            }
+         },
+         _ => {}
+      },
+      "match" => for (idx, operand) in sast.operands.iter().enumerate() {
+         if idx % 2 == 1 {
+            collect_pattern(operand, &mut scope.bound);
+         }
+      },
+      "defprotocol" => {
+         for operand in sast.operands.iter().skip(1) {
+            match *operand {
+               Sexpr(ref method) => { scope.bound.insert(method.op.value.clone()); }
+               _ => {}
+            }
+         }
+         return;
+      }
+      _ => {}
+   }
+   for operand in sast.operands.iter() {
+      collect_bound(operand, scope);
+   }
+}
+
+// Adds every name that could be bound by the pattern.
+fn collect_pattern(pat: &ExprAst, bound: &mut HashSet<String>) {
+   match *pat {
+      Ident(ref ast) => {
+         let name = ast.value.as_slice();
+         bound.insert(if name.ends_with("...") { name.slice_to(name.len() - 3) } else { name }.to_string());
+      }
+      Array(ref ast) => for item in ast.items.iter() { collect_pattern(item, bound) },
+      List(ref ast) => for item in ast.items.iter() { collect_pattern(item, bound) },
+      Sexpr(ref ast) => {
+         bound.insert(ast.op.value.clone());
+         for operand in ast.operands.iter() {
+            collect_pattern(operand, bound);
+         }
+      }
+      _ => {}
+   }
+}
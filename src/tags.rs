@@ -0,0 +1,117 @@
+use ast::*;
+
+// Indexes the names given by define, defglobal, defconst, defn, defstruct and defmacro
+// so that editors can jump to them.  The index is written in the format of ctags (a
+// "tags" file) or etags (a "TAGS" file).
+
+pub struct Definition {
+   pub name: String,
+   pub kind: char,
+   pub span: Span
+}
+
+struct Collector {
+   definitions: Vec<Definition>
+}
+
+// Finds every definition in the code, including those nested inside other forms.
+pub fn collect_definitions(expr: &ExprAst) -> Vec<Definition> {
+   let mut collector = Collector { definitions: vec!() };
+   expr.walk(&mut collector);
+   collector.definitions
+}
+
+impl Visitor for Collector {
+   fn visit(&mut self, expr: &ExprAst) -> bool {
+      let ast = match *expr {
+         Sexpr(ref ast) => ast,
+         _ => return true
+      };
+      let kind = match ast.op.value.as_slice() {
+         "define" | "defglobal" | "defdynamic" => match ast.operands.as_slice().last() {
+            Some(&Sexpr(ref value)) if value.op.value.as_slice() == "fn" => Some('f'),
+            _ => Some('v')
+         },
+         "defn" => Some('f'),
+         "defconst" => Some('v'),
+         "defstruct" => Some('s'),
+         "defmacro" => Some('m'),
+         _ => None
+      };
+      match (kind, ast.operands.as_slice().get(0)) {
+         (Some(kind), Some(&Ident(ref name))) => self.definitions.push(Definition {
+            name: name.value.clone(),
+            kind: kind,
+            span: ast.span.clone()
+         }),
+         _ => {}
+      }
+      true
+   }
+}
+
+// One line per definition, sorted by name as ctags expects.
+pub fn ctags(files: &[(String, String, Vec<Definition>)]) -> String {
+   let mut lines = vec!();
+   for &(ref file, _, ref definitions) in files.iter() {
+      for definition in definitions.iter().filter(|definition| definition.span.is_known()) {
+         lines.push(format!("{}\t{}\t{};\"\t{}", definition.name, file, definition.span.line, definition.kind));
+      }
+   }
+   lines.sort();
+   let mut result = String::from_str("!_TAG_FILE_FORMAT\t2\t//\n!_TAG_FILE_SORTED\t1\t//\n");
+   for line in lines.iter() {
+      result.push_str(line.as_slice());
+      result.push_char('\n');
+   }
+   result
+}
+
+// A section per file listing the line each definition starts on.  etags wants the text
+// and byte offset of that line, so the files' code is given along with the definitions.
+pub fn etags(files: &[(String, String, Vec<Definition>)]) -> String {
+   let mut result = String::new();
+   for &(ref file, ref code, ref definitions) in files.iter() {
+      let lines: Vec<&str> = code.as_slice().lines().collect();
+      let mut offsets = vec!(0u);
+      for line in code.as_slice().split('\n') {
+         let next = *offsets.last().unwrap() + line.len() + 1;
+         offsets.push(next);
+      }
+      let mut section = String::new();
+      // a definition whose span is not known has no line to point to
+      for definition in definitions.iter().filter(|definition| definition.span.is_known()) {
+         let line = definition.span.line;
+         let text = lines.as_slice().get(line - 1).map(|text| text.trim_right()).unwrap_or("");
+         section.push_str(format!("{}\x7f{}\x01{},{}\n", text, definition.name, line, offsets[line - 1]).as_slice());
+      }
+      result.push_str(format!("\x0c\n{},{}\n{}", file, section.len(), section).as_slice());
+   }
+   result
+}
+
+#[cfg(test)]
+mod test {
+   use ast::Span;
+   use super::{Definition, ctags, etags};
+
+   fn files() -> Vec<(String, String, Vec<Definition>)> {
+      let code = "(import \"../lib/std\")\n(define x 1)\n".to_string();
+      let definitions = vec!(
+         Definition { name: "x".to_string(), kind: 'v', span: Span::new(2, 1, 22, 34) },
+         Definition { name: "made".to_string(), kind: 'f', span: Span::unknown() }
+      );
+      vec!(("x.irl".to_string(), code, definitions))
+   }
+
+   #[test]
+   fn etags_skips_unknown_spans() {
+      let section = "(define x 1)\x7fx\x012,22\n";
+      assert_eq!(etags(files().as_slice()), format!("\x0c\nx.irl,{}\n{}", section.len(), section));
+   }
+
+   #[test]
+   fn ctags_skips_unknown_spans() {
+      assert!(!ctags(files().as_slice()).as_slice().contains("made"));
+   }
+}
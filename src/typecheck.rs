@@ -0,0 +1,346 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::*;
+use interp;
+use interp::{EnvCode, Environment, Interpreter};
+
+// Optional type annotations and a best-effort check of them made without running the code.
+// A parameter can be annotated as name:type and a function's result by writing : type
+// after its parameters, e.g. (fn [x:integer y:float] : float ...).  The interpreter ignores
+// the annotations; the checker reports only what is certain from the code alone: the
+// number of arguments given to builtins and to functions defined with define, and the
+// types of arguments that are literals, annotated parameters or calls to functions with an
+// annotated result.
+
+pub struct Problem {
+   pub span: Span,
+   pub message: String
+}
+
+// What is known about a function defined with define.
+struct Signature {
+   min: uint,
+   max: Option<uint>,
+   params: Vec<Option<String>>,
+   result: Option<String>
+}
+
+struct Checker {
+   builtins: Rc<RefCell<Environment>>,
+   functions: HashMap<String, Signature>,
+   // the local names in scope, innermost last, with their types where known
+   locals: Vec<(String, Option<String>)>,
+   problems: Vec<Problem>
+}
+
+// Splits an annotated parameter name like x:integer into the name and the type.  Keywords
+// (names starting with :) are not annotated.
+pub fn split_annotation(name: &str) -> (String, Option<String>) {
+   match name.find(':') {
+      Some(pos) if pos > 0 => (name.slice_to(pos).to_string(), Some(name.slice_from(pos + 1).to_string())),
+      _ => (name.to_string(), None)
+   }
+}
+
+// The parameters with their annotations removed.
+pub fn strip_annotations(params: ArrayAst) -> ArrayAst {
+   let items = params.items.move_iter().map(|param| match param {
+      Ident(ast) => Ident(IdentAst::new(split_annotation(ast.value.as_slice()).val0())),
+      Sexpr(mut ast) => {
+         ast.op = IdentAst::new(split_annotation(ast.op.value.as_slice()).val0());
+         Sexpr(ast)
+      }
+      other => other
+   }).collect();
+   ArrayAst::new(items)
+}
+
+// Removes the : type written before a function's body, returning the type.
+pub fn strip_result_type(body: &mut Vec<ExprAst>) -> Option<String> {
+   let annotated = match body.as_slice() {
+      [Ident(ref colon), Ident(_), _, ..] => colon.value.as_slice() == ":",
+      _ => false
+   };
+   if !annotated {
+      return None;
+   }
+   body.remove(0);
+   match body.remove(0) {
+      Some(Ident(ast)) => Some(ast.value),
+      _ => unreachable!()
+   }
+}
+
+pub fn check(root: &ExprAst) -> Vec<Problem> {
+   let mut checker = Checker {
+      builtins: Interpreter::new().env,
+      functions: HashMap::new(),
+      locals: vec!(),
+      problems: vec!()
+   };
+   // functions can be called before (in the file) they are defined
+   match *root {
+      Root(ref ast) => for expr in ast.asts.iter() {
+         checker.collect_signature(expr);
+      },
+      _ => {}
+   }
+   checker.infer(root);
+   checker.problems
+}
+
+impl Checker {
+   fn collect_signature(&mut self, expr: &ExprAst) {
+      let sast = match *expr {
+         Sexpr(ref sast) if sast.op.value.as_slice() == "define" => sast.clone(),
+         Sexpr(ref sast) => match sast.expand_defn() {
+            Some(define) => define,
+            None => return
+         },
+         _ => return
+      };
+      let (name, value) = match (sast.operands.as_slice().get(0), sast.operands.last()) {
+         (Some(&Ident(ref name)), Some(&Sexpr(ref value))) if value.op.value.as_slice() == "fn" => (name.value.clone(), value),
+         _ => return
+      };
+      let (params, body) = match value.operands.as_slice() {
+         [Array(ref params), ..rest] => (params, rest),
+         _ => return
+      };
+      let mut body = body.to_vec();
+      let result = strip_result_type(&mut body);
+      let types = params.items.iter().filter_map(|param| match *param {
+         Ident(ref ast) if ast.value.as_slice().ends_with("...") => None,
+         Ident(ref ast) => Some(split_annotation(ast.value.as_slice()).val1()),
+         Sexpr(ref ast) => Some(split_annotation(ast.op.value.as_slice()).val1()),
+         _ => Some(None)
+      }).collect();
+      let (min, max) = interp::arity(&strip_annotations(params.clone()));
+      self.functions.insert(name, Signature {
+         min: min,
+         max: max,
+         params: types,
+         result: result
+      });
+   }
+
+   fn report(&mut self, span: &Span, message: String) {
+      self.problems.push(Problem {
+         span: span.clone(),
+         message: message
+      });
+   }
+
+   fn check_type_name(&mut self, span: &Span, name: &str) {
+      let known = name == "any" || name == "number" || interp::type_names().iter().any(|known| *known == name);
+      if !known {
+         self.report(span, format!("{} is not a type", name));
+      }
+   }
+
+   fn local(&self, name: &str) -> Option<&Option<String>> {
+      self.locals.iter().rev().find(|&&(ref local, _)| local.as_slice() == name).map(|pair| match *pair { (_, ref ty) => ty })
+   }
+
+   // The type of the value of expr if it is known, reporting any problems in expr.
+   fn infer(&mut self, expr: &ExprAst) -> Option<String> {
+      match *expr {
+         Root(ref ast) => {
+            for expr in ast.asts.iter() {
+               self.infer(expr);
+            }
+            None
+         }
+         Ident(ref ast) => match self.local(ast.value.as_slice()) {
+            Some(ty) => ty.clone(),
+            None => None
+         },
+         Array(ref ast) => {
+            for item in ast.items.iter() {
+               self.infer(item);
+            }
+            Some("array".to_string())
+         }
+         Sexpr(ref sast) => self.infer_sexpr(sast),
+         ref other => interp::type_name(other).map(|name| name.to_string())
+      }
+   }
+
+   fn infer_body(&mut self, body: &[ExprAst]) -> Option<String> {
+      let mut result = Some("nil".to_string());
+      for expr in body.iter() {
+         result = self.infer(expr);
+      }
+      result
+   }
+
+   fn infer_sexpr(&mut self, sast: &SexprAst) -> Option<String> {
+      let operands = sast.operands.as_slice();
+      match sast.op.value.as_slice() {
+         "quote" | "defprotocol" | "deftest" | "defmacro" => None,
+         "fn" => {
+            match operands {
+               [Array(ref params), ..body] => self.infer_function(sast, params, body),
+               _ => for operand in operands.iter() {
+                  match *operand {
+                     List(ref clause) => match clause.items.as_slice() {
+                        [Array(ref params), ..body] => self.infer_function(sast, params, body),
+                        _ => {}
+                     },
+                     _ => {}
+                  }
+               }
+            }
+            Some("code".to_string())
+         }
+         "let" | "letrec" | "with-open" => {
+            let depth = self.locals.len();
+            // the names bound by letrec are in scope in all of its values
+            match (sast.op.value.as_slice(), operands.get(0)) {
+               ("letrec", Some(&Array(ref bindings))) => for binding in bindings.items.as_slice().chunks(2) {
+                  self.bind_pattern(&binding[0], None);
+               },
+               _ => {}
+            }
+            match operands.get(0) {
+               Some(&Array(ref bindings)) => for binding in bindings.items.as_slice().chunks(2) {
+                  let ty = if binding.len() == 2 { self.infer(&binding[1]) } else { None };
+                  self.bind_pattern(&binding[0], ty);
+               },
+               _ => {}
+            }
+            let result = if operands.len() > 1 { self.infer_body(operands.slice_from(1)) } else { None };
+            self.locals.truncate(depth);
+            result
+         }
+         "do" => self.infer_body(operands),
+         "when" | "unless" => match sast.expand_conditional() {
+            Some(expanded) => self.infer_sexpr(&expanded),
+            None => None
+         },
+         "define" | "defglobal" | "defconst" | "defdynamic" => {
+            for operand in operands.iter().skip(1) {
+               self.infer(operand);
+            }
+            None
+         }
+         "defn" => match sast.expand_defn() {
+            Some(define) => self.infer_sexpr(&define),
+            None => None
+         },
+         "if" => {
+            let types: Vec<Option<String>> = operands.iter().map(|operand| self.infer(operand)).collect();
+            match types.as_slice() {
+               [_, ref ontrue, ref onfalse] if ontrue == onfalse => ontrue.clone(),
+               _ => None
+            }
+         }
+         "match" => {
+            for (idx, operand) in operands.iter().enumerate() {
+               // the patterns are not evaluated
+               if idx == 0 || idx % 2 == 0 {
+                  self.infer(operand);
+               }
+            }
+            None
+         }
+         "extend" => {
+            for operand in operands.iter().skip(2) {
+               self.infer(operand);
+            }
+            None
+         }
+         name => {
+            let args: Vec<Option<String>> = operands.iter().map(|operand| self.infer(operand)).collect();
+            // keyword arguments make the positions of the others unknown
+            let keywords = operands.iter().any(|operand| match *operand {
+               Keyword(_) => true,
+               _ => false
+            });
+            if self.local(name).is_some() || keywords {
+               return None;
+            }
+            self.check_call(sast, name, args)
+         }
+      }
+   }
+
+   fn infer_function(&mut self, sast: &SexprAst, params: &ArrayAst, body: &[ExprAst]) {
+      let depth = self.locals.len();
+      for param in params.items.iter() {
+         let name = match *param {
+            Ident(ref ast) => ast.value.clone(),
+            Sexpr(ref ast) => ast.op.value.clone(),
+            ref other => {
+               self.bind_pattern(other, None);
+               continue;
+            }
+         };
+         let (name, ty) = split_annotation(name.as_slice());
+         match ty {
+            Some(ref ty) => self.check_type_name(&sast.span, ty.as_slice()),
+            None => {}
+         }
+         let name = if name.as_slice().ends_with("...") { name.as_slice().slice_to(name.len() - 3).to_string() } else { name };
+         self.locals.push((name, ty));
+      }
+      let mut body = body.to_vec();
+      let declared = strip_result_type(&mut body);
+      let actual = self.infer_body(body.as_slice());
+      self.locals.truncate(depth);
+      match declared {
+         Some(declared) => {
+            self.check_type_name(&sast.span, declared.as_slice());
+            match actual {
+               Some(ref actual) if !interp::type_matches(declared.as_slice(), actual.as_slice()) =>
+                  self.report(&sast.span, format!("function declared to return {} returns {}", declared, actual)),
+               _ => {}
+            }
+         }
+         None => {}
+      }
+   }
+
+   // Brings the identifiers in a pattern into scope, with the given type if the pattern is
+   // a single identifier.
+   fn bind_pattern(&mut self, pat: &ExprAst, ty: Option<String>) {
+      match *pat {
+         Ident(ref ast) => self.locals.push((ast.value.clone(), ty)),
+         Array(ref ast) => for item in ast.items.iter() { self.bind_pattern(item, None) },
+         Sexpr(ref ast) => {
+            self.locals.push((ast.op.value.clone(), None));
+            for operand in ast.operands.iter() {
+               self.bind_pattern(operand, None);
+            }
+         }
+         _ => {}
+      }
+   }
+
+   fn check_call(&mut self, sast: &SexprAst, name: &str, args: Vec<Option<String>>) -> Option<String> {
+      let (min, max, expected, result) = match self.functions.find_equiv(&name) {
+         Some(sig) => (sig.min, sig.max, sig.params.clone(), sig.result.clone()),
+         None => match self.builtins.borrow().find(&name.to_string()) {
+            Some(EnvCode(builtin)) => {
+               let expected = range(0, args.len()).map(|idx| builtin.expected_type(idx).map(|ty| ty.to_string())).collect();
+               (builtin.min, builtin.max, expected, None)
+            }
+            _ => return None
+         }
+      };
+      match interp::arity_error(name, min, max, args.len()) {
+         Some(message) => self.report(&sast.span, message),
+         None => {}
+      }
+      for (idx, arg) in args.iter().enumerate() {
+         match (expected.as_slice().get(idx), arg) {
+            (Some(&Some(ref expected)), &Some(ref actual)) if !interp::type_matches(expected.as_slice(), actual.as_slice()) =>
+               self.report(&sast.span, format!("{} expects {} for argument {} but is given {}", name, expected, idx + 1, actual)),
+            _ => {}
+         }
+      }
+      result
+   }
+}